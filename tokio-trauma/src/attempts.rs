@@ -0,0 +1,46 @@
+//! Counting the attempts the retry middleware makes for a request, and timing the last one
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Instant;
+
+use async_trait::async_trait;
+use http::Extensions;
+use reqwest::{Request, Response};
+use reqwest_middleware::{Middleware, Next};
+
+/// Request extension counting the attempts made for the request
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Attempts(Arc<AtomicU32>);
+
+impl Attempts {
+    pub(crate) fn count(&self) -> u32 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Response extension holding when the request of the response was sent, its last attempt when retried
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Sent(Instant);
+
+impl Sent {
+    pub(crate) fn of(response: &Response) -> Option<Instant> {
+        response.extensions().get::<Sent>().map(|sent| sent.0)
+    }
+}
+
+/// Middleware below the retry middleware, so it sees every attempt of a request
+pub(crate) struct AttemptCounter;
+
+#[async_trait]
+impl Middleware for AttemptCounter {
+    async fn handle(&self, req: Request, extensions: &mut Extensions, next: Next<'_>) -> reqwest_middleware::Result<Response> {
+        if let Some(attempts) = extensions.get::<Attempts>() {
+            attempts.0.fetch_add(1, Ordering::Relaxed);
+        }
+        let sent = Instant::now();
+        let mut response = next.run(req, extensions).await?;
+        response.extensions_mut().insert(Sent(sent));
+        Ok(response)
+    }
+}
@@ -0,0 +1,80 @@
+//! `indicatif` progress bars driven by progress events
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+
+use crate::download::Status;
+use crate::progress::ProgressEvent;
+
+const BAR_TEMPLATE: &str = "{msg:30!} [{bar:40}] {bytes}/{total_bytes} {bytes_per_sec} {eta}";
+const SPINNER_TEMPLATE: &str = "{msg:30!} {spinner} {bytes} {bytes_per_sec}";
+
+/// One bar per running download and a bar totaling the batch
+pub(crate) struct Bars {
+    multi: MultiProgress,
+    total: ProgressBar,
+    downloads: Mutex<HashMap<String, ProgressBar>>,
+}
+
+impl Bars {
+    pub(crate) fn new() -> Self {
+        let multi = MultiProgress::new();
+        let total = multi.add(ProgressBar::new(0).with_style(style(BAR_TEMPLATE)).with_message("total"));
+        Self { multi, total, downloads: Mutex::default() }
+    }
+
+    pub(crate) fn update(&self, event: &ProgressEvent<'_>) {
+        let mut downloads = self.downloads.lock().unwrap_or_else(|err| err.into_inner());
+        match *event {
+            ProgressEvent::Started { download, total, resumed } => {
+                let bar = match total {
+                    Some(total) => {
+                        self.total.inc_length(total);
+                        ProgressBar::new(total).with_style(style(BAR_TEMPLATE))
+                    }
+                    None => ProgressBar::new_spinner().with_style(style(SPINNER_TEMPLATE)),
+                };
+                let bar = self.multi.insert_before(&self.total, bar.with_message(download.filename.clone()));
+                bar.set_position(resumed);
+                // The total only covers the downloads of known size, or it would go past 100%
+                if total.is_some() {
+                    self.total.inc(resumed);
+                }
+                downloads.insert(download.filename.clone(), bar);
+            }
+            ProgressEvent::Progress { download, bytes, downloaded, total } => {
+                if let Some(bar) = downloads.get(&download.filename) {
+                    bar.set_position(downloaded);
+                }
+                if total.is_some() {
+                    self.total.inc(bytes);
+                }
+            }
+            ProgressEvent::Paused { download } => {
+                if let Some(bar) = downloads.remove(&download.filename) {
+                    bar.abandon_with_message(format!("{} (paused)", download.filename));
+                }
+            }
+            ProgressEvent::Finished { summary } => {
+                if let Some(bar) = downloads.remove(&summary.download().filename) {
+                    match summary.status() {
+                        Status::Fail(err) => bar.abandon_with_message(format!("{}: {}", summary.download().filename, err)),
+                        _ => bar.finish_and_clear(),
+                    }
+                }
+            }
+        }
+    }
+
+    pub(crate) fn finish(&self) {
+        self.total.finish();
+    }
+}
+
+fn style(template: &str) -> ProgressStyle {
+    ProgressStyle::with_template(template)
+        .unwrap_or_else(|_| ProgressStyle::default_bar())
+        .progress_chars("=> ")
+}
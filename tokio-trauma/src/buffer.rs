@@ -0,0 +1,315 @@
+//! Write buffers reused by the downloads of a batch
+//!
+//! Response chunks are accumulated into a pooled buffer and written to the file once it is
+//! full, so a batch allocates at most one buffer per concurrent download instead of one per
+//! download, and small chunks don't turn into as many small writes.
+//!
+//! With a memory limit, buffered bytes hold permits of a semaphore shared by the batch until they
+//! are written. A download whose chunk doesn't fit first writes its own buffer, then waits for
+//! the other downloads to write theirs, so a slow disk slows the downloads down instead of
+//! growing the memory they buffer.
+
+use std::io;
+use std::sync::Mutex;
+
+#[cfg(feature = "zstd")]
+use async_compression::tokio::write::ZstdDecoder;
+#[cfg(feature = "compress")]
+use async_compression::tokio::write::{GzipEncoder, ZstdEncoder};
+use bytes::BytesMut;
+use tokio::fs::File;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::Semaphore;
+
+#[cfg(feature = "compress")]
+use crate::downloader::Compression;
+
+/// Default capacity of a write buffer
+pub(crate) const DEFAULT_WRITE_BUFFER: usize = 64 * 1024;
+
+pub(crate) struct BufferPool {
+    buffers: Mutex<Vec<BytesMut>>,
+    capacity: usize,
+    /// permits for the bytes buffered by all the writers of the pool
+    memory: Option<(Semaphore, usize)>,
+}
+
+impl BufferPool {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self { buffers: Mutex::default(), capacity: capacity.max(1), memory: None }
+    }
+
+    /// Bound the bytes buffered at once by all the writers of the pool
+    pub(crate) fn with_memory_limit(mut self, limit: Option<usize>) -> Self {
+        self.memory = limit.map(|limit| {
+            let limit = limit.clamp(1, Semaphore::MAX_PERMITS);
+            (Semaphore::new(limit), limit)
+        });
+        self
+    }
+
+    fn take(&self) -> BytesMut {
+        let mut buffers = self.buffers.lock().unwrap_or_else(|err| err.into_inner());
+        buffers.pop().unwrap_or_else(|| BytesMut::with_capacity(self.capacity))
+    }
+
+    fn put(&self, mut buffer: BytesMut) {
+        buffer.clear();
+        let mut buffers = self.buffers.lock().unwrap_or_else(|err| err.into_inner());
+        buffers.push(buffer);
+    }
+}
+
+/// Where the buffered bytes are written
+enum Sink {
+    File(File),
+    #[cfg(feature = "compress")]
+    Gzip(GzipEncoder<File>),
+    #[cfg(feature = "compress")]
+    Zstd(ZstdEncoder<File>),
+    #[cfg(feature = "zstd")]
+    Unzstd(ZstdDecoder<File>),
+}
+
+impl Sink {
+    fn writer(&mut self) -> &mut (dyn AsyncWrite + Unpin + Send) {
+        match self {
+            Sink::File(file) => file,
+            #[cfg(feature = "compress")]
+            Sink::Gzip(encoder) => encoder,
+            #[cfg(feature = "compress")]
+            Sink::Zstd(encoder) => encoder,
+            #[cfg(feature = "zstd")]
+            Sink::Unzstd(decoder) => decoder,
+        }
+    }
+
+    fn file(&self) -> &File {
+        match self {
+            Sink::File(file) => file,
+            #[cfg(feature = "compress")]
+            Sink::Gzip(encoder) => encoder.get_ref(),
+            #[cfg(feature = "compress")]
+            Sink::Zstd(encoder) => encoder.get_ref(),
+            #[cfg(feature = "zstd")]
+            Sink::Unzstd(decoder) => decoder.get_ref(),
+        }
+    }
+}
+
+/// File writer accumulating chunks in a buffer taken from the pool
+///
+/// Like a `BufWriter`, buffered bytes are lost unless flushed before dropping.
+pub(crate) struct PooledWriter<'a> {
+    sink: Sink,
+    buffer: BytesMut,
+    pool: &'a BufferPool,
+    /// memory permits held for the buffered bytes
+    held: usize,
+}
+
+impl<'a> PooledWriter<'a> {
+    pub(crate) fn new(file: File, pool: &'a BufferPool) -> Self {
+        Self { sink: Sink::File(file), buffer: pool.take(), pool, held: 0 }
+    }
+
+    /// Compress the written bytes into the file
+    #[cfg(feature = "compress")]
+    pub(crate) fn compressed(file: File, pool: &'a BufferPool, compression: Compression) -> Self {
+        let sink = match compression {
+            Compression::Gzip => Sink::Gzip(GzipEncoder::new(file)),
+            Compression::Zstd => Sink::Zstd(ZstdEncoder::new(file)),
+        };
+        Self { sink, buffer: pool.take(), pool, held: 0 }
+    }
+
+    /// Decompress the written zstd bytes into the file
+    #[cfg(feature = "zstd")]
+    pub(crate) fn unzstd(file: File, pool: &'a BufferPool) -> Self {
+        Self { sink: Sink::Unzstd(ZstdDecoder::new(file)), buffer: pool.take(), pool, held: 0 }
+    }
+
+    pub(crate) async fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+        if self.buffer.len() + chunk.len() > self.pool.capacity {
+            self.write_buffer().await?;
+        }
+        // Chunks at least as large as the buffer gain nothing from being copied
+        if chunk.len() >= self.pool.capacity {
+            self.sink.writer().write_all(chunk).await
+        } else {
+            self.reserve(chunk.len()).await?;
+            self.buffer.extend_from_slice(chunk);
+            Ok(())
+        }
+    }
+
+    /// Hold memory permits for `len` more buffered bytes
+    async fn reserve(&mut self, len: usize) -> io::Result<()> {
+        let Some((memory, limit)) = &self.pool.memory else {
+            return Ok(());
+        };
+        // A chunk larger than the whole limit would never get its permits
+        let permits = len.min(*limit) as u32;
+        if let Ok(permit) = memory.try_acquire_many(permits) {
+            permit.forget();
+        } else {
+            // Waiting while holding permits could deadlock writers waiting for each other
+            self.write_buffer().await?;
+            memory.acquire_many(permits).await.map_err(io::Error::other)?.forget();
+        }
+        self.held += permits as usize;
+        Ok(())
+    }
+
+    async fn write_buffer(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            self.sink.writer().write_all(&self.buffer).await?;
+            self.buffer.clear();
+        }
+        self.release();
+        Ok(())
+    }
+
+    fn release(&mut self) {
+        if let Some((memory, _)) = &self.pool.memory {
+            memory.add_permits(std::mem::take(&mut self.held));
+        }
+    }
+
+    pub(crate) async fn flush(&mut self) -> io::Result<()> {
+        self.write_buffer().await?;
+        self.sink.writer().flush().await
+    }
+
+    /// Flush the buffered bytes and end the compressed or decompressed stream, if any
+    pub(crate) async fn finish(&mut self) -> io::Result<()> {
+        self.write_buffer().await?;
+        match &mut self.sink {
+            Sink::File(file) => file.flush().await,
+            #[cfg(any(feature = "compress", feature = "zstd"))]
+            sink => sink.writer().shutdown().await,
+        }
+    }
+
+    /// Bytes written but not handed to the file yet
+    pub(crate) fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub(crate) fn get_ref(&self) -> &File {
+        self.sink.file()
+    }
+}
+
+impl Drop for PooledWriter<'_> {
+    fn drop(&mut self) {
+        self.release();
+        self.pool.put(std::mem::take(&mut self.buffer));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate test;
+
+    use test::Bencher;
+    use tokio::fs::File;
+    use tokio::io::{AsyncWriteExt, BufWriter};
+    use tokio::runtime::Runtime;
+
+    use crate::buffer::{BufferPool, PooledWriter, DEFAULT_WRITE_BUFFER};
+    use crate::testing::temp_dir;
+
+    /// Split `total` bytes into chunks cycling through `sizes`, like a response body stream
+    fn chunks(sizes: &[usize], total: usize) -> Vec<Vec<u8>> {
+        let mut chunks = Vec::new();
+        let mut written = 0;
+        for (index, size) in sizes.iter().cycle().enumerate() {
+            if written >= total {
+                break;
+            }
+            let size = (*size).min(total - written);
+            chunks.push(vec![index as u8; size]);
+            written += size;
+        }
+        chunks
+    }
+
+    #[tokio::test]
+    async fn test_pooled_writer() {
+        let directory = temp_dir("pooled-writer");
+        let pool = BufferPool::new(16);
+        let chunks = chunks(&[3, 16, 40, 1, 15], 200);
+        let expected: Vec<u8> = chunks.concat();
+
+        let path = directory.join("file.bin");
+        let mut writer = PooledWriter::new(File::create(&path).await.unwrap(), &pool);
+        for chunk in &chunks {
+            writer.write(chunk).await.unwrap();
+        }
+        writer.flush().await.unwrap();
+        drop(writer);
+
+        assert_eq!(expected, std::fs::read(&path).unwrap());
+        assert_eq!(1, pool.buffers.lock().unwrap().len());
+        let _reused = PooledWriter::new(File::create(&path).await.unwrap(), &pool);
+        assert!(pool.buffers.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_memory_limit() {
+        let directory = temp_dir("memory-limit");
+        let pool = BufferPool::new(16).with_memory_limit(Some(20));
+        let memory = |pool: &BufferPool| pool.memory.as_ref().unwrap().0.available_permits();
+
+        let mut first = PooledWriter::new(File::create(directory.join("first.bin")).await.unwrap(), &pool);
+        let mut second = PooledWriter::new(File::create(directory.join("second.bin")).await.unwrap(), &pool);
+        first.write(&[1; 12]).await.unwrap();
+        assert_eq!(8, memory(&pool));
+        // The second writer waits once the first one writes its buffer
+        let (waiting, flushing) = tokio::join!(second.write(&[2; 10]), async {
+            tokio::task::yield_now().await;
+            first.flush().await
+        });
+        waiting.unwrap();
+        flushing.unwrap();
+        assert_eq!(10, memory(&pool));
+        drop(second);
+        assert_eq!(20, memory(&pool));
+        drop(first);
+    }
+
+    const BENCH_SIZE: usize = 4 * 1024 * 1024;
+    const BENCH_CHUNKS: &[usize] = &[1460, 8192, 16384, 2920];
+
+    #[bench]
+    fn bench_buf_writer(b: &mut Bencher) {
+        let runtime = Runtime::new().unwrap();
+        let path = temp_dir("bench-buf-writer").join("file.bin");
+        let chunks = chunks(BENCH_CHUNKS, BENCH_SIZE);
+        b.bytes = BENCH_SIZE as u64;
+        b.iter(|| runtime.block_on(async {
+            let mut writer = BufWriter::new(File::create(&path).await.unwrap());
+            for chunk in &chunks {
+                writer.write_all(chunk).await.unwrap();
+            }
+            writer.flush().await.unwrap();
+        }));
+    }
+
+    #[bench]
+    fn bench_pooled_writer(b: &mut Bencher) {
+        let runtime = Runtime::new().unwrap();
+        let path = temp_dir("bench-pooled-writer").join("file.bin");
+        let chunks = chunks(BENCH_CHUNKS, BENCH_SIZE);
+        let pool = BufferPool::new(DEFAULT_WRITE_BUFFER);
+        b.bytes = BENCH_SIZE as u64;
+        b.iter(|| runtime.block_on(async {
+            let mut writer = PooledWriter::new(File::create(&path).await.unwrap(), &pool);
+            for chunk in &chunks {
+                writer.write(chunk).await.unwrap();
+            }
+            writer.flush().await.unwrap();
+        }));
+    }
+}
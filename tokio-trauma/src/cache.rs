@@ -0,0 +1,95 @@
+//! In-process cache of downloaded content shared by downloads of the same url
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Mutex as AsyncMutex;
+use url::Url;
+
+use crate::download::ContentRange;
+
+/// Slot of a url, locked for the whole fetch so concurrent first fetches run only once
+pub(crate) type Slot = Arc<AsyncMutex<Option<Cached>>>;
+
+#[derive(Default)]
+pub(crate) struct ContentCache {
+    slots: Mutex<HashMap<Url, Slot>>,
+}
+
+impl ContentCache {
+    pub(crate) fn slot(&self, url: &Url) -> Slot {
+        let mut slots = self.slots.lock().unwrap_or_else(|err| err.into_inner());
+        slots.entry(url.clone()).or_default().clone()
+    }
+}
+
+/// A completed download of a url
+#[derive(Debug, Clone)]
+pub(crate) struct Cached {
+    pub(crate) etag: Option<String>,
+    pub(crate) size: u64,
+    pub(crate) path: PathBuf,
+}
+
+impl Cached {
+    /// Whether the probed resource is still the cached content, by ETag or else by size
+    pub(crate) fn matches(&self, probe: &ContentRange) -> bool {
+        match (&self.etag, &probe.etag) {
+            (Some(cached), Some(probed)) => cached == probed,
+            (None, None) => probe.size == Some(self.size),
+            _ => false,
+        }
+    }
+
+    /// Whether the cached file is still on disk with its original size
+    pub(crate) fn is_intact(&self) -> bool {
+        matches!(self.path.metadata(), Ok(metadata) if metadata.len() == self.size)
+    }
+}
+
+/// Make `target` a hard link of `source`, copying when linking is not possible
+pub(crate) fn link_or_copy(source: &Path, target: &Path) -> io::Result<()> {
+    if source == target {
+        return Ok(());
+    }
+    if let Some(parent) = target.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    if let Err(err) = std::fs::remove_file(target) {
+        if err.kind() != io::ErrorKind::NotFound {
+            return Err(err);
+        }
+    }
+    if std::fs::hard_link(source, target).is_err() {
+        std::fs::copy(source, target)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use reqwest::StatusCode;
+
+    use crate::cache::Cached;
+    use crate::download::ContentRange;
+
+    fn probe(size: Option<u64>, etag: Option<&str>) -> ContentRange {
+        ContentRange { status: StatusCode::OK, resume: true, size, etag: etag.map(str::to_string), last_modified: None }
+    }
+
+    #[test]
+    fn test_matches() {
+        let tagged = Cached { etag: Some("\"v1\"".into()), size: 10, path: PathBuf::new() };
+        assert!(tagged.matches(&probe(Some(20), Some("\"v1\""))));
+        assert!(!tagged.matches(&probe(Some(10), Some("\"v2\""))));
+        assert!(!tagged.matches(&probe(Some(10), None)));
+
+        let untagged = Cached { etag: None, ..tagged };
+        assert!(untagged.matches(&probe(Some(10), None)));
+        assert!(!untagged.matches(&probe(None, None)));
+    }
+}
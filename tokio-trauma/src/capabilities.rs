@@ -0,0 +1,101 @@
+//! Capabilities of the hosts of a batch, learned from the first content they serve
+//!
+//! Whether a host accepts ranges, its HTTP version and its content encoding are recorded from
+//! the first response serving content. A later download from the host with nothing on disk,
+//! where the probe would only tell whether ranges are accepted, skips its `HEAD` request and
+//! relies on the recorded capabilities instead. A host answering differently on its paths is
+//! marked inconsistent and its downloads are probed one by one again.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use reqwest::header::{ACCEPT_RANGES, CONTENT_ENCODING};
+use reqwest::{Response, StatusCode, Version};
+use url::Url;
+
+use crate::host;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct Capabilities {
+    pub(crate) ranges: bool,
+    pub(crate) version: Version,
+    pub(crate) encoding: Option<String>,
+}
+
+impl Capabilities {
+    fn of(response: &Response) -> Self {
+        let headers = response.headers();
+        let ranges = response.status() == StatusCode::PARTIAL_CONTENT
+            || headers.get(ACCEPT_RANGES).is_some_and(|val| val != "none");
+        let encoding = headers.get(CONTENT_ENCODING)
+            .and_then(|val| val.to_str().ok())
+            .map(str::to_string);
+        Self { ranges, version: response.version(), encoding }
+    }
+}
+
+/// The capabilities of every host met by a batch, `None` for an inconsistent host
+#[derive(Default)]
+pub(crate) struct HostCapabilities(Mutex<HashMap<String, Option<Capabilities>>>);
+
+impl HostCapabilities {
+    /// The host of `url` with its capabilities, if they are known and consistent
+    pub(crate) fn get(&self, url: &Url) -> Option<(String, Capabilities)> {
+        let key = key(url)?;
+        let hosts = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        let capabilities = hosts.get(&key)?.clone()?;
+        Some((key, capabilities))
+    }
+
+    /// Record the capabilities shown by the response serving the content of `url`
+    pub(crate) fn record(&self, url: &Url, response: &Response) {
+        let Some(key) = key(url) else {
+            return;
+        };
+        let capabilities = Capabilities::of(response);
+        let mut hosts = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        let known = hosts.entry(key.clone()).or_insert_with(|| Some(capabilities.clone()));
+        if known.as_ref().is_some_and(|known| known.ranges != capabilities.ranges) {
+            tracing::debug!("The host {} accepts ranges on some paths only, probing its downloads", key);
+            *known = None;
+        }
+    }
+}
+
+/// The normalized host and port of `url`
+fn key(url: &Url) -> Option<String> {
+    let host = host::url_host(url)?;
+    Some(match url.port_or_known_default() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use reqwest::Version;
+    use url::Url;
+
+    use crate::capabilities::{key, Capabilities, HostCapabilities};
+
+    #[test]
+    fn test_key() {
+        assert_eq!(Some("example.com:443".into()), key(&Url::parse("https://Example.com/file").unwrap()));
+        assert_eq!(Some("example.com:8080".into()), key(&Url::parse("http://example.com:8080/").unwrap()));
+    }
+
+    #[test]
+    fn test_inconsistent_host() {
+        let response = |accept_ranges: &str| reqwest::Response::from(
+            http::Response::builder().header("Accept-Ranges", accept_ranges).body("").unwrap());
+        let hosts = HostCapabilities::default();
+        let url = Url::parse("http://example.com/file").unwrap();
+        assert_eq!(None, hosts.get(&url));
+
+        hosts.record(&url, &response("bytes"));
+        let capabilities = Capabilities { ranges: true, version: Version::HTTP_11, encoding: None };
+        assert_eq!(Some(("example.com:80".into(), capabilities)), hosts.get(&url));
+        hosts.record(&Url::parse("http://example.com/other").unwrap(), &response("none"));
+        assert_eq!(None, hosts.get(&url));
+    }
+}
@@ -0,0 +1,119 @@
+//! HAR-like capture of the http exchanges made while downloading
+//!
+//! Every `fetch` appends one JSON object per line to the capture file, containing the
+//! request url, the probe result, the response status and headers, timings and the bytes written.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use reqwest::header::HeaderMap;
+use reqwest::Response;
+use serde_json::{json, Value};
+
+use crate::download::{ContentRange, Download, Status, Summary};
+
+pub(crate) struct Capture {
+    file: Mutex<File>,
+}
+
+impl Capture {
+    pub(crate) fn open(path: &Path) -> std::io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file: Mutex::new(file) })
+    }
+
+    /// Append an entry as a single JSON line, failures are only logged
+    pub(crate) fn write(&self, entry: Value) {
+        let mut line = entry.to_string();
+        line.push('\n');
+        let mut file = self.file.lock().unwrap_or_else(|err| err.into_inner());
+        if let Err(err) = file.write_all(line.as_bytes()) {
+            tracing::warn!("Failed to write capture entry: {}", err);
+        }
+    }
+}
+
+/// Metadata collected for a single `fetch`
+pub(crate) struct Entry {
+    started: SystemTime,
+    url: String,
+    filename: String,
+    probe: Option<ContentRange>,
+    range: Option<String>,
+    response: Option<Value>,
+}
+
+impl Entry {
+    pub(crate) fn new(download: &Download) -> Self {
+        Self {
+            started: SystemTime::now(),
+            url: download.redacted_url().to_string(),
+            filename: download.filename.clone(),
+            probe: None,
+            range: None,
+            response: None,
+        }
+    }
+
+    pub(crate) fn probe(&mut self, content_range: &ContentRange) {
+        self.probe = Some(content_range.clone());
+    }
+
+    pub(crate) fn range(&mut self, range: &str) {
+        self.range = Some(range.to_string());
+    }
+
+    pub(crate) fn response(&mut self, response: &Response, elapsed: Duration) {
+        self.response = Some(json!({
+            "url": response.url().as_str(),
+            "status": response.status().as_u16(),
+            "version": format!("{:?}", response.version()),
+            "headers": headers(response.headers()),
+            "elapsed_ms": elapsed.as_millis() as u64,
+        }));
+    }
+
+    pub(crate) fn finish(self, summary: &Summary, elapsed: Duration) -> Value {
+        let started = self.started.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let (status, message) = match summary.status() {
+            Status::Fail(message) => ("fail", Some(message.clone())),
+            Status::NotStarted => ("not_started", None),
+            Status::Skipped(reason) => ("skipped", Some(reason.to_string())),
+            Status::Success => ("success", None),
+        };
+
+        json!({
+            "started_ms": started.as_millis() as u64,
+            "elapsed_ms": elapsed.as_millis() as u64,
+            "url": self.url,
+            "filename": self.filename,
+            "probe": self.probe.map(|probe| json!({
+                "status": probe.status.as_u16(),
+                "resume": probe.resume,
+                "size": probe.size,
+                "etag": probe.etag,
+            })),
+            "request": { "range": self.range },
+            "response": self.response,
+            "bytes": summary.size(),
+            "path": summary.path().to_string_lossy(),
+            "status": status,
+            "message": message,
+        })
+    }
+}
+
+fn headers(headers: &HeaderMap) -> Value {
+    headers.iter()
+        .map(|(name, value)| json!({
+            "name": name.as_str(),
+            "value": value.to_str().unwrap_or("<binary>"),
+        }))
+        .collect()
+}
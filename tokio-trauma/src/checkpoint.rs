@@ -0,0 +1,227 @@
+//! Batch checkpoint recording the downloads that did not complete yet
+//!
+//! The checkpoint file is a JSON array of the queued and running downloads, rewritten whenever
+//! a download starts being tracked or completes and removed once nothing remains. A batch whose
+//! future was dropped, e.g. on shutdown, leaves the remaining work behind for [`load`], and the
+//! partial files of its running downloads continue through the per-file resume.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use tokio_trauma::checkpoint;
+//! use tokio_trauma::downloader::DownloaderBuilder;
+//!
+//! # async fn run() -> tokio_trauma::error::Result<()> {
+//! let downloader = DownloaderBuilder::new().checkpoint("batch.checkpoint").build();
+//! let remaining = checkpoint::load("batch.checkpoint")?;
+//! let report = downloader.download(&remaining).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fs;
+use std::io;
+use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde_json::{json, Value};
+use snafu::{location, Location, OptionExt, ResultExt};
+use url::Url;
+
+use crate::download::{ByteRange, DigestKind, Download, Status, Summary};
+use crate::error::{InvalidCheckpointSnafu, IoSnafu, ParseUrlSnafu, Result};
+#[cfg(feature = "minisign")]
+use crate::signature::DetachedSignature;
+
+pub(crate) struct Checkpoint {
+    path: PathBuf,
+    pending: Mutex<Vec<Download>>,
+}
+
+impl Checkpoint {
+    /// Start an empty checkpoint, replacing the file of an earlier batch
+    pub(crate) fn create(path: &Path) -> io::Result<Self> {
+        let checkpoint = Self { path: path.to_path_buf(), pending: Mutex::default() };
+        checkpoint.save(&[])?;
+        Ok(checkpoint)
+    }
+
+    /// Record downloads about to be queued
+    pub(crate) fn add<'a>(&self, downloads: impl IntoIterator<Item = &'a Download>) {
+        let mut pending = self.pending.lock().unwrap_or_else(|err| err.into_inner());
+        pending.extend(downloads.into_iter().cloned());
+        self.persist(&pending);
+    }
+
+    /// Forget the download of `summary` if it completed, failed downloads remain to be retried
+    pub(crate) fn complete(&self, download: &Download, summary: &Summary) {
+        if !matches!(summary.status(), Status::Success | Status::Skipped(_)) {
+            return;
+        }
+        let mut pending = self.pending.lock().unwrap_or_else(|err| err.into_inner());
+        let position = pending.iter()
+            .position(|pending| pending.url == download.url && pending.filename == download.filename);
+        if let Some(position) = position {
+            pending.remove(position);
+            self.persist(&pending);
+        }
+    }
+
+    /// Write the checkpoint, failures are only logged since the downloads themselves are unaffected
+    fn persist(&self, pending: &[Download]) {
+        if let Err(err) = self.save(pending) {
+            tracing::warn!("Failed to write the checkpoint {:?}: {}", self.path, err);
+        }
+    }
+
+    /// Replace the file through a rename, so an interruption never leaves a truncated checkpoint
+    fn save(&self, pending: &[Download]) -> io::Result<()> {
+        if pending.is_empty() {
+            return match fs::remove_file(&self.path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+                _ => Ok(()),
+            };
+        }
+        if let Some(parent) = self.path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+        let entries: Vec<Value> = pending.iter().map(entry).collect();
+        fs::write(&temporary, Value::Array(entries).to_string())?;
+        fs::rename(&temporary, &self.path)
+    }
+}
+
+/// Load the downloads left in a checkpoint file, none if the file does not exist
+pub fn load(path: impl AsRef<Path>) -> Result<Vec<Download>> {
+    let path = path.as_ref();
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).context(IoSnafu { path, location: location!() }),
+    };
+    let invalid = |message: &'static str| InvalidCheckpointSnafu { path, message, location: location!() };
+    let entries: Vec<Value> = serde_json::from_str(&content).ok().context(invalid("not a JSON array"))?;
+    entries.iter().map(|entry| {
+        let url = entry["url"].as_str().context(invalid("an entry has no url"))?;
+        let url = Url::parse(url).context(ParseUrlSnafu { url, location: location!() })?;
+        let filename = entry["filename"].as_str().context(invalid("an entry has no filename"))?;
+        let mut download = Download::new(url, filename.to_string());
+        download.directory = entry["directory"].as_str().map(PathBuf::from);
+        download.expected_size = entry["expected_size"].as_u64();
+        if let (Some(size), Some(tolerance)) = (entry["asserted_size"][0].as_u64(), entry["asserted_size"][1].as_u64()) {
+            download.asserted_size = Some((size, tolerance));
+        }
+        download.retries = entry["retries"].as_u64().map(|retries| retries as u32);
+        download.rate_limit = entry["rate_limit"].as_u64();
+        if let Some(content_types) = entry["content_types"].as_array() {
+            download.content_types = content_types.iter().filter_map(Value::as_str).map(str::to_string).collect();
+        }
+        if let (Some(start), Some(end)) = (entry["range"][0].as_u64(), entry["range"][1].as_u64()) {
+            download.range = Some(ByteRange::new(start, end.max(start)));
+        }
+        download.suffix = entry["suffix"].as_u64().and_then(NonZeroU64::new);
+        if let Some(ranges) = entry["ranges"].as_array() {
+            download.ranges = ranges.iter()
+                .filter_map(|range| Some((range[0].as_u64()?, range[1].as_u64()?)))
+                .map(|(start, end)| ByteRange::new(start, end.max(start)))
+                .collect();
+        }
+        download.if_none_match = entry["if_none_match"].as_str().map(str::to_string);
+        let kind = entry["checksum"]["kind"].as_str().and_then(digest_kind);
+        if let (Some(kind), Some(expected)) = (kind, entry["checksum"]["expected"].as_str()) {
+            download.checksum = Some((kind, expected.to_string()));
+        }
+        #[cfg(feature = "minisign")]
+        if let (Some(url), Some(public_key)) = (entry["signature"]["url"].as_str(), entry["signature"]["public_key"].as_str()) {
+            let url = Url::parse(url).context(ParseUrlSnafu { url, location: location!() })?;
+            download.signature = Some(DetachedSignature::new(url, public_key));
+        }
+        Ok(download)
+    }).collect()
+}
+
+fn entry(download: &Download) -> Value {
+    json!({
+        "url": download.url.as_str(),
+        "filename": download.filename,
+        "directory": download.directory.as_ref().map(|directory| directory.to_string_lossy()),
+        "expected_size": download.expected_size,
+        "asserted_size": download.asserted_size.map(|(size, tolerance)| [size, tolerance]),
+        "retries": download.retries,
+        "rate_limit": download.rate_limit,
+        "content_types": download.content_types,
+        "range": download.range.map(|range| [range.start, range.end]),
+        "suffix": download.suffix,
+        "ranges": download.ranges.iter().map(|range| [range.start, range.end]).collect::<Vec<_>>(),
+        "if_none_match": download.if_none_match,
+        "checksum": download.checksum.as_ref()
+            .map(|(kind, expected)| json!({ "kind": digest_name(*kind), "expected": expected })),
+        "signature": signature_entry(download),
+    })
+}
+
+#[cfg(feature = "minisign")]
+fn signature_entry(download: &Download) -> Value {
+    download.signature.as_ref()
+        .map(|signature| json!({ "url": signature.url.as_str(), "public_key": signature.public_key }))
+        .into()
+}
+
+#[cfg(not(feature = "minisign"))]
+fn signature_entry(_: &Download) -> Value {
+    Value::Null
+}
+
+pub(crate) fn digest_name(kind: DigestKind) -> &'static str {
+    match kind {
+        DigestKind::Md5 => "md5",
+        DigestKind::Sha1 => "sha1",
+        DigestKind::Sha256 => "sha256",
+        DigestKind::Crc32 => "crc32",
+    }
+}
+
+fn digest_kind(name: &str) -> Option<DigestKind> {
+    match name {
+        "md5" => Some(DigestKind::Md5),
+        "sha1" => Some(DigestKind::Sha1),
+        "sha256" => Some(DigestKind::Sha256),
+        "crc32" => Some(DigestKind::Crc32),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::checkpoint::{self, Checkpoint};
+    use crate::download::{DigestKind, Download, Status, Summary};
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn test_checkpoint() {
+        let path = temp_dir("checkpoint").join("batch.checkpoint");
+        let downloads = vec![
+            Download::try_from("http://domain.com/done.zip").unwrap(),
+            Download::try_from("http://domain.com/failed.zip").unwrap()
+                .with_range(0, 99)
+                .with_checksum(DigestKind::Sha256, "abc"),
+        ];
+        let checkpoint = Checkpoint::create(&path).unwrap();
+        checkpoint.add(&downloads);
+        checkpoint.complete(&downloads[0], &Summary::new(downloads[0].clone()).with_status(Status::Success));
+        checkpoint.complete(&downloads[1], &Summary::new(downloads[1].clone()).fail("failed"));
+
+        let remaining = checkpoint::load(&path).unwrap();
+        assert_eq!(1, remaining.len());
+        assert_eq!("failed.zip", remaining[0].filename);
+        assert_eq!(downloads[1].range(), remaining[0].range());
+        assert_eq!(Some((DigestKind::Sha256, "abc")), remaining[0].checksum());
+
+        checkpoint.complete(&downloads[1], &Summary::new(downloads[1].clone()).with_status(Status::Success));
+        assert!(!path.exists());
+        assert!(checkpoint::load(&path).unwrap().is_empty());
+    }
+}
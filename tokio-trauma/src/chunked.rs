@@ -0,0 +1,156 @@
+//! Storage of a download across numbered chunk files
+//!
+//! A download stored in chunks is written to `<file>.part0`, `<file>.part1`, ... of at most the
+//! chunk size each, so no file being written grows past it. Every chunk but the last one is
+//! full, which makes the bytes committed by an interrupted download the sum of the sizes of its
+//! chunks. The chunks are concatenated into the output file once the content is complete.
+
+use std::io::{self, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+pub(crate) struct ChunkedFile {
+    /// path of the chunks without their index, e.g. `file.part`
+    base: PathBuf,
+    chunk_size: u64,
+    /// index of the chunk being written
+    index: u64,
+    /// bytes in the chunk being written
+    len: u64,
+    file: File,
+}
+
+impl ChunkedFile {
+    /// The bytes committed by the chunks at `base`, up to the first chunk that is not full
+    ///
+    /// A chunk larger than `chunk_size` was written with another chunk size, nothing is committed then.
+    pub(crate) fn committed(base: &Path, chunk_size: u64) -> io::Result<u64> {
+        let mut committed = 0;
+        for index in 0.. {
+            let len = match chunk_path(base, index).metadata() {
+                Ok(metadata) => metadata.len(),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => break,
+                Err(err) => return Err(err),
+            };
+            if len > chunk_size {
+                return Ok(0);
+            }
+            committed += len;
+            if len < chunk_size {
+                break;
+            }
+        }
+        Ok(committed)
+    }
+
+    /// Continue the chunks at `base` after `offset` bytes, chunks past the offset are removed
+    pub(crate) async fn open(base: PathBuf, chunk_size: u64, offset: u64) -> io::Result<Self> {
+        let (index, len) = (offset / chunk_size, offset % chunk_size);
+        remove_from(&base, index + 1)?;
+        let mut file = OpenOptions::new().create(true).write(true).truncate(false)
+            .open(chunk_path(&base, index)).await?;
+        file.set_len(len).await?;
+        file.seek(SeekFrom::Start(len)).await?;
+        Ok(Self { base, chunk_size, index, len, file })
+    }
+
+    /// The paths of the chunks written so far
+    pub(crate) fn paths(&self) -> impl Iterator<Item = PathBuf> + '_ {
+        (0..=self.index).map(|index| chunk_path(&self.base, index))
+    }
+
+    /// Append `data`, starting the next chunk whenever the current one is full
+    pub(crate) async fn write(&mut self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            if self.len == self.chunk_size {
+                self.file.flush().await?;
+                self.index += 1;
+                self.len = 0;
+                self.file = File::create(chunk_path(&self.base, self.index)).await?;
+            }
+            let take = data.len().min((self.chunk_size - self.len) as usize);
+            self.file.write_all(&data[..take]).await?;
+            self.len += take as u64;
+            data = &data[take..];
+        }
+        Ok(())
+    }
+
+    /// Flush the written bytes and sync them to disk
+    pub(crate) async fn finish(&mut self) -> io::Result<()> {
+        self.file.flush().await?;
+        self.file.sync_data().await
+    }
+
+    /// Concatenate the chunks in order into `output` and remove them, returning the size of `output`
+    pub(crate) async fn concatenate(self, output: &Path) -> io::Result<u64> {
+        let mut writer = File::create(output).await?;
+        let mut size = 0;
+        for path in self.paths() {
+            let mut reader = File::open(&path).await?;
+            size += tokio::io::copy(&mut reader, &mut writer).await?;
+        }
+        writer.flush().await?;
+        writer.sync_data().await?;
+        drop(self.file);
+        remove_from(&self.base, 0)?;
+        Ok(size)
+    }
+
+    /// Remove every chunk, e.g. of a content that failed its checks
+    pub(crate) fn remove(self) -> io::Result<()> {
+        drop(self.file);
+        remove_from(&self.base, 0)
+    }
+}
+
+fn chunk_path(base: &Path, index: u64) -> PathBuf {
+    let mut path = base.as_os_str().to_owned();
+    path.push(index.to_string());
+    PathBuf::from(path)
+}
+
+/// Remove the chunks from `index` on, up to the first missing one
+fn remove_from(base: &Path, index: u64) -> io::Result<()> {
+    for index in index.. {
+        match std::fs::remove_file(chunk_path(base, index)) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            result => result?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::chunked::{chunk_path, ChunkedFile};
+    use crate::testing::temp_dir;
+
+    #[tokio::test]
+    async fn test_chunked_file() {
+        let directory = temp_dir("chunked");
+        let base = directory.join("file.txt.part");
+        let mut chunks = ChunkedFile::open(base.clone(), 4, 0).await.unwrap();
+        chunks.write(b"hello").await.unwrap();
+        chunks.finish().await.unwrap();
+        drop(chunks);
+        assert_eq!("hell", fs::read_to_string(chunk_path(&base, 0)).unwrap());
+        assert_eq!(5, ChunkedFile::committed(&base, 4).unwrap());
+        assert_eq!(0, ChunkedFile::committed(&base, 3).unwrap());
+
+        // A stale chunk after the resumed one is dropped
+        fs::write(chunk_path(&base, 2), "stale").unwrap();
+        let mut chunks = ChunkedFile::open(base.clone(), 4, 5).await.unwrap();
+        chunks.write(b" world").await.unwrap();
+        chunks.finish().await.unwrap();
+        assert_eq!(3, chunks.paths().count());
+        let output = directory.join("file.txt");
+        assert_eq!(11, chunks.concatenate(&output).await.unwrap());
+        assert_eq!("hello world", fs::read_to_string(&output).unwrap());
+        assert!(!chunk_path(&base, 0).exists());
+    }
+}
@@ -0,0 +1,123 @@
+//! Time source of the time-based features
+//!
+//! Rate limiting, schedule windows, launch delays, periodic syncs and batch timeouts read the
+//! time and sleep through the clock of the downloader. Tests replace the real clock with a mock
+//! clock that only moves when advanced, so they control time instead of waiting for it.
+
+#[cfg(test)]
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+#[cfg(test)]
+use tokio::sync::watch;
+
+/// The clock of a downloader, `tokio::time` unless mocked in tests
+#[derive(Debug, Clone, Default)]
+pub(crate) enum Clock {
+    #[default]
+    Real,
+    #[cfg(test)]
+    Mock(Arc<MockClock>),
+}
+
+impl PartialEq for Clock {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Real, Self::Real) => true,
+            #[cfg(test)]
+            (Self::Mock(clock), Self::Mock(other)) => Arc::ptr_eq(clock, other),
+            #[cfg(test)]
+            _ => false,
+        }
+    }
+}
+
+impl Clock {
+    /// A mock clock starting at the current time
+    #[cfg(test)]
+    pub(crate) fn mock() -> (Self, Arc<MockClock>) {
+        let clock = Arc::new(MockClock {
+            started: Instant::now(),
+            system: SystemTime::now(),
+            elapsed: watch::Sender::new(Duration::ZERO),
+        });
+        (Self::Mock(clock.clone()), clock)
+    }
+
+    pub(crate) fn now(&self) -> Instant {
+        match self {
+            Self::Real => Instant::now(),
+            #[cfg(test)]
+            Self::Mock(clock) => clock.started + clock.elapsed(),
+        }
+    }
+
+    pub(crate) fn system_time(&self) -> SystemTime {
+        match self {
+            Self::Real => SystemTime::now(),
+            #[cfg(test)]
+            Self::Mock(clock) => clock.system + clock.elapsed(),
+        }
+    }
+
+    pub(crate) async fn sleep(&self, duration: Duration) {
+        self.sleep_until(self.now() + duration).await
+    }
+
+    pub(crate) async fn sleep_until(&self, deadline: Instant) {
+        match self {
+            Self::Real => tokio::time::sleep_until(tokio::time::Instant::from_std(deadline)).await,
+            #[cfg(test)]
+            Self::Mock(clock) => {
+                let deadline = deadline.saturating_duration_since(clock.started);
+                let mut elapsed = clock.elapsed.subscribe();
+                // The sender lives as long as the clock, which the caller holds
+                let _ = elapsed.wait_for(|elapsed| *elapsed >= deadline).await;
+            }
+        }
+    }
+}
+
+/// Clock whose time only moves when advanced
+#[cfg(test)]
+#[derive(Debug)]
+pub(crate) struct MockClock {
+    started: Instant,
+    system: SystemTime,
+    elapsed: watch::Sender<Duration>,
+}
+
+#[cfg(test)]
+impl MockClock {
+    fn elapsed(&self) -> Duration {
+        *self.elapsed.borrow()
+    }
+
+    /// Move the time forward by `duration`, waking the sleeps that are due
+    pub(crate) fn advance(&self, duration: Duration) {
+        self.elapsed.send_modify(|elapsed| *elapsed += duration);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use futures_util::FutureExt;
+
+    use crate::clock::Clock;
+
+    #[tokio::test]
+    async fn test_mock_clock() {
+        let (clock, mock) = Clock::mock();
+        let started = clock.now();
+        let mut sleep = Box::pin(clock.sleep(Duration::from_secs(60)));
+        assert!((&mut sleep).now_or_never().is_none());
+
+        mock.advance(Duration::from_secs(30));
+        assert!((&mut sleep).now_or_never().is_none());
+        mock.advance(Duration::from_secs(30));
+        assert!(sleep.now_or_never().is_some());
+        assert_eq!(Duration::from_secs(60), clock.now() - started);
+    }
+}
@@ -0,0 +1,67 @@
+//! Deciding whether a download is already complete before fetching it
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::collections::HashSet;
+//! use std::path::Path;
+//!
+//! use tokio_trauma::completion::{async_trait, CompletionStrategy};
+//! use tokio_trauma::download::{ContentRange, Download};
+//! use tokio_trauma::downloader::DownloaderBuilder;
+//!
+//! /// Downloads recorded in a manifest are complete
+//! struct Manifest(HashSet<String>);
+//!
+//! #[async_trait]
+//! impl CompletionStrategy for Manifest {
+//!     async fn is_complete(&self, download: &Download, _: &Path, _: Option<&ContentRange>) -> bool {
+//!         self.0.contains(download.url.as_str())
+//!     }
+//! }
+//!
+//! let downloader = DownloaderBuilder::new()
+//!     .completion_strategy(Manifest(HashSet::new()))
+//!     .build();
+//! ```
+
+use std::path::Path;
+
+pub use async_trait::async_trait;
+
+use crate::download::{ContentRange, Download};
+
+/// Decide whether a download is complete, it is then skipped as `SkipReason::Complete`
+///
+/// The check is awaited for every download before its request and holds a concurrency slot
+/// meanwhile, so a lookup in a database or over the network adds its latency to each download.
+#[async_trait]
+pub trait CompletionStrategy: Send + Sync {
+    /// `content_range` is the probe of the resource when resuming is enabled
+    async fn is_complete(&self, download: &Download, output_path: &Path, content_range: Option<&ContentRange>) -> bool;
+}
+
+/// The default strategy: the download is complete when the file on disk has the size of the resource
+///
+/// The size is the probed size or else the expected size of the download. The file on disk is
+/// only considered when the probe allows resuming, otherwise it is downloaded again.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SizeCompletion;
+
+#[async_trait]
+impl CompletionStrategy for SizeCompletion {
+    async fn is_complete(&self, download: &Download, output_path: &Path, content_range: Option<&ContentRange>) -> bool {
+        // The probed size wins like in `Download::total_size`, which already warned about a mismatch
+        let content_length = content_range.and_then(|content_range| content_range.size).or(download.expected_size);
+        let resumable = content_range.is_some_and(|content_range| content_range.resume && !content_range.weak_etag());
+        let size_on_disk = if resumable {
+            tokio::fs::metadata(output_path).await.map(|metadata| metadata.len()).unwrap_or_default()
+        } else {
+            0
+        };
+
+        // 1.If content_length exists and is equal to the size of the file, the download is considered complete.
+        // 2.If the file size is not empty and the size of the resource is unknown, it is considered complete.
+        content_length == Some(size_on_disk) || size_on_disk > 0 && content_length.unwrap_or_default() == 0
+    }
+}
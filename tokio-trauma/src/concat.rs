@@ -0,0 +1,151 @@
+//! Downloads of a file split into parts, concatenated in order once every part is downloaded
+
+use std::fs;
+use std::path::PathBuf;
+
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use url::Url;
+
+use crate::download::{Download, Status, Summary};
+use crate::downloader::Downloader;
+
+impl Downloader {
+    /// Download the parts of a split file and concatenate them in the given order into `output`,
+    /// relative to the download directory.
+    ///
+    /// The parts are downloaded concurrently into a `<output>.parts` directory, in the temporary
+    /// directory when set, and only concatenated once every part succeeded, they are removed
+    /// afterwards. Failed parts are kept so a later call resumes them. The summary reports the
+    /// combined size. Without parts the summary fails, pointing at the file url of `output`.
+    pub async fn download_concat(&self, parts: &[Download], output: PathBuf) -> Summary {
+        let output = self.directory.join(output);
+        let filename = output.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let url = match parts.first() {
+            Some(part) => part.url.clone(),
+            None => Url::from_file_path(&output).unwrap_or_else(|_| Url::parse("file:///").expect("valid url")),
+        };
+        let summary = Summary::new(Download::new(url, filename)).with_path(output.clone());
+        if parts.is_empty() {
+            return summary.fail("no parts to concatenate");
+        }
+
+        let staging = match self.staging_path(&output, ".parts") {
+            Ok(staging) => staging,
+            Err(err) => return summary.fail(err),
+        };
+        let mut downloader = self.clone();
+        downloader.directory = staging.clone();
+        downloader.ordered = true;
+        downloader.filename_template = None;
+        downloader.checkpoint = None;
+        #[cfg(feature = "zip")]
+        {
+            downloader.extract_zip = false;
+        }
+        let parts: Vec<_> = parts.iter().enumerate()
+            .map(|(index, part)| Download { filename: format!("{}.part", index), ..part.clone() })
+            .collect();
+        let report = match downloader.download(&parts).await {
+            Ok(report) => report,
+            Err(err) => return summary.fail(err),
+        };
+        for part in &report {
+            match part.status() {
+                Status::Fail(err) => return summary.fail(format!("part {} failed: {}", part.download().url, err)),
+                Status::NotStarted => return summary.fail(format!("part {} was not started", part.download().url)),
+                _ => {}
+            }
+        }
+
+        if let Some(folder) = output.parent() {
+            if let Err(err) = fs::create_dir_all(folder) {
+                return summary.fail(err);
+            }
+        }
+        let result = OpenOptions::new().create(true).write(true).truncate(true).open(&output).await;
+        let mut file = match result {
+            Ok(file) => file,
+            Err(err) => return summary.fail(err),
+        };
+        let mut size = 0;
+        for part in &report {
+            let mut reader = match File::open(part.path()).await {
+                Ok(reader) => reader,
+                Err(err) => return summary.fail(err),
+            };
+            match tokio::io::copy(&mut reader, &mut file).await {
+                Ok(copied) => size += copied,
+                Err(err) => return summary.fail(err),
+            }
+        }
+        if let Err(err) = file.flush().await {
+            return summary.fail(err);
+        }
+        if let Err(err) = fs::remove_dir_all(&staging) {
+            tracing::warn!("Failed to remove the parts directory {:?}: {}", staging, err);
+        }
+
+        Summary { size, ..summary }.with_status(Status::Success)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::download::{Download, Status};
+    use crate::downloader::DownloaderBuilder;
+    use crate::testing::{response, temp_dir, TestServer};
+
+    #[tokio::test]
+    async fn test_download_concat() {
+        let server = TestServer::start(|request| {
+            let body: &[u8] = if request.path == "/part1" { b"hello " } else { b"world" };
+            response(request, "200 OK", &[], body)
+        }).await;
+        let directory = temp_dir("download-concat");
+        let downloader = DownloaderBuilder::new().directory(&directory).build();
+
+        let parts = [
+            Download::try_from(server.url("/part1").as_str()).unwrap(),
+            Download::try_from(server.url("/part2").as_str()).unwrap(),
+        ];
+        let summary = downloader.download_concat(&parts, "joined.txt".into()).await;
+        assert_eq!(&Status::Success, summary.status());
+        assert_eq!(11, summary.size());
+        assert_eq!("hello world", std::fs::read_to_string(directory.join("joined.txt")).unwrap());
+        assert!(!directory.join("joined.txt.parts").exists());
+
+        let summary = downloader.download_concat(&[], "empty.txt".into()).await;
+        assert_eq!(&Status::Fail("no parts to concatenate".into()), summary.status());
+        assert_eq!(directory.join("empty.txt"), summary.path());
+        assert!(!directory.join("empty.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn test_temp_dir() {
+        let server = TestServer::start(|request| {
+            let body: &[u8] = if request.path == "/part1" { b"hello " } else { b"world" };
+            response(request, "200 OK", &[], body)
+        }).await;
+        let directory = temp_dir("temp-dir");
+        let staging = directory.join("staging");
+        let downloader = DownloaderBuilder::new()
+            .directory(directory.join("output"))
+            .temp_dir(&staging)
+            .build();
+
+        // A part left by an interrupted download is resumed
+        std::fs::create_dir_all(staging.join("joined.txt.parts")).unwrap();
+        std::fs::write(staging.join("joined.txt.parts/0.part"), "hello ").unwrap();
+        let parts = [
+            Download::try_from(server.url("/part1").as_str()).unwrap(),
+            Download::try_from(server.url("/part2").as_str()).unwrap(),
+        ];
+        let summary = downloader.download_concat(&parts, "joined.txt".into()).await;
+        assert_eq!(&Status::Success, summary.status());
+        assert_eq!("hello world", std::fs::read_to_string(directory.join("output/joined.txt")).unwrap());
+        assert!(staging.exists());
+        assert!(!staging.join("joined.txt.parts").exists());
+        assert!(!directory.join("output/joined.txt.parts").exists());
+    }
+}
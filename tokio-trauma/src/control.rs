@@ -0,0 +1,201 @@
+//! Cancellation of a running batch or of single downloads in it
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use tokio_trauma::control::DownloadControl;
+//! use tokio_trauma::download::Download;
+//! use tokio_trauma::downloader::DownloaderBuilder;
+//!
+//! # async fn run() -> tokio_trauma::error::Result<()> {
+//! let downloader = DownloaderBuilder::new().build();
+//! let downloads = vec![Download::try_from("https://example.com/file.zip")?];
+//! let control = DownloadControl::new();
+//! let handle = control.clone();
+//! tokio::spawn(async move { handle.cancel_one("file.zip") });
+//! let report = downloader.download_controlled(&downloads, &control).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashSet;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use tokio_util::sync::CancellationToken;
+
+use crate::download::{Download, Summary};
+use crate::progress::ProgressEvent;
+
+/// Handle cancelling the downloads of a batch started with `download_controlled`
+/// and listing the running ones
+///
+/// Cancelled downloads fail with `cancelled` and keep their partial file for resuming.
+#[derive(Clone, Default)]
+pub struct DownloadControl {
+    state: Arc<State>,
+}
+
+#[derive(Default)]
+struct State {
+    cancel: CancellationToken,
+    running: Mutex<Running>,
+}
+
+#[derive(Default)]
+struct Running {
+    next_id: u64,
+    downloads: Vec<Active>,
+    /// filenames or urls cancelled before their download started
+    cancelled: HashSet<String>,
+}
+
+struct Active {
+    id: u64,
+    url: String,
+    token: CancellationToken,
+    status: DownloadStatus,
+}
+
+/// State of a running download
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DownloadState {
+    Running,
+    /// waiting for the schedule window to open
+    Paused,
+}
+
+/// Snapshot of a running download
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DownloadStatus {
+    pub filename: String,
+    /// bytes on disk, including resumed bytes
+    pub bytes: u64,
+    pub total: Option<u64>,
+    pub state: DownloadState,
+}
+
+impl DownloadControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel every download of the batch
+    pub fn cancel(&self) {
+        self.state.cancel.cancel();
+    }
+
+    /// Cancel the downloads whose filename or url is `filename_or_url`, leaving the others running
+    ///
+    /// When no such download runs, the next one to start is cancelled as soon as it starts.
+    pub fn cancel_one(&self, filename_or_url: &str) {
+        let mut running = self.state.running.lock().unwrap_or_else(|err| err.into_inner());
+        let mut matched = false;
+        for active in &running.downloads {
+            if active.status.filename == filename_or_url || active.url == filename_or_url {
+                active.token.cancel();
+                matched = true;
+            }
+        }
+        if !matched {
+            running.cancelled.insert(filename_or_url.to_string());
+        }
+    }
+
+    /// Run the fetch of `download` to `path` until it completes or is cancelled
+    pub(crate) async fn run(&self, download: &Download, path: PathBuf, fetch: impl Future<Output = Summary>) -> Summary {
+        let (id, token) = self.register(download);
+        let summary = tokio::select! {
+            biased;
+            _ = token.cancelled() => Summary::new(download.clone()).with_path(path).fail("cancelled"),
+            summary = fetch => summary,
+        };
+        let mut running = self.state.running.lock().unwrap_or_else(|err| err.into_inner());
+        running.downloads.retain(|active| active.id != id);
+        summary
+    }
+
+    /// The downloads currently running or paused
+    pub fn active_downloads(&self) -> Vec<DownloadStatus> {
+        let running = self.state.running.lock().unwrap_or_else(|err| err.into_inner());
+        running.downloads.iter().map(|active| active.status.clone()).collect()
+    }
+
+    /// Track the progress of the running downloads
+    pub(crate) fn observe(&self, event: &ProgressEvent<'_>) {
+        let download = match *event {
+            ProgressEvent::Started { download, .. } | ProgressEvent::Progress { download, .. } |
+            ProgressEvent::Paused { download } => download,
+            ProgressEvent::Finished { .. } => return,
+        };
+        let url = download.url.as_str();
+        let mut running = self.state.running.lock().unwrap_or_else(|err| err.into_inner());
+        let active = running.downloads.iter_mut()
+            .find(|active| active.url == url && active.status.filename == download.filename);
+        let Some(active) = active else {
+            return;
+        };
+        let status = &mut active.status;
+        match *event {
+            ProgressEvent::Started { total, resumed, .. } => {
+                status.bytes = resumed;
+                status.total = total;
+                status.state = DownloadState::Running;
+            }
+            ProgressEvent::Progress { downloaded, .. } => status.bytes = downloaded,
+            ProgressEvent::Paused { .. } => status.state = DownloadState::Paused,
+            ProgressEvent::Finished { .. } => {}
+        }
+    }
+
+    fn register(&self, download: &Download) -> (u64, CancellationToken) {
+        let token = self.state.cancel.child_token();
+        let (filename, url) = (download.filename.clone(), download.url.to_string());
+        let mut running = self.state.running.lock().unwrap_or_else(|err| err.into_inner());
+        // A cancellation ahead of the start applies once
+        if running.cancelled.remove(&filename) | running.cancelled.remove(&url) {
+            token.cancel();
+        }
+        let id = running.next_id;
+        running.next_id += 1;
+        let status = DownloadStatus { filename, bytes: 0, total: None, state: DownloadState::Running };
+        running.downloads.push(Active { id, url, token: token.clone(), status });
+        (id, token)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::control::{DownloadControl, DownloadState};
+    use crate::download::Download;
+    use crate::progress::ProgressEvent;
+
+    #[test]
+    fn test_active_downloads() {
+        let control = DownloadControl::new();
+        let download = Download::try_from("http://domain.com/file.zip").unwrap();
+        let (id, _) = control.register(&download);
+        control.observe(&ProgressEvent::Started { download: &download, total: Some(100), resumed: 10 });
+        control.observe(&ProgressEvent::Progress { download: &download, bytes: 5, downloaded: 15, total: Some(100) });
+        control.observe(&ProgressEvent::Paused { download: &download });
+
+        let active = control.active_downloads();
+        assert_eq!(1, active.len());
+        assert_eq!((15, Some(100), DownloadState::Paused), (active[0].bytes, active[0].total, active[0].state));
+        assert_eq!(0, id);
+    }
+
+    #[test]
+    fn test_cancel_one() {
+        let control = DownloadControl::new();
+        let download = Download::try_from("http://domain.com/file.zip").unwrap();
+        // Cancelled ahead of its start, the download is cancelled once
+        control.cancel_one("file.zip");
+        assert!(control.register(&download).1.is_cancelled());
+        assert!(!control.register(&download).1.is_cancelled());
+        // Cancelling running downloads leaves nothing for the next ones
+        control.cancel_one("file.zip");
+        assert!(control.state.running.lock().unwrap().cancelled.is_empty());
+    }
+}
@@ -0,0 +1,340 @@
+//! Delta downloads fetching only the changed blocks of a file already on disk
+//!
+//! The remote file is described by a block manifest listing the SHA-256 of each of its
+//! fixed-size blocks. The local file is hashed block by block and only the blocks whose hash
+//! differs are requested, with range requests, and written in place. The server must accept
+//! range requests for the file.
+//!
+//! # Block manifest
+//!
+//! The manifest is a text file, e.g. served next to the file as `<file>.blocks`:
+//!
+//! ```text
+//! tokio-trauma-blocks 1
+//! size 2500000
+//! block-size 1048576
+//! 6f3b1a0c...
+//! 0d5e2c9b...
+//! 8a41f7e3...
+//! ```
+//!
+//! The first line names the format and its version, followed by the size of the file, the size
+//! of its blocks and the lowercase hex SHA-256 of every block in order. The last block is
+//! shorter when the size is not a multiple of the block size. Blocks are at most 64 MiB, and a
+//! block size larger than the file is reduced to the size of the file. [`BlockManifest::from_reader`]
+//! computes the manifest of a file, e.g. on the server, and its `Display` writes this format.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::fs::File;
+//!
+//! use tokio_trauma::delta::BlockManifest;
+//!
+//! let manifest = BlockManifest::from_reader(File::open("dataset.bin")?, 1 << 20)?;
+//! std::fs::write("dataset.bin.blocks", manifest.to_string())?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use futures_util::{stream, StreamExt};
+use reqwest_middleware::ClientWithMiddleware;
+use sha2::{Digest, Sha256};
+use snafu::location;
+use url::Url;
+
+use crate::digest;
+use crate::download::{ByteRange, Download, Status, Summary};
+use crate::downloader::{directory_error, request_failure, Downloader};
+use crate::error::{InvalidBlockManifestSnafu, Result};
+use crate::positioned::PositionedFile;
+
+/// First line of a block manifest
+const HEADER: &str = "tokio-trauma-blocks 1";
+
+/// Largest block size, a block is held in memory while it is hashed
+const MAX_BLOCK_SIZE: u64 = 64 << 20;
+
+/// The block hashes of a file
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BlockManifest {
+    size: u64,
+    block_size: u64,
+    hashes: Vec<String>,
+}
+
+impl BlockManifest {
+    /// Compute the manifest of the content of `reader` in blocks of `block_size` bytes
+    ///
+    /// # Panics
+    ///
+    /// Panics if `block_size` is zero or larger than 64 MiB.
+    pub fn from_reader(mut reader: impl Read, block_size: u64) -> io::Result<Self> {
+        assert!(block_size > 0, "the block size must be greater than zero");
+        assert!(block_size <= MAX_BLOCK_SIZE, "the block size must be at most 64 MiB");
+        let mut size = 0;
+        let mut hashes = Vec::new();
+        loop {
+            let mut block = Vec::new();
+            let len = (&mut reader).take(block_size).read_to_end(&mut block)?;
+            if len == 0 {
+                break;
+            }
+            size += len as u64;
+            hashes.push(digest::hex(&Sha256::digest(&block)));
+        }
+        Ok(Self { size, block_size: block_size.min(size.max(1)), hashes })
+    }
+
+    /// Parse a manifest in the format described in the module documentation
+    pub fn parse(text: &str) -> Result<Self> {
+        let invalid = |message: &str| InvalidBlockManifestSnafu { message, location: location!() }.fail();
+        let mut lines = text.lines().map(str::trim).filter(|line| !line.is_empty());
+        if lines.next() != Some(HEADER) {
+            return invalid("missing the tokio-trauma-blocks 1 header");
+        }
+        let mut field = |name: &str| lines.next()
+            .and_then(|line| line.strip_prefix(name)?.trim().parse::<u64>().ok());
+        let (Some(size), Some(block_size)) = (field("size"), field("block-size")) else {
+            return invalid("missing the size or the block size");
+        };
+        if block_size == 0 {
+            return invalid("the block size is zero");
+        }
+        if block_size > MAX_BLOCK_SIZE {
+            return invalid("the block size is larger than 64 MiB");
+        }
+        // A single block covers a file smaller than the block size
+        let block_size = block_size.min(size.max(1));
+        let hashes: Vec<_> = lines.map(str::to_ascii_lowercase).collect();
+        if hashes.len() as u64 != size.div_ceil(block_size) {
+            return invalid("the number of blocks does not match the size");
+        }
+        if hashes.iter().any(|hash| hash.len() != 64 || !hash.bytes().all(|byte| byte.is_ascii_hexdigit())) {
+            return invalid("a block hash is not a hex SHA-256");
+        }
+        Ok(Self { size, block_size, hashes })
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    /// The ranges of the blocks of `path` that differ from the manifest, adjacent blocks merged
+    ///
+    /// A missing file differs entirely, bytes past the size of the manifest are ignored.
+    pub(crate) fn changed_ranges(&self, path: &Path) -> io::Result<Vec<ByteRange>> {
+        let mut file = match File::open(path) {
+            Ok(file) => Some(file),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err),
+        };
+        let mut ranges: Vec<ByteRange> = Vec::new();
+        let mut block = Vec::with_capacity(self.block_size as usize);
+        for (index, hash) in self.hashes.iter().enumerate() {
+            let start = index as u64 * self.block_size;
+            let end = start.checked_add(self.block_size).map_or(self.size, |end| end.min(self.size)) - 1;
+            block.clear();
+            if let Some(file) = file.as_mut() {
+                file.take(end - start + 1).read_to_end(&mut block)?;
+            }
+            let unchanged = block.len() as u64 == end - start + 1 && digest::hex(&Sha256::digest(&block)) == *hash;
+            if unchanged {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some(last) if last.end + 1 == start => last.end = end,
+                _ => ranges.push(ByteRange::new(start, end)),
+            }
+        }
+        Ok(ranges)
+    }
+}
+
+impl Display for BlockManifest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", HEADER)?;
+        writeln!(f, "size {}", self.size)?;
+        writeln!(f, "block-size {}", self.block_size)?;
+        for hash in &self.hashes {
+            writeln!(f, "{}", hash)?;
+        }
+        Ok(())
+    }
+}
+
+impl Downloader {
+    /// Update the output file of `download` to the remote file described by the block manifest
+    /// at `manifest`, fetching only the blocks that differ with range requests and writing them
+    /// in place. A missing output file is downloaded whole, block by block.
+    ///
+    /// The patched file is hashed again against the manifest before the download succeeds. An
+    /// interrupted patch leaves a mix of old and new blocks, patching again completes it.
+    /// Digests, filename templates and extraction are not applied to delta downloads.
+    pub async fn download_delta(&self, download: &Download, manifest: &Url) -> Summary {
+        let named = self.named(download);
+        let download: &Download = &named;
+        let output_path = self.output_path(download);
+        let mut summary = Summary::new(download.clone()).with_path(output_path.clone());
+        let batch = match self.batch(None) {
+            Ok(batch) => batch,
+            Err(err) => return summary.fail(err),
+        };
+        let (client, routed) = match self.route(&batch, download) {
+            Ok(route) => route,
+            Err(err) => return summary.fail(err),
+        };
+        let manifest = match self.fetch_manifest(&client, manifest).await {
+            Ok(manifest) => manifest,
+            Err(err) => return summary.fail(err),
+        };
+        if let Err(err) = self.symlink_policy.apply(&output_path) {
+            return summary.fail(err);
+        }
+        if let Some(err) = directory_error(&output_path) {
+            return summary.fail(err);
+        }
+
+        let (manifest, changed) = match changed_blocks(manifest, output_path.clone()).await {
+            Ok(changed) => changed,
+            Err(err) => return summary.fail(err),
+        };
+        let fetched: u64 = changed.iter().map(ByteRange::size).sum();
+        tracing::debug!("Fetching {} of {} bytes of Url: {}", fetched, manifest.size(), download.redacted_url());
+
+        if let Some(folder) = output_path.parent() {
+            if let Err(err) = fs::create_dir_all(folder) {
+                return summary.fail(err);
+            }
+        }
+        let file = match PositionedFile::open(&output_path).await {
+            Ok(file) => file,
+            Err(err) => return summary.fail(err),
+        };
+        let results: Vec<_> = stream::iter(changed)
+            .map(|range| self.fetch_segment(&client, &routed, &file, range))
+            .buffer_unordered(self.concurrent_downloads.max(1) as usize)
+            .collect()
+            .await;
+        let result = match results.into_iter().find_map(|result| result.err()) {
+            Some(err) => Err(err),
+            None => file.sync_data().await.map_err(|err| err.to_string()),
+        };
+        drop(file);
+        let result = result.and_then(|_| {
+            // Bytes past the size of the remote file are dropped
+            fs::OpenOptions::new().write(true).open(&output_path)
+                .and_then(|file| file.set_len(manifest.size()))
+                .map_err(|err| err.to_string())
+        });
+        if let Err(err) = result {
+            return summary.fail(err);
+        }
+        match changed_blocks(manifest, output_path).await {
+            Ok((manifest, changed)) if changed.is_empty() => {
+                summary.size = manifest.size();
+                summary.resume = fetched < manifest.size();
+                summary.with_status(Status::Success)
+            }
+            Ok(_) => summary.fail("the patched file does not match the block manifest"),
+            Err(err) => summary.fail(err),
+        }
+    }
+
+    /// Download and parse the block manifest of a delta download
+    async fn fetch_manifest(&self, client: &ClientWithMiddleware, url: &Url) -> std::result::Result<BlockManifest, String> {
+        let response = client.get(url.as_str()).send().await.map_err(|err| request_failure(&err))?;
+        let response = response.error_for_status().map_err(|err| err.to_string())?;
+        let text = response.text().await.map_err(|err| err.to_string())?;
+        BlockManifest::parse(&text).map_err(|err| err.to_string())
+    }
+}
+
+/// Hash the blocks of `path` off the runtime, returning the manifest with the changed ranges
+async fn changed_blocks(manifest: BlockManifest, path: PathBuf) -> io::Result<(BlockManifest, Vec<ByteRange>)> {
+    tokio::task::spawn_blocking(move || {
+        let changed = manifest.changed_ranges(&path)?;
+        Ok((manifest, changed))
+    }).await.map_err(io::Error::other)?
+}
+
+#[cfg(test)]
+mod test {
+    use url::Url;
+
+    use crate::delta::BlockManifest;
+    use crate::download::{ByteRange, Download, Status};
+    use crate::downloader::DownloaderBuilder;
+    use crate::testing::{response, temp_dir, TestServer};
+
+    #[test]
+    fn test_block_manifest() {
+        let manifest = BlockManifest::from_reader(&b"hello world"[..], 4).unwrap();
+        assert_eq!((11, 4), (manifest.size(), manifest.block_size()));
+        assert_eq!(manifest, BlockManifest::parse(&manifest.to_string()).unwrap());
+        assert!(BlockManifest::parse("tokio-trauma-blocks 1\nsize 11\nblock-size 4\n").is_err());
+        assert!(BlockManifest::parse("size 0\nblock-size 4\n").is_err());
+        assert!(BlockManifest::parse("tokio-trauma-blocks 1\nsize 0\nblock-size 18446744073709551615\n").is_err());
+        // The block size is bounded by the size of the file
+        let small = BlockManifest::from_reader(&b"hello"[..], 4096).unwrap();
+        assert_eq!(5, small.block_size());
+        let text = small.to_string().replace("block-size 5", "block-size 4096");
+        assert_eq!(small, BlockManifest::parse(&text).unwrap());
+    }
+
+    #[test]
+    fn test_changed_ranges() {
+        let directory = temp_dir("delta-changed-ranges");
+        let path = directory.join("file.txt");
+        let manifest = BlockManifest::from_reader(&b"aaaabbbbccccdd"[..], 4).unwrap();
+        assert_eq!(vec![ByteRange::new(0, 13)], manifest.changed_ranges(&path).unwrap());
+
+        std::fs::write(&path, "aaaaXbbbccccd").unwrap();
+        assert_eq!(vec![ByteRange::new(4, 7), ByteRange::new(12, 13)], manifest.changed_ranges(&path).unwrap());
+        std::fs::write(&path, "aaaabbbbccccddtail").unwrap();
+        assert!(manifest.changed_ranges(&path).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_download_delta() {
+        let content: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let manifest = BlockManifest::from_reader(&content[..], 100).unwrap().to_string();
+        let body = content.clone();
+        let server = TestServer::start(move |request| {
+            if request.path == "/data.bin.blocks" {
+                return response(request, "200 OK", &[], manifest.as_bytes());
+            }
+            let range = request.header("range").and_then(|range| range.strip_prefix("bytes=")?.split_once('-'));
+            match range {
+                Some((start, end)) => {
+                    let (start, end): (usize, usize) = (start.parse().unwrap(), end.parse().unwrap());
+                    let content_range = format!("bytes {}-{}/{}", start, end, body.len());
+                    response(request, "206 Partial Content", &[("Content-Range", content_range.as_str())], &body[start..=end])
+                }
+                None => response(request, "200 OK", &[], &body),
+            }
+        }).await;
+        let directory = temp_dir("download-delta");
+        let mut local = content.clone();
+        local[250] ^= 0xff;
+        local.extend_from_slice(b"stale tail");
+        std::fs::write(directory.join("data.bin"), &local).unwrap();
+        let downloader = DownloaderBuilder::new().directory(&directory).build();
+
+        let download = Download::try_from(server.url("/data.bin").as_str()).unwrap();
+        let manifest_url = Url::parse(&server.url("/data.bin.blocks")).unwrap();
+        let summary = downloader.download_delta(&download, &manifest_url).await;
+        assert_eq!((&Status::Success, 1000, true), (summary.status(), summary.size(), summary.resume()));
+        assert_eq!(content, std::fs::read(directory.join("data.bin")).unwrap());
+        let ranges: Vec<_> = server.requests().iter().filter_map(|request| request.header("range").map(str::to_string)).collect();
+        assert_eq!(vec!["bytes=200-299"], ranges);
+    }
+}
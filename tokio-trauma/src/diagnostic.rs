@@ -0,0 +1,78 @@
+//! Non-fatal issues met by a download, reported in its summary
+//!
+//! A download with diagnostics may still succeed, they tell what was unexpected on the way.
+//! Each diagnostic is also logged with `tracing` where it happens.
+
+use std::fmt::{self, Display, Formatter};
+use std::path::PathBuf;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Diagnostic {
+    /// the size reported by the server is off the expected size beyond the tolerance, the
+    /// reported size was used
+    SizeMismatch { reported: u64, expected: u64 },
+    /// the partial file was downloaded again since the weak ETag of the resource can't
+    /// guarantee it matches
+    WeakEtag { etag: String },
+    /// the server answered a resumed request with the whole resource, the partial file was replaced
+    RangeIgnored { size_on_disk: u64 },
+    /// the partial response to a resumed request did not start at the end of the file on disk,
+    /// `start` is where it started if its `Content-Range` was valid, the file was downloaded
+    /// again from scratch
+    RangeMismatch { size_on_disk: u64, start: Option<u64> },
+    /// the content did not match its checksum and was downloaded again from scratch
+    ChecksumRedownload { restart: u32 },
+    /// a file left by a failed download could not be removed
+    CleanupFailed { path: PathBuf, message: String },
+    /// the download was resumed while compressed encodings were accepted, the range of an
+    /// encoded response may not continue the file on disk
+    CompressedResume { size_on_disk: u64 },
+    /// the redirect policy refused a redirect to `host`, `chain` is the requested url, the urls
+    /// it was redirected to and the refused target
+    RedirectRefused { chain: Vec<Url>, host: String },
+    /// the download was not probed, the capabilities learned from an earlier download of its
+    /// host decided whether it could be resumed
+    HostCapabilities { host: String, ranges: bool, version: String, encoding: Option<String> },
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Diagnostic::SizeMismatch { reported, expected } => {
+                write!(f, "the server reported {} bytes but {} bytes were expected", reported, expected)
+            }
+            Diagnostic::WeakEtag { etag } => write!(f, "not resumed with the weak ETag {}", etag),
+            Diagnostic::RangeIgnored { size_on_disk } => {
+                write!(f, "the server ignored the range after {} bytes on disk", size_on_disk)
+            }
+            Diagnostic::RangeMismatch { size_on_disk, start: Some(start) } => {
+                write!(f, "the server returned a range from {} after {} bytes on disk", start, size_on_disk)
+            }
+            Diagnostic::RangeMismatch { size_on_disk, start: None } => {
+                write!(f, "the server returned an invalid range after {} bytes on disk", size_on_disk)
+            }
+            Diagnostic::ChecksumRedownload { restart } => write!(f, "downloaded again after a checksum mismatch ({})", restart),
+            Diagnostic::CleanupFailed { path, message } => write!(f, "failed to remove {:?}: {}", path, message),
+            Diagnostic::CompressedResume { size_on_disk } => {
+                write!(f, "resumed after {} bytes on disk while accepting compressed encodings", size_on_disk)
+            }
+            Diagnostic::RedirectRefused { chain, host } => {
+                let chain: Vec<_> = chain.iter().map(Url::as_str).collect();
+                write!(f, "refused the redirect to {} after {}", host, chain.join(" -> "))
+            }
+            Diagnostic::HostCapabilities { host, ranges, version, encoding } => {
+                let ranges = if *ranges { "accepts" } else { "does not accept" };
+                write!(f, "not probed, {} {} ranges over {}", host, ranges, version)?;
+                match encoding {
+                    Some(encoding) => write!(f, " with {} encoding", encoding),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
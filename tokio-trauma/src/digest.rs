@@ -0,0 +1,221 @@
+//! Integrity checks computed while streaming a download
+
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use md5::{Digest, Md5};
+use reqwest::header::{HeaderMap, CONTENT_ENCODING};
+use reqwest::StatusCode;
+use sha1::Sha1;
+use sha2::Sha256;
+use tokio::io::AsyncReadExt;
+
+use crate::download::DigestKind;
+use crate::hash::DigestUpdate;
+
+/// Verification of the `Content-MD5` response header
+pub(crate) struct ContentMd5 {
+    expected: Vec<u8>,
+    hasher: Md5,
+}
+
+impl ContentMd5 {
+    /// Create a verifier when the response carries a `Content-MD5` header describing its body
+    ///
+    /// The header describes the whole entity before any content coding, so partial and
+    /// encoded responses are not verified.
+    pub(crate) fn from_response(status: StatusCode, headers: &HeaderMap) -> Option<Self> {
+        if status == StatusCode::PARTIAL_CONTENT || headers.contains_key(CONTENT_ENCODING) {
+            return None;
+        }
+
+        let value = headers.get("content-md5")?.to_str().ok()?;
+        match STANDARD.decode(value.trim()) {
+            Ok(expected) if expected.len() == 16 => Some(Self { expected, hasher: Md5::new() }),
+            _ => {
+                tracing::warn!("Ignoring invalid Content-MD5 header: {}", value);
+                None
+            }
+        }
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    pub(crate) fn verify(self) -> Result<(), String> {
+        let actual = self.hasher.finalize();
+        if actual.as_slice() == self.expected.as_slice() {
+            Ok(())
+        } else {
+            Err(format!("Content-MD5 mismatch: expected {}, got {}",
+                        STANDARD.encode(&self.expected), STANDARD.encode(actual)))
+        }
+    }
+}
+
+enum Hasher {
+    Md5(Md5),
+    Sha1(Sha1),
+    Sha256(Sha256),
+    Crc32(crc32fast::Hasher),
+}
+
+impl Hasher {
+    fn new(kind: DigestKind) -> Self {
+        match kind {
+            DigestKind::Md5 => Hasher::Md5(Md5::new()),
+            DigestKind::Sha1 => Hasher::Sha1(Sha1::new()),
+            DigestKind::Sha256 => Hasher::Sha256(Sha256::new()),
+            DigestKind::Crc32 => Hasher::Crc32(crc32fast::Hasher::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Md5(hasher) => hasher.update(data),
+            Hasher::Sha1(hasher) => hasher.update(data),
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Crc32(hasher) => hasher.update(data),
+        }
+    }
+
+    fn finish(self) -> String {
+        match self {
+            Hasher::Md5(hasher) => hex(&hasher.finalize()),
+            Hasher::Sha1(hasher) => hex(&hasher.finalize()),
+            Hasher::Sha256(hasher) => hex(&hasher.finalize()),
+            Hasher::Crc32(hasher) => format!("{:08x}", hasher.finalize()),
+        }
+    }
+}
+
+/// Several digests computed in a single pass over the data
+pub(crate) struct Digests {
+    hashers: Vec<(DigestKind, Hasher)>,
+    /// digest of a user algorithm
+    custom: Option<Box<dyn DigestUpdate>>,
+}
+
+impl Digests {
+    pub(crate) fn new(kinds: impl IntoIterator<Item = DigestKind>) -> Self {
+        let mut hashers: Vec<(DigestKind, Hasher)> = Vec::new();
+        for kind in kinds {
+            if hashers.iter().all(|(computed, _)| *computed != kind) {
+                hashers.push((kind, Hasher::new(kind)));
+            }
+        }
+        Self { hashers, custom: None }
+    }
+
+    /// Also feed the data to `custom`
+    pub(crate) fn with_custom(mut self, custom: Option<Box<dyn DigestUpdate>>) -> Self {
+        self.custom = custom;
+        self
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.hashers.is_empty() && self.custom.is_none()
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        for (_, hasher) in &mut self.hashers {
+            hasher.update(data);
+        }
+        if let Some(custom) = self.custom.as_mut() {
+            custom.update(data);
+        }
+    }
+
+    /// The digest of the user algorithm, if any
+    pub(crate) fn finish_custom(&mut self) -> Option<Vec<u8>> {
+        self.custom.take().map(DigestUpdate::finalize)
+    }
+
+    /// Feed the content of a file, e.g. the partial file a download resumes
+    pub(crate) async fn update_from_file(&mut self, path: &Path) -> io::Result<()> {
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            match file.read(&mut buf).await? {
+                0 => return Ok(()),
+                n => self.update(&buf[..n]),
+            }
+        }
+    }
+
+    /// The lowercase hex digests
+    pub(crate) fn finish(self) -> HashMap<DigestKind, String> {
+        self.hashers.into_iter().map(|(kind, hasher)| (kind, hasher.finish())).collect()
+    }
+}
+
+/// Hex SHA-256 of a file
+pub(crate) async fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut digests = Digests::new([DigestKind::Sha256]);
+    digests.update_from_file(path).await?;
+    Ok(digests.finish().remove(&DigestKind::Sha256).unwrap_or_default())
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use reqwest::header::{HeaderMap, HeaderValue, CONTENT_ENCODING};
+    use reqwest::StatusCode;
+
+    use crate::digest::{hex, ContentMd5, Digests};
+    use crate::download::DigestKind;
+
+    /// base64 of md5("hello world")
+    const HELLO_MD5: &str = "XrY7u+Ae7tCTyyK7j1rNww==";
+
+    fn headers(md5: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-md5", HeaderValue::from_str(md5).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_content_md5() {
+        let mut md5 = ContentMd5::from_response(StatusCode::OK, &headers(HELLO_MD5)).unwrap();
+        md5.update(b"hello ");
+        md5.update(b"world");
+        assert!(md5.verify().is_ok());
+
+        let mut md5 = ContentMd5::from_response(StatusCode::OK, &headers(HELLO_MD5)).unwrap();
+        md5.update(b"hello");
+        assert!(md5.verify().is_err());
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!("00ff1a", hex(&[0x00, 0xff, 0x1a]));
+    }
+
+    #[test]
+    fn test_digests() {
+        let mut digests = Digests::new([DigestKind::Sha256, DigestKind::Sha1, DigestKind::Crc32, DigestKind::Sha256]);
+        digests.update(b"hello ");
+        digests.update(b"world");
+        let digests = digests.finish();
+        assert_eq!(3, digests.len());
+        assert_eq!("b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9", digests[&DigestKind::Sha256]);
+        assert_eq!("2aae6c35c94fcfb415dbe95f408b9ce91ee846ed", digests[&DigestKind::Sha1]);
+        assert_eq!("0d4a1185", digests[&DigestKind::Crc32]);
+    }
+
+    #[test]
+    fn test_content_md5_skipped() {
+        assert!(ContentMd5::from_response(StatusCode::PARTIAL_CONTENT, &headers(HELLO_MD5)).is_none());
+        let mut encoded = headers(HELLO_MD5);
+        encoded.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        assert!(ContentMd5::from_response(StatusCode::OK, &encoded).is_none());
+        assert!(ContentMd5::from_response(StatusCode::OK, &headers("invalid")).is_none());
+    }
+}
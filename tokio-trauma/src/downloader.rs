@@ -1,28 +1,150 @@
-use std::{env, fs, io};
-use std::path::PathBuf;
-
-use futures_util::{stream, StreamExt};
-use reqwest::{Proxy, StatusCode};
-use reqwest::header::{HeaderMap, HeaderValue, IntoHeaderName, RANGE};
-use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
-use reqwest_retry::RetryTransientMiddleware;
-use reqwest_tracing::{DefaultSpanBackend, TracingMiddleware};
+use std::{env, fs, io, panic};
+use std::io::SeekFrom;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use futures_util::{future, stream, StreamExt};
+use reqwest::{redirect, Method, Proxy, Response, StatusCode};
+use reqwest::header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_RANGE, CONTENT_TYPE, ETAG, HeaderMap, HeaderValue, IF_NONE_MATCH, IF_RANGE, IntoHeaderName, RANGE};
+use reqwest_middleware::{ClientBuilder, ClientWithMiddleware, Middleware};
+use reqwest_retry::{default_on_request_failure, Retryable, RetryableStrategy, RetryTransientMiddleware};
+use reqwest_tracing::{DefaultSpanBackend, ReqwestOtelSpanBackend, TracingMiddleware};
 use retry_policies::policies::ExponentialBackoff;
+use retry_policies::{RetryDecision, RetryPolicy};
 use snafu::{location, Location, ResultExt};
-use tokio::fs::OpenOptions;
-use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::runtime::Handle;
+use tokio::task::JoinHandle;
+use tracing::Instrument;
 use url::Url;
 
-use crate::download::{Download, Status, Summary};
-use crate::error::{ReqwestSnafu, Result};
+use crate::attempts::{AttemptCounter, Attempts, Sent};
+use crate::buffer::{self, BufferPool, PooledWriter};
+use crate::cache::{self, Cached, ContentCache};
+use crate::capabilities::{Capabilities, HostCapabilities};
+use crate::capture::{Capture, Entry};
+use crate::checkpoint::Checkpoint;
+use crate::chunked::ChunkedFile;
+use crate::clock::Clock;
+use crate::completion::{CompletionStrategy, SizeCompletion};
+use crate::control::DownloadControl;
+use crate::diagnostic::Diagnostic;
+use crate::digest::{self, ContentMd5, Digests};
+use crate::download::{ByteRange, DigestKind, Download, EnglishMessages, FilenameStrategy, SkipReason, Status, StatusMessages, Summary};
+#[cfg(feature = "progress")]
+use crate::bars::Bars;
+#[cfg(feature = "encrypt")]
+use crate::encrypt::{Cipher, Encryptor};
+#[cfg(feature = "zip")]
+use crate::extract;
+use crate::finalize;
+use crate::host;
+use crate::error::{DisallowedSchemeSnafu, Error, IoSnafu, OutputIsDirectorySnafu, RequestFailedSnafu, ReqwestSnafu, Result, UnsupportedSchemeSnafu};
+use crate::progress::{ProgressEvent, ProgressHook};
+use crate::redirect::{refused, RedirectFollower, RedirectHook, RedirectRefused};
+use crate::queue::DownloadQueue;
+#[cfg(feature = "otel")]
+use crate::otel;
+#[cfg(feature = "pinning")]
+use crate::pinning::{self, CertPins};
+use crate::report::{DownloadReport, SizeEstimate};
+use crate::schedule::{AdaptiveConcurrency, Concurrency, ConcurrencyGauge, InFlight, ScheduleWindow, Stagger};
+use crate::shared::Shared;
+#[cfg(feature = "signal")]
+use crate::signal::Interrupt;
+use crate::template::{FilenameTemplate, Variables};
+use crate::throttle::{HostBuckets, Throttle, TokenBucket};
+use crate::transform::{Pipeline, StreamTransform, TransformFactory};
+
+/// `SETTINGS_MAX_CONCURRENT_STREAMS` most HTTP/2 servers advertise
+const HTTP2_STREAM_LIMIT: u8 = 100;
+
+/// Size of the reads of a local file copied by a `file://` download
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
 
 #[derive(Debug, Clone)]
 pub struct Downloader {
-    directory: PathBuf,
+    pub(crate) directory: PathBuf,
     retries: u32,
-    concurrent_downloads: u8,
+    pub(crate) concurrent_downloads: u8,
+    adaptive_concurrency: Option<AdaptiveConcurrency>,
+    concurrency_ramp: Duration,
     resume: bool,
+    pub(crate) ordered: bool,
     headers: Option<HeaderMap>,
+    resolve: Vec<(String, SocketAddr)>,
+    read_timeout: Option<Duration>,
+    tracing: Tracing,
+    capture: Option<PathBuf>,
+    pub(crate) filename_template: Option<FilenameTemplate>,
+    verify_content_md5: bool,
+    fail_on_empty: bool,
+    schedule_window: Option<ScheduleWindow>,
+    fsync_interval: Option<FsyncInterval>,
+    size_threshold: Option<u64>,
+    small_concurrency: Option<u8>,
+    large_concurrency: Option<u8>,
+    content_cache: Option<Shared<ContentCache>>,
+    pub(crate) follow_pagination: bool,
+    skip_missing: bool,
+    on_skip: Option<Shared<SkipHook>>,
+    on_batch_complete: Option<Shared<BatchHook>>,
+    on_progress: Option<Shared<ProgressHook>>,
+    digests: Vec<DigestKind>,
+    write_buffer_size: usize,
+    retry_classifier: Option<Shared<StatusClassifier>>,
+    default_filename: Option<FilenameStrategy>,
+    pub(crate) symlink_policy: SymlinkPolicy,
+    resume_mode: ResumeMode,
+    pub(crate) checkpoint: Option<PathBuf>,
+    completion: Option<Shared<dyn CompletionStrategy>>,
+    reject_html_for: Vec<String>,
+    redirect_policy: Option<RedirectPolicy>,
+    batch_timeout: Option<Duration>,
+    max_buffer_memory: Option<usize>,
+    response_gate: Option<Shared<ResponseGate>>,
+    single_connection: bool,
+    redownload_on_checksum_failure: u32,
+    launch_delay: Duration,
+    launch_jitter: Duration,
+    preallocate: bool,
+    create_new: bool,
+    no_truncate: bool,
+    clock: Clock,
+    retry: bool,
+    on_redirect: Option<Shared<RedirectHook>>,
+    pub(crate) temp_dir: Option<PathBuf>,
+    coalesce_identical_urls: bool,
+    /// directories created by `prepare`, shared by the clones of the downloader
+    prepared: Shared<Mutex<HashSet<PathBuf>>>,
+    max_retry_duration: Option<Duration>,
+    order_by: Option<Shared<DownloadOrder>>,
+    report_path: Option<PathBuf>,
+    accept_encoding: Option<Vec<Encoding>>,
+    status_messages: Option<Shared<dyn StatusMessages>>,
+    split_storage: Option<u64>,
+    allowed_schemes: Vec<String>,
+    per_host_rate_limit: Option<u64>,
+    /// buckets of `per_host_rate_limit`, shared by the clones of the downloader
+    host_buckets: Shared<HostBuckets>,
+    runtime_handle: Option<Handle>,
+    transforms: Vec<Shared<TransformFactory>>,
+    #[cfg(feature = "zip")]
+    pub(crate) extract_zip: bool,
+    #[cfg(feature = "compress")]
+    compress_output: Option<Compression>,
+    #[cfg(feature = "pinning")]
+    cert_pins: CertPins,
+    #[cfg(feature = "signal")]
+    signal_handler: bool,
+    #[cfg(feature = "zstd")]
+    decompress_zstd: bool,
 }
 
 impl Downloader {
@@ -36,202 +158,4910 @@ impl Downloader {
 }
 
 impl Downloader {
-    pub async fn download(&self, downloads: impl AsRef<[Download]>) -> Result<Vec<Summary>> {
+    pub async fn download(&self, downloads: impl AsRef<[Download]>) -> Result<DownloadReport> {
         self.proxy_download(downloads.as_ref(), None).await
     }
 
-    pub async fn proxy_download(&self, downloads: &[Download], proxy: Option<Proxy>) -> Result<Vec<Summary>> {
-        let mut client_builder = reqwest::Client::builder();
-        if let Some(proxy) = proxy {
-            client_builder = client_builder.proxy(proxy);
+    /// Download every url to its destination path, e.g. `(url, "/data/a.bin".into())`, the
+    /// filename is the last component of the path and its parent overrides the downloader
+    /// directory. A relative destination is relative to the current directory.
+    pub async fn download_pairs(&self, pairs: impl IntoIterator<Item = (Url, PathBuf)>) -> Result<DownloadReport> {
+        let downloads: Vec<_> = pairs.into_iter()
+            .map(|(url, destination)| {
+                let filename = destination.file_name().unwrap_or_default().to_string_lossy().into_owned();
+                let directory = destination.parent().map(Path::to_path_buf).unwrap_or_default();
+                Download::new(url, filename).with_directory(directory)
+            })
+            .collect();
+        self.download(&downloads).await
+    }
+
+    /// Download again the failed downloads of `report`, e.g. a report of an earlier batch, with the
+    /// configuration each had such as its checksum or range. Resuming continues their partial files.
+    ///
+    /// The new report only has the summaries of the retried downloads, in the order of `report`
+    /// when `ordered`. It is empty when nothing failed.
+    pub async fn retry_failed(&self, report: &DownloadReport) -> Result<DownloadReport> {
+        let failed: Vec<_> = report.failures().map(|summary| summary.download().clone()).collect();
+        if failed.is_empty() {
+            return Ok(DownloadReport::default());
         }
-        if let Some(headers) = &self.headers {
-            client_builder = client_builder.default_headers(headers.clone());
+        self.download(&failed).await
+    }
+
+    pub async fn proxy_download(&self, downloads: &[Download], proxy: Option<Proxy>) -> Result<DownloadReport> {
+        let mut batch = self.checkpointed(self.batch(proxy)?)?;
+        batch.deadline = self.batch_timeout.map(|timeout| self.clock.now() + timeout);
+        #[cfg(feature = "signal")]
+        if let Some(interrupt) = self.interruptible(&mut batch) {
+            return interrupt.run(self.run(batch, downloads)).await;
         }
-        let client = client_builder.build()
-            .context(ReqwestSnafu { location: location!() })?;
+        Ok(self.run(batch, downloads).await)
+    }
 
-        let retry_policy = ExponentialBackoff::builder()
-            .build_with_max_retries(self.retries);
-        let client = ClientBuilder::new(client)
-            .with(TracingMiddleware::<DefaultSpanBackend>::new())  // Trace Http Request
-            .with(RetryTransientMiddleware::new_with_policy(retry_policy))  // Retry failed requests
-            .build();
+    /// Download while `control` may cancel the whole batch or single downloads
+    pub async fn download_controlled(&self, downloads: &[Download], control: &DownloadControl) -> Result<DownloadReport> {
+        let mut batch = self.checkpointed(self.batch(None)?)?;
+        batch.control = Some(control.clone());
+        batch.deadline = self.batch_timeout.map(|timeout| self.clock.now() + timeout);
+        let observed = self.observed_by(control);
+        #[cfg(feature = "signal")]
+        if let Some(interrupt) = self.interruptible(&mut batch) {
+            return interrupt.run(observed.run(batch, downloads)).await;
+        }
+        Ok(observed.run(batch, downloads).await)
+    }
 
-        let summaries = stream::iter(downloads)
-            .map(|download| self.fetch(&client, download))
-            .buffer_unordered(self.concurrent_downloads as usize)
-            .collect()
-            .await;
-        Ok(summaries)
+    /// Listen to Ctrl-C for the batch until the returned listener is dropped, if the signal handler is enabled
+    #[cfg(feature = "signal")]
+    fn interruptible(&self, batch: &mut Batch) -> Option<Interrupt> {
+        self.signal_handler
+            .then(|| Interrupt::listen(batch.control.get_or_insert_with(DownloadControl::new).clone()))
     }
 
-    async fn fetch(&self, client: &ClientWithMiddleware, download: &Download) -> Summary {
-        let mut size_on_disk: u64 = 0;
-        let mut can_resume = false;
-        let output_path = self.directory.join(&download.filename);
-        let mut summary = Summary {
-            download: download.clone(),
-            status_code: StatusCode::BAD_REQUEST,
-            size: size_on_disk,
-            status: Status::NotStarted,
-            resume: can_resume,
-        };
-        let mut content_length = None;
-
-        // Handling interrupted file downloads
-        if self.resume {
-            match download.fetch_range(client).await {
-                Ok(data) => {
-                    can_resume = data.resume;
-                    content_length = data.size;
+    async fn run(&self, batch: Batch, downloads: &[Download]) -> DownloadReport {
+        #[cfg(feature = "otel")]
+        let span = otel::batch_span(downloads.len());
+        let running = self.run_on_runtime(batch, downloads);
+        #[cfg(feature = "otel")]
+        let running = running.instrument(span.clone());
+        let report = running.await;
+        #[cfg(feature = "otel")]
+        otel::record_batch(&span, &report);
+        report
+    }
+
+    /// Run the batch as a task of the `runtime_handle`, or in place without one
+    ///
+    /// The task is aborted when the returned future is dropped, as the downloads would be when
+    /// running in place. A panic of the task is resumed in the caller.
+    async fn run_on_runtime(&self, batch: Batch, downloads: &[Download]) -> DownloadReport {
+        let Some(handle) = &self.runtime_handle else {
+            return self.run_batch(&batch, downloads).await;
+        };
+        let downloader = self.clone();
+        let owned = downloads.to_vec();
+        let running = async move { downloader.run_batch(&batch, &owned).await };
+        let mut task = AbortOnDrop(handle.spawn(running.in_current_span()));
+        match (&mut task.0).await {
+            Ok(report) => report,
+            Err(err) if err.is_panic() => panic::resume_unwind(err.into_panic()),
+            // The runtime shut down before the batch completed
+            Err(_) => DownloadReport::new(downloads.iter()
+                .map(|download| Summary::new(self.named(download).into_owned()).fail("the download runtime shut down"))
+                .collect()),
+        }
+    }
+
+    /// The runtime the downloads are spawned onto, the current one without a `runtime_handle`
+    ///
+    /// # Panics
+    ///
+    /// Panics without a `runtime_handle` when called outside of a tokio runtime.
+    pub(crate) fn runtime(&self) -> Handle {
+        self.runtime_handle.clone().unwrap_or_else(Handle::current)
+    }
+
+    async fn run_batch(&self, batch: &Batch, downloads: &[Download]) -> DownloadReport {
+        if let Some(checkpoint) = &batch.checkpoint {
+            checkpoint.add(downloads);
+        }
+        let mut downloads = downloads.iter().enumerate().collect::<Vec<_>>();
+        if let Some(order) = &self.order_by {
+            downloads.sort_by(|(_, a), (_, b)| order(a, b));
+        }
+        let (downloads, duplicates) = self.coalesced(downloads);
+        let mut summaries = match self.size_threshold {
+            Some(threshold) => self.drive_by_size(batch, downloads, threshold).await,
+            None => self.drive(batch, downloads, self.concurrency()).await,
+        };
+        if !duplicates.is_empty() {
+            let fanned_out = self.fan_out(batch, &summaries, duplicates);
+            summaries.extend(fanned_out);
+        }
+        if self.ordered {
+            summaries.sort_by_key(|(index, _)| *index);
+        }
+        let summaries = summaries.into_iter().map(|(_, summary)| summary).collect();
+        let report = DownloadReport::new(summaries).with_concurrency(batch.running.stats());
+        // The downloads are done, a report that can't be written does not fail them
+        if let Some(path) = &self.report_path {
+            let messages = self.status_messages.as_deref().unwrap_or(&EnglishMessages);
+            if let Err(err) = report.write_json_with(path, messages) {
+                tracing::warn!("Failed to write the report {:?}: {}", path, err);
+            }
+        }
+        if let Some(on_batch_complete) = &self.on_batch_complete {
+            on_batch_complete(&report);
+        }
+        report
+    }
+
+    /// Create the directories of all the downloads once before downloading them, failing on the
+    /// first directory that can't be created instead of failing every download in it
+    ///
+    /// Downloads into the prepared directories then skip creating their directory, the
+    /// directories must stay in place until they are written.
+    pub fn prepare(&self, downloads: impl AsRef<[Download]>) -> Result<()> {
+        let folders: BTreeSet<_> = downloads.as_ref().iter()
+            .filter_map(|download| self.output_path(&self.named(download)).parent().map(Path::to_path_buf))
+            .collect();
+        let mut prepared = self.prepared.lock().unwrap_or_else(|err| err.into_inner());
+        for folder in folders {
+            if prepared.contains(&folder) {
+                continue;
+            }
+            tracing::debug!("Creating destination directory {:?}", folder);
+            fs::create_dir_all(&folder).context(IoSnafu { path: folder.clone(), location: location!() })?;
+            prepared.insert(folder);
+        }
+        Ok(())
+    }
+
+    /// Whether `prepare` created `folder`
+    fn is_prepared(&self, folder: &Path) -> bool {
+        self.prepared.lock().unwrap_or_else(|err| err.into_inner()).contains(folder)
+    }
+
+    /// Split off the downloads of a url an earlier download of the batch fetches, paired with the
+    /// index of that earlier download
+    fn coalesced<'a>(&self, downloads: Vec<(usize, &'a Download)>) -> (Vec<(usize, &'a Download)>, Vec<Duplicate<'a>>) {
+        if !self.coalesce_identical_urls {
+            return (downloads, Vec::new());
+        }
+        let mut leaders = HashMap::new();
+        let mut duplicates = Vec::new();
+        let downloads = downloads.into_iter()
+            .filter(|&(index, download)| {
+                // Partial fetches of a url get different bytes
+                if download.partial() {
+                    return true;
                 }
-                Err(err) => return summary.fail(err),
-            };
+                match leaders.get(&download.url) {
+                    Some(leader) => {
+                        duplicates.push(Duplicate { index, download, leader: *leader });
+                        false
+                    }
+                    None => {
+                        leaders.insert(&download.url, index);
+                        true
+                    }
+                }
+            })
+            .collect();
+        (downloads, duplicates)
+    }
 
-            // check if there is a file on disk already
-            if can_resume && output_path.exists() {
-                size_on_disk = match output_path.metadata() {
-                    Ok(metadata) => metadata.len(),
-                    Err(err) => return summary.fail(err),
+    /// Link or copy the file of every coalesced download from the download of its url, which
+    /// gives its status to the coalesced downloads when it has no file to share
+    fn fan_out(&self, batch: &Batch, summaries: &[(usize, Summary)], duplicates: Vec<Duplicate<'_>>) -> Vec<(usize, Summary)> {
+        let leaders: HashMap<_, _> = summaries.iter().map(|(index, summary)| (*index, summary)).collect();
+        duplicates.into_iter()
+            .map(|duplicate| {
+                let named = self.named(duplicate.download);
+                let output_path = self.output_path(&named);
+                let mut summary = Summary::new(named.clone().into_owned()).with_path(output_path.clone());
+                summary = match leaders.get(&duplicate.leader).map(|leader| (leader, leader.status())) {
+                    Some((leader, Status::Success | Status::Skipped(SkipReason::Complete | SkipReason::Cached))) => {
+                        let linked = self.symlink_policy.apply(&output_path)
+                            .and_then(|_| cache::link_or_copy(leader.path(), &output_path));
+                        match linked {
+                            Ok(()) => {
+                                tracing::debug!("Coalesced {:?} with {:?}", output_path, leader.path());
+                                summary.size = leader.size;
+                                summary.etag = leader.etag.clone();
+                                summary.with_status(Status::Skipped(SkipReason::Cached))
+                            }
+                            Err(err) => summary.fail(err),
+                        }
+                    }
+                    Some((_, status)) => summary.with_status(status.clone()),
+                    None => summary,
                 };
+                self.finished(batch, duplicate.download, &summary);
+                (duplicate.index, summary)
+            })
+            .collect()
+    }
+
+    /// Estimate the total size of the downloads without downloading them.
+    ///
+    /// Every download is probed with a `HEAD` request, `concurrent_downloads` at a time. Downloads
+    /// whose size stays unknown, because the server omits it or the probe failed, are counted
+    /// instead of failing the estimate.
+    pub async fn total_size(&self, downloads: impl AsRef<[Download]>) -> Result<SizeEstimate> {
+        let batch = self.batch(None)?;
+        let downloads = downloads.as_ref().iter().enumerate().collect::<Vec<_>>();
+        let estimate = self.probe_sizes(&batch, &downloads).await.into_iter()
+            .fold(SizeEstimate::default(), |mut estimate, (_, size)| {
+                match size {
+                    Some(size) => estimate.known_bytes += size,
+                    None => estimate.unknown_count += 1,
+                }
+                estimate
+            });
+        Ok(estimate)
+    }
+
+    /// Download while drawing an `indicatif` progress bar per running download and a total bar
+    ///
+    /// A hook set with `on_progress` is still called.
+    #[cfg(feature = "progress")]
+    pub async fn download_with_bars(&self, downloads: impl AsRef<[Download]>) -> Result<DownloadReport> {
+        let bars = Arc::new(Bars::new());
+        let mut downloader = self.clone();
+        let previous = downloader.on_progress.take();
+        let hook = bars.clone();
+        downloader.on_progress = Some(Shared(Arc::new(move |event: &ProgressEvent<'_>| {
+            hook.update(event);
+            if let Some(previous) = &previous {
+                previous(event);
             }
+        })));
+        let report = downloader.download(downloads).await;
+        bars.finish();
+        report
+    }
 
-            // update summary resume field
-            summary.resume = can_resume;
+    /// Create a queue fed with downloads over time and driven by a background task
+    pub fn queue(&self) -> Result<DownloadQueue> {
+        self.queue_with_proxy(None)
+    }
+
+    pub fn queue_with_proxy(&self, proxy: Option<Proxy>) -> Result<DownloadQueue> {
+        let mut batch = self.checkpointed(self.batch(proxy)?)?;
+        let control = DownloadControl::new();
+        batch.control = Some(control.clone());
+        Ok(DownloadQueue::spawn(self.observed_by(&control), batch, control))
+    }
+
+    /// A downloader also reporting its progress to `control`
+    fn observed_by(&self, control: &DownloadControl) -> Downloader {
+        let mut downloader = self.clone();
+        let previous = downloader.on_progress.take();
+        let control = control.clone();
+        downloader.on_progress = Some(Shared(Arc::new(move |event: &ProgressEvent<'_>| {
+            control.observe(event);
+            if let Some(previous) = &previous {
+                previous(event);
+            }
+        })));
+        downloader
+    }
+
+    /// Stream the body of `download` into `writer` instead of a file, e.g. into a pipe
+    ///
+    /// Nothing is written to disk and nothing is resumed. A failed request or a body interrupted
+    /// midway is reported in the summary, the part of the body already written stays written. A
+    /// failing writer, such as a closed pipe, is the error. The writer is flushed once the body ends.
+    pub async fn download_to_writer<W>(&self, download: &Download, writer: &mut W) -> Result<Summary>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let batch = self.batch(None)?;
+        let summary = self.stream_to(&batch, download, writer).await?;
+        self.progress(ProgressEvent::Finished { summary: &summary });
+        Ok(summary)
+    }
+
+    /// Stream the body of `download` to the standard output, see `download_to_writer`
+    pub async fn download_to_stdout(&self, download: &Download) -> Result<Summary> {
+        self.download_to_writer(download, &mut tokio::io::stdout()).await
+    }
+
+    async fn stream_to<W>(&self, batch: &Batch, download: &Download, writer: &mut W) -> Result<Summary>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut summary = Summary::new(download.clone());
+        let (client, routed) = match self.route(batch, download) {
+            Ok(route) => route,
+            Err(err) => return Ok(summary.fail(err)),
+        };
+        tracing::debug!("Fetching Url: {}", download.redacted_url());
+        let attempts = Attempts::default();
+        let sent = routed.request(&client, Method::GET).with_extension(attempts.clone()).send().await;
+        summary.attempts = attempts.count();
+        let response = match sent {
+            Ok(response) => response,
+            Err(err) => return Ok(fail_request(summary, &err)),
+        };
+        summary.status_code = Some(response.status());
+        if let Err(err) = response.error_for_status_ref() {
+            return Ok(summary.fail(err));
+        }
+        if let Err(message) = self.gate(&response) {
+            return Ok(summary.fail(message));
         }
 
-        // 1.If content_length exists and is equal to the size of the file, the download is considered complete.
-        // 2.If the file size is not empty and is equal to the sum of the two, it is considered that the download is completed.
-        let size = content_length.unwrap_or_default() + size_on_disk;
-        if matches!(content_length, Some(content_length) if content_length == size_on_disk) ||
-            size_on_disk > 0 && size == size_on_disk {
-            return summary.with_status(Status::Skipped(String::from("the file was already full download")));
+        let expected = response.content_length();
+        self.progress(ProgressEvent::Started { download, total: expected, resumed: 0 });
+        let mut bucket = self.throttle(download);
+        let sent = Sent::of(&response);
+        let mut stream = response.bytes_stream();
+        while let Some(chunk) = stream.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(err) => return Ok(summary.fail(err)),
+            };
+            if summary.ttfb.is_none() {
+                summary.ttfb = sent.map(|sent| sent.elapsed());
+            }
+            let len = chunk.len() as u64;
+            if let Some(bucket) = bucket.as_mut() {
+                summary.throttled |= bucket.acquire(len).await;
+            }
+            writer.write_all(&chunk).await
+                .context(IoSnafu { path: PathBuf::new(), location: location!() })?;
+            summary.size += len;
+            self.progress(ProgressEvent::Progress { download, bytes: len, downloaded: summary.size, total: expected });
         }
+        writer.flush().await
+            .context(IoSnafu { path: PathBuf::new(), location: location!() })?;
 
-        // Create download request object
-        tracing::debug!("Fetching Url: {}", &download.url);
-        let mut request = client.get(download.url.as_str());
-        if self.resume && can_resume {
-            request = request.header(RANGE, format!("bytes={}-", size_on_disk));
+        // A connection closed early can end the stream without an error
+        if let Some(expected) = expected.filter(|expected| *expected != summary.size) {
+            return Ok(summary.fail(format!("incomplete: got {} of {} bytes", summary.size, expected)));
         }
-        if let Some(ref header) = self.headers {
-            request = request.headers(header.clone());
+        Ok(summary.with_status(Status::Success))
+    }
+
+    /// Download at most `max` bytes of a resource into memory, e.g. to sniff its header or preview it
+    ///
+    /// Returns the bytes and whether the content was longer and cut at `max`. The rest of the
+    /// body is never read: the response is dropped, which closes its connection instead of
+    /// returning it to the pool. The rate limit of the download applies to the bytes read.
+    pub async fn download_bytes_capped(&self, download: &Download, max: usize) -> Result<(Vec<u8>, bool)> {
+        let batch = self.batch(None)?;
+        let (client, routed) = self.route(&batch, download)?;
+        let failed = |message: String| RequestFailedSnafu { url: download.redacted_url().to_string(), message, location: location!() };
+        tracing::debug!("Fetching at most {} bytes of Url: {}", max, download.redacted_url());
+        let response = routed.request(&client, Method::GET).send().await
+            .map_err(|err| failed(request_failure(&err)).build())?;
+        let response = response.error_for_status()
+            .context(ReqwestSnafu { location: location!() })?;
+        self.gate(&response).map_err(|message| failed(message).build())?;
+
+        let mut bucket = self.throttle(download);
+        let mut body = Vec::with_capacity(response.content_length().map_or(max, |len| len as usize).min(max));
+        let mut stream = response.bytes_stream();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.context(ReqwestSnafu { location: location!() })?;
+            let take = chunk.len().min(max - body.len());
+            if let Some(bucket) = bucket.as_mut() {
+                bucket.acquire(take as u64).await;
+            }
+            body.extend_from_slice(&chunk[..take]);
+            if take < chunk.len() {
+                return Ok((body, true));
+            }
         }
+        Ok((body, false))
+    }
 
-        // Sending download request
-        let response = match request.send().await {
-            Ok(response) => response,
-            Err(err) => return summary.fail(err),
+    /// Resume an in-memory download whose first bytes are `existing`, appending the rest of the
+    /// resource to them, e.g. after an interrupted `download_bytes_capped`
+    ///
+    /// The rest is requested from `existing.len()` and the server must answer it with
+    /// `206 Partial Content` starting at that offset. The combined content must have the total
+    /// size reported by the server, already complete bytes are returned as is.
+    pub async fn download_bytes_resume(&self, download: &Download, mut existing: Vec<u8>) -> Result<Vec<u8>> {
+        let batch = self.batch(None)?;
+        let (client, routed) = self.route(&batch, download)?;
+        let failed = |message: String| RequestFailedSnafu { url: download.redacted_url().to_string(), message, location: location!() };
+        let offset = existing.len() as u64;
+        tracing::debug!("Resuming from {} bytes Url: {}", offset, download.redacted_url());
+        let mut request = routed.request(&client, Method::GET);
+        if offset > 0 {
+            request = request.header(RANGE, format!("bytes={}-", offset));
+        }
+        let response = request.send().await
+            .map_err(|err| failed(request_failure(&err)).build())?;
+        let content_range = response.headers().get(CONTENT_RANGE)
+            .and_then(|val| val.to_str().ok())
+            .map(str::to_string);
+        // A range starting at the end of the resource is not satisfiable, `bytes */<total>`
+        if offset > 0 && response.status() == StatusCode::RANGE_NOT_SATISFIABLE
+            && content_range.as_deref().and_then(|val| val.strip_prefix("bytes */")) == Some(offset.to_string().as_str()) {
+            return Ok(existing);
+        }
+        let response = response.error_for_status()
+            .context(ReqwestSnafu { location: location!() })?;
+        self.gate(&response).map_err(|message| failed(message).build())?;
+
+        let total = if offset > 0 {
+            if response.status() != StatusCode::PARTIAL_CONTENT {
+                return Err(failed(format!("the server ignored the range request and returned {}", response.status())).build());
+            }
+            match content_range.as_deref().and_then(ByteRange::parse_content_range) {
+                Some((returned, total)) if returned.start == offset => total,
+                Some((returned, _)) => return Err(failed(format!(
+                    "the server resumed from {} instead of {}", returned.start, offset)).build()),
+                None => return Err(failed("the server response does not contain a valid Content-Range".into()).build()),
+            }
+        } else {
+            response.content_length()
         };
-        summary.status_code = response.status();
-        summary.size = size;
-        summary.resume = can_resume;
-        if let Err(err) = response.error_for_status_ref() {
-            return summary.fail(err);
+
+        let mut bucket = self.throttle(download);
+        let mut stream = response.bytes_stream();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.context(ReqwestSnafu { location: location!() })?;
+            if let Some(bucket) = bucket.as_mut() {
+                bucket.acquire(chunk.len() as u64).await;
+            }
+            existing.extend_from_slice(&chunk);
+        }
+        match total {
+            Some(total) if existing.len() as u64 != total => {
+                Err(failed(format!("incomplete: got {} of {} bytes", existing.len(), total)).build())
+            }
+            _ => Ok(existing),
         }
+    }
 
-        // Process the directory where downloaded files are stored
-        let folder = output_path.parent().unwrap_or(&output_path);
-        tracing::debug!("Creating destination directory {:?}", folder);
-        if let Err(err) = fs::create_dir_all(folder) {
-            return summary.fail(err);
+    pub(crate) fn concurrency(&self) -> Concurrency {
+        if self.single_connection {
+            return Concurrency::new(HTTP2_STREAM_LIMIT);
         }
+        if self.adaptive_concurrency.is_none() && !self.concurrency_ramp.is_zero() {
+            return Concurrency::ramped(self.concurrent_downloads, self.concurrency_ramp, self.clock.clone());
+        }
+        match self.adaptive_concurrency {
+            Some(bounds) => Concurrency::adaptive(bounds, self.clock.clone()),
+            None => Concurrency::new(self.concurrent_downloads),
+        }
+    }
 
-        let result = OpenOptions::new().create(true)
-            .write(true).append(can_resume)
-            .open(output_path).await;
-        let file = match result {
-            Ok(file) => file,
-            Err(err) => return summary.fail(err),
+    /// Build the http client and the shared state of a batch
+    pub(crate) fn batch(&self, proxy: Option<Proxy>) -> Result<Batch> {
+        let mut client_builder = self.client_builder()?;
+        if let Some(proxy) = proxy {
+            client_builder = client_builder.proxy(proxy);
+        }
+        let http = client_builder.build()
+            .context(ReqwestSnafu { location: location!() })?;
+        let client = self.with_middleware(http.clone(), self.retries);
+
+        let capture = match &self.capture {
+            Some(path) => Some(Capture::open(path)
+                .context(IoSnafu { path: path.clone(), location: location!() })?),
+            None => None,
         };
-        let mut file = BufWriter::new(file);
+        Ok(Batch {
+            http,
+            client,
+            clients: Mutex::default(),
+            #[cfg(unix)]
+            sockets: Mutex::default(),
+            capture,
+            buffers: BufferPool::new(self.write_buffer_size).with_memory_limit(self.max_buffer_memory),
+            control: None,
+            checkpoint: None,
+            deadline: None,
+            stagger: (!self.launch_delay.is_zero() || !self.launch_jitter.is_zero())
+                .then(|| Stagger::new(self.launch_delay, self.launch_jitter, self.clock.clone())),
+            running: ConcurrencyGauge::new(self.clock.clone()),
+            hosts: HostCapabilities::default(),
+        })
+    }
 
-        // Stream response content and write to file
-        let mut final_size = size_on_disk;
-        let mut stream = response.bytes_stream();
-        while let Some(data) = stream.next().await {
-            let mut chunk = match data {
-                Ok(chunk) => chunk,
-                Err(err) => return summary.fail(err),
+    /// Record the pending downloads of the batch in the checkpoint file, if any
+    fn checkpointed(&self, mut batch: Batch) -> Result<Batch> {
+        if let Some(path) = &self.checkpoint {
+            let checkpoint = Checkpoint::create(path)
+                .context(IoSnafu { path: path.clone(), location: location!() })?;
+            batch.checkpoint = Some(Arc::new(checkpoint));
+        }
+        Ok(batch)
+    }
+
+    /// The http client configuration shared by every client of a batch
+    fn client_builder(&self) -> Result<reqwest::ClientBuilder> {
+        let mut client_builder = reqwest::Client::builder();
+        for (host, addr) in &self.resolve {
+            client_builder = client_builder.resolve(host, *addr);
+        }
+        if let Some(timeout) = self.read_timeout {
+            client_builder = client_builder.read_timeout(timeout);
+        }
+        // Redirects whose targets get rewritten are followed by the redirect middleware
+        if self.on_redirect.is_some() {
+            client_builder = client_builder.redirect(redirect::Policy::none());
+        } else if let Some(policy) = self.redirect_policy {
+            client_builder = client_builder.redirect(policy.policy());
+        }
+        if self.single_connection {
+            client_builder = client_builder
+                .http2_prior_knowledge()
+                .http2_adaptive_window(true)
+                .pool_max_idle_per_host(1);
+        }
+        // Common headers are set once on the client, requests only carry per-download headers
+        if let Some(headers) = &self.headers {
+            client_builder = client_builder.default_headers(headers.clone());
+        }
+        #[cfg(feature = "pinning")]
+        if !self.cert_pins.is_empty() {
+            client_builder = client_builder.use_preconfigured_tls(pinning::client_config(&self.cert_pins)?);
+        }
+        Ok(client_builder)
+    }
+
+    /// Wrap the http client into the tracing and retry middlewares
+    fn with_middleware(&self, http: reqwest::Client, retries: u32) -> ClientWithMiddleware {
+        let retry_policy = TimedRetries {
+            backoff: ExponentialBackoff::builder().build_with_max_retries(retries),
+            budget: self.max_retry_duration,
+        };
+        let mut client = ClientBuilder::new(http);
+        // Trace Http Request
+        match &self.tracing {
+            Tracing::Default => client = client.with(TracingMiddleware::<DefaultSpanBackend>::new()),
+            Tracing::Custom(middleware) => client = client.with_arc(middleware.0.clone()),
+            Tracing::Disabled => {}
+        }
+        // Retry failed requests
+        if self.retry {
+            client = match &self.retry_classifier {
+                Some(classifier) => client.with(RetryTransientMiddleware::new_with_policy_and_strategy(
+                    retry_policy, RetryClassifier(classifier.clone()))),
+                None => client.with(RetryTransientMiddleware::new_with_policy(retry_policy)),
             };
+        }
+        client = client.with(AttemptCounter);
+        if let Some(hook) = &self.on_redirect {
+            client = client.with(RedirectFollower { policy: self.redirect_policy, hook: hook.clone() });
+        }
+        client.build()
+    }
 
-            final_size += chunk.len() as u64;
-            match file.write_all_buf(&mut chunk).await {
-                Ok(_) => {}
-                Err(err) => return summary.fail(err),
+    /// Run the downloads while keeping the number of running downloads at the concurrency limit
+    async fn drive(&self, batch: &Batch, downloads: Vec<(usize, &Download)>,
+                   mut concurrency: Concurrency) -> Vec<(usize, Summary)> {
+        let mut summaries = Vec::with_capacity(downloads.len());
+        let mut pending = downloads.into_iter();
+        let mut in_flight = InFlight::new(self.ordered);
+        loop {
+            while in_flight.len() < concurrency.limit() {
+                match pending.next() {
+                    Some((index, download)) => in_flight.push(async move {
+                        (index, self.fetch(batch, download).await)
+                    }),
+                    None => break,
+                }
+            }
+            // A ramping limit lets more downloads start without waiting for a completion
+            let next = match concurrency.next_increase() {
+                Some(increase) => tokio::select! {
+                    next = in_flight.next() => next,
+                    _ = self.clock.sleep_until(increase) => continue,
+                },
+                _ => in_flight.next().await,
+            };
+            match next {
+                Some((index, summary)) => {
+                    concurrency.record(&summary);
+                    summaries.push((index, summary));
+                }
+                None => break,
             }
         }
+        summaries
+    }
 
-        summary.with_status(Status::Success)
+    /// Probe the size of every download, then run small and large downloads in separate pools
+    ///
+    /// Downloads whose size is unknown are considered large.
+    async fn drive_by_size(&self, batch: &Batch, downloads: Vec<(usize, &Download)>,
+                           threshold: u64) -> Vec<(usize, Summary)> {
+        let sizes = self.probe_sizes(batch, &downloads).await;
+        let small_indexes: HashSet<_> = sizes.into_iter()
+            .filter(|(_, size)| matches!(size, Some(size) if *size < threshold))
+            .map(|(index, _)| index)
+            .collect();
+        let (small, large): (Vec<_>, Vec<_>) = downloads.into_iter()
+            .partition(|(index, _)| small_indexes.contains(index));
+
+        let small_concurrency = self.small_concurrency.unwrap_or(self.concurrent_downloads);
+        let large_concurrency = self.large_concurrency.unwrap_or(self.concurrent_downloads);
+        let (mut small, large) = future::join(
+            self.drive(batch, small, Concurrency::new(small_concurrency)),
+            self.drive(batch, large, Concurrency::new(large_concurrency)),
+        ).await;
+        small.extend(large);
+        small
     }
-}
 
-impl Default for Downloader {
-    fn default() -> Self {
-        Self {
-            directory: env::current_dir().unwrap_or_default(),
-            retries: 0,
-            concurrent_downloads: 32,
-            resume: true,
-            headers: None,
-        }
+    /// Probe the sizes of the downloads with `HEAD` requests, `concurrent_downloads` at a time
+    ///
+    /// Downloads whose probe fails fall back to their expected size.
+    async fn probe_sizes(&self, batch: &Batch, downloads: &[(usize, &Download)]) -> Vec<(usize, Option<u64>)> {
+        stream::iter(downloads)
+            .map(|(index, download)| async move {
+                let probe = match self.route(batch, download) {
+                    Ok((client, routed)) => routed.fetch_range(&client).await.map_err(|err| err.to_string()),
+                    Err(err) => Err(err.to_string()),
+                };
+                let size = match probe {
+                    Ok(data) => download.total_size(data.size),
+                    Err(err) => {
+                        tracing::debug!("Failed to probe the size of {}: {}", download.url, err);
+                        download.expected_size
+                    }
+                };
+                (*index, size)
+            })
+            .buffer_unordered(self.concurrent_downloads.max(1) as usize)
+            .collect()
+            .await
     }
-}
 
-#[repr(transparent)]
-#[derive(Debug, Clone)]
-pub struct DownloaderBuilder(Downloader);
+    pub(crate) async fn fetch(&self, batch: &Batch, download: &Download) -> Summary {
+        let named = &self.named(download);
+        if let Err(err) = self.check_scheme(&download.url) {
+            let summary = Summary::new(named.clone().into_owned()).fail(err);
+            self.finished(batch, download, &summary);
+            return summary;
+        }
+        #[cfg(feature = "otel")]
+        let span = otel::download_span(named);
+        let running = batch.running.enter();
+        let started = self.clock.now();
+        let fetching = async {
+            match batch.deadline {
+                Some(deadline) => self.fetch_until(batch, named, deadline).await,
+                None => self.fetch_controlled(batch, named).await,
+            }
+        };
+        #[cfg(feature = "otel")]
+        let fetching = fetching.instrument(span.clone());
+        let mut summary = fetching.await;
+        summary.elapsed = self.clock.now() - started;
+        summary.concurrency = running.count;
+        drop(running);
+        #[cfg(feature = "otel")]
+        otel::record(&span, &summary);
+        self.finished(batch, download, &summary);
+        summary
+    }
 
-impl DownloaderBuilder {
-    pub fn new() -> Self {
-        Self(Downloader::new())
+    /// Record the end of a download in the checkpoint and report it to the hooks
+    fn finished(&self, batch: &Batch, download: &Download, summary: &Summary) {
+        if let Some(checkpoint) = &batch.checkpoint {
+            checkpoint.complete(download, summary);
+        }
+        if let (Some(on_skip), Status::Skipped(reason)) = (&self.on_skip, &summary.status) {
+            on_skip(&summary.download, reason);
+        }
+        self.progress(ProgressEvent::Finished { summary });
     }
 
-    pub fn directory(mut self, directory: impl Into<PathBuf>) -> Self {
-        self.0.directory = directory.into();
-        self
+    /// Fetch unless the batch timeout expires first, the partial file is then kept for resuming
+    async fn fetch_until(&self, batch: &Batch, download: &Download, deadline: Instant) -> Summary {
+        let timed_out = || Summary::new(download.clone()).fail("batch timeout");
+        if self.clock.now() >= deadline {
+            return timed_out();
+        }
+        tokio::select! {
+            summary = self.fetch_controlled(batch, download) => summary,
+            _ = self.clock.sleep_until(deadline) => timed_out(),
+        }
     }
 
-    pub fn retries(mut self, retries: u32) -> Self {
-        self.0.retries = retries;
-        self
+    async fn fetch_controlled(&self, batch: &Batch, download: &Download) -> Summary {
+        if let Some(stagger) = &batch.stagger {
+            stagger.wait().await;
+        }
+        match &batch.control {
+            Some(control) => control.run(download, self.output_path(download), self.fetch_scheduled(batch, download)).await,
+            None => self.fetch_scheduled(batch, download).await,
+        }
     }
 
-    pub fn concurrent_downloads(mut self, concurrent: u8) -> Self {
-        self.0.concurrent_downloads = concurrent;
-        self
+    pub(crate) fn progress(&self, event: ProgressEvent<'_>) {
+        if let Some(on_progress) = &self.on_progress {
+            on_progress(&event);
+        }
     }
 
-    pub fn headers(mut self, headers: HeaderMap) -> Self {
-        let headers = match self.0.headers {
-            None => HeaderMap::from(headers),
-            Some(mut header) => {
-                header.extend(headers);
-                header
+    /// Name downloads without a filename according to the default filename strategy
+    pub(crate) fn named<'a>(&self, download: &'a Download) -> Cow<'a, Download> {
+        match &self.default_filename {
+            Some(strategy) if download.filename.is_empty() => {
+                Cow::Owned(Download { filename: strategy.filename(&download.url), ..download.clone() })
             }
+            _ => Cow::Borrowed(download),
+        }
+    }
+
+    async fn fetch_scheduled(&self, batch: &Batch, download: &Download) -> Summary {
+        let Some(window) = &self.schedule_window else {
+            return self.fetch_cached(batch, download).await;
         };
-        self.0.headers = Some(headers);
-        self
+
+        loop {
+            window.wait(&self.clock).await;
+            let summary = self.fetch_cached(batch, download).await;
+            // A download interrupted by the closing window comes back as not started,
+            // it continues through the resume machinery once the window opens again
+            if summary.status != Status::NotStarted {
+                return summary;
+            }
+        }
     }
 
-    pub fn header<K: IntoHeaderName>(mut self, name: K, value: HeaderValue) -> Self {
-        let headers = match self.0.headers {
-            None => {
-                let mut headers = HeaderMap::new();
-                headers.insert(name, value);
-                headers
+    /// Reuse the content of an earlier download of the same url when it did not change
+    async fn fetch_cached(&self, batch: &Batch, download: &Download) -> Summary {
+        let Some(cache) = self.content_cache.as_ref().filter(|_| !download.partial()) else {
+            return self.fetch_signed(batch, download).await;
+        };
+
+        // Holding the slot makes concurrent downloads of the url wait for the first one
+        let slot = cache.slot(&download.url);
+        let mut cached = slot.lock().await;
+        if let Some(cached) = cached.as_ref() {
+            if let Some(summary) = self.reuse(batch, download, cached).await {
+                return summary;
             }
-            Some(mut headers) => {
-                headers.insert(name, value);
-                headers
+        }
+
+        let summary = self.fetch_signed(batch, download).await;
+        if summary.status == Status::Success {
+            if let Ok(metadata) = summary.path.metadata() {
+                *cached = Some(Cached { etag: summary.etag.clone(), size: metadata.len(), path: summary.path.clone() });
+            }
+        }
+        summary
+    }
+
+    /// Link or copy the cached file to the output path if the resource still matches it
+    async fn reuse(&self, batch: &Batch, download: &Download, cached: &Cached) -> Option<Summary> {
+        let client = batch.client_for(self, download);
+        let probe = match download.fetch_range(&client).await {
+            Ok(probe) => probe,
+            Err(err) => {
+                tracing::debug!("Failed to validate the cached content of {}: {}", download.url, err);
+                return None;
             }
         };
-        self.0.headers = Some(headers);
-        self
+        if !cached.matches(&probe) || !cached.is_intact() {
+            return None;
+        }
+
+        let output_path = self.output_path(download);
+        tracing::debug!("Reusing {:?} for {:?}", cached.path, output_path);
+        if let Err(err) = cache::link_or_copy(&cached.path, &output_path) {
+            tracing::warn!("Failed to reuse {:?} for {:?}: {}", cached.path, output_path, err);
+            return None;
+        }
+
+        let mut summary = Summary::new(download.clone()).with_path(output_path);
+        summary.size = cached.size;
+        summary.etag = cached.etag.clone();
+        Some(summary.with_status(Status::Skipped(SkipReason::Cached)))
     }
 
-    pub fn build(self) -> Downloader {
-        self.0
+    /// Fetch the download, then verify the file against its detached signature if it has one
+    async fn fetch_signed(&self, batch: &Batch, download: &Download) -> Summary {
+        let summary = self.fetch_captured(batch, download).await;
+        #[cfg(feature = "minisign")]
+        if let Some(detached) = download.signature.as_ref().filter(|_| summary.status == Status::Success) {
+            return self.verify_signature(batch, summary, detached).await;
+        }
+        summary
+    }
+
+    async fn fetch_captured(&self, batch: &Batch, download: &Download) -> Summary {
+        if download.url.scheme() == "file" {
+            return self.copy_local(download).await;
+        }
+        let (client, routed) = match self.route(batch, download) {
+            Ok(route) => route,
+            Err(err) => return Summary::new(download.clone()).fail(err),
+        };
+        let mut summary = self.fetch_recorded(batch, &client, &routed).await;
+        // Resuming keeps the corrupt bytes already written, the restarts download from scratch
+        for restart in 1..=self.redownload_on_checksum_failure {
+            if !summary.checksum_mismatch() {
+                break;
+            }
+            tracing::warn!("Downloading {} again after a checksum mismatch ({}/{})",
+                download.redacted_url(), restart, self.redownload_on_checksum_failure);
+            let mut diagnostics = std::mem::take(&mut summary.diagnostics);
+            diagnostics.push(Diagnostic::ChecksumRedownload { restart });
+            let fresh = Downloader { resume: false, ..self.clone() };
+            summary = fresh.fetch_recorded(batch, &client, &routed).await;
+            diagnostics.append(&mut summary.diagnostics);
+            summary.diagnostics = diagnostics;
+        }
+        summary.download.url = download.url.clone();
+        summary
+    }
+
+    /// Fetch the download, recording the exchange when the batch captures traffic
+    async fn fetch_recorded(&self, batch: &Batch, client: &ClientWithMiddleware, download: &Download) -> Summary {
+        match &batch.capture {
+            None => self.fetch_entry(client, &batch.buffers, &batch.hosts, download, None, false).await,
+            Some(capture) => {
+                let started = Instant::now();
+                let mut entry = Entry::new(download);
+                let summary = self.fetch_entry(client, &batch.buffers, &batch.hosts, download, Some(&mut entry), false).await;
+                capture.write(entry.finish(&summary, started.elapsed()));
+                summary
+            }
+        }
+    }
+
+    /// Copy the local file of a `file://` url to the output path
+    ///
+    /// An output file shorter than the source is resumed from its size, one of the same size is
+    /// complete. The copy reports progress and is checked like a response body.
+    async fn copy_local(&self, download: &Download) -> Summary {
+        let output_path = self.output_path(download);
+        let mut summary = Summary::new(download.clone()).with_path(output_path.clone());
+        let Ok(source) = download.url.to_file_path() else {
+            return summary.fail(format!("{} is not a local path", download.url));
+        };
+        let size = match tokio::fs::metadata(&source).await {
+            Ok(metadata) if metadata.is_file() => metadata.len(),
+            Ok(_) => return summary.fail(format!("{} is not a regular file", source.display())),
+            Err(err) => return summary.fail(format!("{}: {}", source.display(), err)),
+        };
+        summary.size = size;
+        if let Err(err) = self.symlink_policy.apply(&output_path) {
+            return summary.fail(err);
+        }
+        if let Some(err) = directory_error(&output_path) {
+            return summary.fail(err);
+        }
+
+        // An output file longer than the source is not a part of it, it is copied again
+        let resumed = match output_path.metadata() {
+            Ok(metadata) if self.resume && metadata.len() <= size => metadata.len(),
+            _ => 0,
+        };
+        if self.resume && resumed == size && output_path.exists() {
+            return summary.with_status(Status::Skipped(SkipReason::Complete));
+        }
+        summary.resume = resumed > 0;
+
+        let folder = output_path.parent().unwrap_or(&output_path);
+        if !self.is_prepared(folder) {
+            tracing::debug!("Creating destination directory {:?}", folder);
+            if let Err(err) = fs::create_dir_all(folder) {
+                return summary.fail(err);
+            }
+        }
+        let mut digests = self.content_digests(download);
+        if resumed > 0 && !digests.is_empty() {
+            if let Err(err) = digests.update_from_file(&output_path).await {
+                return summary.fail(err);
+            }
+        }
+        let mut reader = match File::open(&source).await {
+            Ok(reader) => reader,
+            Err(err) => return summary.fail(format!("{}: {}", source.display(), err)),
+        };
+        if let Err(err) = reader.seek(SeekFrom::Start(resumed)).await {
+            return summary.fail(err);
+        }
+        let mut file = match self.open_options(resumed > 0, false).open(&output_path).await {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists =>
+                return summary.fail(format!("{} already exists", output_path.display())),
+            Err(err) => return summary.fail(err),
+        };
+        self.progress(ProgressEvent::Started { download, total: Some(size), resumed });
+
+        let mut copied = resumed;
+        let mut buffer = vec![0; COPY_BUFFER_SIZE];
+        loop {
+            let len = match reader.read(&mut buffer).await {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(err) => return summary.fail(err),
+            };
+            digests.update(&buffer[..len]);
+            if let Err(err) = file.write_all(&buffer[..len]).await {
+                return summary.fail(err);
+            }
+            copied += len as u64;
+            self.progress(ProgressEvent::Progress { download, bytes: len as u64, downloaded: copied, total: Some(size) });
+        }
+        if let Err(err) = file.flush().await {
+            return summary.fail(err);
+        }
+        drop(file);
+
+        if let Err(err) = verify_digests(&mut summary, digests) {
+            discard(&mut summary, &output_path);
+            return summary.fail(err);
+        }
+        summary.with_status(Status::Success)
+    }
+
+    /// The rate limits of a download, `None` when it is not limited
+    fn throttle(&self, download: &Download) -> Option<Throttle> {
+        let own = download.rate_limit.map(|rate| TokenBucket::new(rate, self.clock.clone()));
+        let host = self.per_host_rate_limit.zip(host::url_host(&download.url))
+            .map(|(rate, host)| self.host_buckets.get(host, rate, &self.clock));
+        (own.is_some() || host.is_some()).then_some(Throttle { own, host })
+    }
+
+    /// Reject a url whose scheme is not allowed, before anything is requested or written
+    pub(crate) fn check_scheme(&self, url: &Url) -> Result<()> {
+        let scheme = url.scheme();
+        if !self.allowed_schemes.iter().any(|allowed| allowed == scheme) {
+            return DisallowedSchemeSnafu { scheme, location: location!() }.fail();
+        }
+        Ok(())
+    }
+
+    /// The client and the download to request according to the url scheme
+    pub(crate) fn route<'a>(&self, batch: &Batch, download: &'a Download) -> Result<(ClientWithMiddleware, Cow<'a, Download>)> {
+        self.check_scheme(&download.url)?;
+        match download.url.scheme() {
+            "http" | "https" => Ok((batch.client_for(self, download), Cow::Borrowed(download))),
+            #[cfg(unix)]
+            "unix" => {
+                let (socket, url) = download.unix_endpoint()?;
+                let client = batch.socket_client(self, download, socket)?;
+                Ok((client, Cow::Owned(Download { url, ..download.clone() })))
+            }
+            scheme => UnsupportedSchemeSnafu { scheme, location: location!() }.fail(),
+        }
+    }
+
+    /// Fetch the download, from scratch when `restart` is set even if a partial file is on disk
+    async fn fetch_entry(&self, client: &ClientWithMiddleware, buffers: &BufferPool, hosts: &HostCapabilities,
+                         download: &Download, mut entry: Option<&mut Entry>, restart: bool) -> Summary {
+        let started = Instant::now();
+        let mut size_on_disk: u64 = 0;
+        let mut can_resume = false;
+        let output_path = self.output_path(download);
+        let mut summary = Summary::new(download.clone()).with_path(output_path.clone());
+        // Before resuming, the size on disk must be the size of the file that is written
+        if let Err(err) = self.symlink_policy.apply(&output_path) {
+            return summary.fail(err);
+        }
+        if let Some(err) = directory_error(&output_path) {
+            return summary.fail(err);
+        }
+
+        // Partial-range downloads bypass the resume machinery
+        if let Some(range) = download.range {
+            return self.fetch_slice(client, buffers, summary, range, &output_path, entry).await;
+        }
+        if let Some(length) = download.suffix {
+            return self.fetch_suffix(client, buffers, summary, length.get(), &output_path, entry).await;
+        }
+        if !download.ranges.is_empty() {
+            return self.fetch_ranges(client, download, summary, &output_path).await;
+        }
+
+        let mut content_length = download.expected_size;
+        let mut validator = None;
+        let mut probe = None;
+
+        // Handling interrupted file downloads, paginated downloads are fetched from scratch
+        let resume = !restart && self.resume && self.resume_mode != ResumeMode::Never && !self.follow_pagination
+            && !self.compressed() && !self.decompresses_zstd() && self.transforms.is_empty();
+        // With nothing on disk the probe would only tell whether ranges are accepted, which the
+        // earlier downloads of the host already told
+        let fresh = resume && !self.skip_missing && self.completion.is_none() && download.expected_size.is_none()
+            && self.chunk_size().is_none() && !output_path.exists();
+        if let Some((host, capabilities)) = fresh.then(|| hosts.get(&download.url)).flatten() {
+            can_resume = capabilities.ranges;
+            summary.resume = can_resume;
+            let version = format!("{:?}", capabilities.version);
+            let Capabilities { ranges, encoding, .. } = capabilities;
+            summary.diagnose(Diagnostic::HostCapabilities { host, ranges, version, encoding });
+        } else if resume || self.skip_missing {
+            let data = match download.fetch_range(client).await {
+                Ok(data) => data,
+                Err(err) => return fail_request(summary, &err),
+            };
+            if let Some(entry) = entry.as_deref_mut() {
+                entry.probe(&data);
+            }
+            // Expected-missing files are skipped without a GET, server errors are still attempted
+            if self.skip_missing && matches!(data.status, StatusCode::NOT_FOUND | StatusCode::GONE) {
+                summary.status_code = Some(data.status);
+                return summary.with_status(Status::Skipped(SkipReason::NotFound));
+            }
+            if resume {
+                // A weak ETag does not guarantee the partial file matches, download it again
+                if data.resume && data.weak_etag() {
+                    tracing::debug!("Not resuming {} with the weak ETag {:?}", download.url, data.etag);
+                    summary.diagnose(Diagnostic::WeakEtag { etag: data.etag.clone().unwrap_or_default() });
+                }
+                can_resume = match self.resume_mode {
+                    ResumeMode::Always => true,
+                    // Only a validator sent as If-Range lets the server refuse a changed resource
+                    ResumeMode::Strict => data.resume && data.validator().is_some(),
+                    ResumeMode::Auto | ResumeMode::Never => data.resume && !data.weak_etag(),
+                };
+                content_length = download.total_size(data.size);
+                if let Some(diagnostic) = download.size_mismatch(data.size) {
+                    summary.diagnose(diagnostic);
+                }
+                validator = data.validator().map(str::to_string);
+                summary.etag = data.etag.clone();
+                probe = Some(data);
+            }
+
+            // check if there is a file on disk already, or chunks of it
+            if can_resume {
+                let committed = match self.chunk_size() {
+                    Some(chunk_size) => self.staging_path(&output_path, ".part")
+                        .and_then(|base| ChunkedFile::committed(&base, chunk_size)),
+                    None if output_path.exists() => output_path.metadata().map(|metadata| metadata.len()),
+                    None => Ok(0),
+                };
+                size_on_disk = match committed {
+                    Ok(size) => size,
+                    Err(err) => return summary.fail(err),
+                };
+            }
+
+            // update summary resume field
+            summary.resume = can_resume;
+        }
+
+        let complete = match &self.completion {
+            Some(strategy) => strategy.is_complete(download, &output_path, probe.as_ref()).await,
+            // A decompressed file has another size than the resource
+            None if self.decompresses_zstd() => false,
+            None => SizeCompletion.is_complete(download, &output_path, probe.as_ref()).await,
+        };
+        if complete {
+            return summary.with_status(Status::Skipped(SkipReason::Complete));
+        }
+
+        // Create download request object
+        tracing::debug!("Fetching Url: {}", download.redacted_url());
+        let attempts = Attempts::default();
+        let mut request = download.request(client, Method::GET).with_extension(attempts.clone());
+        if self.resume && can_resume {
+            let range = format!("bytes={}-", size_on_disk);
+            if let Some(entry) = entry.as_deref_mut() {
+                entry.range(&range);
+            }
+            request = request.header(RANGE, range);
+            // A range applies to the encoded content, which the file on disk may not be
+            if size_on_disk > 0 && self.accept_encoding.as_ref().is_some_and(|encodings| Encoding::compresses(encodings)) {
+                tracing::warn!("Resuming {} while accepting compressed encodings", download.redacted_url());
+                summary.diagnose(Diagnostic::CompressedResume { size_on_disk });
+            }
+            // The server sends the whole resource instead of the range if it changed meanwhile
+            if let Some(validator) = validator {
+                request = request.header(IF_RANGE, validator);
+            }
+        }
+        if let Some(etag) = &download.if_none_match {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(encodings) = &self.accept_encoding {
+            request = request.header(ACCEPT_ENCODING, Encoding::header(encodings));
+        } else if self.decompresses_zstd() {
+            request = request.header(ACCEPT_ENCODING, "zstd");
+        }
+
+        // Sending download request
+        let sent = request.send().await;
+        summary.attempts = attempts.count();
+        let response = match sent {
+            Ok(response) => response,
+            Err(err) => return fail_request(summary, &err),
+        };
+        if let Some(entry) = entry.as_deref_mut() {
+            entry.response(&response, started.elapsed());
+        }
+        // Only a partial response continues the file on disk
+        let append = can_resume && response.status() == StatusCode::PARTIAL_CONTENT;
+        if append {
+            let start = response.headers().get(CONTENT_RANGE)
+                .and_then(|val| val.to_str().ok())
+                .and_then(ByteRange::parse_content_range)
+                .map(|(range, _)| range.start);
+            if start != Some(size_on_disk) {
+                tracing::debug!("The range of {} does not continue the file, downloading it again", download.redacted_url());
+                let mut diagnostics = std::mem::take(&mut summary.diagnostics);
+                diagnostics.push(Diagnostic::RangeMismatch { size_on_disk, start });
+                drop(response);
+                let fetch: future::BoxFuture<'_, Summary> = Box::pin(self.fetch_entry(client, buffers, hosts, download, entry, true));
+                let mut summary = fetch.await;
+                diagnostics.append(&mut summary.diagnostics);
+                summary.diagnostics = diagnostics;
+                return summary;
+            }
+        }
+        if can_resume && size_on_disk > 0 && response.status() == StatusCode::OK {
+            tracing::debug!("The server ignored the range of {}, downloading it again", download.redacted_url());
+            summary.diagnose(Diagnostic::RangeIgnored { size_on_disk });
+        }
+        summary.status_code = Some(response.status());
+        // The probed size is the whole resource while a partial response only carries the remaining bytes
+        summary.size = match response.content_length() {
+            Some(remaining) if append => size_on_disk + remaining,
+            Some(length) => length,
+            None => content_length.unwrap_or_default(),
+        };
+        summary.resume = can_resume;
+        if let Some(etag) = response.headers().get(ETAG).and_then(|val| val.to_str().ok()) {
+            summary.etag = Some(etag.to_string());
+        }
+        // Returned before the file is opened, which would truncate it
+        if response.status() == StatusCode::NOT_MODIFIED {
+            summary.size = output_path.metadata().map(|metadata| metadata.len()).unwrap_or_default();
+            return summary.with_status(Status::Skipped(SkipReason::NotModified));
+        }
+        if let Err(err) = response.error_for_status_ref() {
+            return summary.fail(err);
+        }
+        if let Err(message) = self.gate(&response) {
+            return summary.fail(message);
+        }
+        hosts.record(&download.url, &response);
+
+        match self.chunk_size() {
+            Some(chunk_size) => {
+                let offset = if append { size_on_disk } else { 0 };
+                self.store_chunked(summary, response, &output_path, chunk_size, offset).await
+            }
+            None => self.store(client, buffers, summary, response, &output_path, append).await,
+        }
+    }
+
+    /// Where the download is written, with the extension of the output compression
+    pub(crate) fn output_path(&self, download: &Download) -> PathBuf {
+        let mut filename = download.filename.as_str();
+        // Decompressed files are written without their `.zst` extension
+        if self.decompresses_zstd() {
+            filename = filename.strip_suffix(".zst").unwrap_or(filename);
+        }
+        let output_path = download.directory.as_ref().unwrap_or(&self.directory).join(filename);
+        #[cfg(feature = "compress")]
+        if let Some(compression) = self.compress_output {
+            let mut output_path = output_path.into_os_string();
+            output_path.push(compression.extension());
+            return PathBuf::from(output_path);
+        }
+        output_path
+    }
+
+    /// The intermediate path of `output` with `suffix`, in the temporary directory when set,
+    /// which is created if missing
+    pub(crate) fn staging_path(&self, output: &Path, suffix: &str) -> io::Result<PathBuf> {
+        let mut staging = match &self.temp_dir {
+            Some(temp_dir) => {
+                fs::create_dir_all(temp_dir)?;
+                temp_dir.join(output.file_name().unwrap_or_default()).into_os_string()
+            }
+            None => output.as_os_str().to_owned(),
+        };
+        staging.push(suffix);
+        Ok(PathBuf::from(staging))
+    }
+
+    /// Ask the response gate whether the response may be written
+    pub(crate) fn gate(&self, response: &Response) -> std::result::Result<(), String> {
+        match &self.response_gate {
+            Some(gate) => gate(response.headers(), response.status()),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether the filename has an extension rejecting HTML and the response is an HTML page
+    pub(crate) fn unexpected_html(&self, download: &Download, content_type: Option<&str>) -> bool {
+        let Some(extension) = Path::new(&download.filename).extension() else {
+            return false;
+        };
+        let extension = extension.to_string_lossy().to_ascii_lowercase();
+        let html = content_type.and_then(|content_type| content_type.split(';').next())
+            .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("text/html"));
+        html && self.reject_html_for.contains(&extension)
+    }
+
+    /// Whether downloads are compressed, their offsets differ from the resource so they can't be resumed
+    #[cfg(feature = "compress")]
+    pub(crate) fn compressed(&self) -> bool {
+        self.compress_output.is_some()
+    }
+
+    #[cfg(not(feature = "compress"))]
+    pub(crate) fn compressed(&self) -> bool {
+        false
+    }
+
+    /// The size of the chunk files downloads are stored in, compressed, decompressed and
+    /// paginated downloads are stored as a single file
+    fn chunk_size(&self) -> Option<u64> {
+        self.split_storage.filter(|_| !self.compressed() && !self.decompresses_zstd() && !self.follow_pagination
+            && self.transforms.is_empty())
+    }
+
+    /// Whether zstd responses are decompressed, unless the output is compressed
+    #[cfg(feature = "zstd")]
+    fn decompresses_zstd(&self) -> bool {
+        self.decompress_zstd && !self.compressed()
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    fn decompresses_zstd(&self) -> bool {
+        false
+    }
+
+    /// Whether the response body is zstd to decompress while writing it, by its
+    /// `Content-Encoding` or a `.zst` url
+    fn unzstd(&self, download: &Download, headers: &HeaderMap) -> bool {
+        let encoded = headers.get(CONTENT_ENCODING).and_then(|val| val.to_str().ok())
+            .is_some_and(|encoding| encoding.trim().eq_ignore_ascii_case("zstd"));
+        self.decompresses_zstd() && (encoded || download.url.path().ends_with(".zst"))
+    }
+
+    /// The writer of the output file, compressing or decompressing the written bytes
+    #[cfg_attr(not(feature = "zstd"), allow(unused_variables))]
+    fn writer<'a>(&self, file: File, buffers: &'a BufferPool, unzstd: bool) -> PooledWriter<'a> {
+        #[cfg(feature = "zstd")]
+        if unzstd {
+            return PooledWriter::unzstd(file, buffers);
+        }
+        #[cfg(feature = "compress")]
+        if let Some(compression) = self.compress_output {
+            return PooledWriter::compressed(file, buffers, compression);
+        }
+        PooledWriter::new(file, buffers)
+    }
+
+    /// Fetch only the requested byte range of the resource, independent of the resume machinery
+    async fn fetch_slice(&self, client: &ClientWithMiddleware, buffers: &BufferPool, mut summary: Summary, range: ByteRange,
+                         output_path: &Path, mut entry: Option<&mut Entry>) -> Summary {
+        let started = Instant::now();
+        let header = range.to_header();
+        if let Some(entry) = entry.as_deref_mut() {
+            entry.range(&header);
+        }
+
+        tracing::debug!("Fetching range {} of Url: {}", header, summary.download.redacted_url());
+        let attempts = Attempts::default();
+        let request = summary.download.request(client, Method::GET).header(RANGE, header).with_extension(attempts.clone());
+        let sent = request.send().await;
+        summary.attempts = attempts.count();
+        let response = match sent {
+            Ok(response) => response,
+            Err(err) => return fail_request(summary, &err),
+        };
+        if let Some(entry) = entry.as_deref_mut() {
+            entry.response(&response, started.elapsed());
+        }
+        summary.status_code = Some(response.status());
+        summary.size = range.size();
+        if let Err(err) = response.error_for_status_ref() {
+            return summary.fail(err);
+        }
+        if let Err(message) = self.gate(&response) {
+            return summary.fail(message);
+        }
+
+        // The server must honor the range, otherwise the whole resource would be written
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            return summary.fail(format!("the server ignored the range request and returned {}", response.status()));
+        }
+        let content_range = response.headers().get(CONTENT_RANGE)
+            .and_then(|val| val.to_str().ok())
+            .and_then(ByteRange::parse_content_range);
+        match content_range {
+            Some((returned, _)) if returned == range => {}
+            Some((returned, _)) => return summary.fail(format!(
+                "the server returned the range {}-{} instead of {}-{}", returned.start, returned.end, range.start, range.end)),
+            None => return summary.fail("the server response does not contain a valid Content-Range"),
+        }
+
+        summary.range = Some(range);
+        self.store(client, buffers, summary, response, output_path, false).await
+    }
+
+    /// Download the last `length` bytes of the resource
+    async fn fetch_suffix(&self, client: &ClientWithMiddleware, buffers: &BufferPool, mut summary: Summary, length: u64,
+                          output_path: &Path, mut entry: Option<&mut Entry>) -> Summary {
+        let started = Instant::now();
+        let header = format!("bytes=-{}", length);
+        if let Some(entry) = entry.as_deref_mut() {
+            entry.range(&header);
+        }
+
+        tracing::debug!("Fetching range {} of Url: {}", header, summary.download.redacted_url());
+        let attempts = Attempts::default();
+        let request = summary.download.request(client, Method::GET).header(RANGE, header).with_extension(attempts.clone());
+        let sent = request.send().await;
+        summary.attempts = attempts.count();
+        let response = match sent {
+            Ok(response) => response,
+            Err(err) => return fail_request(summary, &err),
+        };
+        if let Some(entry) = entry.as_deref_mut() {
+            entry.response(&response, started.elapsed());
+        }
+        summary.status_code = Some(response.status());
+        if let Err(err) = response.error_for_status_ref() {
+            return summary.fail(err);
+        }
+        if let Err(message) = self.gate(&response) {
+            return summary.fail(message);
+        }
+
+        // A server ignoring suffix ranges sends the whole resource, whose size locates the tail
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            let Some(size) = response.content_length().filter(|size| *size > 0) else {
+                return summary.fail(format!("the server ignored the suffix range request and returned {}", response.status()));
+            };
+            drop(response);
+            let range = ByteRange::new(size.saturating_sub(length), size - 1);
+            return self.fetch_slice(client, buffers, summary, range, output_path, entry).await;
+        }
+        let content_range = response.headers().get(CONTENT_RANGE)
+            .and_then(|val| val.to_str().ok())
+            .and_then(ByteRange::parse_content_range);
+        // A resource shorter than the suffix is returned whole
+        match content_range {
+            Some((returned, _)) if returned.size() <= length => {
+                summary.size = returned.size();
+                summary.range = Some(returned);
+            }
+            Some((returned, _)) => return summary.fail(format!(
+                "the server returned the range {}-{} instead of the last {} bytes", returned.start, returned.end, length)),
+            None => return summary.fail("the server response does not contain a valid Content-Range"),
+        }
+
+        self.store(client, buffers, summary, response, output_path, false).await
+    }
+
+    /// The options opening the output file, appending to it when resuming
+    fn open_options(&self, append: bool, preallocate: bool) -> OpenOptions {
+        let mut options = OpenOptions::new();
+        options.write(true).append(append && !preallocate);
+        if self.create_new && !append {
+            options.create_new(true);
+        } else {
+            options.create(true).truncate(!append && !self.no_truncate);
+        }
+        options
+    }
+
+    /// Stream the response body into the output file
+    async fn store(&self, client: &ClientWithMiddleware, buffers: &BufferPool, mut summary: Summary,
+                   response: Response, output_path: &Path, append: bool) -> Summary {
+        let content_type = response.headers().get(CONTENT_TYPE)
+            .and_then(|val| val.to_str().ok())
+            .map(str::to_string);
+        // Checked before opening the file, so neither a partial file nor a previous download is touched
+        if self.unexpected_html(&summary.download, content_type.as_deref()) {
+            return summary.fail("expected binary, got HTML");
+        }
+        if !summary.download.accepts(content_type.as_deref()) {
+            return summary.fail("unexpected content type");
+        }
+        let mut content_md5 = if self.verify_content_md5 && !append {
+            ContentMd5::from_response(response.status(), response.headers())
+        } else {
+            None
+        };
+        let mut expected = response.content_length();
+        let advertised_empty = expected == Some(0);
+        let unzstd = self.unzstd(&summary.download, response.headers());
+        let mut digests = self.content_digests(&summary.download);
+        // A resumed download hashes the partial file first so the digests cover the whole content
+        if append && !digests.is_empty() && output_path.exists() {
+            if let Err(err) = digests.update_from_file(output_path).await {
+                return summary.fail(err);
+            }
+        }
+
+        // Process the directory where downloaded files are stored
+        let folder = output_path.parent().unwrap_or(output_path);
+        if !self.is_prepared(folder) {
+            tracing::debug!("Creating destination directory {:?}", folder);
+            if let Err(err) = fs::create_dir_all(folder) {
+                return summary.fail(err);
+            }
+        }
+
+        // A preallocated file is longer than its content, resumed writes can't append at its end
+        let mut pipeline = Pipeline::new(self.transforms.iter().map(|factory| factory(&summary.download)).collect());
+        let preallocate = self.preallocate && !self.compressed() && !unzstd && pipeline.is_empty()
+            && expected.is_some_and(|expected| expected > 0);
+        let result = self.open_options(append, preallocate).open(output_path).await;
+        let mut file = match result {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists =>
+                return summary.fail(format!("{} already exists", output_path.display())),
+            Err(err) => return summary.fail(err),
+        };
+        let resumed = if append {
+            file.metadata().await.map(|metadata| metadata.len()).unwrap_or_default()
+        } else {
+            0
+        };
+        let mut allocation = None;
+        if let Some(expected) = expected.filter(|_| preallocate) {
+            match Preallocation::allocate(&mut file, output_path, resumed, expected).await {
+                Ok(allocated) => allocation = Some(allocated),
+                Err(err) => return summary.fail(err),
+            }
+        }
+        // The size of paginated content is unknown until the last page
+        let mut next = self.next_page(&response);
+        let total = expected.filter(|_| next.is_none()).map(|expected| expected + resumed);
+        self.progress(ProgressEvent::Started { download: &summary.download, total, resumed });
+        let mut file = self.writer(file, buffers, unzstd);
+
+        // Stream response content and write to file
+        let mut durability = self.fsync_interval.map(|interval| Durability::new(interval, self.clock.clone()));
+        let mut bucket = self.throttle(&summary.download);
+        let mut written: u64 = 0;
+        let mut page_start: u64 = 0;
+        let sent = Sent::of(&response);
+        let mut stream = response.bytes_stream();
+        summary.pages = 1;
+        loop {
+            while let Some(data) = stream.next().await {
+                let chunk = match data {
+                    Ok(chunk) => chunk,
+                    Err(err) => {
+                        // Keep what was written for resuming
+                        if let Some(allocation) = allocation.as_mut() {
+                            if let Err(err) = allocation.settle(&mut file, written).await {
+                                tracing::warn!("Failed to trim the preallocated {:?}: {}", output_path, err);
+                                let message = err.to_string();
+                                summary.diagnose(Diagnostic::CleanupFailed { path: output_path.to_path_buf(), message });
+                            }
+                        }
+                        return summary.fail(err);
+                    }
+                };
+                if summary.ttfb.is_none() {
+                    summary.ttfb = sent.map(|sent| sent.elapsed());
+                }
+                if let Some(md5) = content_md5.as_mut() {
+                    md5.update(&chunk);
+                }
+                digests.update(&chunk);
+
+                let len = chunk.len() as u64;
+                if let Some(bucket) = bucket.as_mut() {
+                    summary.throttled |= bucket.acquire(len).await;
+                }
+                let data = match pipeline.transform(&chunk) {
+                    Ok(data) => data,
+                    Err(err) => return summary.fail(err),
+                };
+                match file.write(&data).await {
+                    Ok(_) => written += len,
+                    Err(err) => return summary.fail(err),
+                }
+                if let Some(allocation) = allocation.as_mut() {
+                    allocation.record(&file, written);
+                }
+                let downloaded = resumed + written;
+                self.progress(ProgressEvent::Progress { download: &summary.download, bytes: len, downloaded, total });
+
+                // Periodically commit the written bytes so a crash only loses the latest ones
+                if let Some(durability) = durability.as_mut() {
+                    if durability.record(len) {
+                        if let Err(err) = sync(&mut file).await {
+                            return summary.fail(err);
+                        }
+                    }
+                }
+
+                if let Some(window) = &self.schedule_window {
+                    if window.until_open(self.clock.system_time()).is_some() {
+                        tracing::debug!("Schedule window closed, pausing {:?}", output_path);
+                        let flushed = match allocation.as_mut() {
+                            Some(allocation) => allocation.settle(&mut file, written).await,
+                            None => file.flush().await,
+                        };
+                        if let Err(err) = flushed {
+                            return summary.fail(err);
+                        }
+                        self.progress(ProgressEvent::Paused { download: &summary.download });
+                        return summary.with_status(Status::NotStarted);
+                    }
+                }
+            }
+
+            // Follow the next page only once the current one is complete
+            let complete = expected.is_none_or(|expected| written - page_start == expected);
+            let Some(url) = next.take().filter(|_| complete) else {
+                break;
+            };
+            let response;
+            (summary, response) = match self.fetch_page(client, summary, url).await {
+                Ok(page) => page,
+                Err(summary) => return summary,
+            };
+            // Content-MD5 describes a single page
+            content_md5 = None;
+            next = self.next_page(&response);
+            expected = response.content_length();
+            page_start = written;
+            stream = response.bytes_stream();
+            summary.pages += 1;
+        }
+        if summary.pages > 1 {
+            summary.size = written;
+        }
+        // The transforms hand over the bytes they still hold once the content ended
+        if !pipeline.is_empty() {
+            let flushed = match pipeline.finish() {
+                Ok(tail) => file.write(&tail).await,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = flushed {
+                return summary.fail(err);
+            }
+        }
+        if let Err(err) = file.finish().await {
+            return summary.fail(err);
+        }
+        // Trim the overshoot of a preallocation larger than the content
+        if let Some(allocation) = allocation.as_mut() {
+            if let Err(err) = allocation.settle(&mut file, written).await {
+                return summary.fail(err);
+            }
+        }
+        if durability.is_some() {
+            if let Err(err) = file.get_ref().sync_data().await {
+                return summary.fail(err);
+            }
+        }
+        if self.compressed() {
+            summary.compressed_size = file.get_ref().metadata().await.ok().map(|metadata| metadata.len());
+        }
+        // The summary reports the decompressed size, the received bytes are the resource
+        let received = resumed + written;
+        if unzstd || !pipeline.is_empty() {
+            summary.size = file.get_ref().metadata().await.map(|metadata| metadata.len()).unwrap_or_default();
+        }
+        drop(file);
+
+        // A connection closed early can end the stream without an error
+        if let Some(expected) = expected {
+            if written - page_start != expected {
+                return summary.fail(format!("incomplete: got {} of {} bytes", written - page_start, expected));
+            }
+        }
+
+        if let Err(err) = summary.download.check_size(if unzstd { summary.size } else { received }) {
+            discard(&mut summary, output_path);
+            return summary.fail(err);
+        }
+
+        // A successful status with nothing to write usually means a misconfigured server
+        if self.fail_on_empty && written == 0 && !append && !advertised_empty {
+            discard(&mut summary, output_path);
+            return summary.fail("empty response");
+        }
+
+        if let Some(Err(err)) = content_md5.map(ContentMd5::verify) {
+            discard(&mut summary, output_path);
+            return summary.fail(err);
+        }
+
+        if let Err(err) = verify_digests(&mut summary, digests) {
+            discard(&mut summary, output_path);
+            return summary.fail(err);
+        }
+
+        let summary = match &self.filename_template {
+            Some(template) => self.rename(summary, template, output_path, content_type.as_deref()),
+            None => summary.with_status(Status::Success),
+        };
+
+        #[cfg(feature = "zip")]
+        if self.extract_zip && !self.compressed() && summary.status == Status::Success && extract::is_zip(&summary.download.filename) {
+            return Self::extract(summary, output_path.with_file_name(&summary.download.filename)).await;
+        }
+        summary
+    }
+
+    /// Stream the response body into chunk files of at most `chunk_size` bytes after the first
+    /// `offset` bytes of the content, then concatenate the chunks into the output file
+    ///
+    /// Chunks of an incomplete content are kept for resuming, those of a content failing its
+    /// checks are removed.
+    async fn store_chunked(&self, mut summary: Summary, response: Response, output_path: &Path, chunk_size: u64,
+                           offset: u64) -> Summary {
+        let content_type = response.headers().get(CONTENT_TYPE)
+            .and_then(|val| val.to_str().ok())
+            .map(str::to_string);
+        if self.unexpected_html(&summary.download, content_type.as_deref()) {
+            return summary.fail("expected binary, got HTML");
+        }
+        if !summary.download.accepts(content_type.as_deref()) {
+            return summary.fail("unexpected content type");
+        }
+        let folder = output_path.parent().unwrap_or(output_path);
+        if !self.is_prepared(folder) {
+            tracing::debug!("Creating destination directory {:?}", folder);
+            if let Err(err) = fs::create_dir_all(folder) {
+                return summary.fail(err);
+            }
+        }
+        let result = match self.staging_path(output_path, ".part") {
+            Ok(base) => ChunkedFile::open(base, chunk_size, offset).await,
+            Err(err) => Err(err),
+        };
+        let mut chunks = match result {
+            Ok(chunks) => chunks,
+            Err(err) => return summary.fail(err),
+        };
+        let mut digests = self.content_digests(&summary.download);
+        // A resumed download hashes the committed chunks first so the digests cover the whole content
+        if offset > 0 && !digests.is_empty() {
+            for path in chunks.paths() {
+                if let Err(err) = digests.update_from_file(&path).await {
+                    return summary.fail(err);
+                }
+            }
+        }
+
+        let expected = response.content_length();
+        let total = expected.map(|expected| expected + offset);
+        self.progress(ProgressEvent::Started { download: &summary.download, total, resumed: offset });
+        let mut bucket = self.throttle(&summary.download);
+        let mut written: u64 = 0;
+        let sent = Sent::of(&response);
+        let mut stream = response.bytes_stream();
+        while let Some(data) = stream.next().await {
+            let chunk = match data {
+                Ok(chunk) => chunk,
+                Err(err) => {
+                    // Keep what was written for resuming
+                    if let Err(err) = chunks.finish().await {
+                        tracing::warn!("Failed to flush the chunks of {:?}: {}", output_path, err);
+                    }
+                    return summary.fail(err);
+                }
+            };
+            if summary.ttfb.is_none() {
+                summary.ttfb = sent.map(|sent| sent.elapsed());
+            }
+            digests.update(&chunk);
+            let len = chunk.len() as u64;
+            if let Some(bucket) = bucket.as_mut() {
+                summary.throttled |= bucket.acquire(len).await;
+            }
+            if let Err(err) = chunks.write(&chunk).await {
+                return summary.fail(err);
+            }
+            written += len;
+            let downloaded = offset + written;
+            self.progress(ProgressEvent::Progress { download: &summary.download, bytes: len, downloaded, total });
+        }
+        if let Err(err) = chunks.finish().await {
+            return summary.fail(err);
+        }
+
+        // A connection closed early can end the stream without an error
+        if let Some(expected) = expected.filter(|expected| *expected != written) {
+            return summary.fail(format!("incomplete: got {} of {} bytes", written, expected));
+        }
+        let checked = summary.download.check_size(offset + written)
+            .and_then(|_| verify_digests(&mut summary, digests));
+        if let Err(err) = checked {
+            if let Err(err) = chunks.remove() {
+                tracing::warn!("Failed to remove the chunks of {:?}: {}", output_path, err);
+            }
+            return summary.fail(err);
+        }
+
+        tracing::debug!("Concatenating the chunks of {:?}", output_path);
+        match chunks.concatenate(output_path).await {
+            Ok(size) => Summary { size, ..summary }.with_status(Status::Success),
+            Err(err) => summary.fail(err),
+        }
+    }
+
+    /// The digests computed over the content of `download`
+    fn content_digests(&self, download: &Download) -> Digests {
+        let checksum = download.checksum.as_ref().map(|(kind, _)| *kind);
+        Digests::new(self.digests.iter().copied().chain(checksum))
+            .with_custom(download.digest_check.as_ref().map(|check| (check.digest)()))
+    }
+
+    /// Extract a downloaded zip archive next to it
+    #[cfg(feature = "zip")]
+    async fn extract(mut summary: Summary, archive: PathBuf) -> Summary {
+        let directory = archive.parent().map(Path::to_path_buf).unwrap_or_default();
+        tracing::debug!("Extracting {:?} into {:?}", archive, directory);
+        let result = tokio::task::spawn_blocking(move || extract::extract_zip(&archive, &directory)).await;
+        match result {
+            Ok(Ok(extracted)) => {
+                summary.extracted = extracted;
+                summary
+            }
+            Ok(Err(err)) => summary.fail(err),
+            Err(err) => summary.fail(err),
+        }
+    }
+
+    /// Rename a completed download according to the filename template
+    fn rename(&self, mut summary: Summary, template: &FilenameTemplate, output_path: &Path,
+              content_type: Option<&str>) -> Summary {
+        let filename = template.expand(&Variables {
+            filename: &summary.download.filename,
+            url: &summary.download.url,
+            content_type,
+            now: self.clock.system_time(),
+        });
+        let target = output_path.with_file_name(&filename);
+        tracing::debug!("Renaming {:?} to {:?}", output_path, target);
+        if let Err(err) = finalize::move_file(output_path, &target) {
+            return summary.fail(err);
+        }
+
+        summary.download.filename = filename;
+        summary.path = target;
+        summary.with_status(Status::Success)
+    }
+}
+
+/// Whether a response status is transient and retried
+type StatusClassifier = dyn Fn(StatusCode) -> bool + Send + Sync;
+
+/// Retry strategy classifying response statuses with a custom classifier
+struct RetryClassifier(Shared<StatusClassifier>);
+
+impl RetryableStrategy for RetryClassifier {
+    fn handle(&self, res: &reqwest_middleware::Result<Response>) -> Option<Retryable> {
+        match res {
+            Ok(response) if response.status().is_success() => None,
+            Ok(response) if (self.0)(response.status()) => Some(Retryable::Transient),
+            Ok(_) => Some(Retryable::Fatal),
+            // Connection errors keep the default classification
+            Err(err) => default_on_request_failure(err),
+        }
+    }
+}
+
+/// Exponential backoff also giving up once a retry would start after the time budget
+struct TimedRetries {
+    backoff: ExponentialBackoff,
+    budget: Option<Duration>,
+}
+
+impl RetryPolicy for TimedRetries {
+    fn should_retry(&self, request_start_time: SystemTime, n_past_retries: u32) -> RetryDecision {
+        match self.backoff.should_retry(request_start_time, n_past_retries) {
+            RetryDecision::Retry { execute_after } if self.budget.is_some_and(|budget| {
+                execute_after.duration_since(request_start_time).unwrap_or_default() > budget
+            }) => RetryDecision::DoNotRetry,
+            decision => decision,
+        }
+    }
+}
+
+/// Callback vetoing a response before its body is written
+type ResponseGate = dyn Fn(&HeaderMap, StatusCode) -> std::result::Result<(), String> + Send + Sync;
+
+/// Comparator of the launch order of downloads
+type DownloadOrder = dyn Fn(&Download, &Download) -> Ordering + Send + Sync;
+
+/// Callback notified of skipped downloads
+type SkipHook = dyn Fn(&Download, &SkipReason) + Send + Sync;
+
+/// Callback notified of the report of every batch
+type BatchHook = dyn Fn(&DownloadReport) + Send + Sync;
+
+/// Tracing middleware wrapping every request
+#[derive(Debug, Clone, PartialEq)]
+enum Tracing {
+    Default,
+    Disabled,
+    Custom(Shared<dyn Middleware>),
+}
+
+/// Compression of the downloaded files on disk
+#[cfg(feature = "compress")]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+#[cfg(feature = "compress")]
+impl Compression {
+    fn extension(self) -> &'static str {
+        match self {
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+        }
+    }
+}
+
+/// Which redirects are followed
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RedirectPolicy {
+    /// never follow redirects, the redirect response fails the download
+    None,
+    /// follow at most the given number of redirects, reqwest follows 10 by default
+    Limited(usize),
+    /// follow any number of redirects within a host, like CDNs shuffling between their paths,
+    /// but at most `max_cross_host` redirects to another host
+    SameHostUnlimited { max_cross_host: usize },
+}
+
+impl RedirectPolicy {
+    fn policy(self) -> redirect::Policy {
+        match self {
+            RedirectPolicy::None => redirect::Policy::none(),
+            // Checked like the client would, but the error keeps the chain of the refused redirect
+            RedirectPolicy::Limited(_) | RedirectPolicy::SameHostUnlimited { .. } => redirect::Policy::custom(move |attempt| {
+                match self.check(attempt.previous(), attempt.url()) {
+                    Ok(()) => attempt.follow(),
+                    Err(message) => {
+                        let refused = RedirectRefused::new(message, attempt.previous(), attempt.url());
+                        attempt.error(refused)
+                    }
+                }
+            }),
+        }
+    }
+
+    /// Whether the redirect to `next` is followed after visiting the `previous` urls, starting
+    /// with the requested one
+    pub(crate) fn check(self, previous: &[Url], next: &Url) -> std::result::Result<(), String> {
+        match self {
+            RedirectPolicy::None => Err(format!("redirects are not followed, got one to {}", next)),
+            RedirectPolicy::Limited(max) if previous.len() > max => Err("too many redirects".to_string()),
+            RedirectPolicy::Limited(_) => Ok(()),
+            RedirectPolicy::SameHostUnlimited { max_cross_host } => {
+                // Without a limit on the hops, a loop is the only way to never end
+                if previous.contains(next) {
+                    return Err(format!("redirect loop at {}", next));
+                }
+                let hosts: Vec<_> = previous.iter().chain([next]).map(host::url_host).collect();
+                let cross_host = hosts.windows(2).filter(|pair| pair[0] != pair[1]).count();
+                if cross_host > max_cross_host {
+                    let from = previous.last().and_then(host::url_host).unwrap_or_default();
+                    let to = host::url_host(next).unwrap_or_default();
+                    return Err(format!("blocked cross-host redirect from {} to {}, at most {} allowed",
+                        from, to, max_cross_host));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Fail the download with the message of a failed request, a refused redirect is also
+/// reported with its chain in the diagnostics
+pub(crate) fn fail_request(mut summary: Summary, err: &reqwest_middleware::Error) -> Summary {
+    if let Some(redirect) = refused(err) {
+        tracing::debug!("Refused redirect chain of {}: {:?}", summary.download.redacted_url(), redirect.chain);
+        summary.diagnose(Diagnostic::RedirectRefused { chain: redirect.chain.clone(), host: redirect.host() });
+    }
+    summary.fail(request_failure(err))
+}
+
+/// The message of a failed request, including why a redirect was refused
+pub(crate) fn request_failure(err: &reqwest_middleware::Error) -> String {
+    match err {
+        reqwest_middleware::Error::Reqwest(error) if error.is_redirect() => match std::error::Error::source(error) {
+            Some(source) => format!("{}: {}", error, source),
+            None => error.to_string(),
+        },
+        // The cause of a failed connection, e.g. a rejected certificate, ends the source chain
+        reqwest_middleware::Error::Reqwest(error) if error.is_connect() => {
+            let mut cause = std::error::Error::source(error);
+            while let Some(source) = cause.and_then(std::error::Error::source) {
+                cause = Some(source);
+            }
+            match cause {
+                Some(cause) => format!("{}: {}", error, cause),
+                None => error.to_string(),
+            }
+        }
+        err => err.to_string(),
+    }
+}
+
+/// The error of an output path that is an existing directory, which opening it for writing
+/// would only report as an obscure OS error
+pub(crate) fn directory_error(output_path: &Path) -> Option<Error> {
+    output_path.is_dir()
+        .then(|| OutputIsDirectorySnafu { path: output_path, location: location!() }.build())
+}
+
+/// What to do when the output path of a download is an existing symlink
+///
+/// On Windows both file and directory symlinks count, junctions don't and are followed.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SymlinkPolicy {
+    /// write through the symlink to its target, possibly outside the download directory
+    Follow,
+    /// fail the download, the default
+    Reject,
+    /// remove the symlink and write a regular file in its place
+    Replace,
+}
+
+impl SymlinkPolicy {
+    pub(crate) fn apply(self, path: &Path) -> io::Result<()> {
+        let Ok(metadata) = fs::symlink_metadata(path) else {
+            return Ok(());
+        };
+        if !metadata.file_type().is_symlink() {
+            return Ok(());
+        }
+        match self {
+            SymlinkPolicy::Follow => Ok(()),
+            SymlinkPolicy::Reject => Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                format!("the output path {:?} is a symlink", path))),
+            #[cfg(windows)]
+            SymlinkPolicy::Replace if std::os::windows::fs::FileTypeExt::is_symlink_dir(&metadata.file_type()) => {
+                fs::remove_dir(path)
+            }
+            SymlinkPolicy::Replace => fs::remove_file(path),
+        }
+    }
+}
+
+/// How a partial file on disk is continued, see `DownloaderBuilder::resume_mode`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ResumeMode {
+    /// resume when the probe advertises ranges without a weak ETag, the default
+    Auto,
+    /// always request the remaining range, trusting the server to honor it
+    Always,
+    /// always download from scratch
+    Never,
+    /// resume only with a strong validator sent as `If-Range`, downloading from scratch otherwise
+    Strict,
+}
+
+/// Content coding accepted in responses, see `DownloaderBuilder::accept_encoding`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Deflate,
+    Brotli,
+    Zstd,
+}
+
+impl Encoding {
+    fn token(self) -> &'static str {
+        match self {
+            Encoding::Identity => "identity",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+        }
+    }
+
+    /// The `Accept-Encoding` value of `encodings`, identity when empty
+    fn header(encodings: &[Encoding]) -> String {
+        match encodings {
+            [] => Encoding::Identity.token().to_string(),
+            encodings => encodings.iter().map(|encoding| encoding.token()).collect::<Vec<_>>().join(", "),
+        }
+    }
+
+    fn compresses(encodings: &[Encoding]) -> bool {
+        encodings.iter().any(|encoding| *encoding != Encoding::Identity)
+    }
+}
+
+/// How often written bytes are flushed and synced to disk
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum FsyncInterval {
+    /// after every given number of bytes
+    Bytes(u64),
+    /// after the given time elapsed since the last sync
+    Elapsed(Duration),
+}
+
+/// Record the digests of the content in the summary and verify them against the checksum and
+/// the digest check of the download
+fn verify_digests(summary: &mut Summary, mut digests: Digests) -> std::result::Result<(), String> {
+    let custom = digests.finish_custom();
+    summary.digests = digests.finish();
+    if let (Some(check), Some(actual)) = (&summary.download.digest_check, custom) {
+        if actual != check.expected {
+            return Err(format!("digest mismatch: expected {}, got {}", digest::hex(&check.expected), digest::hex(&actual)));
+        }
+    }
+    if let Some((kind, expected)) = &summary.download.checksum {
+        let actual = summary.digests.get(kind).map(String::as_str).unwrap_or_default();
+        if !actual.eq_ignore_ascii_case(expected.trim()) {
+            return Err(format!("{:?} mismatch: expected {}, got {}", kind, expected, actual));
+        }
+    }
+    Ok(())
+}
+
+/// Remove the file of a failed download, a file that can't be removed is reported in the summary
+pub(crate) fn discard(summary: &mut Summary, path: &Path) {
+    if let Err(err) = fs::remove_file(path) {
+        tracing::warn!("Failed to remove {:?}: {}", path, err);
+        summary.diagnose(Diagnostic::CleanupFailed { path: path.to_path_buf(), message: err.to_string() });
+    }
+}
+
+/// Tracks when the next periodic sync is due
+struct Durability {
+    interval: FsyncInterval,
+    bytes: u64,
+    synced: Instant,
+    clock: Clock,
+}
+
+impl Durability {
+    fn new(interval: FsyncInterval, clock: Clock) -> Self {
+        Self { interval, bytes: 0, synced: clock.now(), clock }
+    }
+
+    /// Record written bytes and tell whether a sync is due
+    fn record(&mut self, written: u64) -> bool {
+        self.bytes += written;
+        let due = match self.interval {
+            FsyncInterval::Bytes(bytes) => self.bytes >= bytes,
+            FsyncInterval::Elapsed(elapsed) => self.clock.now().duration_since(self.synced) >= elapsed,
+        };
+        if due {
+            self.bytes = 0;
+            self.synced = self.clock.now();
+        }
+        due
+    }
+}
+
+/// Flush the buffered bytes and sync the file data to disk
+async fn sync(file: &mut PooledWriter<'_>) -> io::Result<()> {
+    file.flush().await?;
+    file.get_ref().sync_data().await
+}
+
+/// A file allocated to its expected size before it is written
+///
+/// Its length no longer tells how much was written, so unless it is settled to the written
+/// bytes the file is trimmed to the bytes that reached it when the guard drops, on errors as
+/// when the download is cancelled or times out, and resumes from there instead of from zeroes.
+/// The trim runs on the blocking pool since dropping can't wait for it.
+struct Preallocation {
+    path: PathBuf,
+    /// the file trimmed when the guard drops unsettled
+    file: Option<std::fs::File>,
+    /// bytes written by previous attempts
+    resumed: u64,
+    /// length of the content handed to the file, bytes still buffered are lost on drop
+    on_disk: u64,
+    settled: bool,
+}
+
+impl Preallocation {
+    /// Extend the file by `expected` bytes after the `resumed` ones and write from there
+    async fn allocate(file: &mut File, path: &Path, resumed: u64, expected: u64) -> io::Result<Self> {
+        file.seek(SeekFrom::Start(resumed)).await?;
+        file.set_len(resumed + expected).await?;
+        let trimmed = file.try_clone().await?.into_std().await;
+        Ok(Self { path: path.to_path_buf(), file: Some(trimmed), resumed, on_disk: resumed, settled: false })
+    }
+
+    /// Record the `written` bytes, less those still buffered by `file`
+    fn record(&mut self, file: &PooledWriter<'_>, written: u64) {
+        self.on_disk = self.resumed + written - file.buffered() as u64;
+    }
+
+    /// Flush the buffered bytes and trim the file to the written bytes
+    async fn settle(&mut self, file: &mut PooledWriter<'_>, written: u64) -> io::Result<()> {
+        file.flush().await?;
+        file.get_ref().set_len(self.resumed + written).await?;
+        self.settled = true;
+        Ok(())
+    }
+}
+
+impl Drop for Preallocation {
+    fn drop(&mut self) {
+        let Some(file) = self.file.take().filter(|_| !self.settled) else {
+            return;
+        };
+        let (path, len) = (std::mem::take(&mut self.path), self.on_disk);
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn_blocking(move || {
+                    if let Err(err) = file.set_len(len) {
+                        tracing::warn!("Failed to trim the preallocated {:?}: {}", path, err);
+                    }
+                });
+            }
+            Err(_) => tracing::warn!("Failed to trim the preallocated {:?} outside of a runtime", path),
+        }
+    }
+}
+
+/// A download of a batch coalesced with the earlier download of its url
+struct Duplicate<'a> {
+    index: usize,
+    download: &'a Download,
+    leader: usize,
+}
+
+/// State shared by the downloads of one batch
+pub(crate) struct Batch {
+    http: reqwest::Client,
+    client: ClientWithMiddleware,
+    /// clients for downloads overriding the retries, sharing the connection pool of `http`
+    clients: Mutex<HashMap<u32, ClientWithMiddleware>>,
+    /// clients connecting to unix domain sockets, by socket path and retries
+    #[cfg(unix)]
+    sockets: Mutex<HashMap<(PathBuf, u32), ClientWithMiddleware>>,
+    capture: Option<Capture>,
+    /// write buffers reused by the downloads
+    buffers: BufferPool,
+    control: Option<DownloadControl>,
+    /// the pending downloads, shared with the queue enqueueing into the batch
+    pub(crate) checkpoint: Option<Arc<Checkpoint>>,
+    /// when the batch timeout expires
+    deadline: Option<Instant>,
+    /// spacing of the download starts
+    stagger: Option<Stagger>,
+    /// the downloads running at the same time
+    running: ConcurrencyGauge,
+    /// the capabilities of the hosts that served content
+    hosts: HostCapabilities,
+}
+
+/// A spawned task aborted when dropped, so dropping the future awaiting it cancels it
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+impl Batch {
+    /// The client honoring the retry budget of the download
+    ///
+    /// The retry middleware is built into a client, so downloads overriding the global
+    /// retries get their own middleware stack on top of the same pooled http client.
+    pub(crate) fn client_for(&self, downloader: &Downloader, download: &Download) -> ClientWithMiddleware {
+        match download.retries {
+            Some(retries) if retries != downloader.retries => {
+                let mut clients = self.clients.lock().unwrap_or_else(|err| err.into_inner());
+                clients.entry(retries)
+                    .or_insert_with(|| downloader.with_middleware(self.http.clone(), retries))
+                    .clone()
+            }
+            _ => self.client.clone(),
+        }
+    }
+
+    /// The client sending the requests of the download over the unix domain socket
+    #[cfg(unix)]
+    fn socket_client(&self, downloader: &Downloader, download: &Download, socket: PathBuf) -> Result<ClientWithMiddleware> {
+        let retries = download.retries.unwrap_or(downloader.retries);
+        let mut sockets = self.sockets.lock().unwrap_or_else(|err| err.into_inner());
+        if let Some(client) = sockets.get(&(socket.clone(), retries)) {
+            return Ok(client.clone());
+        }
+        let http = downloader.client_builder()?
+            .unix_socket(socket.clone())
+            .build()
+            .context(ReqwestSnafu { location: location!() })?;
+        let client = downloader.with_middleware(http, retries);
+        sockets.insert((socket, retries), client.clone());
+        Ok(client)
+    }
+}
+
+impl Default for Downloader {
+    fn default() -> Self {
+        Self {
+            directory: env::current_dir().unwrap_or_default(),
+            retries: 0,
+            concurrent_downloads: 32,
+            adaptive_concurrency: None,
+            concurrency_ramp: Duration::ZERO,
+            resume: true,
+            ordered: false,
+            headers: None,
+            resolve: Vec::new(),
+            read_timeout: None,
+            tracing: Tracing::Default,
+            capture: None,
+            filename_template: None,
+            verify_content_md5: false,
+            fail_on_empty: false,
+            schedule_window: None,
+            fsync_interval: None,
+            size_threshold: None,
+            small_concurrency: None,
+            large_concurrency: None,
+            content_cache: None,
+            follow_pagination: false,
+            skip_missing: false,
+            on_skip: None,
+            on_batch_complete: None,
+            on_progress: None,
+            digests: Vec::new(),
+            write_buffer_size: buffer::DEFAULT_WRITE_BUFFER,
+            retry_classifier: None,
+            default_filename: None,
+            symlink_policy: SymlinkPolicy::Reject,
+            resume_mode: ResumeMode::Auto,
+            checkpoint: None,
+            completion: None,
+            reject_html_for: Vec::new(),
+            redirect_policy: None,
+            batch_timeout: None,
+            max_buffer_memory: None,
+            response_gate: None,
+            single_connection: false,
+            redownload_on_checksum_failure: 0,
+            launch_delay: Duration::ZERO,
+            launch_jitter: Duration::ZERO,
+            preallocate: false,
+            create_new: false,
+            no_truncate: false,
+            clock: Clock::Real,
+            retry: true,
+            on_redirect: None,
+            temp_dir: None,
+            coalesce_identical_urls: false,
+            prepared: Shared(Arc::default()),
+            max_retry_duration: None,
+            order_by: None,
+            report_path: None,
+            accept_encoding: None,
+            status_messages: None,
+            split_storage: None,
+            allowed_schemes: ["http", "https"].map(String::from).to_vec(),
+            per_host_rate_limit: None,
+            host_buckets: Shared(Arc::default()),
+            runtime_handle: None,
+            transforms: Vec::new(),
+            #[cfg(feature = "zip")]
+            extract_zip: false,
+            #[cfg(feature = "compress")]
+            compress_output: None,
+            #[cfg(feature = "pinning")]
+            cert_pins: CertPins::new(),
+            #[cfg(feature = "signal")]
+            signal_handler: false,
+            #[cfg(feature = "zstd")]
+            decompress_zstd: false,
+        }
+    }
+}
+
+#[repr(transparent)]
+#[derive(Debug, Clone)]
+pub struct DownloaderBuilder(Downloader);
+
+impl From<Downloader> for DownloaderBuilder {
+    fn from(downloader: Downloader) -> Self {
+        Self(downloader)
+    }
+}
+
+/// Override the fields of `$target` whose value in `$other` differs from `$default`
+macro_rules! overlay {
+    ($target:expr, $other:expr, $default:expr, $($field:ident),* $(,)?) => {
+        $(
+            if $other.$field != $default.$field {
+                $target.$field = $other.$field;
+            }
+        )*
+    };
+}
+
+impl DownloaderBuilder {
+    pub fn new() -> Self {
+        Self(Downloader::new())
+    }
+
+    /// Layer the configuration of `other` on top of this builder.
+    ///
+    /// Headers are merged, with the headers of `other` replacing those of the same name,
+    /// and host overrides are appended.
+    /// Every other field of `other` overrides this builder unless it still has its default value.
+    pub fn merge(mut self, other: Downloader) -> Self {
+        let default = Downloader::default();
+        let mut other = other;
+        if let Some(headers) = other.headers.take() {
+            self = self.headers(headers);
+        }
+        self.0.resolve.append(&mut other.resolve);
+        if let Some(handle) = other.runtime_handle.take() {
+            self.0.runtime_handle = Some(handle);
+        }
+        overlay!(self.0, other, default,
+            directory,
+            retries,
+            concurrent_downloads,
+            adaptive_concurrency,
+            concurrency_ramp,
+            resume,
+            ordered,
+            read_timeout,
+            tracing,
+            capture,
+            filename_template,
+            verify_content_md5,
+            fail_on_empty,
+            schedule_window,
+            fsync_interval,
+            size_threshold,
+            small_concurrency,
+            large_concurrency,
+            content_cache,
+            follow_pagination,
+            skip_missing,
+            on_skip,
+            on_batch_complete,
+            on_progress,
+            digests,
+            write_buffer_size,
+            retry_classifier,
+            default_filename,
+            symlink_policy,
+            resume_mode,
+            checkpoint,
+            completion,
+            reject_html_for,
+            redirect_policy,
+            batch_timeout,
+            max_buffer_memory,
+            response_gate,
+            single_connection,
+            redownload_on_checksum_failure,
+            launch_delay,
+            launch_jitter,
+            preallocate,
+            create_new,
+            no_truncate,
+            clock,
+            retry,
+            on_redirect,
+            temp_dir,
+            coalesce_identical_urls,
+            max_retry_duration,
+            order_by,
+            report_path,
+            accept_encoding,
+            status_messages,
+            split_storage,
+            allowed_schemes,
+            per_host_rate_limit,
+            transforms,
+        );
+        #[cfg(feature = "zip")]
+        overlay!(self.0, other, default, extract_zip);
+        #[cfg(feature = "compress")]
+        overlay!(self.0, other, default, compress_output);
+        #[cfg(feature = "pinning")]
+        overlay!(self.0, other, default, cert_pins);
+        #[cfg(feature = "signal")]
+        overlay!(self.0, other, default, signal_handler);
+        #[cfg(feature = "zstd")]
+        overlay!(self.0, other, default, decompress_zstd);
+        self
+    }
+
+    pub fn directory(mut self, directory: impl Into<PathBuf>) -> Self {
+        self.0.directory = directory.into();
+        self
+    }
+
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.0.retries = retries;
+        self
+    }
+
+    /// Stop retrying a request once `duration` elapsed since its first attempt, counting the
+    /// backoff before the next retry. The retry count still applies, whichever limit is reached
+    /// first ends the retries and the last error is returned.
+    pub fn max_retry_duration(mut self, duration: Duration) -> Self {
+        self.0.max_retry_duration = Some(duration);
+        self
+    }
+
+    /// Leave out the retry middleware when `retry` is false, even for downloads overriding the retries.
+    ///
+    /// Unlike `retries(0)`, which still goes through the middleware, request errors then come
+    /// straight from reqwest and a transient failure fails the download at its first attempt.
+    pub fn retry(mut self, retry: bool) -> Self {
+        self.0.retry = retry;
+        self
+    }
+
+    /// Only retry responses with one of `statuses` instead of the default transient statuses
+    /// (`408`, `429` and `5xx`), connection errors are still classified as by default.
+    ///
+    /// Retried responses wait according to the exponential backoff, a `Retry-After`
+    /// header does not change the delay.
+    pub fn retryable_status(self, statuses: Vec<StatusCode>) -> Self {
+        self.retry_classifier(move |status| statuses.contains(&status))
+    }
+
+    /// Retry the responses whose status `classifier` considers transient,
+    /// like `retryable_status` with a custom classification.
+    pub fn retry_classifier(mut self, classifier: impl Fn(StatusCode) -> bool + Send + Sync + 'static) -> Self {
+        self.0.retry_classifier = Some(Shared(Arc::new(classifier)));
+        self
+    }
+
+    pub fn concurrent_downloads(mut self, concurrent: u8) -> Self {
+        self.0.concurrent_downloads = concurrent;
+        self
+    }
+
+    /// Adapt the number of concurrent downloads to the observed throughput.
+    ///
+    /// The batch starts with `min` downloads and grows or shrinks the limit by one, within
+    /// `min..=max`, whenever the aggregate throughput of a window of completed downloads is
+    /// more than 5% faster or slower than the previous window. Replaces `concurrent_downloads`,
+    /// disabled by default.
+    pub fn adaptive_concurrency(mut self, min: u8, max: u8) -> Self {
+        self.0.adaptive_concurrency = Some(AdaptiveConcurrency { min, max });
+        self
+    }
+
+    /// Grow the concurrency linearly from one download at the start of a batch up to
+    /// `concurrent_downloads` after `ramp`, so an origin is not hit by every download at once.
+    /// Unlike the launch delay, which spaces the starts, the ramp raises the concurrency ceiling.
+    ///
+    /// The adaptive concurrency already starts low and grows on its own, it takes precedence and
+    /// the ramp is ignored when both are set. Downloads split by size run without the ramp.
+    pub fn concurrency_ramp(mut self, ramp: Duration) -> Self {
+        self.0.concurrency_ramp = ramp;
+        self
+    }
+
+    /// Run the batches on the runtime of `handle` instead of the runtime polling the download
+    /// future, e.g. a dedicated I/O runtime keeping the download load away from latency-sensitive
+    /// work. The batch is spawned as one task there, its connections, file writes and blocking
+    /// work all run on that runtime, and the download future only waits for its report.
+    ///
+    /// The spawned batch stays tied to the download future: dropping the future aborts the batch,
+    /// like the downloads running in place, and a `DownloadControl` cancels it as usual. A panic
+    /// in the batch is resumed in the caller. If the runtime shuts down first, the downloads of
+    /// the batch fail. A queue runs its background task on the runtime too. Single downloads,
+    /// such as segmented ones, still run in place.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use tokio_trauma::download::Download;
+    /// use tokio_trauma::downloader::DownloaderBuilder;
+    ///
+    /// # fn run() -> Result<(), Box<dyn std::error::Error>> {
+    /// let io = tokio::runtime::Builder::new_multi_thread()
+    ///     .worker_threads(2)
+    ///     .thread_name("downloads")
+    ///     .enable_all()
+    ///     .build()?;
+    /// let downloader = DownloaderBuilder::new()
+    ///     .runtime_handle(io.handle().clone())
+    ///     .build();
+    ///
+    /// let app = tokio::runtime::Runtime::new()?;
+    /// let report = app.block_on(downloader.download([Download::try_from("https://example.com/file.zip")?]))?;
+    /// assert!(report.all_succeeded());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn runtime_handle(mut self, handle: Handle) -> Self {
+        self.0.runtime_handle = Some(handle);
+        self
+    }
+
+    /// Pass the response bytes through the transform created by `transform` for every download
+    /// attempt before they are written, after the transforms registered earlier
+    ///
+    /// See the [`transform`](crate::transform) module for the ordering of the pipeline and its
+    /// interaction with checksums. Downloads are not resumed with transforms, a partial file of
+    /// transformed bytes is downloaded again.
+    pub fn transform(mut self, transform: impl Fn(&Download) -> Box<dyn StreamTransform> + Send + Sync + 'static) -> Self {
+        self.0.transforms.push(Shared(Arc::new(transform)));
+        self
+    }
+
+    /// Encrypt the downloaded files with `key` while they are written, so their plaintext never
+    /// reaches the disk, in the format of the [`encrypt`](crate::encrypt) module, read back with
+    /// `encrypt::decrypt_to_writer`
+    ///
+    /// The encryption is a transform, registered after the transforms registered before, which
+    /// see the plaintext. Like with every transform the downloads are not resumed, and checksums
+    /// still verify the plaintext of the response. Output compression, zip extraction and
+    /// signatures see the encrypted file and are not to be combined with it.
+    #[cfg(feature = "encrypt")]
+    pub fn encrypt(self, key: [u8; 32], cipher: Cipher) -> Self {
+        self.transform(move |_| Box::new(Encryptor::new(&key, cipher)) as Box<dyn StreamTransform>)
+    }
+
+    /// Use the fixed `concurrent_downloads` limit again
+    pub fn fixed_concurrency(mut self) -> Self {
+        self.0.adaptive_concurrency = None;
+        self
+    }
+
+    /// Send `Accept-Encoding` with `encodings` instead of leaving the negotiation to the client,
+    /// an empty list or `[Encoding::Identity]` asks for the content as is. Identity is the
+    /// recommended pairing with resume: a range applies to the encoded content, so resuming a
+    /// compressed response appends bytes that don't continue the file on disk. Resuming while
+    /// compressed encodings are accepted reports `Diagnostic::CompressedResume`.
+    ///
+    /// This replaces the `Accept-Encoding: zstd` of `decompress_zstd`, which then needs
+    /// `Encoding::Zstd` in `encodings`.
+    pub fn accept_encoding(mut self, encodings: Vec<Encoding>) -> Self {
+        self.0.accept_encoding = Some(encodings);
+        self
+    }
+
+    /// Write the report of every batch as JSON to `path` once the batch is done, also when
+    /// downloads failed, see `DownloadReport::write_json`. A report that can't be written is
+    /// logged and does not fail the batch.
+    pub fn write_report(mut self, path: impl Into<PathBuf>) -> Self {
+        self.0.report_path = Some(path.into());
+        self
+    }
+
+    /// Word the statuses of the report written by `write_report` with `messages`, e.g. to
+    /// localize the skip reasons. The statuses themselves stay structured, only their text changes.
+    pub fn status_messages(mut self, messages: impl StatusMessages + 'static) -> Self {
+        self.0.status_messages = Some(Shared(Arc::new(messages)));
+        self
+    }
+
+    /// Start the downloads of a batch in the order of `compare` instead of their order in the
+    /// batch, e.g. `order::by_size` for shortest job first. This is the order downloads are
+    /// launched in as slots free up, they still complete in any order and the report keeps the
+    /// order of the batch when `ordered`.
+    pub fn order_by(mut self, compare: impl Fn(&Download, &Download) -> Ordering + Send + Sync + 'static) -> Self {
+        self.0.order_by = Some(Shared(Arc::new(compare)));
+        self
+    }
+
+    /// Split the batch into downloads smaller than `bytes` and larger ones, each with its own
+    /// concurrency limit, so a few huge files don't hog the slots of many small ones.
+    ///
+    /// The sizes are probed up front with a `HEAD` request, downloads of unknown size are
+    /// considered large. Both pools run at the same time with fixed concurrency.
+    pub fn size_threshold(mut self, bytes: u64) -> Self {
+        self.0.size_threshold = Some(bytes);
+        self
+    }
+
+    /// Concurrency of the small downloads pool, defaults to `concurrent_downloads`
+    pub fn small_concurrency(mut self, concurrent: u8) -> Self {
+        self.0.small_concurrency = Some(concurrent);
+        self
+    }
+
+    /// Concurrency of the large downloads pool, defaults to `concurrent_downloads`
+    pub fn large_concurrency(mut self, concurrent: u8) -> Self {
+        self.0.large_concurrency = Some(concurrent);
+        self
+    }
+
+    /// Yield summaries in the same order as the input downloads.
+    ///
+    /// Downloads still run concurrently, but a slow early download holds back
+    /// the summaries of later downloads that have already finished.
+    pub fn ordered(mut self, ordered: bool) -> Self {
+        self.0.ordered = ordered;
+        self
+    }
+
+    pub fn headers(mut self, headers: HeaderMap) -> Self {
+        let headers = match self.0.headers {
+            None => HeaderMap::from(headers),
+            Some(mut header) => {
+                header.extend(headers);
+                header
+            }
+        };
+        self.0.headers = Some(headers);
+        self
+    }
+
+    pub fn header<K: IntoHeaderName>(mut self, name: K, value: HeaderValue) -> Self {
+        let headers = match self.0.headers {
+            None => {
+                let mut headers = HeaderMap::new();
+                headers.insert(name, value);
+                headers
+            }
+            Some(mut headers) => {
+                headers.insert(name, value);
+                headers
+            }
+        };
+        self.0.headers = Some(headers);
+        self
+    }
+
+    /// Resolve `host` to `addr` instead of using DNS, may be called once per host.
+    ///
+    /// Only the connection target changes, the url, the `Host` header and the TLS SNI
+    /// still use the original host. The port of `addr` is ignored in favor of the url port.
+    /// `host` matches however it is spelled, in any case or as an internationalized name.
+    pub fn resolve(mut self, host: impl Into<String>, addr: SocketAddr) -> Self {
+        let host = host.into();
+        self.0.resolve.push((host::normalize(&host).unwrap_or(host), addr));
+        self
+    }
+
+    /// Fail a request when a single read of the response waits longer than `timeout`.
+    ///
+    /// Unlike a total timeout this does not limit how long a large download may take, it
+    /// only catches stalled connections, which makes it the recommended way to detect dead
+    /// connections. A download stalled while streaming the body fails with the timeout error
+    /// and continues through resuming on the next run. Disabled by default.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.0.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Wrap requests in the `reqwest_tracing` middleware, enabled by default
+    ///
+    /// With the `otel` feature every batch and download also gets a span with the fields of the
+    /// OpenTelemetry HTTP conventions, such as `http.url` and `http.status_code`, plus the bytes
+    /// and the resume of the download. The request spans nest in the span of their download.
+    pub fn tracing(mut self, enabled: bool) -> Self {
+        self.0.tracing = if enabled { Tracing::Default } else { Tracing::Disabled };
+        self
+    }
+
+    /// Trace requests with a custom span backend instead of `DefaultSpanBackend`
+    ///
+    /// ```
+    /// use reqwest_tracing::SpanBackendWithUrl;
+    /// use tokio_trauma::downloader::DownloaderBuilder;
+    ///
+    /// let downloader = DownloaderBuilder::new().span_backend::<SpanBackendWithUrl>().build();
+    /// ```
+    pub fn span_backend<S: ReqwestOtelSpanBackend + Send + Sync + 'static>(mut self) -> Self {
+        self.0.tracing = Tracing::Custom(Shared(Arc::new(TracingMiddleware::<S>::new())));
+        self
+    }
+
+    /// Record request/response metadata of every download as JSON lines into `path`.
+    ///
+    /// Meant for diagnosing misbehaving servers, nothing is recorded unless enabled.
+    pub fn capture(mut self, path: impl Into<PathBuf>) -> Self {
+        self.0.capture = Some(path.into());
+        self
+    }
+
+    /// Name downloads whose url yields no filename, e.g. `https://host/download?id=1`,
+    /// according to `strategy` instead of writing to the download directory itself.
+    pub fn default_filename_strategy(mut self, strategy: FilenameStrategy) -> Self {
+        self.0.default_filename = Some(strategy);
+        self
+    }
+
+    /// Record the queued and running downloads of each batch in a checkpoint file at `path`,
+    /// replaced when a batch starts and removed once every download completed.
+    ///
+    /// After an interrupted batch, `checkpoint::load` returns the downloads left to do. Failed
+    /// downloads stay in the checkpoint, as do downloads enqueued into a queue but never run.
+    pub fn checkpoint(mut self, path: impl Into<PathBuf>) -> Self {
+        self.0.checkpoint = Some(path.into());
+        self
+    }
+
+    /// Decide with `strategy` whether a download is already complete and skipped instead of
+    /// comparing the file on disk with the size of the resource.
+    pub fn completion_strategy(mut self, strategy: impl CompletionStrategy + 'static) -> Self {
+        self.0.completion = Some(Shared(Arc::new(strategy)));
+        self
+    }
+
+    /// Veto responses from their headers and status before their body is written, a download whose
+    /// response `gate` rejects fails with the returned message and no file is written.
+    ///
+    /// The gate runs once the status is known to be successful, for every download of the batch.
+    pub fn response_gate(mut self, gate: impl Fn(&HeaderMap, StatusCode) -> std::result::Result<(), String> + Send + Sync + 'static) -> Self {
+        self.0.response_gate = Some(Shared(Arc::new(gate)));
+        self
+    }
+
+    /// Multiplex the downloads as HTTP/2 streams over a single pooled connection per host
+    /// instead of opening a connection per concurrent download.
+    ///
+    /// The server must speak HTTP/2 without negotiation (prior knowledge), for `https` too.
+    /// The concurrency becomes the usual stream limit of servers, 100, replacing
+    /// `concurrent_downloads` and `adaptive_concurrency`.
+    pub fn single_connection(mut self, single_connection: bool) -> Self {
+        self.0.single_connection = single_connection;
+        self
+    }
+
+    /// Space the starts of the downloads of a batch by `delay`, so connections to a fragile
+    /// origin are not all opened at once. Started downloads still run concurrently.
+    ///
+    /// A download waiting for its start already holds a slot of the concurrency limit, and
+    /// a download freeing a slot is replaced no sooner than `delay` after the previous start.
+    pub fn launch_delay(mut self, delay: Duration) -> Self {
+        self.0.launch_delay = delay;
+        self
+    }
+
+    /// Add a random delay up to `jitter` between the starts of two downloads, on top of the launch delay
+    pub fn launch_jitter(mut self, jitter: Duration) -> Self {
+        self.0.launch_jitter = jitter;
+        self
+    }
+
+    /// Allocate the file to the size of the content before writing it when the size is known,
+    /// which reduces fragmentation and fails early when the disk is too small.
+    ///
+    /// A resumed download is extended by the remaining bytes, and the file is trimmed to the
+    /// written bytes once the content ends, or when the download fails or is cancelled so it
+    /// resumes from them. Compressed output is never preallocated.
+    pub fn preallocate(mut self, preallocate: bool) -> Self {
+        self.0.preallocate = preallocate;
+        self
+    }
+
+    /// Create the output file exclusively, failing the download when the file already exists
+    /// instead of overwriting it, even when another process creates it meanwhile (`O_EXCL`).
+    ///
+    /// A resumed download appends to its existing partial file as usual. Disabled by default.
+    pub fn create_new(mut self, create_new: bool) -> Self {
+        self.0.create_new = create_new;
+        self
+    }
+
+    /// Write over an existing output file from its start without truncating it first, the bytes
+    /// past the end of the new content are kept. Disabled by default.
+    pub fn no_truncate(mut self, no_truncate: bool) -> Self {
+        self.0.no_truncate = no_truncate;
+        self
+    }
+
+    /// Download a file whose content does not match its checksum again from scratch, up to
+    /// `restarts` times, instead of failing it right away.
+    ///
+    /// The corrupt file is removed and the restart does not resume, since the corrupt bytes
+    /// would be kept. The failure after the last restart reports the last digest.
+    pub fn redownload_on_checksum_failure(mut self, restarts: u32) -> Self {
+        self.0.redownload_on_checksum_failure = restarts;
+        self
+    }
+
+    /// Fail downloads whose filename has one of `extensions`, such as `zip` or `iso`, when the
+    /// response is `text/html` instead, which captive portals and some CDNs send with a `200`.
+    ///
+    /// Only the `Content-Type` is looked at, not the content, and `html` or `htm` are never
+    /// rejected so legitimate pages still download. Nothing is written for a rejected download.
+    pub fn reject_html_for(mut self, extensions: Vec<impl Into<String>>) -> Self {
+        self.0.reject_html_for = extensions.into_iter()
+            .map(|extension| extension.into().trim_start_matches('.').to_ascii_lowercase())
+            .filter(|extension| !matches!(extension.as_str(), "html" | "htm"))
+            .collect();
+        self
+    }
+
+    /// Bound the wall-clock time of a whole `download` batch to `timeout`.
+    ///
+    /// Downloads still running or not started when it expires fail with `batch timeout`, the
+    /// summaries of the finished ones are kept and partial files stay for resuming. Per-request
+    /// timeouts such as `read_timeout` still apply, whichever expires first fails the download.
+    /// Queues are not bounded.
+    pub fn batch_timeout(mut self, timeout: Duration) -> Self {
+        self.0.batch_timeout = Some(timeout);
+        self
+    }
+
+    /// Which redirects are followed, the reqwest default of at most 10 redirects otherwise
+    pub fn redirect_policy(mut self, policy: RedirectPolicy) -> Self {
+        self.0.redirect_policy = Some(policy);
+        self
+    }
+
+    /// Rewrite the target of every redirect with `hook` before following it, e.g. to force `https`
+    /// or swap the host of a CDN. Returning `None` follows the redirect as is.
+    ///
+    /// The redirect policy applies to the rewritten targets, and `Authorization` and cookies are
+    /// dropped when the target is on another host. Redirects are then followed by the middleware
+    /// stack instead of the client, see the `redirect` module for how it differs.
+    pub fn on_redirect(mut self, hook: impl Fn(&Url) -> Option<Url> + Send + Sync + 'static) -> Self {
+        self.0.on_redirect = Some(Shared(Arc::new(hook)));
+        self
+    }
+
+    /// Write the intermediate files of concatenated and segmented downloads to `temp_dir` instead
+    /// of next to their output, e.g. to keep them on a faster disk. The directory is created if
+    /// missing, and parts left by an interrupted download are resumed from there.
+    ///
+    /// When `temp_dir` is on another filesystem than the download directory, moving a completed
+    /// file can't be a rename: it is copied next to its target then renamed, which needs the space
+    /// of the file twice on the target filesystem for the time of the copy.
+    pub fn temp_dir(mut self, temp_dir: impl Into<PathBuf>) -> Self {
+        self.0.temp_dir = Some(temp_dir.into());
+        self
+    }
+
+    /// Only download urls with one of `schemes`, e.g. `vec!["https".into()]`, other downloads fail
+    /// with `Error::DisallowedScheme` before any request is sent or file written. This guards
+    /// against urls such as `file://` or `javascript:` in untrusted lists. Only `http` and
+    /// `https` are allowed by default, schemes without support still fail as unsupported.
+    ///
+    /// Allowing `file` copies the local files of `file://` urls, so lists can mix local and
+    /// remote files. Allowing `unix` requests `unix://` urls over their unix domain socket, which
+    /// lets a list reach local daemons such as `/var/run/docker.sock`, only allow it for trusted
+    /// lists.
+    pub fn allowed_schemes(mut self, schemes: Vec<String>) -> Self {
+        self.0.allowed_schemes = schemes.into_iter().map(|scheme| scheme.to_ascii_lowercase()).collect();
+        self
+    }
+
+    /// Write the downloads of each host at most `bytes_per_sec` together, so a shared origin is
+    /// spared while downloads from different hosts still use the whole bandwidth. Hosts are
+    /// normalized, `Example.com` and `example.com.` share their limit.
+    ///
+    /// The limit composes with the rate limit of a download: every byte is taken from both
+    /// buckets, so a download runs at the lower of its own limit and its share of the host
+    /// limit. The host limits are shared by the batches of the downloader and its clones.
+    pub fn per_host_rate_limit(mut self, bytes_per_sec: u64) -> Self {
+        self.0.per_host_rate_limit = Some(bytes_per_sec);
+        self.0.host_buckets = Shared(Arc::default());
+        self
+    }
+
+    /// Store the content of downloads in numbered chunk files of at most `chunk_size` bytes,
+    /// `<filename>.part0`, `<filename>.part1`, ..., concatenated into the output file once the
+    /// content is complete and then removed, e.g. for filesystems limiting the size of files.
+    ///
+    /// An interrupted download keeps its chunks, in the temporary directory when set, and resumes
+    /// after the bytes committed across all of them. Compressed, decompressed and paginated
+    /// downloads are stored as a single file, and filename templates and extraction are not
+    /// applied to chunked downloads.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is zero.
+    pub fn split_storage(mut self, chunk_size: u64) -> Self {
+        assert!(chunk_size > 0, "the chunk size is zero");
+        self.0.split_storage = Some(chunk_size);
+        self
+    }
+
+    /// What to do when the output path is an existing symlink, `SymlinkPolicy::Reject` by default
+    /// so a download never writes outside the download directory through a planted symlink.
+    pub fn symlink_policy(mut self, policy: SymlinkPolicy) -> Self {
+        self.0.symlink_policy = policy;
+        self
+    }
+
+    /// How partial files on disk are resumed, `ResumeMode::Auto` by default
+    ///
+    /// In every mode a partial response is only appended if its `Content-Range` starts where the
+    /// file ends, the file is downloaded again from scratch otherwise.
+    ///
+    /// `ResumeMode::Strict` is the recommended mode: a file is only continued with a range the
+    /// server checked against the strong `ETag` or `Last-Modified` of the probe with `If-Range`,
+    /// so a changed resource or a misbehaving server never yields a file mixing two versions.
+    /// `ResumeMode::Always` requests a range even from servers not advertising them,
+    /// `ResumeMode::Never` downloads every file from scratch.
+    pub fn resume_mode(mut self, mode: ResumeMode) -> Self {
+        self.0.resume_mode = mode;
+        self
+    }
+
+    /// Rename completed downloads according to a template such as `{date}-{host}-{filename}`.
+    ///
+    /// The placeholders `{filename}`, `{stem}`, `{ext}`, `{host}`, `{date}` (UTC, `YYYY-MM-DD`)
+    /// and `{content_type}` are expanded once the download succeeded, unknown placeholders are
+    /// left literally and illegal path characters are replaced by `_`. Resuming and skipping
+    /// complete files still look at the original filename.
+    pub fn filename_template(mut self, template: impl Into<String>) -> Self {
+        self.0.filename_template = Some(FilenameTemplate::new(template.into()));
+        self
+    }
+
+    /// Verify the body against the `Content-MD5` response header when the server sends one.
+    ///
+    /// Resumed, ranged and content-encoded responses are not verified since the header
+    /// describes the whole, unencoded entity. A mismatching file is removed.
+    pub fn verify_content_md5(mut self, verify: bool) -> Self {
+        self.0.verify_content_md5 = verify;
+        self
+    }
+
+    /// Fail downloads whose response body is empty unless the server advertised
+    /// `Content-Length: 0`, the empty file is removed.
+    pub fn fail_on_empty(mut self, fail: bool) -> Self {
+        self.0.fail_on_empty = fail;
+        self
+    }
+
+    /// Only run downloads between `start_hour` and `end_hour`, in UTC.
+    ///
+    /// Downloads wait for the window to open, those still running when it closes are flushed,
+    /// paused and continued through resuming once it opens again. Without resume support they
+    /// restart from scratch. The window wraps around midnight when `start_hour > end_hour`.
+    pub fn schedule_window(mut self, start_hour: u8, end_hour: u8) -> Self {
+        self.0.schedule_window = Some(ScheduleWindow::new(start_hour, end_hour));
+        self
+    }
+
+    /// Size of the buffer response chunks are accumulated in before being written, 64 KiB by default.
+    ///
+    /// The buffers are reused by the downloads of a batch, larger buffers mean fewer writes.
+    pub fn write_buffer_size(mut self, bytes: usize) -> Self {
+        self.0.write_buffer_size = bytes;
+        self
+    }
+
+    /// Bound the bytes buffered at once by all the downloads of a batch to `bytes`.
+    ///
+    /// A download whose next chunk doesn't fit writes its buffer early and waits for the others
+    /// to write theirs, so a slow disk applies backpressure instead of growing memory. A limit
+    /// below `write_buffer_size` times the concurrency makes buffers get written before they are
+    /// full. Chunks at least as large as the write buffer are written without being buffered,
+    /// and a chunk larger than the whole limit takes the whole limit instead of waiting forever.
+    pub fn max_buffer_memory(mut self, bytes: usize) -> Self {
+        self.0.max_buffer_memory = Some(bytes);
+        self
+    }
+
+    /// Flush and sync the written bytes to disk at the given interval.
+    ///
+    /// A resume after a crash then starts at most one interval behind, since resuming
+    /// continues from the size on disk. Syncing costs throughput, the shorter the
+    /// interval the larger the cost.
+    pub fn fsync_interval(mut self, interval: FsyncInterval) -> Self {
+        self.0.fsync_interval = Some(interval);
+        self
+    }
+
+    /// Fetch a url requested by several downloads of a batch only once, the other downloads get
+    /// a hard link or a copy of its file, whatever their filename and directory, and are skipped
+    /// as cached. A failed fetch fails them all.
+    ///
+    /// Unlike `dedup_by_content` the file is shared without checking the resource again, and only
+    /// within a batch. Range and suffix downloads are never coalesced.
+    pub fn coalesce_identical_urls(mut self, coalesce: bool) -> Self {
+        self.0.coalesce_identical_urls = coalesce;
+        self
+    }
+
+    /// Reuse the content of an earlier download of the same url in this process for downloads
+    /// with another filename, they are hard-linked or copied and skipped as cached.
+    ///
+    /// The earlier content is reused when a `HEAD` request returns the same `ETag`, or the same
+    /// size when the server sends no `ETag`. The cache is shared by the clones of the downloader.
+    pub fn dedup_by_content(mut self, dedup: bool) -> Self {
+        self.0.content_cache = dedup.then(|| Shared(Arc::new(ContentCache::default())));
+        self
+    }
+
+    /// Follow `Link: <url>; rel="next"` headers and append every page to the same file.
+    ///
+    /// Pages are followed until a response has no next link, at most 1000 pages per download,
+    /// and the summary reports the number of pages. Paginated downloads are not resumed and
+    /// `Content-MD5` is only verified for single-page responses.
+    ///
+    /// Every page is requested like the first one, with the credentials of the url, and goes
+    /// through the same response gate and content type checks. A next link to another scheme, host
+    /// or port than the download fails it.
+    pub fn follow_pagination(mut self, follow: bool) -> Self {
+        self.0.follow_pagination = follow;
+        self
+    }
+
+    /// Probe every download with a `HEAD` request and skip it as not found without a `GET`
+    /// when the server answers `404` or `410`.
+    ///
+    /// Meant for probing candidate urls, only these statuses are skipped, server errors
+    /// are still retried and downloaded.
+    pub fn skip_missing(mut self, skip: bool) -> Self {
+        self.0.skip_missing = skip;
+        self
+    }
+
+    /// Call `hook` with the download and the reason whenever a download is skipped,
+    /// e.g. to report up-to-date files apart from downloads and failures.
+    pub fn on_skip(mut self, hook: impl Fn(&Download, &SkipReason) + Send + Sync + 'static) -> Self {
+        self.0.on_skip = Some(Shared(Arc::new(hook)));
+        self
+    }
+
+    /// Call `hook` with the report once every download of a batch settled, e.g. to print a final
+    /// summary line or emit a metric. It is called once per batch, also for a cancelled or timed
+    /// out batch with the downloads that did not complete, after the files were written and
+    /// finalized and the report of `write_report` was written. A batch whose future is dropped
+    /// never completes and doesn't call it.
+    pub fn on_batch_complete(mut self, hook: impl Fn(&DownloadReport) + Send + Sync + 'static) -> Self {
+        self.0.on_batch_complete = Some(Shared(Arc::new(hook)));
+        self
+    }
+
+    /// Compute the given digests of every download in the same pass as writing it, they are
+    /// reported in the summary and verify the checksum of downloads setting one.
+    ///
+    /// Each digest costs CPU time for every byte, SHA-256 is the most expensive and CRC32 the
+    /// cheapest, so a fast link may become bound by hashing with several digests. A resumed
+    /// download reads its partial file once to hash it. Skipped downloads have no digests.
+    pub fn compute_digests(mut self, kinds: &[DigestKind]) -> Self {
+        self.0.digests = kinds.to_vec();
+        self
+    }
+
+    /// Call `hook` with the progress events of every download, from the byte level
+    /// to the finished summary.
+    ///
+    /// The hook runs on the download task for every written chunk, so it should be cheap.
+    pub fn on_progress(mut self, hook: impl Fn(&ProgressEvent<'_>) + Send + Sync + 'static) -> Self {
+        self.0.on_progress = Some(Shared(Arc::new(hook)));
+        self
+    }
+
+    /// Extract downloaded `.zip` archives into the directory they were downloaded to.
+    ///
+    /// The archive is kept and the extracted files are listed in the summary.
+    #[cfg(feature = "zip")]
+    pub fn extract_zip(mut self, extract: bool) -> Self {
+        self.0.extract_zip = extract;
+        self
+    }
+
+    /// Compress downloads on the fly while writing them, `.gz` or `.zst` is appended to the filename.
+    ///
+    /// Compressed downloads are never resumed since their offsets on disk differ from the resource,
+    /// and compressed archives are not extracted. Progress events report the downloaded bytes,
+    /// the compressed size on disk is reported in the summary.
+    #[cfg(feature = "compress")]
+    pub fn compress_output(mut self, compression: Compression) -> Self {
+        self.0.compress_output = Some(compression);
+        self
+    }
+
+    /// Cancel the running batch on the first Ctrl-C, its partial files are kept for resuming, and
+    /// abort it on the second one, for command line tools without their own handling. An aborted
+    /// batch fails with `Error::Interrupted`, the caller decides whether to exit the process then.
+    ///
+    /// This installs a process-wide Ctrl-C handler the first time a batch runs, which stays for
+    /// the life of the process: a Ctrl-C between batches no longer terminates the process then.
+    /// Leave it disabled, the default, and cancel through `download_controlled` to handle signals
+    /// yourself.
+    #[cfg(feature = "signal")]
+    pub fn install_signal_handler(mut self, install: bool) -> Self {
+        self.0.signal_handler = install;
+        self
+    }
+
+    /// Decompress zstd responses while writing them, those with `Content-Encoding: zstd` or from a
+    /// `.zst` url, which are then written without their `.zst` extension. Requests advertise
+    /// `Accept-Encoding: zstd` so servers may compress any response.
+    ///
+    /// Like compressed output, downloads are never resumed since the file on disk is not the
+    /// resource, and a complete file can't be recognized from its size: downloads are fetched
+    /// again unless a completion strategy says otherwise. The summary reports the decompressed
+    /// size while digests cover the received bytes. Compressed output takes precedence, the
+    /// responses are then written as received.
+    #[cfg(feature = "zstd")]
+    pub fn decompress_zstd(mut self, decompress: bool) -> Self {
+        self.0.decompress_zstd = decompress;
+        self
+    }
+
+    /// Pin the certificate public key of `host`, called again to pin several keys, e.g. the
+    /// current and the next key. `sha256_spki` is the SHA-256 of the DER `SubjectPublicKeyInfo`
+    /// of the leaf certificate in base64 or hex, as printed by
+    /// `openssl x509 -pubkey -noout | openssl pkey -pubin -outform der | openssl dgst -sha256 -binary | base64`.
+    ///
+    /// Connections to `host` whose certificate has none of its pinned keys fail after the usual
+    /// verification against the webpki roots, the summary of the download reporting the pin
+    /// mismatch. Pinning needs the rustls backend, the client then uses rustls for every host.
+    ///
+    /// `host` matches however it is spelled, in any case or as an internationalized name, and
+    /// covers every port of the host. Downloading fails with `Error::InvalidCertPin` if `host` is
+    /// not a valid host or has a port.
+    #[cfg(feature = "pinning")]
+    pub fn pin_cert(mut self, host: impl AsRef<str>, sha256_spki: impl Into<String>) -> Self {
+        self.0.cert_pins.entry(host.as_ref().to_string()).or_default().push(sha256_spki.into());
+        self
+    }
+
+    pub fn build(self) -> Downloader {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate test;
+
+    use std::collections::HashSet;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+
+    use futures_util::future;
+    use reqwest::header::{HeaderMap, HeaderName, HeaderValue, ACCEPT, USER_AGENT};
+    use reqwest::StatusCode;
+    use test::Bencher;
+    use tokio::runtime::Runtime;
+    use url::Url;
+
+    use crate::clock::Clock;
+    use crate::completion::{async_trait, CompletionStrategy};
+    use crate::control::DownloadControl;
+    use crate::diagnostic::Diagnostic;
+    use crate::download::{ByteRange, ContentRange, DigestKind, Download, SkipReason, Status, StatusMessages};
+    use crate::error::Error;
+    use crate::progress::ProgressEvent;
+    use crate::downloader::{DownloaderBuilder, Encoding, RedirectPolicy, ResumeMode, SymlinkPolicy};
+    use crate::hash::{Crc32Digest, DigestUpdate};
+    use crate::testing::{response, start_h2, temp_dir, TestServer};
+    use crate::transform::{Hashing, NormalizeNewlines, StreamTransform};
+
+    #[test]
+    fn test_merge() {
+        let base = DownloaderBuilder::new()
+            .retries(3)
+            .concurrent_downloads(8)
+            .header(USER_AGENT, HeaderValue::from_static("base"))
+            .header(ACCEPT, HeaderValue::from_static("*/*"));
+        let job = DownloaderBuilder::new()
+            .directory("/data/job")
+            .header(USER_AGENT, HeaderValue::from_static("job"))
+            .build();
+
+        let merged = base.merge(job).build();
+        assert_eq!(3, merged.retries);
+        assert_eq!(8, merged.concurrent_downloads);
+        assert_eq!("/data/job", merged.directory.to_str().unwrap());
+        let headers = merged.headers.unwrap();
+        assert_eq!("job", headers[USER_AGENT]);
+        assert_eq!("*/*", headers[ACCEPT]);
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let server = TestServer::start(|request| response(request, "200 OK", &[], b"content")).await;
+        let directory = temp_dir("resolve");
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .resolve("Files.Example.TEST", server.addr)
+            .build();
+
+        let url = format!("http://files.example.test:{}/file.txt", server.addr.port());
+        let report = downloader.download([Download::try_from(url.as_str()).unwrap()]).await.unwrap();
+        assert_eq!(&Status::Success, report[0].status());
+        assert_eq!("content", std::fs::read_to_string(directory.join("file.txt")).unwrap());
+        assert!(server.requests().iter().all(|request| request.header("host").unwrap().starts_with("files.example.test")));
+    }
+
+    #[tokio::test]
+    async fn test_fail_on_empty() {
+        let server = TestServer::start(|request| {
+            response(request, "200 OK", &[("Transfer-Encoding", "chunked")], b"0\r\n\r\n")
+        }).await;
+        let directory = temp_dir("fail-on-empty");
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .fail_on_empty(true)
+            .build();
+
+        let download = Download::try_from(server.url("/missing.bin").as_str()).unwrap();
+        let report = downloader.download([download]).await.unwrap();
+        assert_eq!(&Status::Fail("empty response".into()), report[0].status());
+        assert!(!directory.join("missing.bin").exists());
+    }
+
+    #[tokio::test]
+    async fn test_fail_on_empty_advertised() {
+        let server = TestServer::start(|request| response(request, "200 OK", &[], b"")).await;
+        let directory = temp_dir("fail-on-empty-advertised");
+        let mut downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .fail_on_empty(true)
+            .build();
+        downloader.resume = false;
+
+        let download = Download::try_from(server.url("/empty.txt").as_str()).unwrap();
+        let report = downloader.download([download]).await.unwrap();
+        assert_eq!(&Status::Success, report[0].status());
+        assert!(directory.join("empty.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn test_dedup_by_content() {
+        let server = TestServer::start(|request| response(request, "200 OK", &[("ETag", "\"v1\"")], b"shared")).await;
+        let directory = temp_dir("dedup-by-content");
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .dedup_by_content(true)
+            .build();
+
+        let url = server.url("/dependency.tar");
+        let downloads = [
+            Download::new(url.parse().unwrap(), "first.tar".into()),
+            Download::new(url.parse().unwrap(), "second.tar".into()),
+        ];
+        let report = downloader.download(downloads).await.unwrap();
+        assert_eq!(1, report.successes().count());
+        assert_eq!(Some(&Status::Skipped(SkipReason::Cached)), report.skipped().next().map(|summary| summary.status()));
+        assert_eq!("shared", std::fs::read_to_string(directory.join("first.tar")).unwrap());
+        assert_eq!("shared", std::fs::read_to_string(directory.join("second.tar")).unwrap());
+        assert_eq!(1, server.requests().iter().filter(|request| request.method == "GET").count());
+    }
+
+    #[tokio::test]
+    async fn test_weak_etag_not_resumed() {
+        let server = TestServer::start(|request| {
+            let headers = [("ETag", "W/\"v1\""), ("Accept-Ranges", "bytes")];
+            response(request, "200 OK", &headers, b"hello world")
+        }).await;
+        let directory = temp_dir("weak-etag");
+        std::fs::write(directory.join("file.txt"), "hel").unwrap();
+        let downloader = DownloaderBuilder::new().directory(&directory).build();
+
+        let download = Download::try_from(server.url("/file.txt").as_str()).unwrap();
+        let report = downloader.download([download]).await.unwrap();
+        assert_eq!(&Status::Success, report[0].status());
+        assert!(!report[0].resume());
+        assert_eq!("hello world", std::fs::read_to_string(directory.join("file.txt")).unwrap());
+        assert!(server.requests().iter().all(|request| request.header("range").is_none()));
+    }
+
+    #[tokio::test]
+    async fn test_total_size() {
+        let server = TestServer::start(|request| match request.path.as_str() {
+            "/known.bin" => response(request, "200 OK", &[], &[0; 4096]),
+            _ => response(request, "200 OK", &[("Transfer-Encoding", "chunked")], b"0\r\n\r\n"),
+        }).await;
+        let downloader = DownloaderBuilder::new().build();
+
+        let downloads = [
+            Download::try_from(server.url("/known.bin").as_str()).unwrap(),
+            Download::try_from(server.url("/unknown.bin").as_str()).unwrap(),
+            Download::try_from(server.url("/manifest.bin").as_str()).unwrap().with_expected_size(100),
+        ];
+        let estimate = downloader.total_size(downloads).await.unwrap();
+        assert_eq!(4196, estimate.known_bytes);
+        assert_eq!(1, estimate.unknown_count);
+        assert!(server.requests().iter().all(|request| request.method == "HEAD"));
+    }
+
+    #[tokio::test]
+    async fn test_on_skip() {
+        let server = TestServer::start(|request| response(request, "200 OK", &[("Accept-Ranges", "bytes")], b"hello")).await;
+        let directory = temp_dir("on-skip");
+        std::fs::write(directory.join("file.txt"), "hello").unwrap();
+        let skipped = Arc::new(AtomicUsize::new(0));
+        let counter = skipped.clone();
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .on_skip(move |_, reason| {
+                assert_eq!(&SkipReason::Complete, reason);
+                counter.fetch_add(1, Ordering::SeqCst);
+            })
+            .build();
+
+        let download = Download::try_from(server.url("/file.txt").as_str()).unwrap();
+        let report = downloader.download([download]).await.unwrap();
+        assert_eq!(&Status::Skipped(SkipReason::Complete), report[0].status());
+        assert_eq!(1, skipped.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_on_batch_complete() {
+        let server = TestServer::start(|request| match request.path.as_str() {
+            "/missing.txt" => response(request, "404 Not Found", &[], b""),
+            _ => response(request, "200 OK", &[], b"content"),
+        }).await;
+        let directory = temp_dir("on-batch-complete");
+        let completed = Arc::new(Mutex::new(Vec::new()));
+        let reports = completed.clone();
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .on_batch_complete(move |report| {
+                let written = report.iter().filter(|summary| summary.path().exists()).count();
+                reports.lock().unwrap().push((report.len(), report.failures().count(), written));
+            })
+            .build();
+
+        let downloads = [
+            Download::try_from(server.url("/file.txt").as_str()).unwrap(),
+            Download::try_from(server.url("/missing.txt").as_str()).unwrap(),
+        ];
+        downloader.download(downloads).await.unwrap();
+        assert_eq!(vec![(2, 1, 1)], *completed.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_compute_digests() {
+        let server = TestServer::start(|request| response(request, "200 OK", &[], b"hello world")).await;
+        let directory = temp_dir("compute-digests");
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .compute_digests(&[DigestKind::Sha256, DigestKind::Crc32])
+            .ordered(true)
+            .build();
+
+        let downloads = [
+            Download::try_from(server.url("/valid.txt").as_str()).unwrap()
+                .with_checksum(DigestKind::Sha1, "2aae6c35c94fcfb415dbe95f408b9ce91ee846ed"),
+            Download::try_from(server.url("/corrupt.txt").as_str()).unwrap()
+                .with_checksum(DigestKind::Sha1, "0000000000000000000000000000000000000000"),
+        ];
+        let report = downloader.download(downloads).await.unwrap();
+        assert_eq!(&Status::Success, report[0].status());
+        assert_eq!(Some("0d4a1185"), report[0].digest(DigestKind::Crc32));
+        assert_eq!(3, report[0].digests().len());
+        assert!(matches!(report[1].status(), Status::Fail(_)));
+        assert!(!directory.join("corrupt.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn test_skip_missing() {
+        let server = TestServer::start(|request| match request.path.as_str() {
+            "/gone.txt" => response(request, "410 Gone", &[], b""),
+            _ => response(request, "404 Not Found", &[], b"not found"),
+        }).await;
+        let directory = temp_dir("skip-missing");
+        let mut downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .skip_missing(true)
+            .build();
+        downloader.resume = false;
+
+        let downloads = [
+            Download::try_from(server.url("/missing.txt").as_str()).unwrap(),
+            Download::try_from(server.url("/gone.txt").as_str()).unwrap(),
+        ];
+        let report = downloader.download(downloads).await.unwrap();
+        assert!(report.iter().all(|summary| summary.status() == &Status::Skipped(SkipReason::NotFound)));
+        assert!(server.requests().iter().all(|request| request.method == "HEAD"));
+    }
+
+    #[tokio::test]
+    async fn test_on_progress() {
+        let server = TestServer::start(|request| response(request, "200 OK", &[], &[1; 1000])).await;
+        let directory = temp_dir("on-progress");
+        let written = Arc::new(AtomicUsize::new(0));
+        let finished = Arc::new(AtomicUsize::new(0));
+        let (bytes, done) = (written.clone(), finished.clone());
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .on_progress(move |event| match event {
+                ProgressEvent::Started { total, resumed, .. } => assert_eq!((Some(1000), 0), (*total, *resumed)),
+                ProgressEvent::Progress { bytes: len, .. } => {
+                    bytes.fetch_add(*len as usize, Ordering::SeqCst);
+                }
+                ProgressEvent::Finished { .. } => {
+                    done.fetch_add(1, Ordering::SeqCst);
+                }
+                ProgressEvent::Paused { .. } => unreachable!("no schedule window"),
+            })
+            .build();
+
+        let download = Download::try_from(server.url("/file.bin").as_str()).unwrap();
+        downloader.download([download]).await.unwrap();
+        assert_eq!(1000, written.load(Ordering::SeqCst));
+        assert_eq!(1, finished.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_progress_unknown_size() {
+        let server = TestServer::start(|request| {
+            response(request, "200 OK", &[("Transfer-Encoding", "chunked")], b"5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n")
+        }).await;
+        let directory = temp_dir("progress-unknown-size");
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .on_progress(move |event| match *event {
+                ProgressEvent::Started { total, .. } => recorded.lock().unwrap().push((0, total, event.percent())),
+                ProgressEvent::Progress { downloaded, total, .. } => {
+                    recorded.lock().unwrap().push((downloaded, total, event.percent()));
+                }
+                _ => {}
+            })
+            .build();
+
+        let download = Download::try_from(server.url("/stream.txt").as_str()).unwrap();
+        let report = downloader.download([download]).await.unwrap();
+        assert_eq!(&Status::Success, report[0].status());
+        let events = events.lock().unwrap();
+        assert!(events.len() >= 2);
+        assert!(events.iter().all(|(_, total, percent)| total.is_none() && percent.is_none()));
+        assert!(events.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert_eq!(11, events.last().unwrap().0);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_one() {
+        let server = TestServer::start(|request| response(request, "200 OK", &[], b"content")).await;
+        let directory = temp_dir("cancel-one");
+        let downloader = DownloaderBuilder::new().directory(&directory).ordered(true).build();
+
+        let downloads = [
+            Download::try_from(server.url("/kept.txt").as_str()).unwrap(),
+            Download::try_from(server.url("/removed.txt").as_str()).unwrap(),
+        ];
+        let control = DownloadControl::new();
+        control.cancel_one("removed.txt");
+        let report = downloader.download_controlled(&downloads, &control).await.unwrap();
+        assert_eq!(&Status::Success, report[0].status());
+        assert_eq!(&Status::Fail("cancelled".into()), report[1].status());
+        assert_eq!(directory.join("removed.txt"), report[1].path());
+    }
+
+    #[cfg(feature = "compress")]
+    #[tokio::test]
+    async fn test_compress_output() {
+        let server = TestServer::start(|request| response(request, "200 OK", &[], &[b'a'; 4096])).await;
+        let directory = temp_dir("compress-output");
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .compress_output(crate::downloader::Compression::Gzip)
+            .build();
+
+        let download = Download::try_from(server.url("/file.txt").as_str()).unwrap();
+        let report = downloader.download([download]).await.unwrap();
+        assert_eq!(&Status::Success, report[0].status());
+        let compressed = std::fs::read(directory.join("file.txt.gz")).unwrap();
+        assert_eq!([0x1f, 0x8b], compressed[..2]);
+        assert_eq!(Some(compressed.len() as u64), report[0].compressed_size());
+    }
+
+    #[cfg(feature = "pinning")]
+    #[tokio::test]
+    async fn test_pin_cert_invalid_host() {
+        let downloader = DownloaderBuilder::new().pin_cert("example.com:443", "key").build();
+        let downloads = [Download::try_from("https://example.com/file.txt").unwrap()];
+
+        let err = downloader.download(downloads).await.unwrap_err();
+        assert!(matches!(err, crate::error::Error::InvalidCertPin { .. }));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_symlink_policy() {
+        let server = TestServer::start(|request| response(request, "200 OK", &[], b"content")).await;
+        let directory = temp_dir("symlink-policy");
+        let target = directory.join("target.txt");
+        let output = directory.join("downloads").join("file.txt");
+        std::fs::create_dir_all(output.parent().unwrap()).unwrap();
+        let download = Download::try_from(server.url("/file.txt").as_str()).unwrap();
+
+        let mut statuses = Vec::new();
+        for policy in [SymlinkPolicy::Reject, SymlinkPolicy::Follow, SymlinkPolicy::Replace] {
+            std::fs::write(&target, "target").unwrap();
+            let _ = std::fs::remove_file(&output);
+            std::os::unix::fs::symlink(&target, &output).unwrap();
+            let mut downloader = DownloaderBuilder::new()
+                .directory(output.parent().unwrap())
+                .symlink_policy(policy)
+                .build();
+            downloader.resume = false;
+            let report = downloader.download([download.clone()]).await.unwrap();
+            let is_symlink = output.symlink_metadata().unwrap().file_type().is_symlink();
+            statuses.push((report[0].status().clone(), std::fs::read_to_string(&target).unwrap(), is_symlink));
+        }
+
+        assert!(matches!(statuses[0], (Status::Fail(_), ref target, true) if target == "target"));
+        assert_eq!((Status::Success, "content".to_string(), true), statuses[1]);
+        assert_eq!((Status::Success, "target".to_string(), false), statuses[2]);
+        assert_eq!("content", std::fs::read_to_string(&output).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint() {
+        let server = TestServer::start(|request| match request.path.as_str() {
+            "/done.txt" => response(request, "200 OK", &[], b"content"),
+            _ => response(request, "500 Internal Server Error", &[], b""),
+        }).await;
+        let directory = temp_dir("batch-checkpoint");
+        let path = directory.join("batch.checkpoint");
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .checkpoint(&path)
+            .build();
+
+        let downloads = vec![
+            Download::try_from(server.url("/done.txt").as_str()).unwrap(),
+            Download::try_from(server.url("/broken.txt").as_str()).unwrap(),
+        ];
+        downloader.download(&downloads).await.unwrap();
+        let remaining = crate::checkpoint::load(&path).unwrap();
+        assert_eq!(vec!["broken.txt"], remaining.iter().map(|download| download.filename.as_str()).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_url_credentials() {
+        let server = TestServer::start(|request| response(request, "200 OK", &[], b"content")).await;
+        let directory = temp_dir("url-credentials");
+        let capture = directory.join("capture.jsonl");
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .capture(&capture)
+            .build();
+
+        let url = format!("http://user:p%40ss@{}/file.txt", server.addr);
+        let report = downloader.download([Download::try_from(url.as_str()).unwrap()]).await.unwrap();
+        assert_eq!(&Status::Success, report[0].status());
+        let requests = server.requests();
+        assert!(requests.iter().all(|request| request.header("authorization") == Some("Basic dXNlcjpwQHNz")));
+        let captured = std::fs::read_to_string(&capture).unwrap();
+        assert!(captured.contains(&format!("http://{}/file.txt", server.addr)));
+        assert!(!captured.contains("p%40ss"));
+    }
+
+    #[tokio::test]
+    async fn test_completion_strategy() {
+        struct Manifest;
+
+        #[async_trait]
+        impl CompletionStrategy for Manifest {
+            async fn is_complete(&self, download: &Download, _: &Path, _: Option<&ContentRange>) -> bool {
+                download.filename == "recorded.txt"
+            }
+        }
+
+        let server = TestServer::start(|request| response(request, "200 OK", &[], b"content")).await;
+        let directory = temp_dir("completion-strategy");
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .completion_strategy(Manifest)
+            .ordered(true)
+            .build();
+
+        let downloads = vec![
+            Download::try_from(server.url("/recorded.txt").as_str()).unwrap(),
+            Download::try_from(server.url("/new.txt").as_str()).unwrap(),
+        ];
+        let report = downloader.download(&downloads).await.unwrap();
+        assert_eq!(&Status::Skipped(SkipReason::Complete), report[0].status());
+        assert_eq!(&Status::Success, report[1].status());
+        assert!(server.requests().iter().all(|request| request.method == "HEAD" || request.path == "/new.txt"));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let server = TestServer::start(|request| response(request, "200 OK", &[], &[b'a'; 15_000])).await;
+        let directory = temp_dir("rate-limit");
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .ordered(true)
+            .build();
+
+        let downloads = vec![
+            Download::try_from(server.url("/limited.bin").as_str()).unwrap().with_rate_limit(10_000),
+            Download::try_from(server.url("/unlimited.bin").as_str()).unwrap(),
+        ];
+        let started = Instant::now();
+        let report = downloader.download(&downloads).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(450));
+        assert_eq!((&Status::Success, true), (report[0].status(), report[0].throttled()));
+        assert_eq!((&Status::Success, false), (report[1].status(), report[1].throttled()));
+    }
+
+    #[tokio::test]
+    async fn test_per_host_rate_limit() {
+        let server = TestServer::start(|request| response(request, "200 OK", &[], &[b'a'; 10_000])).await;
+        let directory = temp_dir("per-host-rate-limit");
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .per_host_rate_limit(10_000)
+            .build();
+
+        // Both downloads share the bucket of the host, the second 10 KB wait for a second
+        let downloads = ["/first.bin", "/second.bin"]
+            .map(|path| Download::try_from(server.url(path).as_str()).unwrap());
+        let started = Instant::now();
+        let report = downloader.download(downloads).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(900));
+        assert!(report.all_succeeded());
+        assert!(report.iter().any(|summary| summary.throttled()));
+    }
+
+    #[tokio::test]
+    async fn test_transforms() {
+        let server = TestServer::start(|request| response(request, "200 OK", &[], b"one\r\ntwo\r\n")).await;
+        let directory = temp_dir("transforms");
+        std::fs::write(directory.join("file.txt"), "on").unwrap();
+        let digest = Arc::new(Mutex::new(Vec::new()));
+        let recorded = digest.clone();
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .transform(|_| Box::new(NormalizeNewlines::default()) as Box<dyn StreamTransform>)
+            .transform(move |_| {
+                let recorded = recorded.clone();
+                Box::new(Hashing::new(Box::new(Crc32Digest::default()), move |digest| *recorded.lock().unwrap() = digest))
+                    as Box<dyn StreamTransform>
+            })
+            .build();
+
+        // The partial file is not resumed, the transformed file is written from scratch
+        let download = Download::try_from(server.url("/file.txt").as_str()).unwrap()
+            .with_checksum(DigestKind::Crc32, "e3ca44a0");
+        let report = downloader.download([download]).await.unwrap();
+        assert_eq!(&Status::Success, report[0].status());
+        assert!(!report[0].resume());
+        assert_eq!(8, report[0].size());
+        assert_eq!("one\ntwo\n", std::fs::read_to_string(directory.join("file.txt")).unwrap());
+        let mut expected = Box::new(Crc32Digest::default());
+        expected.update(b"one\ntwo\n");
+        assert_eq!(expected.finalize(), *digest.lock().unwrap());
+    }
+
+    #[cfg(feature = "encrypt")]
+    #[tokio::test]
+    async fn test_encrypt() {
+        use crate::encrypt::{self, Cipher};
+
+        let content: Vec<u8> = (0..=255).cycle().take(200_000).collect();
+        let body = content.clone();
+        let server = TestServer::start(move |request| response(request, "200 OK", &[], &body)).await;
+        let directory = temp_dir("encrypt");
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .encrypt([3; 32], Cipher::ChaCha20Poly1305)
+            .build();
+
+        let report = downloader.download([Download::try_from(server.url("/data.bin").as_str()).unwrap()]).await.unwrap();
+        assert_eq!(&Status::Success, report[0].status());
+        let encrypted = std::fs::read(directory.join("data.bin")).unwrap();
+        assert_ne!(content, encrypted);
+        let mut decrypted = Vec::new();
+        assert_eq!(200_000, encrypt::decrypt_to_writer(&encrypted[..], &mut decrypted, &[3; 32]).unwrap());
+        assert_eq!(content, decrypted);
+    }
+
+    #[tokio::test]
+    async fn test_runtime_handle() {
+        let server = TestServer::start(|request| response(request, "200 OK", &[], b"content")).await;
+        let directory = temp_dir("runtime-handle");
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("trauma-io")
+            .enable_all()
+            .build()
+            .unwrap();
+        let threads = Arc::new(Mutex::new(HashSet::new()));
+        let recorded = threads.clone();
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .runtime_handle(runtime.handle().clone())
+            .on_progress(move |_| {
+                let name = std::thread::current().name().map(str::to_string);
+                recorded.lock().unwrap().insert(name);
+            })
+            .build();
+
+        let downloads = ["/a.txt", "/b.txt"].map(|path| Download::try_from(server.url(path).as_str()).unwrap());
+        let report = downloader.download(downloads).await.unwrap();
+        assert!(report.all_succeeded());
+        assert_eq!(HashSet::from([Some("trauma-io".to_string())]), *threads.lock().unwrap());
+        runtime.shutdown_background();
+    }
+
+    #[tokio::test]
+    async fn test_reject_html_for() {
+        let server = TestServer::start(|request| {
+            response(request, "200 OK", &[("Content-Type", "text/html; charset=utf-8")], b"<html>portal</html>")
+        }).await;
+        let directory = temp_dir("reject-html");
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .reject_html_for(vec![".ZIP", "html"])
+            .ordered(true)
+            .build();
+
+        let downloads = vec![
+            Download::try_from(server.url("/file.zip").as_str()).unwrap(),
+            Download::try_from(server.url("/page.html").as_str()).unwrap(),
+        ];
+        let report = downloader.download(&downloads).await.unwrap();
+        assert_eq!(&Status::Fail("expected binary, got HTML".into()), report[0].status());
+        assert!(!directory.join("file.zip").exists());
+        assert_eq!(&Status::Success, report[1].status());
+    }
+
+    #[tokio::test]
+    async fn test_redirect_policy() {
+        let server = TestServer::start(|request| match request.path.as_str() {
+            "/first.txt" => response(request, "302 Found", &[("Location", "/second.txt")], b""),
+            "/second.txt" => response(request, "302 Found", &[("Location", "/file.txt")], b""),
+            "/file.txt" => response(request, "200 OK", &[], b"content"),
+            _ => {
+                let port = request.header("host").unwrap().rsplit(':').next().unwrap();
+                let location = format!("http://localhost:{}/file.txt", port);
+                response(request, "302 Found", &[("Location", location.as_str())], b"")
+            }
+        }).await;
+        let directory = temp_dir("redirect-policy");
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .redirect_policy(RedirectPolicy::SameHostUnlimited { max_cross_host: 0 })
+            .ordered(true)
+            .build();
+
+        let downloads = vec![
+            Download::try_from(server.url("/first.txt").as_str()).unwrap(),
+            Download::new(url::Url::parse(&server.url("/elsewhere")).unwrap(), "elsewhere.txt".into()),
+        ];
+        let report = downloader.download(&downloads).await.unwrap();
+        assert_eq!(&Status::Success, report[0].status());
+        assert_eq!("content", std::fs::read_to_string(directory.join("first.txt")).unwrap());
+        assert!(matches!(report[1].status(), Status::Fail(message) if message.contains("blocked cross-host redirect")));
+    }
+
+    #[tokio::test]
+    async fn test_if_range() {
+        const DATE: &str = "Wed, 21 Oct 2015 07:28:00 GMT";
+        let server = TestServer::start(|request| {
+            // The resource of `/changed.txt` changes between the probe and the download
+            let (validator, content): (_, &[u8]) = match (request.path.as_str(), request.method.as_str()) {
+                ("/changed.txt", "GET") => (("ETag", "\"v2\""), b"HELLO WORLD"),
+                ("/dated.txt", _) => (("Last-Modified", DATE), b"hello world"),
+                _ => (("ETag", "\"v1\""), b"hello world"),
+            };
+            match request.header("range") {
+                Some("bytes=5-") if request.header("if-range") == Some(validator.1) => {
+                    let headers = [("Accept-Ranges", "bytes"), ("Content-Range", "bytes 5-10/11"), validator];
+                    response(request, "206 Partial Content", &headers, &content[5..])
+                }
+                _ => response(request, "200 OK", &[("Accept-Ranges", "bytes"), validator], content),
+            }
+        }).await;
+        let directory = temp_dir("if-range");
+        for filename in ["unchanged.txt", "changed.txt", "dated.txt"] {
+            std::fs::write(directory.join(filename), "hello").unwrap();
+        }
+        let downloader = DownloaderBuilder::new().directory(&directory).ordered(true).build();
+
+        let downloads = ["/unchanged.txt", "/changed.txt", "/dated.txt"]
+            .map(|path| Download::try_from(server.url(path).as_str()).unwrap());
+        let report = downloader.download(downloads).await.unwrap();
+        assert!(report.all_succeeded());
+
+        // Unchanged, the range continues the file
+        assert_eq!(Some(StatusCode::PARTIAL_CONTENT), report[0].status_code());
+        assert_eq!("hello world", std::fs::read_to_string(directory.join("unchanged.txt")).unwrap());
+        // Changed, the whole new content replaces the partial file
+        assert_eq!(Some(StatusCode::OK), report[1].status_code());
+        assert_eq!("HELLO WORLD", std::fs::read_to_string(directory.join("changed.txt")).unwrap());
+        assert_eq!(&[Diagnostic::RangeIgnored { size_on_disk: 5 }], report[1].diagnostics());
+        // Without ETag the date validates the range
+        assert_eq!(Some(StatusCode::PARTIAL_CONTENT), report[2].status_code());
+        assert_eq!("hello world", std::fs::read_to_string(directory.join("dated.txt")).unwrap());
+        let requests = server.requests();
+        let dated = requests.iter().find(|request| request.path == "/dated.txt" && request.method == "GET").unwrap();
+        assert_eq!(Some(DATE), dated.header("if-range"));
+    }
+
+    #[tokio::test]
+    async fn test_resume_mode() {
+        let server = TestServer::start(|request| {
+            let content = b"hello world";
+            match (request.path.as_str(), request.header("range")) {
+                // Advertises ranges with a strong ETag and honors If-Range
+                ("/cooperative.txt", Some("bytes=5-")) if request.header("if-range") == Some("\"v1\"") => {
+                    let headers = [("Accept-Ranges", "bytes"), ("ETag", "\"v1\""), ("Content-Range", "bytes 5-10/11")];
+                    response(request, "206 Partial Content", &headers, &content[5..])
+                }
+                ("/cooperative.txt", _) => response(request, "200 OK", &[("Accept-Ranges", "bytes"), ("ETag", "\"v1\"")], content),
+                // Honors ranges without advertising them or sending a validator
+                ("/unadvertised.txt", Some("bytes=5-")) => {
+                    response(request, "206 Partial Content", &[("Content-Range", "bytes 5-10/11")], &content[5..])
+                }
+                ("/unadvertised.txt", _) => response(request, "200 OK", &[], content),
+                // Answers every range with the whole resource as a partial response
+                (_, Some(_)) => {
+                    let headers = [("Accept-Ranges", "bytes"), ("ETag", "\"v1\""), ("Content-Range", "bytes 0-10/11")];
+                    response(request, "206 Partial Content", &headers, content)
+                }
+                _ => response(request, "200 OK", &[("Accept-Ranges", "bytes"), ("ETag", "\"v1\"")], content),
+            }
+        }).await;
+
+        let (partial, ok) = (Some(StatusCode::PARTIAL_CONTENT), Some(StatusCode::OK));
+        let cases = [
+            (ResumeMode::Auto, [partial, ok, ok]),
+            (ResumeMode::Always, [partial, partial, ok]),
+            (ResumeMode::Never, [ok, ok, ok]),
+            (ResumeMode::Strict, [partial, ok, ok]),
+        ];
+        for (mode, status_codes) in cases {
+            let directory = temp_dir(&format!("resume-mode-{:?}", mode).to_lowercase());
+            let paths = ["/cooperative.txt", "/unadvertised.txt", "/misplaced.txt"];
+            for path in paths {
+                std::fs::write(directory.join(&path[1..]), "hello").unwrap();
+            }
+            let downloader = DownloaderBuilder::new().directory(&directory).resume_mode(mode).ordered(true).build();
+
+            let downloads = paths.map(|path| Download::try_from(server.url(path).as_str()).unwrap());
+            let report = downloader.download(downloads).await.unwrap();
+            for (summary, status_code) in report.iter().zip(status_codes) {
+                assert_eq!(status_code, summary.status_code(), "{:?} {}", mode, summary.download.url);
+            }
+            for path in paths {
+                assert_eq!("hello world", std::fs::read_to_string(directory.join(&path[1..])).unwrap(), "{:?} {}", mode, path);
+            }
+            // A partial response that does not continue the file is never appended to it
+            if mode != ResumeMode::Never {
+                assert_eq!(&[Diagnostic::RangeMismatch { size_on_disk: 5, start: Some(0) }], report[2].diagnostics());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_redirect_chain() {
+        let server = TestServer::start(|request| {
+            let hop: usize = request.path.trim_start_matches("/hop/").parse().unwrap_or_default();
+            let location = format!("/hop/{}", hop + 1);
+            response(request, "302 Found", &[("Location", location.as_str())], b"")
+        }).await;
+        let directory = temp_dir("redirect-chain");
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .redirect_policy(RedirectPolicy::Limited(2))
+            .build();
+
+        let download = Download::new(url::Url::parse(&server.url("/hop/0")).unwrap(), "hop.txt".into());
+        let report = downloader.download([download]).await.unwrap();
+        assert!(matches!(report[0].status(), Status::Fail(message) if message.contains("too many redirects")));
+        let chain: Vec<_> = (0..4).map(|hop| url::Url::parse(&server.url(&format!("/hop/{}", hop))).unwrap()).collect();
+        let host = chain[0].host_str().unwrap().to_string();
+        assert_eq!(&[Diagnostic::RedirectRefused { chain, host }], report[0].diagnostics());
+    }
+
+    #[tokio::test]
+    async fn test_host_capabilities() {
+        let server = TestServer::start(|request| {
+            response(request, "200 OK", &[("Accept-Ranges", "bytes")], b"content")
+        }).await;
+        let directory = temp_dir("host-capabilities");
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .concurrent_downloads(1)
+            .build();
+
+        let downloads = ["/a.txt", "/b.txt", "/c.txt"]
+            .map(|path| Download::try_from(server.url(path).as_str()).unwrap());
+        let report = downloader.download(downloads).await.unwrap();
+        assert!(report.iter().all(|summary| summary.status() == &Status::Success));
+        let heads = server.requests().iter().filter(|request| request.method == "HEAD").count();
+        assert_eq!(1, heads);
+        assert!(report[0].diagnostics().is_empty());
+        assert!(matches!(&report[2].diagnostics()[0], Diagnostic::HostCapabilities { ranges: true, version, .. }
+            if version == "HTTP/1.1"));
+        assert!(report[2].resume());
+    }
+
+    #[tokio::test]
+    async fn test_batch_timeout() {
+        let server = TestServer::start(|request| response(request, "200 OK", &[], &[b'a'; 20_000])).await;
+        let directory = temp_dir("batch-timeout");
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .batch_timeout(Duration::from_millis(300))
+            .concurrent_downloads(1)
+            .ordered(true)
+            .build();
+
+        let downloads = vec![
+            Download::try_from(server.url("/fast.bin").as_str()).unwrap(),
+            Download::try_from(server.url("/slow.bin").as_str()).unwrap().with_rate_limit(10_000),
+            Download::try_from(server.url("/queued.bin").as_str()).unwrap(),
+        ];
+        let report = downloader.download(&downloads).await.unwrap();
+        assert_eq!(&Status::Success, report[0].status());
+        assert_eq!(&Status::Fail("batch timeout".into()), report[1].status());
+        assert_eq!(&Status::Fail("batch timeout".into()), report[2].status());
+    }
+
+    #[tokio::test]
+    async fn test_response_gate() {
+        let server = TestServer::start(|request| match request.path.as_str() {
+            "/large.bin" => response(request, "200 OK", &[("X-Tier", "premium")], b"content"),
+            _ => response(request, "200 OK", &[], b"content"),
+        }).await;
+        let directory = temp_dir("response-gate");
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .response_gate(|headers, _| match headers.get("x-tier") {
+                Some(tier) if tier == "premium" => Err("premium content".to_string()),
+                _ => Ok(()),
+            })
+            .ordered(true)
+            .build();
+
+        let downloads = vec![
+            Download::try_from(server.url("/large.bin").as_str()).unwrap(),
+            Download::try_from(server.url("/small.bin").as_str()).unwrap(),
+        ];
+        let report = downloader.download(&downloads).await.unwrap();
+        assert_eq!(&Status::Fail("premium content".into()), report[0].status());
+        assert!(!directory.join("large.bin").exists());
+        assert_eq!(&Status::Success, report[1].status());
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_ramp() {
+        let server = TestServer::start(|request| response(request, "200 OK", &[], b"content")).await;
+        let directory = temp_dir("concurrency-ramp");
+        let (clock, mock) = Clock::mock();
+        let mut downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .concurrent_downloads(3)
+            .concurrency_ramp(Duration::from_secs(10))
+            .build();
+        downloader.clock = clock;
+        downloader.resume = false;
+        assert_eq!(1, downloader.concurrency().limit());
+        let adaptive = DownloaderBuilder::from(downloader.clone()).adaptive_concurrency(2, 4).build();
+        assert_eq!(2, adaptive.concurrency().limit());
+
+        let downloads: Vec<_> = (0..3)
+            .map(|i| Download::try_from(server.url(&format!("/file{}.txt", i)).as_str()).unwrap())
+            .collect();
+        let (report, _) = future::join(downloader.download(&downloads), async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            mock.advance(Duration::from_secs(10));
+        }).await;
+        assert_eq!(3, report.successes().count());
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_stats() {
+        let server = TestServer::start(|request| response(request, "200 OK", &[], b"content")).await;
+        let directory = temp_dir("concurrency-stats");
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .concurrent_downloads(1)
+            .build();
+
+        let downloads: Vec<_> = (0..3)
+            .map(|i| Download::try_from(server.url(&format!("/file{}.txt", i)).as_str()).unwrap())
+            .collect();
+        let report = downloader.download(&downloads).await.unwrap();
+        assert_eq!(1, report.concurrency().peak);
+        assert!(report.concurrency().average <= 1.0);
+        assert!(report.iter().all(|summary| summary.concurrency() == 1));
+    }
+
+    #[test]
+    fn test_single_connection() {
+        let downloader = DownloaderBuilder::new().concurrent_downloads(4).build();
+        assert_eq!(4, downloader.concurrency().limit());
+        let downloader = DownloaderBuilder::from(downloader).single_connection(true).build();
+        assert_eq!(100, downloader.concurrency().limit());
+    }
+
+    #[tokio::test]
+    async fn test_redownload_on_checksum_failure() {
+        let gets = AtomicUsize::new(0);
+        let server = TestServer::start(move |request| {
+            // The first download and the first attempt of the second one are corrupt
+            if request.method == "GET" && gets.fetch_add(1, Ordering::SeqCst) < 2 {
+                return response(request, "200 OK", &[], b"hellp world");
+            }
+            response(request, "200 OK", &[], b"hello world")
+        }).await;
+        let directory = temp_dir("redownload-checksum");
+        let download = |path: &str| Download::try_from(server.url(path).as_str()).unwrap()
+            .with_checksum(DigestKind::Sha1, "2aae6c35c94fcfb415dbe95f408b9ce91ee846ed");
+
+        let downloader = DownloaderBuilder::new().directory(&directory).build();
+        let report = downloader.download([download("/first.txt")]).await.unwrap();
+        assert!(matches!(report[0].status(), Status::Fail(message) if message.contains("expected 2aae6c35")));
+
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .redownload_on_checksum_failure(1)
+            .build();
+        let report = downloader.download([download("/second.txt")]).await.unwrap();
+        assert_eq!(&Status::Success, report[0].status());
+        assert_eq!("hello world", std::fs::read_to_string(directory.join("second.txt")).unwrap());
+        let gets = server.requests().iter().filter(|request| request.method == "GET").count();
+        assert_eq!(3, gets);
+    }
+
+    #[tokio::test]
+    async fn test_expect_content_type() {
+        let server = TestServer::start(|request| {
+            response(request, "200 OK", &[("Content-Type", "Application/Zip; charset=binary")], b"PK")
+        }).await;
+        let directory = temp_dir("expect-content-type");
+        let downloader = DownloaderBuilder::new().directory(&directory).ordered(true).build();
+
+        let downloads = [
+            Download::try_from(server.url("/archive.zip").as_str()).unwrap()
+                .expect_content_type("application/zip"),
+            Download::try_from(server.url("/image.png").as_str()).unwrap()
+                .expect_content_type("image/png")
+                .expect_content_type("image/jpeg"),
+        ];
+        let report = downloader.download(downloads).await.unwrap();
+        assert_eq!(&Status::Success, report[0].status());
+        assert_eq!(&Status::Fail("unexpected content type".into()), report[1].status());
+        assert!(!directory.join("image.png").exists());
+    }
+
+    #[tokio::test]
+    async fn test_launch_delay() {
+        let server = TestServer::start(|request| response(request, "200 OK", &[], b"content")).await;
+        let directory = temp_dir("launch-delay");
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .launch_delay(Duration::from_millis(100))
+            .build();
+
+        let downloads = ["/a.txt", "/b.txt", "/c.txt"]
+            .map(|path| Download::try_from(server.url(path).as_str()).unwrap());
+        let started = Instant::now();
+        let report = downloader.download(downloads).await.unwrap();
+        assert!(report.all_succeeded());
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn test_create_new() {
+        let server = TestServer::start(|request| response(request, "200 OK", &[], b"content")).await;
+        let directory = temp_dir("create-new");
+        std::fs::write(directory.join("existing.txt"), "previous content").unwrap();
+        let mut downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .create_new(true)
+            .ordered(true)
+            .build();
+        downloader.resume = false;
+
+        let downloads = ["/existing.txt", "/fresh.txt"]
+            .map(|path| Download::try_from(server.url(path).as_str()).unwrap());
+        let report = downloader.download(&downloads).await.unwrap();
+        let message = format!("{} already exists", directory.join("existing.txt").display());
+        assert_eq!(&Status::Fail(message), report[0].status());
+        assert_eq!("previous content", std::fs::read_to_string(directory.join("existing.txt")).unwrap());
+        assert_eq!(&Status::Success, report[1].status());
+        assert_eq!("content", std::fs::read_to_string(directory.join("fresh.txt")).unwrap());
+
+        // Without truncation the tail of the previous content is kept
+        let downloader = DownloaderBuilder::from(downloader).create_new(false).no_truncate(true).build();
+        let report = downloader.download(&downloads[..1]).await.unwrap();
+        assert!(report.all_succeeded());
+        assert_eq!("contents content", std::fs::read_to_string(directory.join("existing.txt")).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_preallocate() {
+        let content: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let body = content.clone();
+        let server = TestServer::start(move |request| {
+            match request.header("range").and_then(|range| range.strip_prefix("bytes=")?.strip_suffix('-')) {
+                Some(start) => {
+                    let start: usize = start.parse().unwrap();
+                    let content_range = format!("bytes {}-{}/{}", start, body.len() - 1, body.len());
+                    let headers = [("Accept-Ranges", "bytes"), ("Content-Range", content_range.as_str())];
+                    response(request, "206 Partial Content", &headers, &body[start..])
+                }
+                None => response(request, "200 OK", &[("Accept-Ranges", "bytes")], &body),
+            }
+        }).await;
+        let directory = temp_dir("preallocate");
+        std::fs::write(directory.join("resumed.bin"), &content[..400]).unwrap();
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .preallocate(true)
+            .ordered(true)
+            .build();
+
+        let downloads = ["/fresh.bin", "/resumed.bin"]
+            .map(|path| Download::try_from(server.url(path).as_str()).unwrap());
+        let report = downloader.download(downloads).await.unwrap();
+        assert!(report.all_succeeded());
+        assert!(report[1].resume());
+        assert_eq!(content, std::fs::read(directory.join("fresh.bin")).unwrap());
+        assert_eq!(content, std::fs::read(directory.join("resumed.bin")).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_preallocate_timed_out() {
+        let server = TestServer::start(|request| response(request, "200 OK", &[], &[b'a'; 1_000_000])).await;
+        let directory = temp_dir("preallocate-timed-out");
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .preallocate(true)
+            .write_buffer_size(1024)
+            .batch_timeout(Duration::from_millis(300))
+            .build();
+
+        let download = Download::try_from(server.url("/slow.bin").as_str()).unwrap().with_rate_limit(50_000);
+        let report = downloader.download([download]).await.unwrap();
+        assert_eq!(&Status::Fail("batch timeout".into()), report[0].status());
+        // The dropped download is trimmed to its written bytes in the background instead of removed
+        let path = directory.join("slow.bin");
+        for _ in 0..100 {
+            if std::fs::metadata(&path).unwrap().len() < 1_000_000 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let content = std::fs::read(&path).unwrap();
+        assert!(!content.is_empty() && content.len() < 1_000_000);
+        assert!(content.iter().all(|byte| *byte == b'a'));
+    }
+
+    #[tokio::test]
+    async fn test_mock_clock_schedule_window() {
+        let server = TestServer::start(|request| response(request, "200 OK", &[], b"content")).await;
+        let directory = temp_dir("mock-clock-schedule");
+        let (clock, mock) = Clock::mock();
+        let hour = (clock.system_time().duration_since(UNIX_EPOCH).unwrap().as_secs() / 3600 % 24) as u8;
+        let mut downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .schedule_window((hour + 1) % 24, (hour + 2) % 24)
+            .build();
+        downloader.clock = clock;
+
+        let download = Download::try_from(server.url("/file.txt").as_str()).unwrap();
+        let (report, _) = future::join(downloader.download([download]), async {
+            // The download waits for the window without any request
+            tokio::task::yield_now().await;
+            assert!(server.requests().is_empty());
+            mock.advance(Duration::from_secs(3600));
+        }).await;
+        assert!(report.unwrap().all_succeeded());
+    }
+
+    #[tokio::test]
+    async fn test_download_bytes_capped() {
+        let server = TestServer::start(|request| match request.path.as_str() {
+            "/missing.bin" => response(request, "404 Not Found", &[], b""),
+            _ => response(request, "200 OK", &[], &[b'a'; 100_000]),
+        }).await;
+        let downloader = DownloaderBuilder::new().build();
+
+        let download = Download::try_from(server.url("/file.bin").as_str()).unwrap();
+        let (head, truncated) = downloader.download_bytes_capped(&download, 16).await.unwrap();
+        assert_eq!((vec![b'a'; 16], true), (head, truncated));
+        let (body, truncated) = downloader.download_bytes_capped(&download, 100_000).await.unwrap();
+        assert_eq!((100_000, false), (body.len(), truncated));
+
+        let missing = Download::try_from(server.url("/missing.bin").as_str()).unwrap();
+        assert!(downloader.download_bytes_capped(&missing, 16).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_suffix_range() {
+        let body: Vec<u8> = (0..100).collect();
+        let content = body.clone();
+        let server = TestServer::start(move |request| {
+            let range = request.header("range").and_then(|range| range.strip_prefix("bytes="))
+                .and_then(|range| range.split_once('-'));
+            let (start, end) = match range {
+                // Only the first path honors suffix ranges
+                Some(("", length)) if request.path == "/suffix.bin" => (body.len() - length.parse::<usize>().unwrap(), body.len() - 1),
+                Some((start, end)) if !start.is_empty() => (start.parse().unwrap(), end.parse().unwrap()),
+                _ => return response(request, "200 OK", &[], &body),
+            };
+            let content_range = format!("bytes {}-{}/{}", start, end, body.len());
+            response(request, "206 Partial Content", &[("Content-Range", content_range.as_str())], &body[start..=end])
+        }).await;
+        let directory = temp_dir("suffix-range");
+        let downloader = DownloaderBuilder::new().directory(&directory).ordered(true).build();
+
+        let downloads = ["/suffix.bin", "/explicit.bin"]
+            .map(|path| Download::try_from(server.url(path).as_str()).unwrap().with_suffix_range(10));
+        let report = downloader.download(downloads).await.unwrap();
+        for (summary, filename) in report.iter().zip(["suffix.bin", "explicit.bin"]) {
+            assert_eq!(&Status::Success, summary.status());
+            assert_eq!(Some(ByteRange::new(90, 99)), summary.range());
+            assert_eq!(content[90..], std::fs::read(directory.join(filename)).unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resumed_progress() {
+        let body = vec![7; 1000];
+        let server = TestServer::start(move |request| {
+            match request.header("range").and_then(|range| range.strip_prefix("bytes=")?.strip_suffix('-')) {
+                Some(start) => {
+                    let start: usize = start.parse().unwrap();
+                    let content_range = format!("bytes {}-{}/{}", start, body.len() - 1, body.len());
+                    let headers = [("Accept-Ranges", "bytes"), ("Content-Range", content_range.as_str())];
+                    response(request, "206 Partial Content", &headers, &body[start..])
+                }
+                None => response(request, "200 OK", &[("Accept-Ranges", "bytes")], &body),
+            }
+        }).await;
+        let directory = temp_dir("resumed-progress");
+        std::fs::write(directory.join("half.bin"), [7; 600]).unwrap();
+        let started = Arc::new(Mutex::new(None));
+        let events = started.clone();
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .on_progress(move |event| if let ProgressEvent::Started { total, resumed, .. } = event {
+                *events.lock().unwrap() = Some((*total, *resumed));
+            })
+            .build();
+
+        let download = Download::try_from(server.url("/half.bin").as_str()).unwrap();
+        let report = downloader.download([download]).await.unwrap();
+        assert_eq!(&Status::Success, report[0].status());
+        assert_eq!(Some((Some(1000), 600)), *started.lock().unwrap());
+        assert_eq!(1000, report[0].size());
+        assert_eq!(1000, std::fs::metadata(directory.join("half.bin")).unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_retry_disabled() {
+        let server = TestServer::start(|request| response(request, "503 Service Unavailable", &[], b"")).await;
+        let directory = temp_dir("retry-disabled");
+        let mut downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .retries(3)
+            .retry(false)
+            .build();
+        downloader.resume = false;
+
+        let download = Download::try_from(server.url("/file.txt").as_str()).unwrap();
+        let report = downloader.download([download]).await.unwrap();
+        assert!(matches!(report[0].status(), Status::Fail(_)));
+        assert_eq!((1, 1), (report[0].attempts(), server.requests().len()));
+    }
+
+    #[tokio::test]
+    async fn test_download_to_writer() {
+        let server = TestServer::start(|request| match request.path.as_str() {
+            "/missing.txt" => response(request, "404 Not Found", &[], b""),
+            _ => response(request, "200 OK", &[], b"piped content"),
+        }).await;
+        let directory = temp_dir("download-to-writer");
+        let downloader = DownloaderBuilder::new().directory(&directory).build();
+
+        let mut output = Vec::new();
+        let download = Download::try_from(server.url("/file.txt").as_str()).unwrap();
+        let summary = downloader.download_to_writer(&download, &mut output).await.unwrap();
+        assert_eq!((&Status::Success, 13), (summary.status(), summary.size()));
+        assert_eq!(b"piped content", &output[..]);
+        assert!(!directory.join("file.txt").exists());
+
+        let missing = Download::try_from(server.url("/missing.txt").as_str()).unwrap();
+        let summary = downloader.download_to_writer(&missing, &mut output).await.unwrap();
+        assert_eq!(Some(StatusCode::NOT_FOUND), summary.status_code());
+        assert_eq!(13, output.len());
+    }
+
+    #[tokio::test]
+    async fn test_on_redirect() {
+        let server = TestServer::start(|request| match request.path.as_str() {
+            "/start.txt" => response(request, "302 Found", &[("Location", "/blocked.txt")], b""),
+            "/allowed.txt" => response(request, "200 OK", &[], b"rewritten"),
+            _ => response(request, "403 Forbidden", &[], b""),
+        }).await;
+        let directory = temp_dir("on-redirect");
+        let mut downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .on_redirect(|target| {
+                let mut target = target.clone();
+                (target.path() == "/blocked.txt").then(|| {
+                    target.set_path("/allowed.txt");
+                    target
+                })
+            })
+            .build();
+        downloader.resume = false;
+
+        let download = Download::try_from(server.url("/start.txt").as_str()).unwrap();
+        let report = downloader.download([download]).await.unwrap();
+        assert_eq!(&Status::Success, report[0].status());
+        assert_eq!("rewritten", std::fs::read_to_string(directory.join("start.txt")).unwrap());
+        let paths: Vec<_> = server.requests().into_iter().map(|request| request.path).collect();
+        assert_eq!(vec!["/start.txt", "/allowed.txt"], paths);
+    }
+
+    #[tokio::test]
+    async fn test_diagnostics() {
+        let server = TestServer::start(|request| {
+            response(request, "200 OK", &[("Accept-Ranges", "bytes"), ("ETag", "W/\"v1\"")], b"content")
+        }).await;
+        let directory = temp_dir("diagnostics");
+        std::fs::write(directory.join("file.txt"), "con").unwrap();
+        let downloader = DownloaderBuilder::new().directory(&directory).build();
+
+        let download = Download::try_from(server.url("/file.txt").as_str()).unwrap().with_expected_size(100);
+        let report = downloader.download([download]).await.unwrap();
+        assert_eq!(&Status::Success, report[0].status());
+        assert_eq!(&[
+            Diagnostic::WeakEtag { etag: "W/\"v1\"".into() },
+            Diagnostic::SizeMismatch { reported: 7, expected: 100 },
+        ], report[0].diagnostics());
+    }
+
+    #[tokio::test]
+    async fn test_not_modified() {
+        let server = TestServer::start(|request| match request.header("if-none-match") {
+            Some("\"v1\"") => response(request, "304 Not Modified", &[("ETag", "\"v1\"")], b""),
+            _ => response(request, "200 OK", &[("ETag", "\"v2\"")], b"new content"),
+        }).await;
+        let directory = temp_dir("not-modified");
+        let path = directory.join("file.txt");
+        std::fs::write(&path, "old").unwrap();
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+        let mut downloader = DownloaderBuilder::new().directory(&directory).build();
+        downloader.resume = false;
+
+        let download = Download::try_from(server.url("/file.txt").as_str()).unwrap().with_if_none_match("\"v1\"");
+        let report = downloader.download([download]).await.unwrap();
+        assert_eq!(&Status::Skipped(SkipReason::NotModified), report[0].status());
+        assert_eq!(3, report[0].size());
+        assert_eq!("old", std::fs::read_to_string(&path).unwrap());
+        assert_eq!(modified, std::fs::metadata(&path).unwrap().modified().unwrap());
+
+        let download = Download::try_from(server.url("/file.txt").as_str()).unwrap().with_if_none_match("\"v0\"");
+        let report = downloader.download([download]).await.unwrap();
+        assert_eq!((&Status::Success, Some("\"v2\"")), (report[0].status(), report[0].etag()));
+        assert_eq!("new content", std::fs::read_to_string(&path).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_expect_size() {
+        let server = TestServer::start(|request| response(request, "200 OK", &[], &[b'a'; 100])).await;
+        let directory = temp_dir("expect-size");
+        let downloader = DownloaderBuilder::new().directory(&directory).build();
+
+        let close = Download::try_from(server.url("/close.bin").as_str()).unwrap().expect_size(98, 2);
+        let off = Download::try_from(server.url("/off.bin").as_str()).unwrap().expect_size(90, 5);
+        let report = downloader.download(&[close, off]).await.unwrap();
+        assert_eq!(&Status::Success, report[0].status());
+        assert!(matches!(report[1].status(), Status::Fail(message) if message.contains("expected 90 bytes")));
+        assert!(!directory.join("off.bin").exists());
+    }
+
+    #[tokio::test]
+    async fn test_download_pairs() {
+        let server = TestServer::start(|request| response(request, "200 OK", &[], request.path.as_bytes())).await;
+        let directory = temp_dir("download-pairs");
+        let downloader = DownloaderBuilder::new().directory(directory.join("global")).build();
+
+        let pairs = [
+            (url::Url::parse(&server.url("/a.bin")).unwrap(), directory.join("first/one.bin")),
+            (url::Url::parse(&server.url("/b.bin")).unwrap(), directory.join("second/two.bin")),
+        ];
+        let report = downloader.download_pairs(pairs).await.unwrap();
+        assert!(report.all_succeeded());
+        assert_eq!("/a.bin", std::fs::read_to_string(directory.join("first/one.bin")).unwrap());
+        assert_eq!("/b.bin", std::fs::read_to_string(directory.join("second/two.bin")).unwrap());
+        assert_eq!(directory.join("first/one.bin"), report[0].path());
+        assert!(!directory.join("global").exists());
+    }
+
+    #[tokio::test]
+    async fn test_download_bytes_resume() {
+        let server = TestServer::start(|request| {
+            let content = b"hello world";
+            match request.header("range").and_then(|range| range.strip_prefix("bytes=")) {
+                Some("11-") => response(request, "416 Range Not Satisfiable", &[("Content-Range", "bytes */11")], b""),
+                Some(range) => {
+                    let start: usize = range.trim_end_matches('-').parse().unwrap();
+                    let content_range = format!("bytes {}-10/11", start);
+                    response(request, "206 Partial Content", &[("Content-Range", content_range.as_str())], &content[start..])
+                }
+                None => response(request, "200 OK", &[], content),
+            }
+        }).await;
+        let downloader = DownloaderBuilder::new().build();
+        let download = Download::try_from(server.url("/file.txt").as_str()).unwrap();
+
+        assert_eq!(b"hello world", &downloader.download_bytes_resume(&download, b"hello".to_vec()).await.unwrap()[..]);
+        assert_eq!(Some("bytes=5-"), server.requests()[0].header("range"));
+        assert_eq!(b"hello world", &downloader.download_bytes_resume(&download, Vec::new()).await.unwrap()[..]);
+        assert_eq!(b"hello world", &downloader.download_bytes_resume(&download, b"hello world".to_vec()).await.unwrap()[..]);
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_identical_urls() {
+        let server = TestServer::start(|request| response(request, "200 OK", &[], b"shared")).await;
+        let directory = temp_dir("coalesce-identical-urls");
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .coalesce_identical_urls(true)
+            .build();
+
+        let url = server.url("/file.txt");
+        let downloads = [
+            Download::try_from(url.as_str()).unwrap(),
+            Download::try_from(url.as_str()).unwrap().with_directory(directory.join("other")),
+            Download::new(url::Url::parse(&url).unwrap(), "renamed.txt".into()),
+        ];
+        let report = downloader.download(downloads).await.unwrap();
+        assert_eq!(&Status::Success, report[0].status());
+        assert_eq!((&Status::Skipped(SkipReason::Cached), 6), (report[1].status(), report[1].size()));
+        assert_eq!("shared", std::fs::read_to_string(directory.join("other/file.txt")).unwrap());
+        assert_eq!("shared", std::fs::read_to_string(directory.join("renamed.txt")).unwrap());
+        assert_eq!(1, server.requests().iter().filter(|request| request.method == "GET").count());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[tokio::test]
+    async fn test_decompress_zstd() {
+        use async_compression::tokio::write::ZstdEncoder;
+        use tokio::io::AsyncWriteExt;
+
+        let content = b"hello zstd ".repeat(100);
+        let mut encoder = ZstdEncoder::new(Vec::new());
+        encoder.write_all(&content).await.unwrap();
+        encoder.shutdown().await.unwrap();
+        let encoded = encoder.into_inner();
+        let server = TestServer::start(move |request| match request.path.as_str() {
+            "/encoded.txt" => response(request, "200 OK", &[("Content-Encoding", "zstd")], &encoded),
+            _ => response(request, "200 OK", &[], &encoded),
+        }).await;
+        let directory = temp_dir("decompress-zstd");
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .decompress_zstd(true)
+            .build();
+
+        let downloads = [
+            Download::try_from(server.url("/encoded.txt").as_str()).unwrap(),
+            Download::try_from(server.url("/archive.txt.zst").as_str()).unwrap(),
+        ];
+        let report = downloader.download(downloads).await.unwrap();
+        assert!(report.all_succeeded());
+        assert_eq!(content.len() as u64, report[0].size());
+        assert_eq!(content, std::fs::read(directory.join("encoded.txt")).unwrap());
+        assert_eq!(content, std::fs::read(directory.join("archive.txt")).unwrap());
+        assert_eq!(Some("zstd"), server.requests().last().unwrap().header("accept-encoding"));
+    }
+
+    #[tokio::test]
+    async fn test_prepare() {
+        let server = TestServer::start(|request| response(request, "200 OK", &[], b"content")).await;
+        let directory = temp_dir("prepare");
+        let downloader = DownloaderBuilder::new().directory(&directory).build();
+
+        let downloads = [
+            Download::new(url::Url::parse(&server.url("/a")).unwrap(), "one/two/a.txt".into()),
+            Download::new(url::Url::parse(&server.url("/b")).unwrap(), "one/b.txt".into()),
+        ];
+        downloader.prepare(&downloads).unwrap();
+        assert!(directory.join("one/two").is_dir());
+        assert!(server.requests().is_empty());
+        assert!(downloader.download(&downloads).await.unwrap().all_succeeded());
+        assert_eq!("content", std::fs::read_to_string(directory.join("one/two/a.txt")).unwrap());
+
+        // A file in the way of a directory fails before anything is downloaded
+        std::fs::write(directory.join("blocked"), "").unwrap();
+        let blocked = [Download::new(url::Url::parse(&server.url("/c")).unwrap(), "blocked/c.txt".into())];
+        assert!(downloader.prepare(blocked).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_max_retry_duration() {
+        let server = TestServer::start(|request| {
+            std::thread::sleep(Duration::from_millis(150));
+            response(request, "503 Service Unavailable", &[], b"")
+        }).await;
+        let directory = temp_dir("max-retry-duration");
+        let mut downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .retries(5)
+            .max_retry_duration(Duration::from_millis(100))
+            .build();
+        downloader.resume = false;
+
+        let download = Download::try_from(server.url("/file.txt").as_str()).unwrap();
+        let report = downloader.download([download]).await.unwrap();
+        assert!(matches!(report[0].status(), Status::Fail(_)));
+        assert_eq!((1, 1), (report[0].attempts(), server.requests().len()));
+    }
+
+    #[tokio::test]
+    async fn test_order_by() {
+        let server = TestServer::start(|request| response(request, "200 OK", &[], b"content")).await;
+        let directory = temp_dir("order-by");
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .concurrent_downloads(1)
+            .order_by(crate::order::by_filename)
+            .build();
+
+        let downloads = [
+            Download::try_from(server.url("/c.txt").as_str()).unwrap(),
+            Download::try_from(server.url("/a.txt").as_str()).unwrap(),
+            Download::try_from(server.url("/b.txt").as_str()).unwrap(),
+        ];
+        let report = downloader.download(downloads).await.unwrap();
+        assert!(report.all_succeeded());
+        let fetched: Vec<_> = server.requests().into_iter()
+            .filter(|request| request.method == "GET")
+            .map(|request| request.path)
+            .collect();
+        assert_eq!(vec!["/a.txt", "/b.txt", "/c.txt"], fetched);
+    }
+
+    #[tokio::test]
+    async fn test_write_report() {
+        let server = TestServer::start(|request| match request.path.as_str() {
+            "/missing.txt" => response(request, "404 Not Found", &[], b""),
+            _ => response(request, "200 OK", &[], b"content"),
+        }).await;
+        let directory = temp_dir("write-report");
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .write_report(directory.join("report.json"))
+            .build();
+
+        let downloads = [
+            Download::try_from(server.url("/file.txt").as_str()).unwrap(),
+            Download::try_from(server.url("/missing.txt").as_str()).unwrap(),
+        ];
+        downloader.download(downloads).await.unwrap();
+        let report: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(directory.join("report.json")).unwrap()).unwrap();
+        assert_eq!(("file.txt", "success"), (report[0]["filename"].as_str().unwrap(), report[0]["status"].as_str().unwrap()));
+        assert_eq!("failed", report[1]["status"].as_str().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_status_messages() {
+        struct Terse;
+        impl StatusMessages for Terse {
+            fn skip_reason(&self, _: &SkipReason) -> String {
+                "skipped!".into()
+            }
+        }
+
+        let server = TestServer::start(|request| response(request, "200 OK", &[("Accept-Ranges", "bytes")], b"content")).await;
+        let directory = temp_dir("status-messages");
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("file.txt"), b"content").unwrap();
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .write_report(directory.join("report.json"))
+            .status_messages(Terse)
+            .build();
+
+        let report = downloader.download([Download::try_from(server.url("/file.txt").as_str()).unwrap()]).await.unwrap();
+        assert_eq!(&Status::Skipped(SkipReason::Complete), report[0].status());
+        let written: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(directory.join("report.json")).unwrap()).unwrap();
+        assert_eq!(("skipped", "skipped!"), (written[0]["status"].as_str().unwrap(), written[0]["reason"].as_str().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_output_is_directory() {
+        let server = TestServer::start(|request| response(request, "200 OK", &[], b"content")).await;
+        let directory = temp_dir("output-is-directory");
+        std::fs::create_dir_all(directory.join("file.txt")).unwrap();
+        let downloader = DownloaderBuilder::new().directory(&directory).build();
+
+        let download = Download::try_from(server.url("/file.txt").as_str()).unwrap();
+        let report = downloader.download([download]).await.unwrap();
+        assert!(matches!(report[0].status(), Status::Fail(message) if message.contains("is a directory")));
+        assert!(directory.join("file.txt").is_dir());
+    }
+
+    #[tokio::test]
+    async fn test_digest_check() {
+        let server = TestServer::start(|request| response(request, "200 OK", &[], b"hello world")).await;
+        let directory = temp_dir("digest-check");
+        let downloader = DownloaderBuilder::new().directory(&directory).build();
+
+        let crc32 = || Box::new(Crc32Digest::default()) as Box<dyn DigestUpdate>;
+        let downloads = [
+            Download::new(url::Url::parse(&server.url("/matching.txt")).unwrap(), "matching.txt".into())
+                .with_digest_check(crc32, [0x0d, 0x4a, 0x11, 0x85]),
+            Download::new(url::Url::parse(&server.url("/mismatching.txt")).unwrap(), "mismatching.txt".into())
+                .with_digest_check(crc32, [0; 4]),
+        ];
+        let report = downloader.download(downloads).await.unwrap();
+        assert_eq!(&Status::Success, report[0].status());
+        assert!(matches!(report[1].status(), Status::Fail(message) if message.contains("digest mismatch")));
+        assert!(!directory.join("mismatching.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn test_accept_encoding() {
+        let server = TestServer::start(|request| response(request, "200 OK", &[("Accept-Ranges", "bytes")], b"content")).await;
+        let directory = temp_dir("accept-encoding");
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("resumed.txt"), b"con").unwrap();
+        let identity = DownloaderBuilder::new().directory(&directory).accept_encoding(vec![]).build();
+        let compressed = DownloaderBuilder::new()
+            .directory(&directory)
+            .accept_encoding(vec![Encoding::Gzip, Encoding::Brotli])
+            .build();
+
+        identity.download([Download::try_from(server.url("/file.txt").as_str()).unwrap()]).await.unwrap();
+        let report = compressed.download([Download::try_from(server.url("/resumed.txt").as_str()).unwrap()]).await.unwrap();
+        let encodings: Vec<_> = server.requests().into_iter()
+            .filter(|request| request.method == "GET")
+            .map(|request| request.header("accept-encoding").map(str::to_string))
+            .collect();
+        assert_eq!(vec![Some("identity".to_string()), Some("gzip, br".to_string())], encodings);
+        assert!(report[0].diagnostics().contains(&Diagnostic::CompressedResume { size_on_disk: 3 }));
+    }
+
+    #[tokio::test]
+    async fn test_split_storage() {
+        let body = b"hello chunked world".to_vec();
+        let server = TestServer::start(move |request| {
+            match request.header("range").and_then(|range| range.strip_prefix("bytes=")?.strip_suffix('-')) {
+                Some(start) => {
+                    let start: usize = start.parse().unwrap();
+                    let content_range = format!("bytes {}-{}/{}", start, body.len() - 1, body.len());
+                    let headers = [("Accept-Ranges", "bytes"), ("Content-Range", content_range.as_str())];
+                    response(request, "206 Partial Content", &headers, &body[start..])
+                }
+                None => response(request, "200 OK", &[("Accept-Ranges", "bytes")], &body),
+            }
+        }).await;
+        let directory = temp_dir("split-storage");
+        std::fs::write(directory.join("file.txt.part0"), b"hello").unwrap();
+        std::fs::write(directory.join("file.txt.part1"), b" ch").unwrap();
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .split_storage(5)
+            .compute_digests(&[DigestKind::Crc32])
+            .build();
+
+        let download = Download::try_from(server.url("/file.txt").as_str()).unwrap();
+        let report = downloader.download([download]).await.unwrap();
+        assert_eq!(&Status::Success, report[0].status());
+        assert_eq!(19, report[0].size());
+        assert_eq!(Some("bytes=8-"), server.requests().last().unwrap().header("range"));
+        assert_eq!("hello chunked world", std::fs::read_to_string(directory.join("file.txt")).unwrap());
+        assert!(!directory.join("file.txt.part0").exists());
+        assert!(report[0].digest(DigestKind::Crc32).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_allowed_schemes() {
+        let directory = temp_dir("allowed-schemes").join("downloads");
+        let downloader = DownloaderBuilder::new().directory(&directory).build();
+
+        let download = Download::try_from("file:///etc/passwd").unwrap();
+        let report = downloader.download([download.clone()]).await.unwrap();
+        assert_eq!(&Status::Fail("Disallowed url scheme: file".into()), report[0].status());
+        assert_eq!(0, report[0].attempts());
+        assert!(!directory.exists());
+        let err = downloader.download_bytes_capped(&download, 16).await.unwrap_err();
+        assert!(matches!(err, Error::DisallowedScheme { scheme, .. } if scheme == "file"));
+        // A unix socket of a local daemon is not reachable from an untrusted list by default
+        let socket = Download::try_from("unix:///var/run/docker.sock:/containers/json").unwrap();
+        let report = downloader.download([socket]).await.unwrap();
+        assert_eq!(&Status::Fail("Disallowed url scheme: unix".into()), report[0].status());
+        assert_eq!(0, report[0].attempts());
+
+        let server = TestServer::start(|request| response(request, "200 OK", &[], b"content")).await;
+        let https_only = DownloaderBuilder::new().directory(&directory).allowed_schemes(vec!["HTTPS".into()]).build();
+        let report = https_only.download([Download::try_from(server.url("/file.txt").as_str()).unwrap()]).await.unwrap();
+        assert!(matches!(report[0].status(), Status::Fail(message) if message.contains("http")));
+        assert!(server.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_file_url() {
+        let source = temp_dir("file-url-source");
+        let content: Vec<u8> = (0..=255).cycle().take(100_000).collect();
+        std::fs::write(source.join("local.bin"), &content).unwrap();
+        let directory = temp_dir("file-url");
+        std::fs::write(directory.join("resumed.bin"), &content[..1000]).unwrap();
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .allowed_schemes(vec!["file".into()])
+            .ordered(true)
+            .build();
+
+        let url = Url::from_file_path(source.join("local.bin")).unwrap();
+        let downloads = [
+            Download::new(url.clone(), "local.bin".into()),
+            Download::new(url, "resumed.bin".into()),
+            Download::new(Url::from_directory_path(&source).unwrap(), "directory.bin".into()),
+            Download::new(Url::from_file_path(source.join("missing.bin")).unwrap(), "missing.bin".into()),
+        ];
+        let report = downloader.download(&downloads).await.unwrap();
+        assert_eq!((&Status::Success, 100_000, false), (report[0].status(), report[0].size(), report[0].resume()));
+        assert_eq!((&Status::Success, true), (report[1].status(), report[1].resume()));
+        assert_eq!(content, std::fs::read(directory.join("local.bin")).unwrap());
+        assert_eq!(content, std::fs::read(directory.join("resumed.bin")).unwrap());
+        assert!(matches!(report[2].status(), Status::Fail(message) if message.ends_with("is not a regular file")));
+        assert!(matches!(report[3].status(), Status::Fail(message) if message.contains("missing.bin")));
+
+        let report = downloader.download(&downloads[..1]).await.unwrap();
+        assert_eq!(&Status::Skipped(SkipReason::Complete), report[0].status());
+    }
+
+    #[tokio::test]
+    async fn test_ttfb() {
+        let server = TestServer::start(|request| response(request, "200 OK", &[("Accept-Ranges", "bytes")], b"content")).await;
+        let directory = temp_dir("ttfb");
+        std::fs::write(directory.join("complete.txt"), b"content").unwrap();
+        let downloader = DownloaderBuilder::new().directory(&directory).ordered(true).build();
+
+        let downloads = ["/file.txt", "/complete.txt"]
+            .map(|path| Download::try_from(server.url(path).as_str()).unwrap());
+        let report = downloader.download(downloads).await.unwrap();
+        assert_eq!(&Status::Success, report[0].status());
+        assert!(report[0].ttfb().is_some_and(|ttfb| ttfb <= report[0].elapsed()));
+        assert_eq!(&Status::Skipped(SkipReason::Complete), report[1].status());
+        assert_eq!(None, report[1].ttfb());
+    }
+
+    #[tokio::test]
+    async fn test_retry_failed() {
+        let attempts = AtomicUsize::new(0);
+        let server = TestServer::start(move |request| match request.path.as_str() {
+            "/flaky.txt" if attempts.fetch_add(1, Ordering::SeqCst) == 0 => response(request, "500 Internal Server Error", &[], b""),
+            _ => response(request, "200 OK", &[], b"content"),
+        }).await;
+        let directory = temp_dir("retry-failed");
+        let mut downloader = DownloaderBuilder::new().directory(&directory).ordered(true).build();
+        downloader.resume = false;
+
+        let downloads = [
+            Download::try_from(server.url("/file.txt").as_str()).unwrap(),
+            Download::try_from(server.url("/flaky.txt").as_str()).unwrap()
+                .with_checksum(DigestKind::Crc32, "fec530a9"),
+        ];
+        let report = downloader.download(downloads).await.unwrap();
+        assert_eq!(1, report.failures().count());
+
+        let retried = downloader.retry_failed(&report).await.unwrap();
+        assert_eq!(1, retried.len());
+        assert_eq!(("flaky.txt", &Status::Success), (retried[0].download().filename.as_str(), retried[0].status()));
+        assert_eq!(Some((DigestKind::Crc32, "fec530a9")), retried[0].download().checksum());
+        assert!(downloader.retry_failed(&retried).await.unwrap().is_empty());
+    }
+
+    /// Download from a server failing the first request with `status`, return the number of requests
+    async fn retried(status: &'static str, retryable: Vec<StatusCode>) -> usize {
+        let attempts = AtomicUsize::new(0);
+        let server = TestServer::start(move |request| match attempts.fetch_add(1, Ordering::SeqCst) {
+            0 => response(request, status, &[], b""),
+            _ => response(request, "200 OK", &[], b"content"),
+        }).await;
+        let directory = temp_dir(&format!("retryable-status-{}", &status[..3]));
+        let mut downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .retries(1)
+            .retryable_status(retryable)
+            .build();
+        downloader.resume = false;
+
+        let download = Download::try_from(server.url("/file.txt").as_str()).unwrap();
+        downloader.download([download]).await.unwrap();
+        server.requests().len()
+    }
+
+    #[tokio::test]
+    async fn test_retryable_status() {
+        assert_eq!(2, retried("503 Service Unavailable", vec![StatusCode::SERVICE_UNAVAILABLE]).await);
+        assert_eq!(1, retried("408 Request Timeout", vec![StatusCode::SERVICE_UNAVAILABLE]).await);
+    }
+
+    #[tokio::test]
+    async fn test_attempts() {
+        let attempts = AtomicUsize::new(0);
+        let server = TestServer::start(move |request| match attempts.fetch_add(1, Ordering::SeqCst) {
+            0 => response(request, "503 Service Unavailable", &[], b""),
+            _ => response(request, "200 OK", &[], b"content"),
+        }).await;
+        let directory = temp_dir("attempts");
+        let mut downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .retries(2)
+            .build();
+        downloader.resume = false;
+
+        let download = Download::try_from(server.url("/file.txt").as_str()).unwrap();
+        let report = downloader.download([download.clone()]).await.unwrap();
+        assert_eq!((&Status::Success, 2), (report[0].status(), report[0].attempts()));
+        let report = downloader.download([download]).await.unwrap();
+        assert_eq!(1, report[0].attempts());
+    }
+
+    const BENCH_DOWNLOADS: usize = 10_000;
+
+    /// The requests of a batch of small downloads and the common headers of the batch
+    fn bench_batch() -> (Vec<Url>, HeaderMap) {
+        let urls = (0..BENCH_DOWNLOADS)
+            .map(|index| Url::parse(&format!("http://domain.com/{}.txt", index)).unwrap())
+            .collect();
+        let headers = (0..16)
+            .map(|index| (HeaderName::try_from(format!("x-common-{}", index)).unwrap(), HeaderValue::from_static("value")))
+            .collect();
+        (urls, headers)
+    }
+
+    #[bench]
+    fn bench_headers_per_request(b: &mut Bencher) {
+        let (urls, headers) = bench_batch();
+        let client = reqwest::Client::new();
+        b.iter(|| for url in &urls {
+            test::black_box(client.get(url.clone()).headers(headers.clone()).build().unwrap());
+        });
+    }
+
+    #[bench]
+    fn bench_headers_on_client(b: &mut Bencher) {
+        let (urls, headers) = bench_batch();
+        let downloader = DownloaderBuilder::new().headers(headers).build();
+        let client = downloader.client_builder().unwrap().build().unwrap();
+        b.iter(|| for url in &urls {
+            test::black_box(client.get(url.clone()).build().unwrap());
+        });
+    }
+
+    const BENCH_BODY: &[u8] = &[7; 16 * 1024];
+
+    /// Download a batch of small files from a single host, multiplexed over one HTTP/2
+    /// connection or through the default pool of HTTP/1.1 connections
+    fn bench_connections(b: &mut Bencher, single_connection: bool) {
+        let runtime = Runtime::new().unwrap();
+        let addr = runtime.block_on(async {
+            if single_connection {
+                start_h2(BENCH_BODY).await
+            } else {
+                TestServer::start(|request| response(request, "200 OK", &[], BENCH_BODY)).await.addr
+            }
+        });
+        let directory = temp_dir(&format!("bench-connections-{}", single_connection));
+        let mut downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .single_connection(single_connection)
+            .build();
+        // Every iteration downloads the files again instead of finding them complete
+        downloader.resume = false;
+        let downloads: Vec<_> = (0..200)
+            .map(|index| Download::try_from(format!("http://{}/{}.bin", addr, index).as_str()).unwrap())
+            .collect();
+        b.bytes = (downloads.len() * BENCH_BODY.len()) as u64;
+        b.iter(|| {
+            let report = runtime.block_on(downloader.download(&downloads)).unwrap();
+            assert!(report.all_succeeded());
+        });
+    }
+
+    #[bench]
+    fn bench_single_connection(b: &mut Bencher) {
+        bench_connections(b, true);
+    }
+
+    #[bench]
+    fn bench_connection_pool(b: &mut Bencher) {
+        bench_connections(b, false);
     }
 }
@@ -0,0 +1,247 @@
+//! Encryption at rest of the downloaded files with a supplied key
+//!
+//! With `DownloaderBuilder::encrypt` the content is encrypted while it is written, so the
+//! plaintext never reaches the disk. [`decrypt_to_writer`] reads such a file back.
+//!
+//! # File format
+//!
+//! The file starts with a 20 bytes header: the magic `TTRAUMA` and the format version `1`, the
+//! cipher (`1` for AES-256-GCM, `2` for ChaCha20-Poly1305), the size of the plaintext segments
+//! as a big-endian `u32` and a random 7 bytes nonce prefix. The content follows as segments of
+//! that size, the last one shorter and possibly empty, each encrypted with a 16 bytes tag
+//! appended. The nonce of a segment is the prefix, its big-endian `u32` index and a byte set to
+//! `1` for the last segment only, and the header is authenticated with every segment, so a
+//! reordered, truncated or extended file fails to decrypt.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::fs::File;
+//!
+//! use tokio_trauma::encrypt::{self, Cipher};
+//!
+//! # fn run(key: [u8; 32]) -> std::io::Result<()> {
+//! let encrypted = File::open("secrets.tar.enc")?;
+//! let plaintext = File::create("secrets.tar")?;
+//! encrypt::decrypt_to_writer(encrypted, plaintext, &key)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::io::{self, Read, Write};
+
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, CHACHA20_POLY1305, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::transform::StreamTransform;
+
+const MAGIC: &[u8; 8] = b"TTRAUMA1";
+const HEADER_LEN: usize = MAGIC.len() + 1 + 4 + PREFIX_LEN;
+const PREFIX_LEN: usize = 7;
+const TAG_LEN: usize = 16;
+/// Plaintext bytes of a segment
+const SEGMENT_SIZE: usize = 64 * 1024;
+
+/// The authenticated encryption algorithm of a file
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Cipher {
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl Cipher {
+    fn id(self) -> u8 {
+        match self {
+            Cipher::Aes256Gcm => 1,
+            Cipher::ChaCha20Poly1305 => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(Cipher::Aes256Gcm),
+            2 => Some(Cipher::ChaCha20Poly1305),
+            _ => None,
+        }
+    }
+
+    fn key(self, key: &[u8; 32]) -> LessSafeKey {
+        let algorithm = match self {
+            Cipher::Aes256Gcm => &AES_256_GCM,
+            Cipher::ChaCha20Poly1305 => &CHACHA20_POLY1305,
+        };
+        LessSafeKey::new(UnboundKey::new(algorithm, key).expect("both ciphers take 32 bytes keys"))
+    }
+}
+
+/// Encrypts the content into the format of the module documentation
+///
+/// Registered with `DownloaderBuilder::encrypt`, or as a transform of its own to encrypt the
+/// output of other transforms.
+pub struct Encryptor {
+    key: LessSafeKey,
+    header: Option<[u8; HEADER_LEN]>,
+    cipher: Cipher,
+    segment_size: usize,
+    /// index of the next segment
+    index: u32,
+    /// plaintext of the segment being filled
+    buffer: Vec<u8>,
+}
+
+impl Encryptor {
+    pub fn new(key: &[u8; 32], cipher: Cipher) -> Self {
+        Self::with_segment_size(key, cipher, SEGMENT_SIZE)
+    }
+
+    fn with_segment_size(key: &[u8; 32], cipher: Cipher, segment_size: usize) -> Self {
+        Self { key: cipher.key(key), header: None, cipher, segment_size, index: 0, buffer: Vec::new() }
+    }
+
+    /// The header, written before the first segment, with a fresh nonce prefix
+    fn start(&mut self, output: &mut Vec<u8>) -> io::Result<[u8; HEADER_LEN]> {
+        if let Some(header) = self.header {
+            return Ok(header);
+        }
+        let mut header = [0; HEADER_LEN];
+        header[..MAGIC.len()].copy_from_slice(MAGIC);
+        header[MAGIC.len()] = self.cipher.id();
+        header[MAGIC.len() + 1..MAGIC.len() + 5].copy_from_slice(&(self.segment_size as u32).to_be_bytes());
+        SystemRandom::new().fill(&mut header[HEADER_LEN - PREFIX_LEN..])
+            .map_err(|_| io::Error::other("failed to generate a nonce"))?;
+        output.extend_from_slice(&header);
+        self.header = Some(header);
+        Ok(header)
+    }
+
+    /// Encrypt the first `len` buffered bytes as the next segment
+    fn seal(&mut self, header: &[u8; HEADER_LEN], len: usize, last: bool, output: &mut Vec<u8>) -> io::Result<()> {
+        let mut segment: Vec<u8> = self.buffer.drain(..len).collect();
+        let nonce = nonce(header, self.index, last);
+        self.key.seal_in_place_append_tag(nonce, Aad::from(&header[..]), &mut segment)
+            .map_err(|_| io::Error::other("failed to encrypt a segment"))?;
+        self.index = self.index.checked_add(1).ok_or_else(|| io::Error::other("too many segments to encrypt"))?;
+        output.extend(segment);
+        Ok(())
+    }
+}
+
+impl StreamTransform for Encryptor {
+    fn transform(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        let header = self.start(&mut output)?;
+        self.buffer.extend_from_slice(chunk);
+        // The last segment is only known once the content ended, a full segment is kept until then
+        while self.buffer.len() > self.segment_size {
+            self.seal(&header, self.segment_size, false, &mut output)?;
+        }
+        Ok(output)
+    }
+
+    fn finish(&mut self) -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        let header = self.start(&mut output)?;
+        self.seal(&header, self.buffer.len(), true, &mut output)?;
+        Ok(output)
+    }
+}
+
+fn nonce(header: &[u8; HEADER_LEN], index: u32, last: bool) -> Nonce {
+    let mut nonce = [0; NONCE_LEN];
+    nonce[..PREFIX_LEN].copy_from_slice(&header[HEADER_LEN - PREFIX_LEN..]);
+    nonce[PREFIX_LEN..PREFIX_LEN + 4].copy_from_slice(&index.to_be_bytes());
+    nonce[NONCE_LEN - 1] = last as u8;
+    Nonce::assume_unique_for_key(nonce)
+}
+
+/// Decrypt a file encrypted with `key` from `reader` into `writer`, returning the plaintext size
+///
+/// Fails with `InvalidData` on a file that is not in the format or was not encrypted with `key`,
+/// or that was altered. The plaintext of the segments before the failing one is already written
+/// then, it must be discarded.
+pub fn decrypt_to_writer(mut reader: impl Read, mut writer: impl Write, key: &[u8; 32]) -> io::Result<u64> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+    let mut header = [0; HEADER_LEN];
+    reader.read_exact(&mut header).map_err(|_| invalid("missing the encryption header"))?;
+    if &header[..MAGIC.len()] != MAGIC {
+        return Err(invalid("not an encrypted file"));
+    }
+    let cipher = Cipher::from_id(header[MAGIC.len()]).ok_or_else(|| invalid("unknown cipher"))?;
+    let mut size = [0; 4];
+    size.copy_from_slice(&header[MAGIC.len() + 1..MAGIC.len() + 5]);
+    let segment_len = u32::from_be_bytes(size) as usize + TAG_LEN;
+    let key = cipher.key(key);
+
+    let mut written = 0;
+    let mut segment = read_segment(&mut reader, segment_len)?;
+    for index in 0.. {
+        // A segment is the last one when nothing follows it
+        let next = if segment.len() == segment_len { read_segment(&mut reader, segment_len)? } else { Vec::new() };
+        let last = next.is_empty();
+        let plaintext = key.open_in_place(nonce(&header, index, last), Aad::from(&header[..]), &mut segment)
+            .map_err(|_| invalid("the file was altered or not encrypted with this key"))?;
+        writer.write_all(plaintext)?;
+        written += plaintext.len() as u64;
+        if last {
+            break;
+        }
+        segment = next;
+    }
+    writer.flush()?;
+    Ok(written)
+}
+
+/// Read up to `len` bytes, fewer only at the end of `reader`
+fn read_segment(reader: &mut impl Read, len: usize) -> io::Result<Vec<u8>> {
+    let mut segment = Vec::with_capacity(len);
+    reader.take(len as u64).read_to_end(&mut segment)?;
+    Ok(segment)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use crate::encrypt::{decrypt_to_writer, Cipher, Encryptor, HEADER_LEN, TAG_LEN};
+    use crate::transform::StreamTransform;
+
+    fn encrypt(content: &[u8], cipher: Cipher, segment_size: usize) -> Vec<u8> {
+        let mut encryptor = Encryptor::with_segment_size(&[7; 32], cipher, segment_size);
+        let mut encrypted = Vec::new();
+        for chunk in content.chunks(3) {
+            encrypted.extend(encryptor.transform(chunk).unwrap());
+        }
+        encrypted.extend(encryptor.finish().unwrap());
+        encrypted
+    }
+
+    fn decrypt(encrypted: &[u8], key: &[u8; 32]) -> io::Result<Vec<u8>> {
+        let mut plaintext = Vec::new();
+        decrypt_to_writer(encrypted, &mut plaintext, key)?;
+        Ok(plaintext)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for cipher in [Cipher::Aes256Gcm, Cipher::ChaCha20Poly1305] {
+            for content in [&b""[..], b"1234", b"12345678", b"some content to encrypt"] {
+                let encrypted = encrypt(content, cipher, 4);
+                let segments = content.len() / 4 + usize::from(content.is_empty() || content.len() % 4 != 0);
+                assert_eq!(HEADER_LEN + content.len() + segments * TAG_LEN, encrypted.len());
+                assert_eq!(content, decrypt(&encrypted, &[7; 32]).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn test_altered() {
+        let encrypted = encrypt(b"some content to encrypt", Cipher::Aes256Gcm, 4);
+        assert!(decrypt(&encrypted, &[8; 32]).is_err());
+        // A file truncated at a segment boundary lacks its last segment
+        assert!(decrypt(&encrypted[..HEADER_LEN + 2 * (4 + TAG_LEN)], &[7; 32]).is_err());
+        let mut flipped = encrypted.clone();
+        flipped[HEADER_LEN + 1] ^= 1;
+        assert!(decrypt(&flipped, &[7; 32]).is_err());
+        assert!(decrypt(b"plaintext", &[7; 32]).is_err());
+    }
+}
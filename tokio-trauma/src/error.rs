@@ -1,3 +1,5 @@
+use std::io;
+use std::path::PathBuf;
 use std::string::FromUtf8Error;
 
 use snafu::{Location, Snafu};
@@ -39,4 +41,117 @@ pub enum Error {
         #[snafu(source)]
         error: reqwest::Error,
     },
+
+    #[snafu(display("IO error on path: {}", path.display()))]
+    Io {
+        path: PathBuf,
+        location: Location,
+        #[snafu(source)]
+        error: io::Error,
+    },
+
+    /// the download queue no longer accepts downloads
+    #[snafu(display("The download queue is closed"))]
+    QueueClosed {
+        location: Location,
+    },
+
+    /// some downloads of the batch did not complete
+    #[snafu(display("{} downloads of the batch did not complete", count))]
+    IncompleteBatch {
+        count: usize,
+        location: Location,
+    },
+
+    /// the url scheme is not supported on this platform
+    #[snafu(display("Unsupported url scheme: {}", scheme))]
+    UnsupportedScheme {
+        scheme: String,
+        location: Location,
+    },
+
+    /// the url scheme is not in the allowed schemes of the downloader
+    #[snafu(display("Disallowed url scheme: {}", scheme))]
+    DisallowedScheme {
+        scheme: String,
+        location: Location,
+    },
+
+    /// a tar entry needs its size before its content
+    #[snafu(display("The size of the tar entry {} is unknown", filename))]
+    UnknownEntrySize {
+        filename: String,
+        location: Location,
+    },
+
+    /// the checkpoint file was not written by a batch
+    #[snafu(display("Invalid checkpoint {}: {}", path.display(), message))]
+    InvalidCheckpoint {
+        path: PathBuf,
+        message: String,
+        location: Location,
+    },
+
+    /// downloads of the batch failed, with the filename and reason of each failure
+    #[snafu(display("{} downloads of the batch failed: {}", failures.len(), first_failures(failures)))]
+    BatchFailed {
+        failures: Vec<(String, String)>,
+        location: Location,
+    },
+
+    /// the certificate of a host has none of the keys pinned for it
+    #[snafu(display("Certificate pin mismatch for host {}", host))]
+    CertPinMismatch {
+        host: String,
+        location: Location,
+    },
+
+    /// a host given to `pin_cert` can't be pinned
+    #[snafu(display("Invalid certificate pin for host {}: {}", host, message))]
+    InvalidCertPin {
+        host: String,
+        message: String,
+        location: Location,
+    },
+
+    /// the batch was aborted by a second Ctrl-C, see `DownloaderBuilder::install_signal_handler`
+    #[snafu(display("The batch was aborted by a second interrupt"))]
+    Interrupted {
+        location: Location,
+    },
+
+    /// the output path of a download is an existing directory
+    #[snafu(display("The output path {} is a directory", path.display()))]
+    OutputIsDirectory {
+        path: PathBuf,
+        location: Location,
+    },
+
+    /// the block manifest of a delta download can't be parsed
+    #[snafu(display("Invalid block manifest: {}", message))]
+    InvalidBlockManifest {
+        message: String,
+        location: Location,
+    },
+
+    /// the request of a download was not answered with its content
+    #[snafu(display("Request to {} failed: {}", url, message))]
+    RequestFailed {
+        url: String,
+        message: String,
+        location: Location,
+    },
+}
+
+/// The failures shown in the display of `Error::BatchFailed`
+const SHOWN_FAILURES: usize = 3;
+
+fn first_failures(failures: &[(String, String)]) -> String {
+    let mut shown: Vec<_> = failures.iter().take(SHOWN_FAILURES)
+        .map(|(filename, reason)| format!("{} ({})", filename, reason))
+        .collect();
+    if failures.len() > SHOWN_FAILURES {
+        shown.push(format!("and {} more", failures.len() - SHOWN_FAILURES));
+    }
+    shown.join(", ")
 }
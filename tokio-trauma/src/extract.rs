@@ -0,0 +1,54 @@
+//! Extraction of downloaded zip archives
+//!
+//! The central directory of a zip archive is stored at its end, so entries are extracted
+//! once the archive is fully written to disk. Stored and deflate entries are supported.
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Whether the file name designates a zip archive
+pub(crate) fn is_zip(filename: &str) -> bool {
+    Path::new(filename).extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("zip"))
+}
+
+/// Extract every entry of the archive into `directory` and return the extracted file paths
+///
+/// Entries whose names would escape `directory` are skipped.
+pub(crate) fn extract_zip(archive: &Path, directory: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut archive = zip::ZipArchive::new(File::open(archive)?)?;
+    let mut extracted = Vec::with_capacity(archive.len());
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index)?;
+        let Some(path) = entry.enclosed_name().map(|name| directory.join(name)) else {
+            tracing::warn!("Skipping zip entry with unsafe name: {}", entry.name());
+            continue;
+        };
+
+        if entry.is_dir() {
+            fs::create_dir_all(&path)?;
+            continue;
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut output = File::create(&path)?;
+        io::copy(&mut entry, &mut output)?;
+        extracted.push(path);
+    }
+    Ok(extracted)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::extract::is_zip;
+
+    #[test]
+    fn test_is_zip() {
+        assert!(is_zip("archive.zip"));
+        assert!(is_zip("ARCHIVE.ZIP"));
+        assert!(!is_zip("archive.tar.gz"));
+        assert!(!is_zip("zip"));
+    }
+}
@@ -0,0 +1,78 @@
+//! Moving a completed download to its final path, also across filesystems
+//!
+//! A rename fails with `EXDEV` when both paths are on different filesystems, the file is then
+//! copied next to the target and renamed there. The copy continues from a previous interrupted
+//! copy, so a large file is not copied again from the start.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// Move `source` to `target`, copying it when they are on different filesystems
+pub(crate) fn move_file(source: &Path, target: &Path) -> io::Result<()> {
+    match fs::rename(source, target) {
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+            tracing::debug!("Moving {:?} to {:?} across filesystems", source, target);
+            move_across(source, target)
+        }
+        result => result,
+    }
+}
+
+/// Copy `source` into a file next to `target`, rename it to `target` and remove `source`
+fn move_across(source: &Path, target: &Path) -> io::Result<()> {
+    let copying = copying_path(target);
+    let mut reader = File::open(source)?;
+    let mut writer = OpenOptions::new().create(true).append(true).open(&copying)?;
+    // An interrupted copy of the same source is a prefix of it, a longer one is from another file
+    let copied = writer.metadata()?.len();
+    if copied > reader.metadata()?.len() {
+        writer.set_len(0)?;
+    } else {
+        reader.seek(SeekFrom::Start(copied))?;
+    }
+    io::copy(&mut reader, &mut writer)?;
+    writer.sync_data()?;
+    drop(writer);
+
+    fs::rename(&copying, target)?;
+    fs::remove_file(source)
+}
+
+fn copying_path(target: &Path) -> PathBuf {
+    let mut copying = target.as_os_str().to_owned();
+    copying.push(".moving");
+    PathBuf::from(copying)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::finalize::{copying_path, move_across, move_file};
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn test_move_file() {
+        let directory = temp_dir("move-file");
+        let source = directory.join("source.txt");
+        fs::write(&source, "content").unwrap();
+        move_file(&source, &directory.join("target.txt")).unwrap();
+        assert!(!source.exists());
+        assert_eq!("content", fs::read_to_string(directory.join("target.txt")).unwrap());
+    }
+
+    #[test]
+    fn test_move_across() {
+        let directory = temp_dir("move-across");
+        let source = directory.join("source.txt");
+        let target = directory.join("target.txt");
+        fs::write(&source, "some content").unwrap();
+        // Continue an interrupted copy
+        fs::write(copying_path(&target), "some").unwrap();
+        move_across(&source, &target).unwrap();
+        assert!(!source.exists());
+        assert!(!copying_path(&target).exists());
+        assert_eq!("some content", fs::read_to_string(&target).unwrap());
+    }
+}
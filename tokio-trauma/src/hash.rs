@@ -0,0 +1,84 @@
+//! Digests of any algorithm to verify downloads with, see `Download::with_digest_check`
+//!
+//! The content is fed to the digest while it is written, like the built-in checksums, so the
+//! crate doesn't need to depend on the hash crate of every algorithm. `Sha256Digest` and
+//! `Crc32Digest` show how to implement `DigestUpdate` over a hash crate.
+//!
+//! ```
+//! use tokio_trauma::download::Download;
+//! use tokio_trauma::hash::{DigestUpdate, Sha256Digest};
+//!
+//! # fn run(expected: Vec<u8>) -> tokio_trauma::error::Result<()> {
+//! let download = Download::try_from("https://example.com/file.iso")?
+//!     .with_digest_check(|| Box::new(Sha256Digest::default()) as Box<dyn DigestUpdate>, expected);
+//! # Ok(())
+//! # }
+//! ```
+
+use sha2::{Digest, Sha256};
+
+use crate::shared::Shared;
+
+/// Incremental digest of the content of a download
+pub trait DigestUpdate: Send {
+    fn update(&mut self, data: &[u8]);
+
+    /// The digest of all the data fed
+    fn finalize(self: Box<Self>) -> Vec<u8>;
+}
+
+/// Create a digest for every download attempt
+pub(crate) type DigestFactory = dyn Fn() -> Box<dyn DigestUpdate> + Send + Sync;
+
+/// Expected digest of a download, with the digest computing it
+#[derive(Debug, Clone)]
+pub(crate) struct DigestCheck {
+    pub(crate) digest: Shared<DigestFactory>,
+    pub(crate) expected: Vec<u8>,
+}
+
+/// SHA-256 with the `sha2` crate
+#[derive(Default)]
+pub struct Sha256Digest(Sha256);
+
+impl DigestUpdate for Sha256Digest {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        self.0.finalize().to_vec()
+    }
+}
+
+/// CRC-32 with the `crc32fast` crate, big-endian
+#[derive(Default)]
+pub struct Crc32Digest(crc32fast::Hasher);
+
+impl DigestUpdate for Crc32Digest {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        self.0.finalize().to_be_bytes().to_vec()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::digest::hex;
+    use crate::hash::{Crc32Digest, DigestUpdate, Sha256Digest};
+
+    #[test]
+    fn test_digest_update() {
+        let mut sha256: Box<dyn DigestUpdate> = Box::new(Sha256Digest::default());
+        let mut crc32: Box<dyn DigestUpdate> = Box::new(Crc32Digest::default());
+        for data in [&b"hello "[..], b"world"] {
+            sha256.update(data);
+            crc32.update(data);
+        }
+        assert_eq!("b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9", hex(&sha256.finalize()));
+        assert_eq!("0d4a1185", hex(&crc32.finalize()));
+    }
+}
@@ -0,0 +1,81 @@
+//! Normalized hosts, so host based rules match however the host is spelled
+//!
+//! Hosts are compared in their lowercase ASCII form: internationalized names are converted
+//! to punycode, the trailing dot of a fully qualified name is dropped and default ports are
+//! stripped, so `Example.COM`, `example.com.` and `http://example.com:80` all are `example.com`
+//! while `Bücher.example` is `xn--bcher-kva.example`.
+
+use url::{Host, Url};
+
+/// The normalized host of a url
+pub(crate) fn url_host(url: &Url) -> Option<String> {
+    match url.host()? {
+        Host::Domain(domain) => Some(normalize_domain(domain)),
+        Host::Ipv4(addr) => Some(addr.to_string()),
+        Host::Ipv6(addr) => Some(format!("[{}]", addr)),
+    }
+}
+
+/// Normalize a host given by the user, such as `Bücher.example` or `files.example.com:8080`
+///
+/// A port given with the host is kept.
+pub(crate) fn normalize(host: &str) -> Option<String> {
+    let host = host.trim();
+    let (name, port) = split_port(host);
+    let name = name.strip_prefix('[').and_then(|name| name.strip_suffix(']')).unwrap_or(name);
+    let name = match Host::parse(name).ok()? {
+        Host::Domain(domain) => normalize_domain(&domain),
+        Host::Ipv4(addr) => addr.to_string(),
+        Host::Ipv6(addr) => format!("[{}]", addr),
+    };
+    match port {
+        Some(port) => Some(format!("{}:{}", name, port)),
+        None => Some(name),
+    }
+}
+
+/// Split `host:port`, a bare IPv6 address has no port unless it is bracketed
+fn split_port(host: &str) -> (&str, Option<u16>) {
+    match host.rsplit_once(':') {
+        Some((name, port)) if name.ends_with(']') || !name.contains(':') => match port.parse() {
+            Ok(port) => (name, Some(port)),
+            Err(_) => (host, None),
+        },
+        _ => (host, None),
+    }
+}
+
+fn normalize_domain(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod test {
+    use url::Url;
+
+    use crate::host::{normalize, url_host};
+
+    fn host(url: &str) -> Option<String> {
+        url_host(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn test_url_host() {
+        assert_eq!(Some("example.com".into()), host("http://Example.COM/file.zip"));
+        assert_eq!(Some("example.com".into()), host("https://example.com.:443/file.zip"));
+        assert_eq!(Some("xn--bcher-kva.example".into()), host("http://Bücher.example/file.zip"));
+        assert_eq!(Some("xn--bcher-kva.example".into()), host("http://xn--BCHER-kva.example/file.zip"));
+        assert_eq!(Some("example.com".into()), host("http://EXAMPLE.com:8080/file.zip"));
+        assert_eq!(Some("[::1]".into()), host("http://[::1]:8080/file.zip"));
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(Some("example.com".into()), normalize("Example.COM"));
+        assert_eq!(Some("xn--bcher-kva.example".into()), normalize("bücher.example"));
+        assert_eq!(Some("example.com:8080".into()), normalize("example.com:8080"));
+        assert_eq!(Some("[::1]:8080".into()), normalize("[::1]:8080"));
+        assert_eq!(Some("[::1]".into()), normalize("::1"));
+        assert_eq!(None, normalize("exa mple.com"));
+    }
+}
@@ -19,12 +19,65 @@
 //!     let downloader = DownloaderBuilder::new()
 //!         .directory(PathBuf::from("E:\\data"))
 //!         .build();
-//!     let summary = downloader.download(&vec![download]).await;
+//!     let report = downloader.download(&vec![download]).await.unwrap();
+//!     println!("{} bytes downloaded, all succeeded: {}", report.total_bytes(), report.all_succeeded());
 //! }
 //! ```
 
 #![feature(core_intrinsics)]
+#![cfg_attr(test, feature(test))]
 
+mod attempts;
+mod buffer;
+#[cfg(feature = "progress")]
+mod bars;
+mod cache;
+mod capabilities;
+mod capture;
+pub mod checkpoint;
+mod chunked;
+mod clock;
+pub mod completion;
+mod concat;
+pub mod control;
+#[cfg(feature = "delta")]
+pub mod delta;
+mod digest;
+pub mod diagnostic;
 pub mod download;
+#[cfg(feature = "encrypt")]
+pub mod encrypt;
 pub mod error;
-pub mod downloader;
\ No newline at end of file
+pub mod downloader;
+pub mod progress;
+pub mod queue;
+pub mod report;
+#[cfg(feature = "zip")]
+mod extract;
+mod finalize;
+pub mod hash;
+mod host;
+pub mod order;
+#[cfg(feature = "otel")]
+mod otel;
+mod pagination;
+#[cfg(feature = "pinning")]
+mod pinning;
+mod positioned;
+mod redirect;
+mod schedule;
+mod segmented;
+#[cfg(feature = "serde")]
+mod serialize;
+mod shared;
+#[cfg(feature = "minisign")]
+pub mod signature;
+#[cfg(feature = "signal")]
+mod signal;
+#[cfg(feature = "tar")]
+mod tar;
+mod template;
+mod throttle;
+pub mod transform;
+#[cfg(test)]
+mod testing;
\ No newline at end of file
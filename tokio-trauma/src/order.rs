@@ -0,0 +1,56 @@
+//! Comparators ordering the downloads of a batch, see `DownloaderBuilder::order_by`
+//!
+//! ```
+//! use tokio_trauma::downloader::DownloaderBuilder;
+//! use tokio_trauma::order;
+//!
+//! // Shortest job first by the expected sizes
+//! let downloader = DownloaderBuilder::new().order_by(order::by_size).build();
+//! ```
+
+use std::cmp::Ordering;
+
+use crate::download::Download;
+
+/// Smallest expected size first, downloads of unknown size last
+pub fn by_size(a: &Download, b: &Download) -> Ordering {
+    match (a.expected_size(), b.expected_size()) {
+        (Some(a), Some(b)) => a.cmp(&b),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
+
+/// Grouped by host, in alphabetical order
+pub fn by_host(a: &Download, b: &Download) -> Ordering {
+    a.url.host_str().cmp(&b.url.host_str())
+}
+
+/// Alphabetical order of the filenames
+pub fn by_filename(a: &Download, b: &Download) -> Ordering {
+    a.filename.cmp(&b.filename)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::download::Download;
+    use crate::order::{by_filename, by_host, by_size};
+
+    #[test]
+    fn test_order() {
+        let mut downloads = vec![
+            Download::try_from("http://b.com/unknown.bin").unwrap(),
+            Download::try_from("http://b.com/large.bin").unwrap().with_expected_size(100),
+            Download::try_from("http://a.com/small.bin").unwrap().with_expected_size(10),
+        ];
+        downloads.sort_by(by_size);
+        let filenames: Vec<_> = downloads.iter().map(|download| download.filename.as_str()).collect();
+        assert_eq!(vec!["small.bin", "large.bin", "unknown.bin"], filenames);
+
+        downloads.sort_by(by_filename);
+        assert_eq!("large.bin", downloads[0].filename);
+        downloads.sort_by(by_host);
+        assert_eq!("small.bin", downloads[0].filename);
+    }
+}
@@ -0,0 +1,57 @@
+//! Spans of the batches and downloads following the OpenTelemetry semantic conventions
+//!
+//! The spans are `tracing` spans whose fields are named after the OpenTelemetry HTTP
+//! conventions, `tracing-opentelemetry` exports them as they are. A download span nests in the
+//! span of its batch and the request spans of the tracing middleware nest in their download span.
+
+use tracing::field::Empty;
+use tracing::Span;
+
+use crate::download::{Download, Status, Summary};
+use crate::report::DownloadReport;
+
+/// The span of a batch of `downloads` downloads
+pub(crate) fn batch_span(downloads: usize) -> Span {
+    tracing::info_span!("download_batch",
+        otel.name = "download batch",
+        otel.kind = "internal",
+        otel.status_code = Empty,
+        download.count = downloads,
+        download.bytes = Empty,
+        download.failures = Empty,
+    )
+}
+
+/// Record the outcome of a batch in its span
+pub(crate) fn record_batch(span: &Span, report: &DownloadReport) {
+    let failures = report.failures().count();
+    span.record("download.bytes", report.total_bytes());
+    span.record("download.failures", failures);
+    span.record("otel.status_code", if failures == 0 { "OK" } else { "ERROR" });
+}
+
+/// The span of a download, a client span of its `GET` request
+pub(crate) fn download_span(download: &Download) -> Span {
+    tracing::info_span!("download",
+        otel.name = "GET",
+        otel.kind = "client",
+        otel.status_code = Empty,
+        http.method = "GET",
+        http.url = %download.redacted_url(),
+        http.status_code = Empty,
+        download.filename = %download.filename,
+        download.bytes = Empty,
+        download.resume = Empty,
+    )
+}
+
+/// Record the outcome of a download in its span
+pub(crate) fn record(span: &Span, summary: &Summary) {
+    if let Some(status) = summary.status_code() {
+        span.record("http.status_code", status.as_u16());
+    }
+    span.record("download.bytes", summary.size());
+    span.record("download.resume", summary.resume());
+    let failed = matches!(summary.status(), Status::Fail(_));
+    span.record("otel.status_code", if failed { "ERROR" } else { "OK" });
+}
@@ -0,0 +1,215 @@
+//! Pinning the certificate public key of hosts
+//!
+//! reqwest has no pinning of its own, so the client is given a rustls configuration whose
+//! verifier first verifies the certificate chain against the webpki roots like the default
+//! verifier, then rejects a pinned host whose leaf certificate has none of the pinned keys.
+//! A pin is the SHA-256 of the DER `SubjectPublicKeyInfo` of the certificate, in base64 like
+//! the `pin-sha256` of HTTP Public Key Pinning or in hex. This needs the rustls backend of
+//! reqwest, enabled by the `pinning` feature, and replaces the TLS configuration of the client.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, OtherError, RootCertStore, SignatureScheme};
+use sha2::{Digest, Sha256};
+use snafu::{location, Location};
+
+use crate::digest;
+use crate::error::{CertPinMismatchSnafu, Error, InvalidCertPinSnafu};
+use crate::host;
+
+/// The pinned keys by host, as given to `pin_cert` or normalized, see `host::normalize`
+pub(crate) type CertPins = HashMap<String, Vec<String>>;
+
+/// The pins of `pins` by normalized host
+///
+/// rustls verifies the punycode name of the url without a trailing dot, a pin under another
+/// spelling would never match and the host would go unpinned, so a host that doesn't parse or
+/// has a port is an error.
+fn normalized(pins: &CertPins) -> Result<CertPins, Error> {
+    let mut normalized = CertPins::new();
+    for (host, keys) in pins {
+        let Some(name) = host::normalize(host) else {
+            return InvalidCertPinSnafu { host, message: "not a valid host", location: location!() }.fail();
+        };
+        let port = match name.strip_prefix('[') {
+            Some(addr) => !addr.ends_with(']'),
+            None => name.contains(':'),
+        };
+        if port {
+            return InvalidCertPinSnafu { host, message: "the host has a port", location: location!() }.fail();
+        }
+        normalized.entry(name).or_default().extend(keys.iter().cloned());
+    }
+    Ok(normalized)
+}
+
+/// The keys pinned for the host verified as `server_name`
+fn pinned<'a>(pins: &'a CertPins, server_name: &ServerName<'_>) -> Option<(String, &'a Vec<String>)> {
+    let host = match server_name {
+        ServerName::IpAddress(addr) => match IpAddr::from(*addr) {
+            IpAddr::V4(addr) => addr.to_string(),
+            IpAddr::V6(addr) => format!("[{}]", addr),
+        },
+        name => host::normalize(&name.to_str())?,
+    };
+    let pins = pins.get(&host)?;
+    Some((host, pins))
+}
+
+/// The TLS configuration of a client verifying the pins of `pins`
+pub(crate) fn client_config(pins: &CertPins) -> Result<ClientConfig, Error> {
+    let pins = normalized(pins)?;
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let roots = Arc::new(RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() });
+    let verifier = WebPkiServerVerifier::builder_with_provider(roots, provider.clone())
+        .build()
+        .expect("the webpki roots are not empty");
+    Ok(ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .expect("the ring provider supports the default protocol versions")
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PinnedVerifier { verifier, pins }))
+        .with_no_client_auth())
+}
+
+#[derive(Debug)]
+struct PinnedVerifier {
+    verifier: Arc<WebPkiServerVerifier>,
+    pins: CertPins,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(&self, end_entity: &CertificateDer<'_>, intermediates: &[CertificateDer<'_>],
+                          server_name: &ServerName<'_>, ocsp_response: &[u8],
+                          now: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.verifier.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        if let Some((host, pins)) = pinned(&self.pins, server_name) {
+            if !subject_public_key_info(end_entity).is_some_and(|spki| matches_pin(spki, pins)) {
+                tracing::warn!("The certificate of {} has none of the pinned keys", host);
+                let err = CertPinMismatchSnafu { host, location: location!() }.build();
+                return Err(rustls::Error::Other(OtherError(Arc::new(err))));
+            }
+        }
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>,
+                              dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verifier.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>,
+                              dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verifier.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.verifier.supported_verify_schemes()
+    }
+}
+
+/// Whether the SHA-256 of `spki` is one of `pins`, in base64 or hex
+fn matches_pin(spki: &[u8], pins: &[String]) -> bool {
+    let sha256 = Sha256::digest(spki);
+    let (base64, hex) = (STANDARD.encode(sha256), digest::hex(&sha256));
+    pins.iter().any(|pin| *pin == base64 || pin.eq_ignore_ascii_case(&hex))
+}
+
+/// The DER `SubjectPublicKeyInfo` of a DER X.509 certificate, the seventh field of the
+/// `tbsCertificate` counting the optional version
+fn subject_public_key_info(certificate: &[u8]) -> Option<&[u8]> {
+    let (_, certificate, _) = der_element(certificate)?;
+    let (_, mut fields, _) = der_element(certificate)?;
+    // The version is an explicit [0] tag, absent for v1 certificates
+    if fields.first() == Some(&0xa0) {
+        fields = der_element(fields)?.2;
+    }
+    // serialNumber, signature, issuer, validity and subject
+    for _ in 0..5 {
+        fields = der_element(fields)?.2;
+    }
+    der_element(fields).map(|(element, _, _)| element)
+}
+
+/// Split the first DER element off `input` into the whole element, its content and the rest
+fn der_element(input: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+    let first = *input.get(1)?;
+    let (header, length) = if first < 0x80 {
+        (2, first as usize)
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 {
+            return None;
+        }
+        let length = input.get(2..2 + count)?.iter().fold(0, |length, byte| length << 8 | *byte as usize);
+        (2 + count, length)
+    };
+    let end = header.checked_add(length).filter(|end| *end <= input.len())?;
+    Some((&input[..end], &input[header..end], &input[end..]))
+}
+
+#[cfg(test)]
+mod test {
+    use sha2::{Digest, Sha256};
+
+    use rustls::pki_types::ServerName;
+
+    use crate::digest;
+    use crate::error::Error;
+    use crate::pinning::{der_element, matches_pin, normalized, pinned, subject_public_key_info, CertPins};
+
+    /// The skeleton of a v3 certificate with empty fields and the key info `30 02 05 00`
+    const CERTIFICATE: &[u8] = &[
+        0x30, 0x81, 0x14,
+        0x30, 0x12,
+        0xa0, 0x03, 0x02, 0x01, 0x02,
+        0x02, 0x01, 0x01,
+        0x30, 0x00, 0x30, 0x00, 0x30, 0x00, 0x30, 0x00,
+        0x30, 0x02, 0x05, 0x00,
+    ];
+
+    #[test]
+    fn test_subject_public_key_info() {
+        assert_eq!(Some(&[0x30, 0x02, 0x05, 0x00][..]), subject_public_key_info(CERTIFICATE));
+        assert_eq!(None, subject_public_key_info(&CERTIFICATE[..20]));
+        assert_eq!(None, der_element(&[0x30, 0x05, 0x00]));
+    }
+
+    #[test]
+    fn test_matches_pin() {
+        let spki = [0x30, 0x02, 0x05, 0x00];
+        let hex = digest::hex(&Sha256::digest(spki));
+        assert!(matches_pin(&spki, &[hex.to_uppercase()]));
+        assert!(!matches_pin(&spki, &["AAAA".into()]));
+    }
+
+    #[test]
+    fn test_pinned_host() {
+        let pins = CertPins::from([
+            ("Bücher.example.".to_string(), vec!["key".to_string()]),
+            ("[::1]".to_string(), vec!["other".to_string()]),
+        ]);
+        let pins = normalized(&pins).unwrap();
+        // rustls verifies the punycode name of the url
+        let server_name = ServerName::try_from("xn--bcher-kva.example").unwrap();
+        assert_eq!(Some(("xn--bcher-kva.example".into(), &vec!["key".to_string()])), pinned(&pins, &server_name));
+        let server_name = ServerName::try_from("::1").unwrap();
+        assert_eq!(Some(("[::1]".into(), &vec!["other".to_string()])), pinned(&pins, &server_name));
+        assert_eq!(None, pinned(&pins, &ServerName::try_from("example.com").unwrap()));
+    }
+
+    #[test]
+    fn test_pin_invalid_host() {
+        for host in ["exa mple.com", "example.com:443", "[::1]:443"] {
+            let pins = CertPins::from([(host.to_string(), vec!["key".to_string()])]);
+            assert!(matches!(normalized(&pins), Err(Error::InvalidCertPin { .. })), "{}", host);
+        }
+    }
+}
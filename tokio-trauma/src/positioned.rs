@@ -0,0 +1,119 @@
+//! Positioned writes to a file pre-allocated to its final size
+//!
+//! Segments of a download write their range at its offset instead of appending, so they don't
+//! depend on the order chunks arrive in. On unix and Windows every write carries its offset
+//! (`pwrite`, `WriteFile` with an offset), which makes concurrent writes to disjoint ranges of
+//! the same handle safe. Other platforms fall back to seeking and writing under a lock.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+#[cfg(not(any(unix, windows)))]
+use std::sync::Mutex;
+
+use bytes::Bytes;
+
+#[cfg(any(unix, windows))]
+type Handle = File;
+#[cfg(not(any(unix, windows)))]
+type Handle = Mutex<File>;
+
+#[derive(Clone)]
+pub(crate) struct PositionedFile {
+    handle: Arc<Handle>,
+}
+
+impl PositionedFile {
+    /// Create or truncate the file at `path` and allocate `size` bytes
+    pub(crate) async fn create(path: &Path, size: u64) -> io::Result<Self> {
+        let path = path.to_path_buf();
+        let file = tokio::task::spawn_blocking(move || {
+            let file = OpenOptions::new().create(true).write(true).truncate(true).open(path)?;
+            file.set_len(size)?;
+            Ok::<_, io::Error>(file)
+        }).await.map_err(io::Error::other)??;
+        #[cfg(not(any(unix, windows)))]
+        let file = Mutex::new(file);
+        Ok(Self { handle: Arc::new(file) })
+    }
+
+    /// Open the file at `path` to write in place, created if missing and otherwise kept as is
+    pub(crate) async fn open(path: &Path) -> io::Result<Self> {
+        let path = path.to_path_buf();
+        let file = tokio::task::spawn_blocking(move || {
+            OpenOptions::new().create(true).write(true).truncate(false).open(path)
+        }).await.map_err(io::Error::other)??;
+        #[cfg(not(any(unix, windows)))]
+        let file = Mutex::new(file);
+        Ok(Self { handle: Arc::new(file) })
+    }
+
+    /// Write `data` at `offset` of the file
+    pub(crate) async fn write_at(&self, offset: u64, data: Bytes) -> io::Result<()> {
+        let handle = self.handle.clone();
+        tokio::task::spawn_blocking(move || write_at(&handle, offset, &data)).await.map_err(io::Error::other)?
+    }
+
+    /// Sync the written data to disk
+    pub(crate) async fn sync_data(&self) -> io::Result<()> {
+        let handle = self.handle.clone();
+        tokio::task::spawn_blocking(move || {
+            #[cfg(not(any(unix, windows)))]
+            let handle = handle.lock().unwrap_or_else(|err| err.into_inner());
+            handle.sync_data()
+        }).await.map_err(io::Error::other)?
+    }
+}
+
+#[cfg(unix)]
+fn write_at(file: &File, offset: u64, data: &[u8]) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, data, offset)
+}
+
+#[cfg(windows)]
+fn write_at(file: &File, mut offset: u64, mut data: &[u8]) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+
+    while !data.is_empty() {
+        match file.seek_write(data, offset)? {
+            0 => return Err(io::ErrorKind::WriteZero.into()),
+            written => {
+                data = &data[written..];
+                offset += written as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(any(unix, windows)))]
+fn write_at(file: &Mutex<File>, offset: u64, data: &[u8]) -> io::Result<()> {
+    use std::io::{Seek, SeekFrom, Write};
+
+    let mut file = file.lock().unwrap_or_else(|err| err.into_inner());
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(data)
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+    use futures_util::future;
+
+    use crate::positioned::PositionedFile;
+    use crate::testing::temp_dir;
+
+    #[tokio::test]
+    async fn test_write_at() {
+        let path = temp_dir("positioned").join("file.bin");
+        let file = PositionedFile::create(&path, 12).await.unwrap();
+        let writes = [(8, "more"), (0, "some"), (4, " and")]
+            .map(|(offset, data)| file.write_at(offset, Bytes::from_static(data.as_bytes())));
+        for result in future::join_all(writes).await {
+            result.unwrap();
+        }
+        file.sync_data().await.unwrap();
+        assert_eq!("some andmore", std::fs::read_to_string(&path).unwrap());
+    }
+}
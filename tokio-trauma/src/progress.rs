@@ -0,0 +1,58 @@
+//! Progress events of running downloads
+//!
+//! The size of a download is unknown when the server sends no `Content-Length`, e.g. a chunked
+//! response. Its events then carry no `total` and [`ProgressEvent::percent`] is `None`, a UI
+//! shows the bytes downloaded so far and the speed instead of a percentage.
+
+use crate::download::{Download, Summary};
+
+/// Progress of a download, passed to the `on_progress` hook
+#[derive(Debug, Clone, Copy)]
+pub enum ProgressEvent<'a> {
+    /// the body started streaming, `resumed` bytes were already on disk and `total`
+    /// is the size of the whole file when known
+    Started { download: &'a Download, total: Option<u64>, resumed: u64 },
+    /// `bytes` more bytes were written, `downloaded` bytes of the file are written so far,
+    /// including the resumed ones, out of `total` when known
+    Progress { download: &'a Download, bytes: u64, downloaded: u64, total: Option<u64> },
+    /// the schedule window closed, the download continues once it opens again
+    Paused { download: &'a Download },
+    /// the download finished, successfully or not
+    Finished { summary: &'a Summary },
+}
+
+impl ProgressEvent<'_> {
+    /// The percentage of the file written, `None` when its size is unknown
+    ///
+    /// A server sending more than the size it announced doesn't get the download past 100%.
+    pub fn percent(&self) -> Option<f64> {
+        let (downloaded, total) = match *self {
+            ProgressEvent::Started { total, resumed, .. } => (resumed, total?),
+            ProgressEvent::Progress { downloaded, total, .. } => (downloaded, total?),
+            ProgressEvent::Paused { .. } | ProgressEvent::Finished { .. } => return None,
+        };
+        if total == 0 {
+            return Some(100.0);
+        }
+        Some((downloaded as f64 / total as f64 * 100.0).min(100.0))
+    }
+}
+
+/// Callback notified of progress events
+pub(crate) type ProgressHook = dyn Fn(&ProgressEvent<'_>) + Send + Sync;
+
+#[cfg(test)]
+mod test {
+    use crate::download::Download;
+    use crate::progress::ProgressEvent;
+
+    #[test]
+    fn test_percent() {
+        let download = Download::try_from("http://domain.com/file.zip").unwrap();
+        let progress = |downloaded, total| ProgressEvent::Progress { download: &download, bytes: 1, downloaded, total };
+        assert_eq!(Some(25.0), progress(25, Some(100)).percent());
+        assert_eq!(Some(100.0), progress(120, Some(100)).percent());
+        assert_eq!(None, progress(120, None).percent());
+        assert_eq!(None, ProgressEvent::Started { download: &download, total: None, resumed: 10 }.percent());
+    }
+}
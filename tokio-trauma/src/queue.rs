@@ -0,0 +1,134 @@
+//! Long-lived download queue fed incrementally
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use tokio_trauma::download::Download;
+//! use tokio_trauma::downloader::DownloaderBuilder;
+//! use tokio_trauma::queue::Shutdown;
+//!
+//! # async fn run() -> tokio_trauma::error::Result<()> {
+//! let mut queue = DownloaderBuilder::new().build().queue()?;
+//! queue.enqueue(Download::try_from("https://example.com/file.zip")?)?;
+//! if let Some(summary) = queue.next().await {
+//!     println!("{:?}", summary.status());
+//! }
+//! let remaining = queue.shutdown(Shutdown::Drain).await;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::sync::Arc;
+
+use futures_util::stream::FuturesUnordered;
+use futures_util::StreamExt;
+use snafu::{location, Location};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::checkpoint::Checkpoint;
+use crate::control::{DownloadControl, DownloadStatus};
+use crate::download::{Download, Summary};
+use crate::downloader::{Batch, Downloader};
+use crate::error::{QueueClosedSnafu, Result};
+
+/// How to stop a [`DownloadQueue`]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Shutdown {
+    /// process every queued download before stopping
+    Drain,
+    /// stop running downloads, their partial files are kept for resuming,
+    /// queued downloads are reported as not started
+    Cancel,
+}
+
+/// Downloads enqueued over time, run by a background task within the concurrency limit
+pub struct DownloadQueue {
+    sender: Option<UnboundedSender<Download>>,
+    results: UnboundedReceiver<Summary>,
+    cancel: CancellationToken,
+    control: DownloadControl,
+    checkpoint: Option<Arc<Checkpoint>>,
+    worker: JoinHandle<()>,
+}
+
+impl DownloadQueue {
+    pub(crate) fn spawn(downloader: Downloader, batch: Batch, control: DownloadControl) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let (results_sender, results) = mpsc::unbounded_channel();
+        let cancel = CancellationToken::new();
+        let checkpoint = batch.checkpoint.clone();
+        let worker = downloader.runtime().spawn(work(downloader, batch, receiver, results_sender, cancel.clone()));
+        Self { sender: Some(sender), results, cancel, control, checkpoint, worker }
+    }
+
+    /// Add a download to the queue
+    pub fn enqueue(&self, download: Download) -> Result<()> {
+        let sender = self.sender.as_ref()
+            .ok_or_else(|| QueueClosedSnafu { location: location!() }.build())?;
+        if let Some(checkpoint) = &self.checkpoint {
+            checkpoint.add([&download]);
+        }
+        sender.send(download)
+            .map_err(|_| QueueClosedSnafu { location: location!() }.build())
+    }
+
+    /// The downloads currently running or paused, queued ones are not included
+    pub fn active_downloads(&self) -> Vec<DownloadStatus> {
+        self.control.active_downloads()
+    }
+
+    /// Wait for the next finished download, `None` once the queue stopped
+    pub async fn next(&mut self) -> Option<Summary> {
+        self.results.recv().await
+    }
+
+    /// Stop accepting downloads and return the summaries not yet received
+    pub async fn shutdown(mut self, mode: Shutdown) -> Vec<Summary> {
+        self.sender = None;
+        if mode == Shutdown::Cancel {
+            self.cancel.cancel();
+        }
+
+        let mut summaries = Vec::new();
+        while let Some(summary) = self.results.recv().await {
+            summaries.push(summary);
+        }
+        if let Err(err) = (&mut self.worker).await {
+            tracing::warn!("Download queue worker failed: {}", err);
+        }
+        summaries
+    }
+}
+
+async fn work(downloader: Downloader, batch: Batch, mut receiver: UnboundedReceiver<Download>,
+              results: UnboundedSender<Summary>, cancel: CancellationToken) {
+    let mut concurrency = downloader.concurrency();
+    let mut in_flight = FuturesUnordered::new();
+    let mut open = true;
+    while open || !in_flight.is_empty() {
+        tokio::select! {
+            _ = cancel.cancelled() => break,
+            download = receiver.recv(), if open && in_flight.len() < concurrency.limit() => match download {
+                Some(download) => in_flight.push(fetch(&downloader, &batch, download)),
+                None => open = false,
+            },
+            Some(summary) = in_flight.next(), if !in_flight.is_empty() => {
+                concurrency.record(&summary);
+                let _ = results.send(summary);
+            }
+        }
+    }
+
+    // Running downloads are dropped on cancellation, queued ones never started
+    drop(in_flight);
+    receiver.close();
+    while let Some(download) = receiver.recv().await {
+        let _ = results.send(Summary::new(download));
+    }
+}
+
+async fn fetch(downloader: &Downloader, batch: &Batch, download: Download) -> Summary {
+    downloader.fetch(batch, &download).await
+}
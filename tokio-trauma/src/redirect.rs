@@ -0,0 +1,128 @@
+//! Following redirects in a middleware, so their targets can be rewritten
+//!
+//! A `reqwest::redirect::Policy` can only follow or stop a redirect, not change where it goes.
+//! When a redirect hook is set, the client follows no redirects itself and this middleware,
+//! innermost in the stack, follows them instead: every target is passed to the hook, then
+//! checked against the redirect policy like the client would. Retries and attempt counts cover
+//! the whole redirect chain, as they do when the client follows the redirects.
+//!
+//! A refused redirect fails the request with a `RedirectRefused` error carrying the chain of
+//! urls that led to it, whichever of the client or the middleware followed the redirects.
+
+use std::fmt::{self, Display, Formatter};
+
+use async_trait::async_trait;
+use http::Extensions;
+use reqwest::header::{AUTHORIZATION, COOKIE, LOCATION, PROXY_AUTHORIZATION};
+use reqwest::{Method, Request, Response, StatusCode};
+use reqwest_middleware::{Middleware, Next};
+use url::Url;
+
+use crate::downloader::RedirectPolicy;
+use crate::host;
+use crate::shared::Shared;
+
+/// Rewrite the target of a redirect, `None` follows it as is
+pub(crate) type RedirectHook = dyn Fn(&Url) -> Option<Url> + Send + Sync;
+
+/// The redirects followed when no policy is set, like the reqwest default
+const DEFAULT_MAX_REDIRECTS: usize = 10;
+
+pub(crate) struct RedirectFollower {
+    pub(crate) policy: Option<RedirectPolicy>,
+    pub(crate) hook: Shared<RedirectHook>,
+}
+
+#[async_trait]
+impl Middleware for RedirectFollower {
+    async fn handle(&self, req: Request, extensions: &mut Extensions, next: Next<'_>) -> reqwest_middleware::Result<Response> {
+        let policy = self.policy.unwrap_or(RedirectPolicy::Limited(DEFAULT_MAX_REDIRECTS));
+        let mut previous = Vec::new();
+        let mut request = req;
+        loop {
+            // A request streaming its body can't be sent again
+            let following = request.try_clone();
+            let response = next.clone().run(request, extensions).await?;
+            let (Some(target), Some(mut following)) = (redirect_target(&response), following) else {
+                return Ok(response);
+            };
+            if policy == RedirectPolicy::None {
+                return Ok(response);
+            }
+
+            let target = (self.hook)(&target).unwrap_or(target);
+            previous.push(response.url().clone());
+            policy.check(&previous, &target)
+                .map_err(|message| reqwest_middleware::Error::middleware(RedirectRefused::new(message, &previous, &target)))?;
+            tracing::debug!("Following the redirect from {} to {}", response.url(), target);
+
+            if response.status() == StatusCode::SEE_OTHER && following.method() != Method::HEAD {
+                *following.method_mut() = Method::GET;
+                *following.body_mut() = None;
+            }
+            // Credentials stay with their host
+            if host::url_host(response.url()) != host::url_host(&target) {
+                for name in [AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION] {
+                    following.headers_mut().remove(name);
+                }
+            }
+            *following.url_mut() = target;
+            request = following;
+        }
+    }
+}
+
+/// A redirect refused by the redirect policy
+#[derive(Debug)]
+pub(crate) struct RedirectRefused {
+    message: String,
+    /// the requested url, the urls it was redirected to, then the refused target
+    pub(crate) chain: Vec<Url>,
+}
+
+impl RedirectRefused {
+    pub(crate) fn new(message: String, previous: &[Url], target: &Url) -> Self {
+        Self { message, chain: previous.iter().chain([target]).cloned().collect() }
+    }
+
+    /// The host of the refused target
+    pub(crate) fn host(&self) -> String {
+        self.chain.last().and_then(host::url_host).unwrap_or_default()
+    }
+}
+
+impl Display for RedirectRefused {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for RedirectRefused {}
+
+/// The refused redirect that failed a request, if any
+pub(crate) fn refused(err: &reqwest_middleware::Error) -> Option<&RedirectRefused> {
+    match err {
+        reqwest_middleware::Error::Reqwest(error) => {
+            let mut source = std::error::Error::source(error);
+            while let Some(error) = source {
+                if let Some(refused) = error.downcast_ref() {
+                    return Some(refused);
+                }
+                source = error.source();
+            }
+            None
+        }
+        reqwest_middleware::Error::Middleware(error) => error.downcast_ref(),
+    }
+}
+
+/// The url a redirect response points to
+fn redirect_target(response: &Response) -> Option<Url> {
+    let redirect = matches!(response.status(), StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND | StatusCode::SEE_OTHER
+        | StatusCode::TEMPORARY_REDIRECT | StatusCode::PERMANENT_REDIRECT);
+    if !redirect {
+        return None;
+    }
+    let location = response.headers().get(LOCATION)?.to_str().ok()?;
+    response.url().join(location).ok()
+}
@@ -0,0 +1,324 @@
+use std::fs;
+use std::ops::Deref;
+use std::path::Path;
+
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use snafu::{location, Location, ResultExt};
+
+use crate::checkpoint::digest_name;
+use crate::digest;
+use crate::download::{EnglishMessages, Status, StatusMessages, Summary};
+use crate::error::{BatchFailedSnafu, IncompleteBatchSnafu, IoSnafu, Result};
+
+/// The summaries of a downloaded batch
+#[derive(Debug, Clone, Default)]
+pub struct DownloadReport {
+    summaries: Vec<Summary>,
+    concurrency: ConcurrencyStats,
+}
+
+/// How many downloads ran at the same time during a batch
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ConcurrencyStats {
+    /// the most downloads running at once
+    pub peak: usize,
+    /// the running downloads averaged over the duration of the batch
+    pub average: f64,
+}
+
+impl DownloadReport {
+    pub fn new(summaries: Vec<Summary>) -> Self {
+        Self { summaries, concurrency: ConcurrencyStats::default() }
+    }
+
+    pub(crate) fn with_concurrency(self, concurrency: ConcurrencyStats) -> Self {
+        Self { concurrency, ..self }
+    }
+
+    /// The peak and average number of downloads that ran at the same time, far below the
+    /// concurrency limit when other limits such as a per-host rate limit hold downloads back
+    pub fn concurrency(&self) -> ConcurrencyStats {
+        self.concurrency
+    }
+
+    pub fn summaries(&self) -> &[Summary] {
+        &self.summaries
+    }
+
+    pub fn into_summaries(self) -> Vec<Summary> {
+        self.summaries
+    }
+
+    pub fn successes(&self) -> impl Iterator<Item = &Summary> {
+        self.with_status(|status| matches!(status, Status::Success))
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &Summary> {
+        self.with_status(|status| matches!(status, Status::Fail(_)))
+    }
+
+    pub fn skipped(&self) -> impl Iterator<Item = &Summary> {
+        self.with_status(|status| matches!(status, Status::Skipped(_)))
+    }
+
+    fn with_status(&self, predicate: impl Fn(&Status) -> bool) -> impl Iterator<Item = &Summary> {
+        self.summaries.iter().filter(move |summary| predicate(summary.status()))
+    }
+
+    /// Sum of the sizes of the successful downloads
+    pub fn total_bytes(&self) -> u64 {
+        self.successes().map(Summary::size).sum()
+    }
+
+    /// Whether every download succeeded or was skipped, a failed or not started one did not
+    pub fn all_succeeded(&self) -> bool {
+        !self.summaries.iter().any(|summary| unfinished(summary.status()))
+    }
+
+    /// Single SHA-256 digest over the files of the whole batch
+    ///
+    /// Every file is described by a `sha256sum` line `<hex sha-256>  <filename>\n`, the lines are
+    /// sorted by filename and the aggregate is the hex SHA-256 of their concatenation. Skipped
+    /// files are included, any failed or not started download fails instead so the digest
+    /// never covers a partial batch.
+    pub async fn aggregate_sha256(&self) -> Result<String> {
+        let count = self.summaries.iter()
+            .filter(|summary| unfinished(summary.status()))
+            .count();
+        if count > 0 {
+            return IncompleteBatchSnafu { count, location: location!() }.fail();
+        }
+
+        let mut lines = Vec::with_capacity(self.summaries.len());
+        for summary in &self.summaries {
+            let sha256 = digest::sha256_file(summary.path()).await
+                .context(IoSnafu { path: summary.path(), location: location!() })?;
+            lines.push((summary.download().filename.as_str(), sha256));
+        }
+        lines.sort();
+
+        let mut hasher = Sha256::new();
+        for (filename, sha256) in lines {
+            hasher.update(format!("{}  {}\n", sha256, filename));
+        }
+        Ok(digest::hex(&hasher.finalize()))
+    }
+
+    /// Write the report to `path` as a JSON array with the url, filename, path, status, reason,
+    /// size, digests, elapsed seconds, seconds to the first byte, diagnostics and concurrency of
+    /// every download, the number of downloads of the batch running when it started
+    ///
+    /// The report is written to a temporary file renamed over `path`, so an interrupted write
+    /// never leaves a truncated report.
+    pub fn write_json(&self, path: impl AsRef<Path>) -> Result<()> {
+        self.write_json_with(path, &EnglishMessages)
+    }
+
+    /// Write the report like `write_json` with the skip reasons of `messages`
+    pub fn write_json_with(&self, path: impl AsRef<Path>, messages: &dyn StatusMessages) -> Result<()> {
+        let path = path.as_ref();
+        let entries: Vec<Value> = self.summaries.iter().map(|summary| entry(summary, messages)).collect();
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        fs::write(&temporary, Value::Array(entries).to_string())
+            .and_then(|_| fs::rename(&temporary, path))
+            .context(IoSnafu { path, location: location!() })
+    }
+
+    /// Turn the report into an error carrying the failed and not started summaries, if any
+    pub fn into_result(self) -> Result<(), Vec<Summary>> {
+        let failures: Vec<_> = self.summaries.into_iter()
+            .filter(|summary| unfinished(summary.status()))
+            .collect();
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(failures)
+        }
+    }
+
+    /// Turn the report into `Error::BatchFailed` listing the filename and reason of every failed
+    /// or not started download, if any, for callers propagating the outcome of the whole batch
+    /// with `?`
+    pub fn into_aggregate_error(self) -> Result<()> {
+        let failures: Vec<_> = self.summaries.into_iter()
+            .filter_map(|summary| match summary.status {
+                Status::Fail(reason) => Some((summary.download.filename, reason)),
+                Status::NotStarted => Some((summary.download.filename, "not started".to_string())),
+                _ => None,
+            })
+            .collect();
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            BatchFailedSnafu { failures, location: location!() }.fail()
+        }
+    }
+}
+
+/// Whether `status` leaves the download without its file, failed or never started
+fn unfinished(status: &Status) -> bool {
+    matches!(status, Status::Fail(_) | Status::NotStarted)
+}
+
+fn entry(summary: &Summary, messages: &dyn StatusMessages) -> Value {
+    let (status, reason) = match summary.status() {
+        Status::Success => ("success", None),
+        Status::Skipped(reason) => ("skipped", Some(messages.skip_reason(reason))),
+        Status::Fail(reason) => ("failed", Some(reason.clone())),
+        Status::NotStarted => ("not started", None),
+    };
+    let digests: Map<String, Value> = summary.digests().iter()
+        .map(|(kind, hex)| (digest_name(*kind).to_string(), Value::from(hex.as_str())))
+        .collect();
+    json!({
+        "url": summary.download().redacted_url().as_str(),
+        "filename": summary.download().filename,
+        "path": summary.path().to_string_lossy(),
+        "status": status,
+        "reason": reason,
+        "size": summary.size(),
+        "digests": digests,
+        "elapsed": summary.elapsed().as_secs_f64(),
+        "ttfb": summary.ttfb().map(|ttfb| ttfb.as_secs_f64()),
+        "concurrency": summary.concurrency(),
+        "diagnostics": summary.diagnostics().iter().map(ToString::to_string).collect::<Vec<_>>(),
+    })
+}
+
+/// Estimated size of a batch before downloading it
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct SizeEstimate {
+    /// sum of the known sizes
+    pub known_bytes: u64,
+    /// number of downloads of unknown size
+    pub unknown_count: usize,
+}
+
+impl Deref for DownloadReport {
+    type Target = [Summary];
+
+    fn deref(&self) -> &Self::Target {
+        &self.summaries
+    }
+}
+
+impl From<Vec<Summary>> for DownloadReport {
+    fn from(summaries: Vec<Summary>) -> Self {
+        Self::new(summaries)
+    }
+}
+
+impl IntoIterator for DownloadReport {
+    type Item = Summary;
+    type IntoIter = std::vec::IntoIter<Summary>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.summaries.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a DownloadReport {
+    type Item = &'a Summary;
+    type IntoIter = std::slice::Iter<'a, Summary>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.summaries.iter()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::download::{Download, SkipReason, Status, Summary};
+    use crate::error::Error;
+    use crate::report::DownloadReport;
+    use crate::testing::temp_dir;
+
+    fn summary(filename: &str, size: u64, status: Status) -> Summary {
+        let download = Download::try_from(format!("http://domain.com/{}", filename).as_str()).unwrap();
+        Summary { size, ..Summary::new(download) }.with_status(status)
+    }
+
+    #[test]
+    fn test_report() {
+        let report = DownloadReport::new(vec![
+            summary("a.zip", 10, Status::Success),
+            summary("b.zip", 20, Status::Fail("timeout".into())),
+            summary("c.zip", 30, Status::Success),
+            summary("d.zip", 40, Status::Skipped(SkipReason::Complete)),
+        ]);
+        assert_eq!(40, report.total_bytes());
+        assert!(!report.all_succeeded());
+        assert_eq!(1, report.failures().count());
+
+        let failures = report.into_result().unwrap_err();
+        assert_eq!("b.zip", failures[0].download().filename);
+
+        // Not started downloads did not succeed either
+        let report = DownloadReport::new(vec![
+            summary("a.zip", 10, Status::Success),
+            summary("b.zip", 0, Status::NotStarted),
+        ]);
+        assert!(!report.all_succeeded());
+        assert_eq!(0, report.failures().count());
+        let failures = report.into_result().unwrap_err();
+        assert_eq!("b.zip", failures[0].download().filename);
+    }
+
+    #[tokio::test]
+    async fn test_aggregate_sha256() {
+        let directory = temp_dir("aggregate-sha256");
+        std::fs::write(directory.join("a.txt"), "hello").unwrap();
+        std::fs::write(directory.join("b.txt"), "world").unwrap();
+        let report = DownloadReport::new(vec![
+            summary("b.txt", 5, Status::Success).with_path(directory.join("b.txt")),
+            summary("a.txt", 5, Status::Skipped(SkipReason::Complete)).with_path(directory.join("a.txt")),
+        ]);
+        assert_eq!("087fc60cfa1eebcdcd2cd58fb861b31ef3e2cb6c49ae111320363ceec50bc7bc",
+                   report.aggregate_sha256().await.unwrap());
+
+        let failed = DownloadReport::new(vec![summary("c.txt", 0, Status::Fail("timeout".into()))]);
+        assert!(failed.aggregate_sha256().await.is_err());
+    }
+
+    #[test]
+    fn test_write_json() {
+        let directory = temp_dir("write-json");
+        let report = DownloadReport::new(vec![
+            summary("a.zip", 10, Status::Success),
+            summary("b.zip", 0, Status::Fail("timeout".into())),
+        ]);
+        let path = directory.join("report.json");
+        report.write_json(&path).unwrap();
+        let written: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(("success", 10), (written[0]["status"].as_str().unwrap(), written[0]["size"].as_u64().unwrap()));
+        assert_eq!(("failed", "timeout"), (written[1]["status"].as_str().unwrap(), written[1]["reason"].as_str().unwrap()));
+        assert!(written[1]["ttfb"].is_null());
+        assert_eq!(Some(0), written[1]["diagnostics"].as_array().map(Vec::len));
+        assert_eq!(0, written[1]["concurrency"]);
+        assert!(!directory.join("report.json.tmp").exists());
+    }
+
+    #[test]
+    fn test_into_aggregate_error() {
+        let report = DownloadReport::new(vec![
+            summary("a.zip", 10, Status::Success),
+            summary("b.zip", 0, Status::Fail("timeout".into())),
+            summary("c.zip", 0, Status::Fail("404 Not Found".into())),
+            summary("d.zip", 0, Status::Fail("reset".into())),
+            summary("e.zip", 0, Status::Fail("reset".into())),
+        ]);
+        let err = report.into_aggregate_error().unwrap_err();
+        assert!(matches!(&err, Error::BatchFailed { failures, .. } if failures[0] == ("b.zip".into(), "timeout".into())));
+        assert_eq!("4 downloads of the batch failed: b.zip (timeout), c.zip (404 Not Found), d.zip (reset), and 1 more",
+                   err.to_string());
+
+        let report = DownloadReport::new(vec![summary("a.zip", 10, Status::NotStarted)]);
+        assert_eq!("1 downloads of the batch failed: a.zip (not started)",
+                   report.into_aggregate_error().unwrap_err().to_string());
+
+        let report = DownloadReport::new(vec![summary("a.zip", 10, Status::Skipped(SkipReason::Complete))]);
+        assert!(report.into_aggregate_error().is_ok());
+    }
+}
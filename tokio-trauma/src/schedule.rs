@@ -0,0 +1,458 @@
+//! Driving of the download futures with a bounded, possibly changing, concurrency
+
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures_util::stream::{FuturesOrdered, FuturesUnordered};
+use futures_util::StreamExt;
+
+use crate::clock::Clock;
+use crate::download::{Status, Summary};
+use crate::report::ConcurrencyStats;
+
+/// Relative throughput change considered as a trend rather than noise
+const THROUGHPUT_THRESHOLD: f64 = 0.05;
+
+/// The set of running downloads, yielding either in input or in completion order
+pub(crate) enum InFlight<F: Future> {
+    Ordered(FuturesOrdered<F>),
+    Unordered(FuturesUnordered<F>),
+}
+
+impl<F: Future> InFlight<F> {
+    pub(crate) fn new(ordered: bool) -> Self {
+        if ordered {
+            Self::Ordered(FuturesOrdered::new())
+        } else {
+            Self::Unordered(FuturesUnordered::new())
+        }
+    }
+
+    pub(crate) fn push(&mut self, future: F) {
+        match self {
+            Self::Ordered(futures) => futures.push_back(future),
+            Self::Unordered(futures) => futures.push(future),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Self::Ordered(futures) => futures.len(),
+            Self::Unordered(futures) => futures.len(),
+        }
+    }
+
+    pub(crate) async fn next(&mut self) -> Option<F::Output> {
+        match self {
+            Self::Ordered(futures) => futures.next().await,
+            Self::Unordered(futures) => futures.next().await,
+        }
+    }
+}
+
+const SECONDS_PER_DAY: u64 = 24 * 3600;
+
+/// Hours of the day, in UTC, during which downloads are allowed to run
+///
+/// The window covers `start..end` and wraps around midnight when `start > end`,
+/// `start == end` allows the whole day.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct ScheduleWindow {
+    start: u8,
+    end: u8,
+}
+
+impl ScheduleWindow {
+    pub(crate) fn new(start_hour: u8, end_hour: u8) -> Self {
+        assert!(start_hour < 24 && end_hour < 24, "hours must be within 0..24");
+        Self { start: start_hour, end: end_hour }
+    }
+
+    fn contains(&self, hour: u8) -> bool {
+        match self.start.cmp(&self.end) {
+            std::cmp::Ordering::Equal => true,
+            std::cmp::Ordering::Less => (self.start..self.end).contains(&hour),
+            std::cmp::Ordering::Greater => hour >= self.start || hour < self.end,
+        }
+    }
+
+    /// The time left until the window opens, `None` when it is open at `now`
+    pub(crate) fn until_open(&self, now: SystemTime) -> Option<Duration> {
+        let seconds = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() % SECONDS_PER_DAY;
+        if self.contains((seconds / 3600) as u8) {
+            return None;
+        }
+        let start = self.start as u64 * 3600;
+        Some(Duration::from_secs((start + SECONDS_PER_DAY - seconds) % SECONDS_PER_DAY))
+    }
+
+    /// Wait until the window is open
+    pub(crate) async fn wait(&self, clock: &Clock) {
+        if let Some(wait) = self.until_open(clock.system_time()) {
+            tracing::debug!("Outside of the schedule window, waiting {:?}", wait);
+            clock.sleep(wait).await;
+        }
+    }
+}
+
+/// Spacing of the starts of the downloads of a batch
+pub(crate) struct Stagger {
+    delay: Duration,
+    jitter: Duration,
+    next: Mutex<Option<Instant>>,
+    clock: Clock,
+}
+
+impl Stagger {
+    pub(crate) fn new(delay: Duration, jitter: Duration, clock: Clock) -> Self {
+        Self { delay, jitter, next: Mutex::new(None), clock }
+    }
+
+    /// Wait for the next start, starts are `delay` plus a random part of `jitter` apart
+    pub(crate) async fn wait(&self) {
+        let start = {
+            let mut next = self.next.lock().unwrap_or_else(|err| err.into_inner());
+            let now = self.clock.now();
+            let start = next.map_or(now, |next| next.max(now));
+            *next = Some(start + self.delay + random_below(self.jitter));
+            start
+        };
+        self.clock.sleep_until(start).await;
+    }
+}
+
+/// A random duration below `bound`, from the random keys of the std hasher
+fn random_below(bound: Duration) -> Duration {
+    let nanos = bound.as_nanos().min(u64::MAX as u128) as u64;
+    if nanos == 0 {
+        return Duration::ZERO;
+    }
+    Duration::from_nanos(RandomState::new().build_hasher().finish() % nanos)
+}
+
+/// Bounds of the adaptive concurrency
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct AdaptiveConcurrency {
+    pub(crate) min: u8,
+    pub(crate) max: u8,
+}
+
+/// Concurrency limit of a batch
+///
+/// The adaptive limit starts at `min` and measures the aggregate throughput over windows of
+/// as many completed downloads as the current limit. When a window is more than 5% faster than
+/// the previous one the limit grows by one, when it is more than 5% slower it shrinks by one,
+/// always staying within `min..=max`. Failed and skipped downloads do not count as throughput.
+///
+/// The ramped limit grows linearly from one download at the start of the batch up to `max`
+/// after `duration`, regardless of completions.
+pub(crate) enum Concurrency {
+    Fixed(usize),
+    Ramp {
+        max: usize,
+        duration: Duration,
+        started: Instant,
+        clock: Clock,
+    },
+    Adaptive {
+        bounds: AdaptiveConcurrency,
+        current: usize,
+        window: Window,
+        last_throughput: Option<f64>,
+        clock: Clock,
+    },
+}
+
+pub(crate) struct Window {
+    started: Instant,
+    completed: usize,
+    bytes: u64,
+}
+
+impl Window {
+    fn new(started: Instant) -> Self {
+        Self { started, completed: 0, bytes: 0 }
+    }
+
+    fn throughput(&self, elapsed: Duration) -> f64 {
+        self.bytes as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+impl Concurrency {
+    pub(crate) fn new(fixed: u8) -> Self {
+        Self::Fixed(fixed.max(1) as usize)
+    }
+
+    /// A limit within `bounds` following the throughput measured on `clock`
+    pub(crate) fn adaptive(bounds: AdaptiveConcurrency, clock: Clock) -> Self {
+        Self::Adaptive {
+            bounds,
+            current: bounds.min.max(1) as usize,
+            window: Window::new(clock.now()),
+            last_throughput: None,
+            clock,
+        }
+    }
+
+    /// A fixed limit of `max` reached linearly over `duration` from one download
+    pub(crate) fn ramped(max: u8, duration: Duration, clock: Clock) -> Self {
+        Self::Ramp { max: max.max(1) as usize, duration, started: clock.now(), clock }
+    }
+
+    /// The number of downloads allowed to run at the same time
+    pub(crate) fn limit(&self) -> usize {
+        match self {
+            Self::Fixed(limit) => *limit,
+            Self::Ramp { max, duration, started, clock } => {
+                let elapsed = clock.now().saturating_duration_since(*started);
+                if elapsed >= *duration {
+                    return *max;
+                }
+                1 + ((*max - 1) as u128 * elapsed.as_nanos() / duration.as_nanos()) as usize
+            }
+            Self::Adaptive { current, .. } => *current,
+        }
+    }
+
+    /// When the ramped limit grows next, `None` once it stopped growing or for other limits
+    pub(crate) fn next_increase(&self) -> Option<Instant> {
+        let Self::Ramp { max, duration, started, .. } = self else {
+            return None;
+        };
+        let limit = self.limit();
+        if limit >= *max {
+            return None;
+        }
+        // Rounded up so the limit has grown once the instant is reached
+        let steps = (*max - 1) as u128;
+        let nanos = (limit as u128 * duration.as_nanos()).div_ceil(steps);
+        Some(*started + Duration::from_nanos(nanos.min(u64::MAX as u128) as u64))
+    }
+
+    /// Feed a finished download into the heuristic
+    pub(crate) fn record(&mut self, summary: &Summary) {
+        let Self::Adaptive { bounds, current, window, last_throughput, clock } = self else {
+            return;
+        };
+
+        window.completed += 1;
+        if matches!(summary.status(), Status::Success) {
+            window.bytes += summary.size();
+        }
+        if window.completed < *current {
+            return;
+        }
+
+        let now = clock.now();
+        let throughput = window.throughput(now.saturating_duration_since(window.started));
+        if let Some(last) = *last_throughput {
+            let min = bounds.min.max(1) as usize;
+            let max = bounds.max.max(bounds.min).max(1) as usize;
+            if throughput > last * (1.0 + THROUGHPUT_THRESHOLD) {
+                *current = (*current + 1).min(max);
+            } else if throughput < last * (1.0 - THROUGHPUT_THRESHOLD) {
+                *current = current.saturating_sub(1).max(min);
+            }
+        } else if *current < bounds.max as usize {
+            // Probe upwards after the first window to get a trend
+            *current += 1;
+        }
+        tracing::debug!("Adaptive concurrency: {:.0} B/s, limit {}", throughput, current);
+
+        *last_throughput = Some(throughput);
+        *window = Window::new(now);
+    }
+}
+
+/// The number of downloads running during a batch, from which its peak and average derive
+pub(crate) struct ConcurrencyGauge {
+    state: Mutex<GaugeState>,
+    clock: Clock,
+}
+
+struct GaugeState {
+    running: usize,
+    peak: usize,
+    started: Instant,
+    changed: Instant,
+    /// running downloads integrated over time, in download-seconds
+    integral: f64,
+}
+
+impl GaugeState {
+    fn update(&mut self, now: Instant, running: usize) {
+        self.integral += self.running as f64 * now.saturating_duration_since(self.changed).as_secs_f64();
+        self.changed = now;
+        self.running = running;
+        self.peak = self.peak.max(running);
+    }
+}
+
+impl ConcurrencyGauge {
+    pub(crate) fn new(clock: Clock) -> Self {
+        let now = clock.now();
+        let state = GaugeState { running: 0, peak: 0, started: now, changed: now, integral: 0.0 };
+        Self { state: Mutex::new(state), clock }
+    }
+
+    /// Count a download as running until the returned guard is dropped
+    pub(crate) fn enter(&self) -> Running<'_> {
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        let running = state.running + 1;
+        state.update(self.clock.now(), running);
+        Running { gauge: self, count: running }
+    }
+
+    /// The peak and the time-weighted average of the running downloads so far
+    pub(crate) fn stats(&self) -> ConcurrencyStats {
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        let (now, running) = (self.clock.now(), state.running);
+        state.update(now, running);
+        let elapsed = now.saturating_duration_since(state.started).as_secs_f64();
+        let average = if elapsed > 0.0 { state.integral / elapsed } else { 0.0 };
+        ConcurrencyStats { peak: state.peak, average }
+    }
+}
+
+/// A download counted as running by a gauge
+pub(crate) struct Running<'a> {
+    gauge: &'a ConcurrencyGauge,
+    /// the running downloads when it started, itself included
+    pub(crate) count: usize,
+}
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        let mut state = self.gauge.state.lock().unwrap_or_else(|err| err.into_inner());
+        let running = state.running.saturating_sub(1);
+        state.update(self.gauge.clock.now(), running);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use futures_util::FutureExt;
+
+    use crate::clock::Clock;
+    use crate::download::{Download, Status, Summary};
+    use crate::report::ConcurrencyStats;
+    use crate::schedule::{random_below, AdaptiveConcurrency, Concurrency, ConcurrencyGauge, ScheduleWindow, Stagger};
+
+    const HOUR: u64 = 3600;
+
+    #[test]
+    fn test_schedule_window() {
+        let window = ScheduleWindow::new(1, 6);
+        assert_eq!(None, window.until_open(UNIX_EPOCH + Duration::from_secs(2 * HOUR)));
+        assert_eq!(Some(Duration::from_secs(HOUR / 2)), window.until_open(UNIX_EPOCH + Duration::from_secs(HOUR / 2)));
+        assert_eq!(Some(Duration::from_secs(18 * HOUR)), window.until_open(UNIX_EPOCH + Duration::from_secs(7 * HOUR)));
+    }
+
+    #[test]
+    fn test_schedule_window_wraps() {
+        let window = ScheduleWindow::new(22, 6);
+        assert_eq!(None, window.until_open(UNIX_EPOCH + Duration::from_secs(23 * HOUR)));
+        assert_eq!(None, window.until_open(UNIX_EPOCH + Duration::from_secs(3 * HOUR)));
+        assert_eq!(Some(Duration::from_secs(10 * HOUR)), window.until_open(UNIX_EPOCH + Duration::from_secs(12 * HOUR)));
+        assert_eq!(None, ScheduleWindow::new(5, 5).until_open(UNIX_EPOCH));
+    }
+
+    #[test]
+    fn test_random_below() {
+        assert_eq!(Duration::ZERO, random_below(Duration::ZERO));
+        assert!((0..100).all(|_| random_below(Duration::from_millis(10)) < Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn test_concurrency_ramp() {
+        let (clock, mock) = Clock::mock();
+        let ramp = Concurrency::ramped(5, Duration::from_secs(8), clock.clone());
+        assert_eq!(1, ramp.limit());
+        assert_eq!(Some(clock.now() + Duration::from_secs(2)), ramp.next_increase());
+        mock.advance(Duration::from_secs(3));
+        assert_eq!(2, ramp.limit());
+        assert_eq!(Some(clock.now() + Duration::from_secs(1)), ramp.next_increase());
+        mock.advance(Duration::from_secs(5));
+        assert_eq!(5, ramp.limit());
+        assert_eq!(None, ramp.next_increase());
+
+        let ramp = Concurrency::ramped(1, Duration::from_secs(8), clock);
+        assert_eq!((1, None), (ramp.limit(), ramp.next_increase()));
+    }
+
+    #[test]
+    fn test_concurrency_adaptive() {
+        let (clock, mock) = Clock::mock();
+        let mut adaptive = Concurrency::adaptive(AdaptiveConcurrency { min: 1, max: 4 }, clock);
+        let download = Download::try_from("http://domain.com/file.zip").unwrap();
+        let success = Summary { size: 1000, ..Summary::new(download).with_status(Status::Success) };
+        let window = |concurrency: &mut Concurrency, elapsed: u64| {
+            mock.advance(Duration::from_millis(elapsed));
+            for _ in 0..concurrency.limit() {
+                concurrency.record(&success);
+            }
+            concurrency.limit()
+        };
+
+        // Probes upwards after the first window, then grows while the throughput does
+        assert_eq!(1, adaptive.limit());
+        assert_eq!(2, window(&mut adaptive, 1000));
+        assert_eq!(3, window(&mut adaptive, 1000));
+        assert_eq!(4, window(&mut adaptive, 1000));
+        assert_eq!(4, window(&mut adaptive, 1000));
+        // Shrinks when the throughput drops and holds when it is steady
+        assert_eq!(3, window(&mut adaptive, 2000));
+        assert_eq!(3, window(&mut adaptive, 1500));
+        assert_eq!(2, window(&mut adaptive, 3000));
+        assert_eq!(1, window(&mut adaptive, 3000));
+        assert_eq!(1, window(&mut adaptive, 3000));
+    }
+
+    #[test]
+    fn test_concurrency_gauge() {
+        let (clock, mock) = Clock::mock();
+        let gauge = ConcurrencyGauge::new(clock);
+        let first = gauge.enter();
+        let second = gauge.enter();
+        assert_eq!((1, 2), (first.count, second.count));
+        mock.advance(Duration::from_secs(1));
+        drop(first);
+        mock.advance(Duration::from_secs(1));
+        drop(second);
+        assert_eq!(ConcurrencyStats { peak: 2, average: 1.5 }, gauge.stats());
+        mock.advance(Duration::from_secs(1));
+        assert_eq!(ConcurrencyStats { peak: 2, average: 1.0 }, gauge.stats());
+    }
+
+    #[tokio::test]
+    async fn test_stagger() {
+        let (clock, mock) = Clock::mock();
+        let stagger = Stagger::new(Duration::from_secs(10), Duration::ZERO, clock);
+        let mut starts = Box::pin(futures_util::future::join3(stagger.wait(), stagger.wait(), stagger.wait()));
+        assert!((&mut starts).now_or_never().is_none());
+        mock.advance(Duration::from_secs(10));
+        assert!((&mut starts).now_or_never().is_none());
+        mock.advance(Duration::from_secs(10));
+        assert!(starts.now_or_never().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_schedule_window_wait() {
+        let (clock, mock) = Clock::mock();
+        let window = ScheduleWindow::new(0, 0);
+        assert!(window.wait(&clock).now_or_never().is_some());
+
+        let hour = (clock.system_time().duration_since(UNIX_EPOCH).unwrap().as_secs() / HOUR % 24) as u8;
+        let window = ScheduleWindow::new((hour + 1) % 24, (hour + 2) % 24);
+        let mut wait = Box::pin(window.wait(&clock));
+        assert!((&mut wait).now_or_never().is_none());
+        mock.advance(Duration::from_secs(HOUR));
+        assert!(wait.now_or_never().is_some());
+    }
+}
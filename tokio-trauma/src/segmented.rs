@@ -0,0 +1,206 @@
+//! Downloads of a resource in ranges, fetched concurrently or one after the other and written
+//! in place at their offset
+
+use std::fs;
+use std::path::Path;
+
+use futures_util::{stream, StreamExt};
+use reqwest::header::RANGE;
+use reqwest::{Method, StatusCode};
+use reqwest_middleware::ClientWithMiddleware;
+
+use crate::download::{ByteRange, Download, Status, Summary};
+use crate::downloader::{directory_error, discard, fail_request, request_failure, Downloader};
+use crate::finalize;
+use crate::positioned::PositionedFile;
+
+impl Downloader {
+    /// Download a single resource in `segments` ranges fetched concurrently, each written in
+    /// place into the output file pre-allocated to the size of the resource.
+    ///
+    /// The download falls back to a regular one when the server does not report the size or does
+    /// not accept ranges. A failed segment fails the download and removes the output file, since
+    /// its pre-allocated size would pass for a complete download. With a temporary directory the
+    /// segments are written to a `<filename>.segmented` file there, moved to the output path once
+    /// complete. Digests, filename templates and extraction are not applied to segmented downloads.
+    pub async fn download_segmented(&self, download: &Download, segments: u8) -> Summary {
+        let named = self.named(download);
+        let download: &Download = &named;
+        let mut summary = Summary::new(download.clone()).with_path(self.output_path(download));
+        let batch = match self.batch(None) {
+            Ok(batch) => batch,
+            Err(err) => return summary.fail(err),
+        };
+        let (client, routed) = match self.route(&batch, download) {
+            Ok(route) => route,
+            Err(err) => return summary.fail(err),
+        };
+        let probe = match routed.fetch_range(&client).await {
+            Ok(probe) => probe,
+            Err(err) => return fail_request(summary, &err),
+        };
+        let size = match probe.size {
+            Some(size) if probe.resume && segments > 1 && size >= segments as u64 && !self.compressed() => size,
+            _ => return self.fetch(&batch, download).await,
+        };
+
+        let output_path = summary.path.clone();
+        if let Err(err) = self.symlink_policy.apply(&output_path) {
+            return summary.fail(err);
+        }
+        if let Some(err) = directory_error(&output_path) {
+            return summary.fail(err);
+        }
+        if let Some(folder) = output_path.parent() {
+            if let Err(err) = fs::create_dir_all(folder) {
+                return summary.fail(err);
+            }
+        }
+        let writing = match self.staging_path(&output_path, ".segmented") {
+            Ok(writing) if self.temp_dir.is_some() => writing,
+            Ok(_) => output_path.clone(),
+            Err(err) => return summary.fail(err),
+        };
+        let file = match PositionedFile::create(&writing, size).await {
+            Ok(file) => file,
+            Err(err) => return summary.fail(err),
+        };
+        let segment_size = size.div_ceil(segments as u64);
+        let ranges = (0..size).step_by(segment_size as usize)
+            .map(|start| ByteRange::new(start, (start + segment_size).min(size) - 1));
+        let results: Vec<_> = stream::iter(ranges)
+            .map(|range| self.fetch_segment(&client, &routed, &file, range))
+            .buffer_unordered(self.concurrent_downloads.max(1) as usize)
+            .collect()
+            .await;
+        let result = match results.into_iter().find_map(|result| result.err()) {
+            Some(err) => Err(err),
+            None => file.sync_data().await.map_err(|err| err.to_string()),
+        };
+        let result = result.and_then(|_| if writing != output_path {
+            finalize::move_file(&writing, &output_path).map_err(|err| err.to_string())
+        } else {
+            Ok(())
+        });
+        if let Err(err) = result {
+            discard(&mut summary, &writing);
+            return summary.fail(err);
+        }
+        Summary { size, etag: probe.etag, ..summary }.with_status(Status::Success)
+    }
+
+    /// Fetch one range of a segmented download and write it at its offset
+    pub(crate) async fn fetch_segment(&self, client: &ClientWithMiddleware, download: &Download, file: &PositionedFile,
+                                      range: ByteRange) -> Result<(), String> {
+        tracing::debug!("Fetching segment {} of Url: {}", range.to_header(), download.redacted_url());
+        let request = download.request(client, Method::GET).header(RANGE, range.to_header());
+        let response = request.send().await.map_err(|err| request_failure(&err))?;
+        response.error_for_status_ref().map_err(|err| err.to_string())?;
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(format!("the server ignored the range request and returned {}", response.status()));
+        }
+
+        let mut offset = range.start;
+        let mut stream = response.bytes_stream();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(|err| err.to_string())?;
+            let len = chunk.len() as u64;
+            if offset + len > range.end + 1 {
+                return Err(format!("the server returned more than the range {}-{}", range.start, range.end));
+            }
+            file.write_at(offset, chunk).await.map_err(|err| err.to_string())?;
+            offset += len;
+        }
+        if offset != range.end + 1 {
+            return Err(format!("incomplete segment: got {} of {} bytes", offset - range.start, range.size()));
+        }
+        Ok(())
+    }
+
+    /// Fetch the ranges of a download one after the other, each written at its offset into the
+    /// output file. A failed range leaves the ranges written before it.
+    pub(crate) async fn fetch_ranges(&self, client: &ClientWithMiddleware, download: &Download, mut summary: Summary,
+                                     output_path: &Path) -> Summary {
+        if let Some(folder) = output_path.parent() {
+            if let Err(err) = fs::create_dir_all(folder) {
+                return summary.fail(err);
+            }
+        }
+        let file = match PositionedFile::open(output_path).await {
+            Ok(file) => file,
+            Err(err) => return summary.fail(err),
+        };
+        for range in &download.ranges {
+            if let Err(err) = self.fetch_segment(client, download, &file, *range).await {
+                return summary.fail(err);
+            }
+            summary.size += range.size();
+        }
+        if let Err(err) = file.sync_data().await {
+            return summary.fail(err);
+        }
+        summary.with_status(Status::Success)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::download::{Download, Status};
+    use crate::downloader::DownloaderBuilder;
+    use crate::testing::{response, temp_dir, TestServer};
+
+    #[tokio::test]
+    async fn test_download_segmented() {
+        let content: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let body = content.clone();
+        let server = TestServer::start(move |request| {
+            let range = request.header("range").and_then(|range| range.strip_prefix("bytes="))
+                .and_then(|range| range.split_once('-'))
+                .map(|(start, end)| (start.parse::<usize>().unwrap(), end.parse::<usize>().unwrap()));
+            match range {
+                Some((start, end)) => {
+                    let content_range = format!("bytes {}-{}/{}", start, end, body.len());
+                    let headers = [("Accept-Ranges", "bytes"), ("Content-Range", content_range.as_str())];
+                    response(request, "206 Partial Content", &headers, &body[start..=end])
+                }
+                None => response(request, "200 OK", &[("Accept-Ranges", "bytes")], &body),
+            }
+        }).await;
+        let directory = temp_dir("download-segmented");
+        let downloader = DownloaderBuilder::new().directory(&directory).build();
+
+        let download = Download::try_from(server.url("/file.bin").as_str()).unwrap();
+        let summary = downloader.download_segmented(&download, 3).await;
+        assert_eq!((&Status::Success, 1000), (summary.status(), summary.size()));
+        assert_eq!(content, std::fs::read(directory.join("file.bin")).unwrap());
+        assert_eq!(3, server.requests().iter().filter(|request| request.header("range").is_some()).count());
+    }
+
+    #[tokio::test]
+    async fn test_with_ranges() {
+        let server = TestServer::start(|request| {
+            let body = b"0123456789";
+            let range = request.header("range").and_then(|range| range.strip_prefix("bytes="))
+                .and_then(|range| range.split_once('-'))
+                .map(|(start, end)| (start.parse::<usize>().unwrap(), end.parse::<usize>().unwrap()));
+            match range {
+                Some((start, end)) => {
+                    let content_range = format!("bytes {}-{}/{}", start, end, body.len());
+                    response(request, "206 Partial Content", &[("Content-Range", content_range.as_str())], &body[start..=end])
+                }
+                None => response(request, "200 OK", &[], body),
+            }
+        }).await;
+        let directory = temp_dir("with-ranges");
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("file.bin"), b"xxxxxxxxxx").unwrap();
+        let downloader = DownloaderBuilder::new().directory(&directory).build();
+
+        let download = Download::try_from(server.url("/file.bin").as_str()).unwrap().with_ranges(vec![(1, 2), (6, 8)]);
+        let report = downloader.download([download]).await.unwrap();
+        assert_eq!((&Status::Success, 5), (report[0].status(), report[0].size()));
+        assert_eq!(b"x12xxx678x", &std::fs::read(directory.join("file.bin")).unwrap()[..]);
+        let ranges: Vec<_> = server.requests().iter().filter_map(|request| request.header("range").map(str::to_string)).collect();
+        assert_eq!(vec!["bytes=1-2", "bytes=6-8"], ranges);
+    }
+}
@@ -0,0 +1,98 @@
+//! Interrupting a batch with Ctrl-C
+//!
+//! The first Ctrl-C cancels the downloads of the batch like `DownloadControl::cancel`, they
+//! fail with `cancelled` and keep their partial files for resuming. The second one aborts the
+//! batch at once, dropping its downloads where they are, and the batch fails with
+//! `Error::Interrupted`, leaving the caller to exit with the status 130 of an interrupted process.
+//!
+//! The handler only listens while a batch runs, but tokio never uninstalls its signal handler:
+//! once a batch listened, `SIGINT` stays handled for the life of the process and a Ctrl-C
+//! between batches is ignored instead of terminating it. Applications that need to exit then
+//! listen to Ctrl-C themselves, with `tokio::signal::ctrl_c`, or leave the handler disabled.
+
+use std::future::Future;
+use std::io;
+
+use snafu::location;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::control::DownloadControl;
+use crate::error::{InterruptedSnafu, Result};
+
+/// Listens to Ctrl-C for a batch until dropped
+pub(crate) struct Interrupt {
+    listener: JoinHandle<()>,
+    aborted: CancellationToken,
+}
+
+impl Interrupt {
+    pub(crate) fn listen(control: DownloadControl) -> Self {
+        let aborted = CancellationToken::new();
+        let abort = aborted.clone();
+        let interrupts = interrupts();
+        let listener = tokio::spawn(async move {
+            let mut interrupts = match interrupts {
+                Ok(interrupts) => interrupts,
+                Err(err) => {
+                    tracing::warn!("Failed to listen to Ctrl-C: {}", err);
+                    return;
+                }
+            };
+            if interrupts.recv().await.is_none() {
+                return;
+            }
+            tracing::warn!("Interrupted, cancelling the downloads, interrupt again to abort");
+            control.cancel();
+            if interrupts.recv().await.is_some() {
+                tracing::warn!("Interrupted again, aborting the batch");
+                abort.cancel();
+            }
+        });
+        Self { listener, aborted }
+    }
+
+    /// Run `batch` until it completes or the second Ctrl-C aborts it
+    pub(crate) async fn run<T>(&self, batch: impl Future<Output = T>) -> Result<T> {
+        tokio::select! {
+            biased;
+            _ = self.aborted.cancelled() => InterruptedSnafu { location: location!() }.fail(),
+            output = batch => Ok(output),
+        }
+    }
+}
+
+impl Drop for Interrupt {
+    fn drop(&mut self) {
+        self.listener.abort();
+    }
+}
+
+#[cfg(unix)]
+fn interrupts() -> io::Result<tokio::signal::unix::Signal> {
+    tokio::signal::unix::signal(tokio::signal::unix::SignalKind::interrupt())
+}
+
+#[cfg(windows)]
+fn interrupts() -> io::Result<tokio::signal::windows::CtrlC> {
+    tokio::signal::windows::ctrl_c()
+}
+
+#[cfg(test)]
+mod test {
+    use std::future;
+
+    use crate::control::DownloadControl;
+    use crate::error::Error;
+    use crate::signal::Interrupt;
+
+    #[tokio::test]
+    async fn test_abort() {
+        let interrupt = Interrupt::listen(DownloadControl::new());
+        assert_eq!(Some(7), interrupt.run(async { 7 }).await.ok());
+        // The second Ctrl-C drops the batch where it is instead of exiting the process
+        interrupt.aborted.cancel();
+        let aborted = interrupt.run(future::pending::<()>()).await;
+        assert!(matches!(aborted, Err(Error::Interrupted { .. })));
+    }
+}
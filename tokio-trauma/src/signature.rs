@@ -0,0 +1,207 @@
+//! Detached minisign signatures checked once a download completes
+//!
+//! A download given a signature with `Download::with_signature` is verified after its content
+//! was written and passed its checksums: the signature file is fetched, e.g. `<file>.minisig`
+//! next to the file or a `file://` url of a local signature when the `file` scheme is allowed,
+//! and checked against the ed25519 public key. A file failing the check is removed and the
+//! download fails. Signature files larger than 8 KiB are rejected.
+//!
+//! The public key is the base64 key line of a minisign public key, e.g. `RWQ...`, or the whole
+//! content of its `.pub` file. Both the prehashed signatures minisign creates by default and the
+//! legacy ones are accepted, the trusted comment is checked with the global signature.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use futures_util::StreamExt;
+use minisign_verify::{PublicKey, Signature};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncReadExt;
+use url::Url;
+
+use crate::download::{Download, Summary};
+use crate::downloader::{discard, Batch, Downloader};
+
+/// Size of the reads hashing a prehashed signature
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Largest signature file read, a minisign signature takes a few hundred bytes
+const MAX_SIZE: u64 = 8 * 1024;
+
+/// A detached signature of a download and the key it must be signed with
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DetachedSignature {
+    pub(crate) url: Url,
+    pub(crate) public_key: String,
+}
+
+impl DetachedSignature {
+    pub fn new(url: Url, public_key: impl Into<String>) -> Self {
+        Self { url, public_key: public_key.into() }
+    }
+
+    /// The url of the minisign signature file
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    pub fn public_key(&self) -> &str {
+        &self.public_key
+    }
+}
+
+/// Verify the content of `path` against the minisign `signature` made with `public_key`
+pub(crate) fn verify(path: &Path, signature: &str, public_key: &str) -> Result<(), String> {
+    let public_key = match public_key.trim() {
+        key if key.contains('\n') => PublicKey::decode(key),
+        key => PublicKey::from_base64(key),
+    };
+    let public_key = public_key.map_err(|err| format!("invalid minisign public key: {}", err))?;
+    let signature = Signature::decode(signature).map_err(|err| format!("invalid minisign signature: {}", err))?;
+    let read = |err: io::Error| format!("{}: {}", path.display(), err);
+    let mut file = File::open(path).map_err(read)?;
+    let verified = match public_key.verify_stream(&signature) {
+        Ok(mut verifier) => {
+            let mut buffer = vec![0; READ_BUFFER_SIZE];
+            loop {
+                let len = file.read(&mut buffer).map_err(read)?;
+                if len == 0 {
+                    break;
+                }
+                verifier.update(&buffer[..len]);
+            }
+            verifier.finalize()
+        }
+        // A legacy signature covers the content itself, not its hash
+        Err(_) => {
+            let mut content = Vec::new();
+            file.read_to_end(&mut content).map_err(read)?;
+            public_key.verify(&content, &signature, true)
+        }
+    };
+    verified.map_err(|err| format!("invalid signature: {}", err))
+}
+
+impl Downloader {
+    /// Fetch the signature and verify the file of a successful download with it
+    ///
+    /// A file that can't be verified, because its signature is invalid or can't be fetched, is
+    /// removed so no unverified file is left for the next run to take as complete.
+    pub(crate) async fn verify_signature(&self, batch: &Batch, mut summary: Summary, detached: &DetachedSignature) -> Summary {
+        let path = summary.path.clone();
+        let checked = match self.fetch_signature(batch, &summary.download, &detached.url).await {
+            Ok(text) => {
+                let (path, public_key) = (path.clone(), detached.public_key.clone());
+                tokio::task::spawn_blocking(move || verify(&path, &text, &public_key)).await
+                    .unwrap_or_else(|err| Err(err.to_string()))
+            }
+            Err(err) => Err(format!("failed to fetch the signature {}: {}", detached.url, err)),
+        };
+        match checked {
+            Ok(()) => summary,
+            Err(err) => {
+                tracing::warn!("Removing {:?} failing its signature check: {}", path, err);
+                discard(&mut summary, &path);
+                summary.fail(err)
+            }
+        }
+    }
+
+    /// The text of the signature at `url`, read from disk for a `file://` url
+    ///
+    /// The url must have an allowed scheme like the downloads, and the signature is read up to
+    /// `MAX_SIZE` bytes.
+    async fn fetch_signature(&self, batch: &Batch, download: &Download, url: &Url) -> Result<String, String> {
+        self.check_scheme(url).map_err(|err| err.to_string())?;
+        let mut body = Vec::new();
+        if url.scheme() == "file" {
+            let path = url.to_file_path().map_err(|_| format!("{} is not a local path", url))?;
+            let file = tokio::fs::File::open(&path).await.map_err(|err| err.to_string())?;
+            file.take(MAX_SIZE + 1).read_to_end(&mut body).await.map_err(|err| err.to_string())?;
+        } else {
+            let client = batch.client_for(self, download);
+            let response = client.get(url.clone()).send().await
+                .map_err(|err| err.to_string())?;
+            let response = response.error_for_status().map_err(|err| err.to_string())?;
+            let mut stream = response.bytes_stream();
+            while let Some(chunk) = stream.next().await {
+                body.extend_from_slice(&chunk.map_err(|err| err.to_string())?);
+                if body.len() as u64 > MAX_SIZE {
+                    break;
+                }
+            }
+        }
+        if body.len() as u64 > MAX_SIZE {
+            return Err(format!("the signature is larger than {} bytes", MAX_SIZE));
+        }
+        String::from_utf8(body).map_err(|err| err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use url::Url;
+
+    use crate::download::{Download, Status};
+    use crate::downloader::DownloaderBuilder;
+    use crate::signature::verify;
+    use crate::testing::{response, temp_dir, TestServer, MINISIGN_PUBLIC_KEY, MINISIGN_SIGNATURE};
+
+    #[test]
+    fn test_verify() {
+        let directory = temp_dir("signature-verify");
+        let path = directory.join("file.bin");
+        fs::write(&path, "signed content").unwrap();
+        assert_eq!(Ok(()), verify(&path, MINISIGN_SIGNATURE, MINISIGN_PUBLIC_KEY));
+        let key_file = format!("untrusted comment: minisign public key 0807060504030201\n{}\n", MINISIGN_PUBLIC_KEY);
+        assert_eq!(Ok(()), verify(&path, MINISIGN_SIGNATURE, &key_file));
+
+        fs::write(&path, "tampered content").unwrap();
+        assert!(verify(&path, MINISIGN_SIGNATURE, MINISIGN_PUBLIC_KEY).unwrap_err().starts_with("invalid signature"));
+        assert!(verify(&path, "garbage", MINISIGN_PUBLIC_KEY).unwrap_err().starts_with("invalid minisign signature"));
+        assert!(verify(&path, MINISIGN_SIGNATURE, "garbage").unwrap_err().starts_with("invalid minisign public key"));
+    }
+
+    #[tokio::test]
+    async fn test_signature() {
+        let server = TestServer::start(|request| match request.path.as_str() {
+            "/file.bin.minisig" => response(request, "200 OK", &[], MINISIGN_SIGNATURE.as_bytes()),
+            "/large.bin.minisig" => response(request, "200 OK", &[], &[b'a'; 100_000]),
+            "/file.bin" => response(request, "200 OK", &[], b"signed content"),
+            _ => response(request, "200 OK", &[], b"tampered content"),
+        }).await;
+        let directory = temp_dir("signature");
+        let downloader = DownloaderBuilder::new()
+            .directory(&directory)
+            .ordered(true)
+            .build();
+
+        let signature_url = Url::parse(&server.url("/file.bin.minisig")).unwrap();
+        let downloads = ["/file.bin", "/tampered.bin"].map(|path| Download::try_from(server.url(path).as_str()).unwrap()
+            .with_signature(signature_url.clone(), MINISIGN_PUBLIC_KEY));
+        let report = downloader.download(downloads).await.unwrap();
+        assert_eq!(&Status::Success, report[0].status());
+        assert!(matches!(report[1].status(), Status::Fail(message) if message.starts_with("invalid signature")));
+        assert!(directory.join("file.bin").exists());
+        assert!(!directory.join("tampered.bin").exists());
+
+        // The signature is fetched like a download, with an allowed scheme and a bounded size
+        fs::write(directory.join("local.minisig"), MINISIGN_SIGNATURE).unwrap();
+        let signatures = [
+            Url::from_file_path(directory.join("local.minisig")).unwrap(),
+            Url::parse(&server.url("/large.bin.minisig")).unwrap(),
+        ];
+        let downloads = ["/local.bin", "/large.bin"].into_iter().zip(signatures)
+            .map(|(path, url)| Download::try_from(server.url(path).as_str()).unwrap().with_signature(url, MINISIGN_PUBLIC_KEY))
+            .collect::<Vec<_>>();
+        let report = downloader.download(downloads).await.unwrap();
+        assert!(matches!(report[0].status(), Status::Fail(message) if message.contains("Disallowed url scheme: file")));
+        assert!(matches!(report[1].status(), Status::Fail(message) if message.contains("larger than")));
+        assert!(!directory.join("local.bin").exists() && !directory.join("large.bin").exists());
+    }
+}
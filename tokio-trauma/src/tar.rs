@@ -0,0 +1,164 @@
+//! Batches streamed into a tar archive instead of written as files
+
+use std::io;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use futures_util::StreamExt;
+use reqwest::Method;
+use snafu::{location, Location, ResultExt};
+use tokio::io::{AsyncReadExt, AsyncWrite};
+use tokio_util::io::StreamReader;
+
+use crate::attempts::Attempts;
+use crate::download::{Download, Status, Summary};
+use crate::downloader::{fail_request, Batch, Downloader};
+use crate::error::{IoSnafu, Result, UnknownEntrySizeSnafu};
+use crate::progress::ProgressEvent;
+use crate::report::DownloadReport;
+
+impl Downloader {
+    /// Stream the downloads one after the other into a tar archive written to `writer`, each
+    /// download becoming an entry named by its filename.
+    ///
+    /// A tar header carries the size of its entry, so a response without `Content-Length` is
+    /// buffered in memory when `buffer_unknown` is set and fails the whole archive otherwise, as
+    /// does a body interrupted or shorter than its `Content-Length` after its header was written.
+    /// Failed requests are reported in their summary and left out of the archive. The writer is
+    /// returned once the archive is finished.
+    pub async fn download_to_tar<W>(&self, downloads: &[Download], writer: W,
+                                    buffer_unknown: bool) -> Result<(W, DownloadReport)>
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let batch = self.batch(None)?;
+        let mut archive = tokio_tar::Builder::new(writer);
+        let mut summaries = Vec::with_capacity(downloads.len());
+        for download in downloads {
+            let download = self.named(download);
+            let summary = self.append_entry(&batch, &mut archive, &download, buffer_unknown).await?;
+            self.progress(ProgressEvent::Finished { summary: &summary });
+            summaries.push(summary);
+        }
+        let writer = archive.into_inner().await
+            .context(IoSnafu { path: PathBuf::new(), location: location!() })?;
+        Ok((writer, DownloadReport::new(summaries)))
+    }
+
+    /// Append the response body of `download` to the archive, the error fails the whole archive
+    async fn append_entry<W>(&self, batch: &Batch, archive: &mut tokio_tar::Builder<W>, download: &Download,
+                             buffer_unknown: bool) -> Result<Summary>
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let path = PathBuf::from(&download.filename);
+        let mut summary = Summary::new(download.clone()).with_path(path.clone());
+        let (client, routed) = match self.route(batch, download) {
+            Ok(route) => route,
+            Err(err) => return Ok(summary.fail(err)),
+        };
+        tracing::debug!("Fetching Url: {}", download.redacted_url());
+        let attempts = Attempts::default();
+        let sent = routed.request(&client, Method::GET).with_extension(attempts.clone()).send().await;
+        summary.attempts = attempts.count();
+        let response = match sent {
+            Ok(response) => response,
+            Err(err) => return Ok(fail_request(summary, &err)),
+        };
+        summary.status_code = Some(response.status());
+        if let Err(err) = response.error_for_status_ref() {
+            return Ok(summary.fail(err));
+        }
+        if let Err(message) = self.gate(&response) {
+            return Ok(summary.fail(message));
+        }
+
+        let mut header = tokio_tar::Header::new_gnu();
+        header.set_mode(0o644);
+        header.set_mtime(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs());
+        let appended = match response.content_length() {
+            Some(size) => {
+                header.set_size(size);
+                summary.size = size;
+                let mut received = 0;
+                let body = response.bytes_stream()
+                    .map(|chunk| chunk.map_err(io::Error::other))
+                    .inspect(|chunk| received += chunk.as_ref().map_or(0, |chunk| chunk.len() as u64));
+                let appended = archive.append_data(&mut header, &path, StreamReader::new(body).take(size)).await;
+                // The archive pads a short entry to its size, misaligning the entries after it
+                appended.and_then(|()| if received < size {
+                    Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                        format!("the response of {} ended after {} of {} bytes", download.redacted_url(), received, size)))
+                } else {
+                    Ok(())
+                })
+            }
+            None if buffer_unknown => {
+                let body = match response.bytes().await {
+                    Ok(body) => body,
+                    Err(err) => return Ok(summary.fail(err)),
+                };
+                header.set_size(body.len() as u64);
+                summary.size = body.len() as u64;
+                archive.append_data(&mut header, &path, &body[..]).await
+            }
+            None => return UnknownEntrySizeSnafu { filename: download.filename.clone(), location: location!() }.fail(),
+        };
+        appended.context(IoSnafu { path, location: location!() })?;
+        Ok(summary.with_status(Status::Success))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures_util::StreamExt;
+    use tokio::io::AsyncReadExt;
+
+    use crate::download::{Download, Status};
+    use crate::downloader::DownloaderBuilder;
+    use crate::testing::{response, TestServer};
+
+    #[tokio::test]
+    async fn test_download_to_tar() {
+        let server = TestServer::start(|request| match request.path.as_str() {
+            "/sized.txt" => response(request, "200 OK", &[], b"sized"),
+            "/chunked.txt" => response(request, "200 OK", &[("Transfer-Encoding", "chunked")], b"7\r\nchunked\r\n0\r\n\r\n"),
+            _ => response(request, "404 Not Found", &[], b""),
+        }).await;
+        let downloader = DownloaderBuilder::new().build();
+        let downloads = vec![
+            Download::try_from(server.url("/sized.txt").as_str()).unwrap(),
+            Download::try_from(server.url("/missing.txt").as_str()).unwrap(),
+            Download::try_from(server.url("/chunked.txt").as_str()).unwrap(),
+        ];
+
+        let (tar, report) = downloader.download_to_tar(&downloads, Vec::new(), true).await.unwrap();
+        assert_eq!(&Status::Success, report[0].status());
+        assert!(matches!(report[1].status(), Status::Fail(_)));
+        assert_eq!(&Status::Success, report[2].status());
+        let mut archive = tokio_tar::Archive::new(&tar[..]);
+        let mut entries = archive.entries().unwrap();
+        let mut contents = Vec::new();
+        while let Some(entry) = entries.next().await {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_string_lossy().into_owned();
+            let mut content = String::new();
+            entry.read_to_string(&mut content).await.unwrap();
+            contents.push((path, content));
+        }
+        assert_eq!(vec![("sized.txt".to_string(), "sized".to_string()), ("chunked.txt".into(), "chunked".into())], contents);
+
+        assert!(downloader.download_to_tar(&downloads, Vec::new(), false).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_download_to_tar_short_body() {
+        // The connection closes after 5 of the 10 announced bytes
+        let server = TestServer::start(|request| response(request, "200 OK", &[("Content-Length", "10")], b"short")).await;
+        let downloader = DownloaderBuilder::new().build();
+        let downloads = vec![Download::try_from(server.url("/short.txt").as_str()).unwrap()];
+
+        let err = downloader.download_to_tar(&downloads, Vec::new(), false).await.unwrap_err();
+        assert!(matches!(err, crate::error::Error::Io { .. }));
+    }
+}
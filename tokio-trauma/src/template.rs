@@ -0,0 +1,131 @@
+//! Filename templates expanded after a download completes
+//!
+//! Supported placeholders are `{filename}`, `{stem}`, `{ext}`, `{host}`, `{date}` (UTC, `YYYY-MM-DD`)
+//! and `{content_type}`. Unknown placeholders are left literally in the result.
+
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use url::Url;
+
+use crate::host;
+
+/// Values available to a template expansion
+pub(crate) struct Variables<'a> {
+    pub(crate) filename: &'a str,
+    pub(crate) url: &'a Url,
+    pub(crate) content_type: Option<&'a str>,
+    pub(crate) now: SystemTime,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct FilenameTemplate(String);
+
+impl FilenameTemplate {
+    pub(crate) fn new(template: String) -> Self {
+        Self(template)
+    }
+
+    /// Expand the placeholders and sanitize the result into a valid file name
+    pub(crate) fn expand(&self, vars: &Variables) -> String {
+        let mut output = String::with_capacity(self.0.len());
+        let mut rest = self.0.as_str();
+        while let Some(start) = rest.find('{') {
+            let Some(len) = rest[start..].find('}') else {
+                break;
+            };
+            output.push_str(&rest[..start]);
+            let placeholder = &rest[start + 1..start + len];
+            match Self::value(placeholder, vars) {
+                Some(value) => output.push_str(&value),
+                None => output.push_str(&rest[start..=start + len]),
+            }
+            rest = &rest[start + len + 1..];
+        }
+        output.push_str(rest);
+        sanitize(&output)
+    }
+
+    fn value(placeholder: &str, vars: &Variables) -> Option<String> {
+        let path = Path::new(vars.filename);
+        let value = match placeholder {
+            "filename" => vars.filename.to_string(),
+            "stem" => path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default(),
+            "ext" => path.extension().map(|ext| ext.to_string_lossy().into_owned()).unwrap_or_default(),
+            "host" => host::url_host(vars.url).unwrap_or_default(),
+            "date" => date(vars.now),
+            "content_type" => vars.content_type
+                .map(|content_type| content_type.split(';').next().unwrap_or_default().trim().to_string())
+                .unwrap_or_default(),
+            _ => return None,
+        };
+        Some(value)
+    }
+}
+
+/// Replace characters that are illegal in file names on common platforms
+pub(crate) fn sanitize(name: &str) -> String {
+    let name: String = name.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    match name.trim_matches(|c: char| c == '.' || c.is_whitespace()) {
+        "" => String::from("_"),
+        trimmed => trimmed.to_string(),
+    }
+}
+
+/// Format the UTC date of `time` as `YYYY-MM-DD`
+fn date(time: SystemTime) -> String {
+    let days = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 86400;
+    // Civil-from-days algorithm by Howard Hinnant
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use url::Url;
+
+    use crate::template::{date, sanitize, FilenameTemplate, Variables};
+
+    #[test]
+    fn test_expand() {
+        let url = Url::parse("https://cdn.example.com/files/archive.tar.gz").unwrap();
+        let vars = Variables {
+            filename: "archive.tar.gz",
+            url: &url,
+            content_type: Some("application/gzip; charset=binary"),
+            now: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        };
+        let template = FilenameTemplate::new("{date}-{host}-{filename}".into());
+        assert_eq!("2023-11-14-cdn.example.com-archive.tar.gz", template.expand(&vars));
+        let template = FilenameTemplate::new("{stem}.{ext} {content_type} {unknown}".into());
+        assert_eq!("archive.tar.gz application_gzip {unknown}", template.expand(&vars));
+    }
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!("a_b_c", sanitize("a/b\\c"));
+        assert_eq!("_", sanitize(".."));
+    }
+
+    #[test]
+    fn test_date() {
+        assert_eq!("1970-01-01", date(UNIX_EPOCH));
+        assert_eq!("2000-02-29", date(UNIX_EPOCH + Duration::from_secs(951_782_400)));
+    }
+}
@@ -0,0 +1,151 @@
+//! Minimal http server for tests
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// Request head received by the test server
+#[derive(Debug, Clone)]
+pub(crate) struct Request {
+    pub(crate) method: String,
+    pub(crate) path: String,
+    pub(crate) headers: Vec<(String, String)>,
+}
+
+impl Request {
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Serve every connection with the response produced by `handler`
+pub(crate) struct TestServer {
+    pub(crate) addr: SocketAddr,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl TestServer {
+    pub(crate) async fn start(handler: impl Fn(&Request) -> Vec<u8> + Send + Sync + 'static) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let handler = Arc::new(handler);
+
+        let received = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let handler = handler.clone();
+                let received = received.clone();
+                tokio::spawn(async move {
+                    let mut buf = Vec::new();
+                    let mut chunk = [0; 1024];
+                    while !buf.windows(4).any(|window| window == b"\r\n\r\n") {
+                        match stream.read(&mut chunk).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                        }
+                    }
+                    let request = parse(&String::from_utf8_lossy(&buf));
+                    let response = handler(&request);
+                    received.lock().unwrap().push(request);
+                    let _ = stream.write_all(&response).await;
+                    let _ = stream.shutdown().await;
+                });
+            }
+        });
+        Self { addr, requests }
+    }
+
+    pub(crate) fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    pub(crate) fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+fn parse(head: &str) -> Request {
+    let mut lines = head.split("\r\n");
+    let mut start = lines.next().unwrap_or_default().split(' ');
+    let method = start.next().unwrap_or_default().to_string();
+    let path = start.next().unwrap_or_default().to_string();
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+    Request { method, path, headers }
+}
+
+/// Build a raw http response, the body is left out for `HEAD` requests
+pub(crate) fn response(request: &Request, status: &str, headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+    let mut response = format!("HTTP/1.1 {}\r\nConnection: close\r\n", status);
+    for (name, value) in headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    let framed = headers.iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("content-length") || name.eq_ignore_ascii_case("transfer-encoding"));
+    if !framed {
+        response.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    response.push_str("\r\n");
+
+    let mut response = response.into_bytes();
+    if request.method != "HEAD" {
+        response.extend_from_slice(body);
+    }
+    response
+}
+
+/// Serve `body` to every request over HTTP/2 with prior knowledge, multiplexing the requests of
+/// a connection as streams
+pub(crate) async fn start_h2(body: &'static [u8]) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let Ok(mut connection) = h2::server::handshake(stream).await else {
+                    return;
+                };
+                while let Some(Ok((request, mut respond))) = connection.accept().await {
+                    let head = request.method() == http::Method::HEAD;
+                    let response = http::Response::builder()
+                        .header(http::header::CONTENT_LENGTH, body.len())
+                        .body(())
+                        .unwrap();
+                    if let Ok(mut stream) = respond.send_response(response, head) {
+                        if !head {
+                            let _ = stream.send_data(Bytes::from_static(body), true);
+                        }
+                    }
+                }
+            });
+        }
+    });
+    addr
+}
+
+/// A fresh directory under the system temp directory
+pub(crate) fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("tokio-trauma-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Public key of the secret key that signed `MINISIGN_SIGNATURE`
+#[cfg(feature = "minisign")]
+pub(crate) const MINISIGN_PUBLIC_KEY: &str = "RWQBAgMEBQYHCAOhB7/zzhC+HXDdGOdLwJln5NYwm6UNXx3chmQSVTG4";
+/// Prehashed signature of `signed content`
+#[cfg(feature = "minisign")]
+pub(crate) const MINISIGN_SIGNATURE: &str = "untrusted comment: signature from minisign secret key\n\
+    RUQBAgMEBQYHCMXtZQANFMRugWMlVvY/sHGgAKslGbHB4Bs/dRl12cSm5wsboEG4AksF2ixH8vZPRitnWOWALJtB3oex/MjzgA0=\n\
+    trusted comment: timestamp:0\tfile:file.bin\n\
+    Ao5Olat5xcvQV2viq8HbeCRqPS9VaCgJgV70nrOCMrYp943+V7wLKThDRV848xw56J7JhfRs4gLueZ73gXGKCQ==\n";
@@ -0,0 +1,129 @@
+//! Token buckets capping the write rate of a single download and of the downloads of a host
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::clock::Clock;
+
+/// Bucket refilled at `rate` bytes per second and holding at most one second worth of bytes
+///
+/// A chunk larger than the bucket goes into debt, the next chunks then wait until it is paid.
+pub(crate) struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    refilled: Instant,
+    clock: Clock,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(rate: u64, clock: Clock) -> Self {
+        let rate = rate.max(1);
+        Self { rate, tokens: rate as f64, refilled: clock.now(), clock }
+    }
+
+    /// Take `bytes` from the bucket, waiting for them when it is empty
+    ///
+    /// Returns whether the caller was throttled.
+    pub(crate) async fn acquire(&mut self, bytes: u64) -> bool {
+        let now = self.clock.now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.refilled = now;
+
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            return false;
+        }
+        self.clock.sleep(Duration::from_secs_f64(-self.tokens / self.rate as f64)).await;
+        true
+    }
+}
+
+/// The buckets shared by the downloads of each host, keyed by normalized host
+#[derive(Default)]
+pub(crate) struct HostBuckets(Mutex<HashMap<String, Arc<tokio::sync::Mutex<TokenBucket>>>>);
+
+impl HostBuckets {
+    /// The bucket of `host`, created at `rate` by its first download
+    pub(crate) fn get(&self, host: String, rate: u64, clock: &Clock) -> Arc<tokio::sync::Mutex<TokenBucket>> {
+        let mut buckets = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        buckets.entry(host)
+            .or_insert_with(|| Arc::new(tokio::sync::Mutex::new(TokenBucket::new(rate, clock.clone()))))
+            .clone()
+    }
+}
+
+/// The limits of a download, its own bucket and the bucket of its host
+///
+/// Bytes are taken from every bucket in turn, so the most restrictive one sets the rate.
+pub(crate) struct Throttle {
+    pub(crate) own: Option<TokenBucket>,
+    /// held while waiting, the downloads of the host take their bytes one after another
+    pub(crate) host: Option<Arc<tokio::sync::Mutex<TokenBucket>>>,
+}
+
+impl Throttle {
+    /// Take `bytes` from the buckets, waiting for them when one is empty
+    ///
+    /// Returns whether the caller was throttled.
+    pub(crate) async fn acquire(&mut self, bytes: u64) -> bool {
+        let mut throttled = false;
+        if let Some(own) = self.own.as_mut() {
+            throttled |= own.acquire(bytes).await;
+        }
+        if let Some(host) = &self.host {
+            throttled |= host.lock().await.acquire(bytes).await;
+        }
+        throttled
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use futures_util::FutureExt;
+
+    use crate::clock::Clock;
+    use crate::throttle::{HostBuckets, Throttle, TokenBucket};
+
+    #[tokio::test]
+    async fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(1000, Clock::Real);
+        let started = Instant::now();
+        assert!(!bucket.acquire(1000).await);
+        assert!(bucket.acquire(200).await);
+        assert!(started.elapsed() >= Duration::from_millis(180));
+    }
+
+    #[tokio::test]
+    async fn test_token_bucket_mock_clock() {
+        let (clock, mock) = Clock::mock();
+        let mut bucket = TokenBucket::new(1000, clock);
+        assert_eq!(Some(false), bucket.acquire(1000).now_or_never());
+
+        let mut acquire = Box::pin(bucket.acquire(200));
+        assert!((&mut acquire).now_or_never().is_none());
+        mock.advance(Duration::from_millis(199));
+        assert!((&mut acquire).now_or_never().is_none());
+        mock.advance(Duration::from_millis(1));
+        assert_eq!(Some(true), acquire.now_or_never());
+    }
+
+    #[tokio::test]
+    async fn test_host_buckets() {
+        let (clock, mock) = Clock::mock();
+        let buckets = HostBuckets::default();
+        let throttle = |host: &str| Throttle { own: None, host: Some(buckets.get(host.into(), 1000, &clock)) };
+        let (mut first, mut second, mut other) = (throttle("example.com"), throttle("example.com"), throttle("other.com"));
+        assert_eq!(Some(false), first.acquire(1000).now_or_never());
+        assert_eq!(Some(false), other.acquire(1000).now_or_never());
+
+        // The downloads of a host share its bucket
+        let mut acquire = Box::pin(second.acquire(500));
+        assert!((&mut acquire).now_or_never().is_none());
+        mock.advance(Duration::from_millis(500));
+        assert_eq!(Some(true), acquire.now_or_never());
+    }
+}
@@ -0,0 +1,194 @@
+//! Transforms the response bytes pass through before they are written
+//!
+//! The transforms registered with `DownloaderBuilder::transform` form a pipeline: every chunk of
+//! the response goes through the transforms in the order they were registered, each getting the
+//! output of the previous one, and the output of the last one is written to the file. Once the
+//! response ended, each transform is finished in order and the bytes it still holds go through
+//! the transforms after it. A transform is created for every download attempt, so it can keep
+//! state across the chunks of a download, e.g. a decoder or a digest.
+//!
+//! Checksums, `Content-MD5` and the size checks apply to the response bytes before the
+//! transforms, the file holds the transformed bytes. A partial file of transformed bytes can't
+//! be continued with a range of the resource, so downloads are not resumed with transforms.
+//! Range, suffix and segmented downloads, chunked storage and `file://` copies are written as
+//! received.
+//!
+//! # Examples
+//!
+//! ```
+//! use tokio_trauma::downloader::DownloaderBuilder;
+//! use tokio_trauma::hash::Sha256Digest;
+//! use tokio_trauma::transform::{Hashing, NormalizeNewlines, StreamTransform};
+//!
+//! let downloader = DownloaderBuilder::new()
+//!     .transform(|_| Box::new(NormalizeNewlines::default()) as Box<dyn StreamTransform>)
+//!     .transform(|download| {
+//!         let url = download.url.clone();
+//!         Box::new(Hashing::new(Box::new(Sha256Digest::default()), move |digest| {
+//!             println!("{} normalized to SHA-256 {:02x?}", url, digest);
+//!         })) as Box<dyn StreamTransform>
+//!     })
+//!     .build();
+//! ```
+
+use std::borrow::Cow;
+use std::io;
+
+use crate::download::Download;
+use crate::hash::DigestUpdate;
+
+/// A transformation of the bytes of a download
+pub trait StreamTransform: Send {
+    /// Transform the next chunk, the returned bytes are passed on and may be empty
+    fn transform(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>>;
+
+    /// The bytes still held once the content ended, e.g. the trailer of an encoder
+    fn finish(&mut self) -> io::Result<Vec<u8>> {
+        Ok(Vec::new())
+    }
+}
+
+impl<F> StreamTransform for F
+where
+    F: FnMut(&[u8]) -> Vec<u8> + Send,
+{
+    fn transform(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(self(chunk))
+    }
+}
+
+/// Create the transform of a download for every attempt
+pub(crate) type TransformFactory = dyn Fn(&Download) -> Box<dyn StreamTransform> + Send + Sync;
+
+/// The transforms of a download attempt, applied in order
+pub(crate) struct Pipeline(Vec<Box<dyn StreamTransform>>);
+
+impl Pipeline {
+    pub(crate) fn new(transforms: Vec<Box<dyn StreamTransform>>) -> Self {
+        Self(transforms)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Pass `chunk` through every transform, borrowed as is without transforms
+    pub(crate) fn transform<'a>(&mut self, chunk: &'a [u8]) -> io::Result<Cow<'a, [u8]>> {
+        let mut data = Cow::Borrowed(chunk);
+        for transform in &mut self.0 {
+            data = Cow::Owned(transform.transform(&data)?);
+        }
+        Ok(data)
+    }
+
+    /// Finish every transform in order, the bytes a transform still held go through the next ones
+    pub(crate) fn finish(&mut self) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        for transform in &mut self.0 {
+            let mut output = if data.is_empty() { Vec::new() } else { transform.transform(&data)? };
+            output.extend(transform.finish()?);
+            data = output;
+        }
+        Ok(data)
+    }
+}
+
+/// Passes the bytes through unchanged while hashing them, the digest is given to a callback
+/// once the content ended
+///
+/// The digest covers the bytes at its place in the pipeline, e.g. the decoded content after a
+/// decoder, where a checksum of the download covers the response bytes.
+pub struct Hashing {
+    digest: Option<Box<dyn DigestUpdate>>,
+    on_digest: Option<Box<dyn FnOnce(Vec<u8>) + Send>>,
+}
+
+impl Hashing {
+    pub fn new(digest: Box<dyn DigestUpdate>, on_digest: impl FnOnce(Vec<u8>) + Send + 'static) -> Self {
+        Self { digest: Some(digest), on_digest: Some(Box::new(on_digest)) }
+    }
+}
+
+impl StreamTransform for Hashing {
+    fn transform(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        if let Some(digest) = self.digest.as_mut() {
+            digest.update(chunk);
+        }
+        Ok(chunk.to_vec())
+    }
+
+    fn finish(&mut self) -> io::Result<Vec<u8>> {
+        if let (Some(digest), Some(on_digest)) = (self.digest.take(), self.on_digest.take()) {
+            on_digest(digest.finalize());
+        }
+        Ok(Vec::new())
+    }
+}
+
+/// Converts the `\r\n` line endings of a text to `\n`, also when split across chunks
+#[derive(Debug, Default)]
+pub struct NormalizeNewlines {
+    /// whether the previous chunk ended with a `\r` not passed on yet
+    pending_cr: bool,
+}
+
+impl StreamTransform for NormalizeNewlines {
+    fn transform(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        let mut output = Vec::with_capacity(chunk.len() + 1);
+        let mut bytes = chunk.iter().copied().peekable();
+        if std::mem::take(&mut self.pending_cr) && bytes.peek() != Some(&b'\n') {
+            output.push(b'\r');
+        }
+        while let Some(byte) = bytes.next() {
+            match (byte, bytes.peek()) {
+                (b'\r', Some(b'\n')) => {}
+                (b'\r', None) => self.pending_cr = true,
+                _ => output.push(byte),
+            }
+        }
+        Ok(output)
+    }
+
+    fn finish(&mut self) -> io::Result<Vec<u8>> {
+        Ok(if std::mem::take(&mut self.pending_cr) { vec![b'\r'] } else { Vec::new() })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use crate::hash::{Crc32Digest, DigestUpdate};
+    use crate::transform::{Hashing, NormalizeNewlines, Pipeline, StreamTransform};
+
+    #[test]
+    fn test_normalize_newlines() {
+        let mut normalize = NormalizeNewlines::default();
+        assert_eq!(b"a\nb".to_vec(), normalize.transform(b"a\r\nb\r").unwrap());
+        assert_eq!(b"\nc\rd".to_vec(), normalize.transform(b"\nc\rd\r").unwrap());
+        assert_eq!(b"\r".to_vec(), normalize.finish().unwrap());
+    }
+
+    #[test]
+    fn test_pipeline() {
+        let digest = Arc::new(Mutex::new(Vec::new()));
+        let recorded = digest.clone();
+        let hashing = Hashing::new(Box::new(Crc32Digest::default()), move |digest| *recorded.lock().unwrap() = digest);
+        let uppercase = |chunk: &[u8]| chunk.to_ascii_uppercase();
+        let transforms: Vec<Box<dyn StreamTransform>> = vec![
+            Box::new(NormalizeNewlines::default()),
+            Box::new(uppercase),
+            Box::new(hashing),
+        ];
+        let mut pipeline = Pipeline::new(transforms);
+
+        let mut output = pipeline.transform(b"one\r").unwrap().into_owned();
+        output.extend_from_slice(&pipeline.transform(b"\ntwo\r").unwrap());
+        output.extend(pipeline.finish().unwrap());
+        assert_eq!(b"ONE\nTWO\r".to_vec(), output);
+
+        let mut expected = Box::new(Crc32Digest::default());
+        expected.update(b"ONE\nTWO\r");
+        assert_eq!(expected.finalize(), *digest.lock().unwrap());
+    }
+}